@@ -0,0 +1,222 @@
+//! Evaluation and builds as futures, for use in async runtimes such as tokio.
+//!
+//! The work runs on the thread of a [`SendEvalState`], so that it doesn't block the runtime's workers.
+//! Nix has no way to interrupt an evaluation or build, so dropping a future lets its operation run to completion on that thread, and discards the result.
+
+use crate::eval_state::{EvalState, SourceName};
+use crate::oneshot::{self, Call};
+use crate::send_eval_state::{SendEvalState, SendValue, Worker};
+use crate::value::Value;
+use anyhow::Result;
+use nix_store::store::BuildResult;
+
+/// An async interface to a [`SendEvalState`].
+pub struct AsyncEvalState {
+    inner: SendEvalState,
+}
+impl AsyncEvalState {
+    /// See [`SendEvalState::spawn`].
+    pub fn spawn(make: impl FnOnce() -> Result<EvalState> + Send + 'static) -> Result<Self> {
+        Ok(AsyncEvalState {
+            inner: SendEvalState::spawn(make)?,
+        })
+    }
+
+    /// The blocking interface, e.g. for [`SendEvalState::with`].
+    pub fn blocking(&self) -> &SendEvalState {
+        &self.inner
+    }
+
+    /// Submit `f`. If the future is dropped before `f` is done, its result is dropped when it is.
+    fn submit<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Worker) -> Result<R> + Send + 'static,
+    ) -> Call<R> {
+        let (call, completer) = oneshot::call();
+        let submitted = self
+            .inner
+            .submit(Box::new(move |worker| completer.complete(f(worker))));
+        match submitted {
+            Ok(()) => call,
+            Err(e) => Call::ready(Err(e)),
+        }
+    }
+
+    fn submit_value(
+        &self,
+        f: impl FnOnce(&mut Worker) -> Result<Value> + Send + 'static,
+    ) -> Call<SendValue> {
+        // Made on the evaluation thread, so that a cancelled result releases its value when dropped
+        let send_value = self.inner.send_value_maker();
+        self.submit(move |worker| {
+            let v = f(worker)?;
+            Ok(send_value(worker.insert(v)))
+        })
+    }
+
+    /// See [`EvalState::eval_from_string`].
+    pub async fn eval_from_string(
+        &self,
+        expr: impl Into<String>,
+        source: SourceName,
+    ) -> Result<SendValue> {
+        let expr = expr.into();
+        self.submit_value(move |w| w.eval_state().eval_from_string(expr, source))
+            .await
+    }
+    /// See [`EvalState::force`].
+    pub async fn force(&self, v: &SendValue) -> Result<()> {
+        let v = v.value_ref();
+        self.submit(move |w| w.eval_state().force(w.get(&v)?)).await
+    }
+    /// See [`EvalState::call`].
+    pub async fn call(&self, f: &SendValue, arg: &SendValue) -> Result<SendValue> {
+        let f = f.value_ref();
+        let arg = arg.value_ref();
+        self.submit_value(move |w| w.eval_state().call(w.get(&f)?, w.get(&arg)?))
+            .await
+    }
+    /// See [`EvalState::require_string`].
+    pub async fn require_string(&self, v: &SendValue) -> Result<String> {
+        let v = v.value_ref();
+        self.submit(move |w| w.eval_state().require_string(w.get(&v)?))
+            .await
+    }
+    /// See [`EvalState::require_int`].
+    pub async fn require_int(&self, v: &SendValue) -> Result<i64> {
+        let v = v.value_ref();
+        self.submit(move |w| w.eval_state().require_int(w.get(&v)?))
+            .await
+    }
+    /// Build a store path with the store of the evaluator. See [`Store::build`](nix_store::store::Store::build).
+    pub async fn build(&self, path: impl Into<String>) -> Result<BuildResult> {
+        let path = path.into();
+        self.submit(move |w| {
+            let store = w.eval_state().store();
+            store.build(&store.parse_store_path(&path)?)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::init;
+    use ctor::ctor;
+    use nix_store::store::{BuildStatus, Store};
+    use std::future::Future;
+    use std::sync::Arc;
+    use std::task::{Context, RawWaker, RawWakerVTable, Waker};
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    fn spawn() -> Arc<AsyncEvalState> {
+        Arc::new(AsyncEvalState::spawn(|| EvalState::new(Store::open("auto")?)).unwrap())
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn async_eval_concurrently() {
+        let es = spawn();
+        let rt = runtime();
+        let results = rt.block_on(async {
+            let tasks = (0..8)
+                .map(|i| {
+                    let es = es.clone();
+                    tokio::spawn(async move {
+                        let f = es
+                            .eval_from_string("x: x * x", SourceName::Synthetic("test"))
+                            .await?;
+                        let x = es
+                            .eval_from_string(format!("{}", i), SourceName::Synthetic("test"))
+                            .await?;
+                        let r = es.call(&f, &x).await?;
+                        es.force(&r).await?;
+                        es.require_int(&r).await
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut results = Vec::new();
+            for task in tasks {
+                results.push(task.await.unwrap().unwrap());
+            }
+            results
+        });
+        assert_eq!(results, [0, 1, 4, 9, 16, 25, 36, 49]);
+    }
+
+    #[test]
+    fn async_eval_error() {
+        let es = spawn();
+        let rt = runtime();
+        rt.block_on(async {
+            let v = es
+                .eval_from_string("1", SourceName::Synthetic("test"))
+                .await
+                .unwrap();
+            let e = es.require_string(&v).await.unwrap_err();
+            assert_eq!(e.to_string(), "expected a string, but got a Int: 1");
+        });
+    }
+
+    #[test]
+    fn async_build_valid_path() {
+        let es = spawn();
+        let rt = runtime();
+        rt.block_on(async {
+            let v = es
+                .eval_from_string(
+                    "builtins.toFile \"hello.txt\" \"hello\"",
+                    SourceName::Synthetic("test"),
+                )
+                .await
+                .unwrap();
+            let path = es.require_string(&v).await.unwrap();
+            let r = es.build(path).await.unwrap();
+            assert_eq!(r.status, BuildStatus::AlreadyValid);
+        });
+    }
+
+    fn noop_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+    }
+
+    #[test]
+    fn async_eval_cancelled() {
+        let es = spawn();
+        {
+            let fut = es.eval_from_string(
+                "builtins.foldl' builtins.add 0 (builtins.genList (x: x) 100000)",
+                SourceName::Synthetic("test"),
+            );
+            let mut fut = std::pin::pin!(fut);
+            // Submits the evaluation, then drops the future while it runs
+            let _ = fut.as_mut().poll(&mut Context::from_waker(&noop_waker()));
+        }
+        // Calls are handled in order, so the cancelled one has finished by now
+        let rt = runtime();
+        let v = rt
+            .block_on(es.eval_from_string("1", SourceName::Synthetic("test")))
+            .unwrap();
+        let n = es.blocking().with(|w| Ok(w.value_count())).unwrap();
+        // Only the value we just got back; the cancelled result was released
+        assert_eq!(n, 1);
+        drop(v);
+    }
+}
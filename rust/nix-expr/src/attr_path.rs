@@ -0,0 +1,299 @@
+//! Attribute paths, as in `nix build .#deployments.prod` and `nix-build -A`.
+
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use crate::value_path::is_simple_attr_name;
+use anyhow::{bail, Context as _, Result};
+use std::fmt;
+
+/// An attribute path, such as `deployments.prod.resources."my host"`.
+///
+/// Segments that are numbers select list elements.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AttrPath {
+    pub segments: Vec<String>,
+}
+impl AttrPath {
+    /// Parse an attribute path with Nix's rules: segments are separated by `.`, and double quotes protect dots within a segment.
+    /// There are no escapes; a segment can't contain a `"`.
+    pub fn parse(s: &str) -> Result<AttrPath> {
+        let mut segments = Vec::new();
+        let mut cur = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '.' => segments.push(std::mem::take(&mut cur)),
+                '"' => loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => cur.push(c),
+                        None => bail!("missing closing quote in selection path `{}`", s),
+                    }
+                },
+                c => cur.push(c),
+            }
+        }
+        // Like Nix, ignore a trailing dot
+        if !cur.is_empty() {
+            segments.push(cur);
+        }
+        Ok(AttrPath { segments })
+    }
+    fn prefix(&self, len: usize) -> AttrPath {
+        AttrPath {
+            segments: self.segments[..len].to_vec(),
+        }
+    }
+}
+impl fmt::Display for AttrPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            if is_simple_attr_name(segment)
+                || (!segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()))
+            {
+                f.write_str(segment)?;
+            } else {
+                write!(f, "\"{}\"", segment)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl EvalState {
+    /// Select the value at an attribute path, like `nix build` does.
+    ///
+    /// With `auto_call`, functions along the way, and the selected value, are called with an empty attribute set, as [`EvalState::auto_call`] does, so that their default arguments apply.
+    pub fn select_attr_path(&self, v: &Value, path: &AttrPath, auto_call: bool) -> Result<Value> {
+        let no_args = self.new_value_attrs([])?;
+        let call = |v: Value, len: usize| -> Result<Value> {
+            if !auto_call {
+                return Ok(v);
+            }
+            self.auto_call(&v, &no_args).with_context(|| {
+                format!(
+                    "while calling {} in selection path `{}`",
+                    describe_prefix(path, len),
+                    path
+                )
+            })
+        };
+        let mut v = v.clone();
+        for (i, segment) in path.segments.iter().enumerate() {
+            v = call(v, i)?;
+            let t = self.value_type_forced(&v)?;
+            if let Ok(index) = segment.parse::<usize>() {
+                if t != ValueType::List {
+                    bail!(
+                        "{} in selection path `{}` should be a list, but is {}",
+                        describe_prefix(path, i),
+                        path,
+                        t.describe()
+                    );
+                }
+                let len = self.require_list_size(&v)?;
+                if index >= len {
+                    bail!(
+                        "index {} in selection path `{}` is out of bounds; the list has {} elements",
+                        index,
+                        path,
+                        len
+                    );
+                }
+                v = self.require_list_select_idx(&v, index)?;
+                continue;
+            }
+            if t != ValueType::AttrSet {
+                bail!(
+                    "{} in selection path `{}` should be a set, but is {}",
+                    describe_prefix(path, i),
+                    path,
+                    t.describe()
+                );
+            }
+            if segment.is_empty() {
+                bail!("empty attribute name in selection path `{}`", path);
+            }
+            v = match self.require_attrs_select_opt(&v, segment)? {
+                Some(attr) => attr,
+                None => {
+                    let names = self.require_attrs_names(&v)?;
+                    let suggestions = closest(segment, &names, 3);
+                    if suggestions.is_empty() {
+                        bail!(
+                            "attribute `{}` in selection path `{}` not found; {} is empty",
+                            segment,
+                            path,
+                            describe_prefix(path, i)
+                        );
+                    }
+                    bail!(
+                        "attribute `{}` in selection path `{}` not found; did you mean {}?",
+                        segment,
+                        path,
+                        suggestions
+                            .iter()
+                            .map(|s| format!("`{}`", s))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    );
+                }
+            };
+        }
+        call(v, path.segments.len())
+    }
+}
+
+fn describe_prefix(path: &AttrPath, len: usize) -> String {
+    if len == 0 {
+        "the top level".to_string()
+    } else {
+        format!("`{}`", path.prefix(len))
+    }
+}
+
+/// The `n` names that are closest to `name` by edit distance.
+fn closest<'a>(name: &str, names: &'a [String], n: usize) -> Vec<&'a str> {
+    let mut by_distance = names
+        .iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate.as_str()))
+        .collect::<Vec<_>>();
+    by_distance.sort();
+    by_distance.into_iter().take(n).map(|(_, s)| s).collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitute = prev[j] + usize::from(ca != cb);
+            row.push(substitute.min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    const EXPR: &str = r#"{
+      deployments.prod.resources."my host" = { ip = "10.0.0.1"; };
+      deployments.prod.resources."a.b" = 1;
+      deployments.staging = { };
+      lists = [ { x = 1; } ];
+      packages = { system ? "x86_64-linux" }: { hello = { name ? "hello" }: "${name}-${system}"; };
+    }"#;
+
+    #[test]
+    fn attr_path_parse() {
+        let p = AttrPath::parse(r#"deployments.prod.resources."my host""#).unwrap();
+        assert_eq!(p.segments, ["deployments", "prod", "resources", "my host"]);
+        assert_eq!(p.to_string(), r#"deployments.prod.resources."my host""#);
+        let p = AttrPath::parse(r#"a."b.c"d.0."#).unwrap();
+        assert_eq!(p.segments, ["a", "b.cd", "0"]);
+        assert_eq!(p.to_string(), r#"a."b.cd".0"#);
+        assert_eq!(AttrPath::parse("").unwrap().segments, Vec::<String>::new());
+        assert_eq!(AttrPath::parse("a..b").unwrap().segments, ["a", "", "b"]);
+        let e = AttrPath::parse(r#"a."b"#).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            r#"missing closing quote in selection path `a."b`"#
+        );
+    }
+
+    #[test]
+    fn select_attr_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(EXPR, SourceName::Synthetic("test"))
+                .unwrap();
+            let select = |path: &str, auto_call: bool| {
+                es.select_attr_path(&v, &AttrPath::parse(path).unwrap(), auto_call)
+            };
+
+            let ip = select(r#"deployments.prod.resources."my host".ip"#, false).unwrap();
+            assert_eq!(es.require_string(&ip).unwrap(), "10.0.0.1");
+            let one = select(r#"deployments.prod.resources."a.b""#, false).unwrap();
+            assert_eq!(es.require_int(&one).unwrap(), 1);
+            let x = select("lists.0.x", false).unwrap();
+            assert_eq!(es.require_int(&x).unwrap(), 1);
+            let top = select("", false).unwrap();
+            assert_eq!(es.value_type_forced(&top).unwrap(), ValueType::AttrSet);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn select_attr_path_errors() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(EXPR, SourceName::Synthetic("test"))
+                .unwrap();
+            let select_err = |path: &str| {
+                es.select_attr_path(&v, &AttrPath::parse(path).unwrap(), false)
+                    .err()
+                    .unwrap()
+                    .to_string()
+            };
+            assert_eq!(
+                select_err("deployments.prd.resources"),
+                "attribute `prd` in selection path `deployments.prd.resources` not found; did you mean `prod`, `staging`?"
+            );
+            assert_eq!(
+                select_err("deployments.staging.x"),
+                "attribute `x` in selection path `deployments.staging.x` not found; `deployments.staging` is empty"
+            );
+            assert_eq!(
+                select_err("lists.1"),
+                "index 1 in selection path `lists.1` is out of bounds; the list has 1 elements"
+            );
+            assert_eq!(
+                select_err("deployments.prod.resources.\"a.b\".c"),
+                "`deployments.prod.resources.\"a.b\"` in selection path `deployments.prod.resources.\"a.b\".c` should be a set, but is an integer"
+            );
+            assert_eq!(
+                select_err("packages.hello"),
+                "`packages` in selection path `packages.hello` should be a set, but is a function"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn select_attr_path_auto_call() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(EXPR, SourceName::Synthetic("test"))
+                .unwrap();
+            let path = AttrPath::parse("packages.hello").unwrap();
+            let hello = es.select_attr_path(&v, &path, true).unwrap();
+            assert_eq!(es.require_string(&hello).unwrap(), "hello-x86_64-linux");
+            let hello = es
+                .select_attr_path(&v, &AttrPath::parse("packages").unwrap(), true)
+                .unwrap();
+            assert_eq!(es.value_type_forced(&hello).unwrap(), ValueType::AttrSet);
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+//! Equality and ordering of values, as with Nix's `==` and `<`.
+
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use anyhow::{bail, Result};
+use std::cmp::Ordering;
+
+impl EvalState {
+    /// Compare two values with Nix's `==`, evaluating them as far as needed.
+    ///
+    /// Attribute sets and lists are compared deeply, integers and floats by their numeric value, so `1 == 1.0`. String contexts are ignored.
+    /// Functions are never equal to anything, except that Nix may consider attribute sets or lists equal when they contain the very same function value.
+    /// Derivations are equal when their `outPath`s are.
+    pub fn value_eq(&self, a: &Value, b: &Value) -> Result<bool> {
+        // The C API has no comparison
+        let eq = self.glue("a: b: a == b")?;
+        let r = self.call_multi(&eq, &[a.clone(), b.clone()])?;
+        self.require_bool(&r)
+    }
+
+    /// Order two numbers or two strings, like Nix's `<`.
+    ///
+    /// Integers and floats can be compared with each other. Strings are compared by their bytes; their contexts are ignored.
+    pub fn value_cmp(&self, a: &Value, b: &Value) -> Result<Ordering> {
+        let ta = self.value_type_forced(a)?;
+        let tb = self.value_type_forced(b)?;
+        match (&ta, &tb) {
+            (ValueType::Int, ValueType::Int) => Ok(self.require_int(a)?.cmp(&self.require_int(b)?)),
+            (ValueType::Int | ValueType::Float, ValueType::Int | ValueType::Float) => {
+                let x = self.require_number(a)?;
+                let y = self.require_number(b)?;
+                match x.partial_cmp(&y) {
+                    Some(o) => Ok(o),
+                    None => bail!("cannot compare {} with {}", x, y),
+                }
+            }
+            (ValueType::String, ValueType::String) => {
+                Ok(self.require_string(a)?.cmp(&self.require_string(b)?))
+            }
+            _ => bail!("cannot compare {} with {}", ta.describe(), tb.describe()),
+        }
+    }
+
+    fn require_number(&self, v: &Value) -> Result<f64> {
+        match self.value_type_forced(v)? {
+            ValueType::Int => Ok(self.require_int(v)? as f64),
+            _ => self.require_float(v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    fn eval(es: &EvalState, expr: &str) -> Value {
+        es.eval_from_string(expr, SourceName::Synthetic("test"))
+            .unwrap()
+    }
+
+    #[test]
+    fn value_eq_nested() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let a = eval(&es, "{ a = { b = [ 1 \"x\" ]; }; c = null; }");
+            let b = eval(&es, "{ c = null; a.b = [ 1 \"x\" ]; }");
+            assert!(es.value_eq(&a, &b).unwrap());
+
+            let a = eval(&es, "[ 1 [ 2 [ 3 ] ] ]");
+            let b = eval(&es, "[ 1 [ 2 [ 4 ] ] ]");
+            assert!(!es.value_eq(&a, &b).unwrap());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn value_eq_functions_and_numbers() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let a = eval(&es, "x: x");
+            let b = eval(&es, "x: x");
+            assert!(!es.value_eq(&a, &b).unwrap());
+
+            let a = eval(&es, "1");
+            let b = eval(&es, "1.0");
+            assert!(es.value_eq(&a, &b).unwrap());
+            assert_eq!(es.value_cmp(&a, &b).unwrap(), Ordering::Equal);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn value_cmp() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let one = eval(&es, "1");
+            let two = eval(&es, "1 + 1");
+            let half = eval(&es, "0.5");
+            assert_eq!(es.value_cmp(&one, &two).unwrap(), Ordering::Less);
+            assert_eq!(es.value_cmp(&one, &half).unwrap(), Ordering::Greater);
+            let a = eval(&es, "\"a\"");
+            let b = eval(&es, "\"b\"");
+            assert_eq!(es.value_cmp(&b, &a).unwrap(), Ordering::Greater);
+
+            let e = es.value_cmp(&a, &one).unwrap_err();
+            assert_eq!(e.to_string(), "cannot compare a string with an integer");
+            let l = eval(&es, "[ ]");
+            let e = es.value_cmp(&l, &l).unwrap_err();
+            assert_eq!(e.to_string(), "cannot compare a list with a list");
+        })
+        .unwrap();
+    }
+}
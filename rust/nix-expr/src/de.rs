@@ -0,0 +1,603 @@
+//! Deserialize Rust data structures from Nix values with `serde`.
+//!
+//! ```ignore
+//! let config: DeploymentConfig = nix_expr::de::from_value(&es, &v)?;
+//! ```
+//!
+//! Only the attributes that the target type asks for are evaluated, so a configuration may contain attributes that fail to evaluate, as long as they are not needed.
+//! Errors name the attribute path of the offending value, e.g. `resources.web.memory: expected an int, but got a String: "lots"`.
+
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use crate::value_path::ValuePath;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use std::fmt::Display;
+
+/// Deserialize a `T` from a Nix value.
+pub fn from_value<T: DeserializeOwned>(es: &EvalState, v: &Value) -> Result<T, Error> {
+    T::deserialize(NixDeserializer::new(es, v))
+}
+
+/// An error converting between a Nix value and a Rust value, with the location in the value where it occurred.
+#[derive(Debug)]
+pub struct Error {
+    path: Option<ValuePath>,
+    inner: anyhow::Error,
+}
+impl Error {
+    pub(crate) fn at(path: &ValuePath, inner: anyhow::Error) -> Error {
+        Error {
+            path: Some(path.clone()),
+            inner,
+        }
+    }
+    /// Set the location, unless the error already has one.
+    pub(crate) fn or_at(mut self, path: &ValuePath) -> Error {
+        if self.path.is_none() {
+            self.path = Some(path.clone());
+        }
+        self
+    }
+    /// The attribute path of the value that could not be converted, e.g. `resources.web.memory`, or the empty string for the top level.
+    pub fn path(&self) -> String {
+        self.path.as_ref().map(|p| p.dotted()).unwrap_or_default()
+    }
+    /// The underlying error, e.g. for downcasting an evaluation error.
+    pub fn into_inner(self) -> anyhow::Error {
+        self.inner
+    }
+}
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            Some(path) if !path.is_empty() => write!(f, "{}: {:#}", path.dotted(), self.inner),
+            _ => write!(f, "{:#}", self.inner),
+        }
+    }
+}
+impl std::error::Error for Error {}
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error {
+            path: None,
+            inner: anyhow::format_err!("{}", msg),
+        }
+    }
+}
+
+/// A [`serde::Deserializer`] for a Nix value.
+pub struct NixDeserializer<'a> {
+    es: &'a EvalState,
+    value: Value,
+    path: ValuePath,
+}
+impl<'a> NixDeserializer<'a> {
+    pub fn new(es: &'a EvalState, value: &Value) -> Self {
+        NixDeserializer {
+            es,
+            value: value.clone(),
+            path: ValuePath::default(),
+        }
+    }
+    fn check<T>(&self, r: anyhow::Result<T>) -> Result<T, Error> {
+        r.map_err(|e| Error::at(&self.path, e))
+    }
+    /// Locate errors that the visitor produced without a location, e.g. a missing field, at this value.
+    fn locate<T>(&self, r: Result<T, Error>) -> Result<T, Error> {
+        r.map_err(|e| e.or_at(&self.path))
+    }
+    fn value_type(&self) -> Result<ValueType, Error> {
+        self.check(self.es.value_type_forced(&self.value))
+    }
+    fn attrs_access(&self, names: Vec<String>) -> AttrsAccess<'a> {
+        AttrsAccess {
+            es: self.es,
+            attrs: self.value.clone(),
+            path: self.path.clone(),
+            names: names.into_iter(),
+            pending: None,
+        }
+    }
+}
+
+impl<'de> de::Deserializer<'de> for NixDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let r = match self.value_type()? {
+            ValueType::Null => visitor.visit_unit(),
+            ValueType::Bool => visitor.visit_bool(self.check(self.es.require_bool(&self.value))?),
+            ValueType::Int => visitor.visit_i64(self.check(self.es.require_int(&self.value))?),
+            ValueType::Float => visitor.visit_f64(self.check(self.es.require_float(&self.value))?),
+            ValueType::String => {
+                visitor.visit_string(self.check(self.es.require_string(&self.value))?)
+            }
+            ValueType::Path => {
+                let path = self.check(self.es.require_path(&self.value))?;
+                match path.into_os_string().into_string() {
+                    Ok(s) => visitor.visit_string(s),
+                    Err(s) => {
+                        return Err(Error::at(
+                            &self.path,
+                            anyhow::format_err!("path is not valid UTF-8: {:?}", s),
+                        ))
+                    }
+                }
+            }
+            ValueType::List => return self.deserialize_seq(visitor),
+            ValueType::AttrSet => return self.deserialize_map(visitor),
+            t => {
+                return Err(Error::at(
+                    &self.path,
+                    anyhow::format_err!("cannot deserialize {}", t.describe()),
+                ))
+            }
+        };
+        self.locate(r)
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let b = self.check(self.es.require_bool(&self.value))?;
+        self.locate(visitor.visit_bool(b))
+    }
+
+    fn deserialize_i8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_i16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    /// All integer types are read from a Nix int; the visitor checks the range.
+    fn deserialize_i64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let i = self.check(self.es.require_int(&self.value))?;
+        self.locate(visitor.visit_i64(i))
+    }
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_u16<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_i64(visitor)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_f64(visitor)
+    }
+    /// Floats are read from a Nix float or int, as Nix arithmetic would.
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let r = if self.value_type()? == ValueType::Int {
+            visitor.visit_i64(self.check(self.es.require_int(&self.value))?)
+        } else {
+            visitor.visit_f64(self.check(self.es.require_float(&self.value))?)
+        };
+        self.locate(r)
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+    /// Strings are read with [`EvalState::require_string`], so their context is ignored.
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let s = self.check(self.es.require_string(&self.value))?;
+        self.locate(visitor.visit_string(s))
+    }
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_byte_buf(visitor)
+    }
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let s = self.check(self.es.require_string(&self.value))?;
+        self.locate(visitor.visit_byte_buf(s.into_bytes()))
+    }
+
+    /// `null` is `None`; anything else is `Some`.
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.value_type()? == ValueType::Null {
+            self.locate(visitor.visit_none())
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let t = self.value_type()?;
+        if t != ValueType::Null {
+            return Err(Error::at(
+                &self.path,
+                self.es.type_error("null", t, &self.value),
+            ));
+        }
+        self.locate(visitor.visit_unit())
+    }
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_unit(visitor)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let len = self.check(self.es.require_list_size(&self.value))?;
+        let access = ListAccess {
+            es: self.es,
+            list: self.value.clone(),
+            path: self.path.clone(),
+            len,
+            next: 0,
+        };
+        self.locate(visitor.visit_seq(access))
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    /// Maps are read from attribute sets, in the order of the attribute names.
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let names = self.check(self.es.require_attrs_names(&self.value))?;
+        self.locate(visitor.visit_map(self.attrs_access(names)))
+    }
+    /// Only the attributes named by `fields` are evaluated; other attributes are ignored.
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let t = self.value_type()?;
+        if t != ValueType::AttrSet {
+            return Err(Error::at(
+                &self.path,
+                self.es.type_error("an attribute set", t, &self.value),
+            ));
+        }
+        let names = fields.iter().map(|f| f.to_string()).collect();
+        self.locate(visitor.visit_map(self.attrs_access(names)))
+    }
+
+    /// Enums are externally tagged: a unit variant is a string, e.g. `"vm"`, and other variants are an attribute set with a single attribute, e.g. `{ container = { image = "nginx"; }; }`.
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let r = match self.value_type()? {
+            ValueType::String => {
+                let s = self.check(self.es.require_string(&self.value))?;
+                visitor.visit_enum(IntoDeserializer::<Error>::into_deserializer(s))
+            }
+            ValueType::AttrSet => {
+                let names = self.check(self.es.require_attrs_names(&self.value))?;
+                let [variant]: [String; 1] = names.try_into().map_err(|names: Vec<String>| {
+                    Error::at(
+                        &self.path,
+                        anyhow::format_err!(
+                            "expected an attribute set with exactly one attribute for the variant of enum `{}`, but got {} attributes",
+                            name,
+                            names.len()
+                        ),
+                    )
+                })?;
+                let path = self.path.attr(&variant);
+                let value = self
+                    .es
+                    .require_attrs_select(&self.value, &variant)
+                    .map_err(|e| Error::at(&path, e))?;
+                visitor.visit_enum(VariantAccess {
+                    variant,
+                    value: NixDeserializer {
+                        es: self.es,
+                        value,
+                        path,
+                    },
+                })
+            }
+            t => {
+                return Err(Error::at(
+                    &self.path,
+                    self.es.type_error(
+                        &format!("a string or an attribute set for enum `{}`", name),
+                        t,
+                        &self.value,
+                    ),
+                ))
+            }
+        };
+        self.locate(r)
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_string(visitor)
+    }
+    /// Ignored values are not evaluated.
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.locate(visitor.visit_unit())
+    }
+}
+
+struct ListAccess<'a> {
+    es: &'a EvalState,
+    list: Value,
+    path: ValuePath,
+    len: usize,
+    next: usize,
+}
+impl<'de> de::SeqAccess<'de> for ListAccess<'_> {
+    type Error = Error;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.next >= self.len {
+            return Ok(None);
+        }
+        let path = self.path.index(self.next);
+        let value = self
+            .es
+            .require_list_select_idx(&self.list, self.next)
+            .map_err(|e| Error::at(&path, e))?;
+        self.next += 1;
+        seed.deserialize(NixDeserializer {
+            es: self.es,
+            value,
+            path,
+        })
+        .map(Some)
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.next)
+    }
+}
+
+/// Reads the attributes `names` of an attribute set, skipping the ones that do not exist.
+struct AttrsAccess<'a> {
+    es: &'a EvalState,
+    attrs: Value,
+    path: ValuePath,
+    names: std::vec::IntoIter<String>,
+    pending: Option<(ValuePath, Value)>,
+}
+impl<'de> de::MapAccess<'de> for AttrsAccess<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Error> {
+        for name in self.names.by_ref() {
+            let path = self.path.attr(&name);
+            let value = self
+                .es
+                .require_attrs_select_opt(&self.attrs, &name)
+                .map_err(|e| Error::at(&path, e))?;
+            if let Some(value) = value {
+                self.pending = Some((path, value));
+                return seed
+                    .deserialize(IntoDeserializer::<Error>::into_deserializer(name))
+                    .map(Some);
+            }
+        }
+        Ok(None)
+    }
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let (path, value) = self
+            .pending
+            .take()
+            .expect("next_value_seed called before next_key_seed");
+        seed.deserialize(NixDeserializer {
+            es: self.es,
+            value,
+            path,
+        })
+    }
+}
+
+struct VariantAccess<'a> {
+    variant: String,
+    value: NixDeserializer<'a>,
+}
+impl<'de, 'a> de::EnumAccess<'de> for VariantAccess<'a> {
+    type Error = Error;
+    type Variant = NixDeserializer<'a>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self::Variant), Error> {
+        let variant =
+            seed.deserialize(IntoDeserializer::<Error>::into_deserializer(self.variant))?;
+        Ok((variant, self.value))
+    }
+}
+impl<'de> de::VariantAccess<'de> for NixDeserializer<'_> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        de::Deserialize::deserialize(self)
+    }
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        seed.deserialize(self)
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        de::Deserializer::deserialize_struct(self, "", fields, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, SourceName};
+    use nix_store::store::Store;
+    use serde::Deserialize;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct DeploymentConfig {
+        name: String,
+        description: Option<String>,
+        resources: HashMap<String, Resource>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Resource {
+        kind: Kind,
+        memory: u32,
+        ports: Vec<u16>,
+        weight: f64,
+        enabled: Option<bool>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "camelCase")]
+    enum Kind {
+        Host,
+        Vm(String),
+        Container { image: String },
+    }
+
+    const CONFIG: &str = r#"
+        {
+          name = "test";
+          resources = {
+            web = {
+              kind = { container = { image = "nginx"; }; };
+              memory = 512;
+              ports = [ 80 443 ];
+              weight = 1;
+              enabled = true;
+              notUsed = throw "must not be evaluated";
+            };
+            db = {
+              kind = "host";
+              memory = 2048;
+              ports = [ ];
+              weight = 0.5;
+            };
+            vm = {
+              kind = { vm = "nixos"; };
+              memory = 1024;
+              ports = [ 22 ];
+              weight = 2.5;
+              enabled = null;
+            };
+          };
+        }
+    "#;
+
+    fn with_config<R>(overlay: &str, f: impl FnOnce(&EvalState, &Value) -> R) -> R {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    format!("let config = {}; in {}", CONFIG, overlay),
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            f(&es, &v)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn from_value_config() {
+        let config: DeploymentConfig = with_config("config", |es, v| from_value(es, v).unwrap());
+        assert_eq!(config.name, "test");
+        assert_eq!(config.description, None);
+        assert_eq!(config.resources.len(), 3);
+        assert_eq!(
+            config.resources["web"],
+            Resource {
+                kind: Kind::Container {
+                    image: "nginx".to_string()
+                },
+                memory: 512,
+                ports: vec![80, 443],
+                weight: 1.0,
+                enabled: Some(true),
+            }
+        );
+        assert_eq!(config.resources["db"].kind, Kind::Host);
+        assert_eq!(config.resources["db"].enabled, None);
+        assert_eq!(config.resources["vm"].kind, Kind::Vm("nixos".to_string()));
+        assert_eq!(config.resources["vm"].enabled, None);
+    }
+
+    #[test]
+    fn from_value_error_paths() {
+        let check = |overlay: &str, expected: &str| {
+            let e = with_config(overlay, |es, v| {
+                from_value::<DeploymentConfig>(es, v).err().unwrap()
+            });
+            assert_eq!(e.to_string(), expected);
+        };
+        check(
+            r#"config // { resources = config.resources // { web = config.resources.web // { memory = "lots"; }; }; }"#,
+            "resources.web.memory: expected an int, but got a String: \"lots\"",
+        );
+        check(
+            r#"config // { resources = config.resources // { web = config.resources.web // { ports = [ 80 "x" ]; }; }; }"#,
+            "resources.web.ports[1]: expected an int, but got a String: \"x\"",
+        );
+        check(
+            r#"config // { resources = config.resources // { web = config.resources.web // { ports = [ 100000 ]; }; }; }"#,
+            "resources.web.ports[0]: invalid value: integer `100000`, expected u16",
+        );
+        check(
+            r#"config // { resources = config.resources // { db = removeAttrs config.resources.db [ "memory" ]; }; }"#,
+            "resources.db: missing field `memory`",
+        );
+        check(
+            r#"config // { resources = config.resources // { db = config.resources.db // { kind = { a = 1; b = 2; }; }; }; }"#,
+            "resources.db.kind: expected an attribute set with exactly one attribute for the variant of enum `Kind`, but got 2 attributes",
+        );
+        check("[ ]", "expected an attribute set, but got a List: [ ]");
+    }
+
+    #[test]
+    fn from_value_evaluation_error() {
+        let e = with_config(r#"config // { name = throw "no name"; }"#, |es, v| {
+            from_value::<DeploymentConfig>(es, v).err().unwrap()
+        });
+        assert_eq!(e.path(), "name");
+        assert!(e.to_string().contains("no name"), "{}", e);
+    }
+}
@@ -0,0 +1,400 @@
+//! Differences between two values, e.g. between the configuration of the last deployment and the new one.
+
+use crate::attr_path::AttrPath;
+use crate::eval_state::EvalState;
+use crate::print::PrintOptions;
+use crate::value::{Value, ValueType};
+use anyhow::{Context as _, Result};
+
+/// How [`EvalState::diff_values`] compares lists.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ListDiff {
+    /// Element by element, so inserting an element changes all the elements after it.
+    #[default]
+    Positional,
+    /// As sets: elements that are not in the other list, by `==`, are added or removed. Order and duplicates don't matter.
+    Set,
+}
+
+/// Options for [`EvalState::diff_values`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DiffOpts {
+    /// How many levels of attribute sets and lists to compare attribute by attribute; deeper ones are compared as a whole.
+    pub max_depth: Option<usize>,
+    /// Count strings that only differ in their context, the store paths they refer to, as changed.
+    pub compare_context: bool,
+    pub lists: ListDiff,
+    /// How to render [`DiffEntry::before`] and [`DiffEntry::after`].
+    pub print: PrintOptions,
+}
+impl Default for DiffOpts {
+    fn default() -> Self {
+        DiffOpts {
+            max_depth: None,
+            compare_context: false,
+            lists: ListDiff::Positional,
+            print: PrintOptions {
+                max_depth: 2,
+                max_attrs: 8,
+                max_list_items: 8,
+                max_string_length: 160,
+                force: true,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DiffKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// A difference found by [`EvalState::diff_values`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiffEntry {
+    /// Where in the values the difference is. List elements are numbered, as in `nix build .#a.0`.
+    pub path: AttrPath,
+    pub kind: DiffKind,
+    /// The old value, unless it was added.
+    pub before: Option<String>,
+    /// The new value, unless it was removed.
+    pub after: Option<String>,
+}
+
+impl EvalState {
+    /// Compare two values attribute by attribute, and list how `new` differs from `old`, in the order of their paths.
+    ///
+    /// `old` can be a recorded configuration that is read back with [`EvalState::builtin_from_json`].
+    /// Values are compared with `==`, except that functions are not compared: two functions are the same, and a function differs from anything else.
+    /// Derivations are compared by their `outPath`, not attribute by attribute.
+    /// An added or removed value that fails to evaluate is shown as `«error»`; an evaluation error in a value that is in both fails the comparison.
+    pub fn diff_values(&self, old: &Value, new: &Value, opts: DiffOpts) -> Result<Vec<DiffEntry>> {
+        let mut differ = Differ {
+            es: self,
+            opts,
+            path: Vec::new(),
+            entries: Vec::new(),
+        };
+        differ.diff(old, new)?;
+        Ok(differ.entries)
+    }
+}
+
+struct Differ<'a> {
+    es: &'a EvalState,
+    opts: DiffOpts,
+    path: Vec<String>,
+    entries: Vec<DiffEntry>,
+}
+impl Differ<'_> {
+    fn diff(&mut self, old: &Value, new: &Value) -> Result<()> {
+        self.diff_here(old, new).with_context(|| {
+            format!(
+                "while comparing `{}`",
+                AttrPath {
+                    segments: self.path.clone()
+                }
+            )
+        })
+    }
+
+    fn diff_here(&mut self, old: &Value, new: &Value) -> Result<()> {
+        let es = self.es;
+        let t_old = es.value_type_forced(old)?;
+        let t_new = es.value_type_forced(new)?;
+        let descend = self
+            .opts
+            .max_depth
+            .map_or(true, |max| self.path.len() < max);
+        match (t_old, t_new) {
+            (ValueType::Function, ValueType::Function) => Ok(()),
+            (ValueType::AttrSet, ValueType::AttrSet)
+                if descend && !es.is_derivation(old)? && !es.is_derivation(new)? =>
+            {
+                let old_names = es.require_attrs_names(old)?;
+                let new_names = es.require_attrs_names(new)?;
+                let mut names = old_names.iter().chain(&new_names).collect::<Vec<_>>();
+                names.sort();
+                names.dedup();
+                for name in names {
+                    self.path.push(name.clone());
+                    let r = match (old_names.contains(name), new_names.contains(name)) {
+                        (true, true) => self.diff(
+                            &es.require_attrs_select(old, name)?,
+                            &es.require_attrs_select(new, name)?,
+                        ),
+                        (true, false) => {
+                            self.push(Some(&es.require_attrs_select(old, name)?), None)
+                        }
+                        _ => self.push(None, Some(&es.require_attrs_select(new, name)?)),
+                    };
+                    self.path.pop();
+                    r?;
+                }
+                Ok(())
+            }
+            (ValueType::List, ValueType::List) if descend => match self.opts.lists {
+                ListDiff::Positional => self.diff_lists_positional(old, new),
+                ListDiff::Set => self.diff_lists_as_sets(old, new),
+            },
+            (ValueType::String, ValueType::String) => {
+                let changed = es.require_string_bytes(old)? != es.require_string_bytes(new)?
+                    || (self.opts.compare_context
+                        && es.string_get_context(old)? != es.string_get_context(new)?);
+                if changed {
+                    self.push(Some(old), Some(new))?;
+                }
+                Ok(())
+            }
+            (ValueType::Function, _) | (_, ValueType::Function) => self.push(Some(old), Some(new)),
+            _ => {
+                if !es.value_eq(old, new)? {
+                    self.push(Some(old), Some(new))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn diff_lists_positional(&mut self, old: &Value, new: &Value) -> Result<()> {
+        let es = self.es;
+        let old_len = es.require_list_size(old)?;
+        let new_len = es.require_list_size(new)?;
+        for i in 0..old_len.max(new_len) {
+            self.path.push(i.to_string());
+            let r = if i < old_len && i < new_len {
+                self.diff(
+                    &es.require_list_select_idx(old, i)?,
+                    &es.require_list_select_idx(new, i)?,
+                )
+            } else if i < old_len {
+                self.push(Some(&es.require_list_select_idx(old, i)?), None)
+            } else {
+                self.push(None, Some(&es.require_list_select_idx(new, i)?))
+            };
+            self.path.pop();
+            r?;
+        }
+        Ok(())
+    }
+
+    fn diff_lists_as_sets(&mut self, old: &Value, new: &Value) -> Result<()> {
+        let es = self.es;
+        // The indices of the elements of `a` that are not in `b`
+        let missing = es.glue(
+            "a: b: builtins.filter (i: !(builtins.elem (builtins.elemAt a i) b)) (builtins.genList (i: i) (builtins.length a))",
+        )?;
+        let removed = es.call_multi(&missing, &[old.clone(), new.clone()])?;
+        let added = es.call_multi(&missing, &[new.clone(), old.clone()])?;
+        let mut elements = Vec::new();
+        for (indices, list, is_old) in [(removed, old, true), (added, new, false)] {
+            for j in 0..es.require_list_size(&indices)? {
+                let i = es.require_int(&es.require_list_select_idx(&indices, j)?)? as usize;
+                elements.push((i, is_old, es.require_list_select_idx(list, i)?));
+            }
+        }
+        // By index, which is stable, so that removals come before additions at the same index
+        elements.sort_by_key(|(i, _, _)| *i);
+        for (i, is_old, element) in elements {
+            self.path.push(i.to_string());
+            let r = if is_old {
+                self.push(Some(&element), None)
+            } else {
+                self.push(None, Some(&element))
+            };
+            self.path.pop();
+            r?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, before: Option<&Value>, after: Option<&Value>) -> Result<()> {
+        let kind = match (before, after) {
+            (Some(_), Some(_)) => DiffKind::Changed,
+            (Some(_), None) => DiffKind::Removed,
+            _ => DiffKind::Added,
+        };
+        let render = |v: Option<&Value>| {
+            v.map(|v| self.es.value_to_display_string(v, self.opts.print))
+                .transpose()
+        };
+        self.entries.push(DiffEntry {
+            path: AttrPath {
+                segments: self.path.clone(),
+            },
+            kind,
+            before: render(before)?,
+            after: render(after)?,
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    const OLD: &str = r#"{
+      a = 1;
+      b = { c = "x"; d = [ 1 2 3 ]; };
+      gone = true;
+      f = x: x;
+      g = x: x;
+      l = [ 1 2 ];
+    }"#;
+    const NEW: &str = r#"{
+      a = 2;
+      b = { c = "x"; d = [ 1 3 ]; e = null; };
+      f = y: y;
+      g = 1;
+      l = [ 2 1 ];
+      added = { z = 1; };
+    }"#;
+
+    fn show(entries: &[DiffEntry]) -> Vec<String> {
+        entries
+            .iter()
+            .map(|e| {
+                format!(
+                    "{:?} {}: {} -> {}",
+                    e.kind,
+                    e.path,
+                    e.before.as_deref().unwrap_or("-"),
+                    e.after.as_deref().unwrap_or("-")
+                )
+            })
+            .collect()
+    }
+
+    fn diff(es: &EvalState, old: &str, new: &str, opts: DiffOpts) -> Vec<String> {
+        let old = es
+            .eval_from_string(old, SourceName::Synthetic("old"))
+            .unwrap();
+        let new = es
+            .eval_from_string(new, SourceName::Synthetic("new"))
+            .unwrap();
+        show(&es.diff_values(&old, &new, opts).unwrap())
+    }
+
+    #[test]
+    fn diff_values() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            assert_eq!(
+                diff(&es, OLD, NEW, DiffOpts::default()),
+                [
+                    "Changed a: 1 -> 2",
+                    "Added added: - -> { z = 1; }",
+                    "Changed b.d.1: 2 -> 3",
+                    "Removed b.d.2: 3 -> -",
+                    "Added b.e: - -> null",
+                    "Changed g: «lambda» -> 1",
+                    "Removed gone: true -> -",
+                    "Changed l.0: 1 -> 2",
+                    "Changed l.1: 2 -> 1",
+                ]
+            );
+            assert_eq!(diff(&es, OLD, OLD, DiffOpts::default()), Vec::<String>::new());
+            assert_eq!(
+                diff(&es, "1", "1.0", DiffOpts::default()),
+                Vec::<String>::new()
+            );
+            assert_eq!(
+                diff(&es, "{ }", "{ x = throw \"no\"; }", DiffOpts::default()),
+                ["Added x: - -> «error»"]
+            );
+            let old = es
+                .eval_from_string("{ x = 1; }", SourceName::Synthetic("old"))
+                .unwrap();
+            let new = es
+                .eval_from_string("{ x = throw \"no\"; }", SourceName::Synthetic("new"))
+                .unwrap();
+            let e = es
+                .diff_values(&old, &new, DiffOpts::default())
+                .unwrap_err();
+            assert_eq!(e.to_string(), "while comparing `x`");
+            assert_eq!(
+                diff(&es, "1", "\"1\"", DiffOpts::default()),
+                [r#"Changed : 1 -> "1""#]
+            );
+
+            let opts = DiffOpts {
+                max_depth: Some(1),
+                ..Default::default()
+            };
+            assert_eq!(
+                diff(&es, OLD, NEW, opts),
+                [
+                    "Changed a: 1 -> 2",
+                    "Added added: - -> { z = 1; }",
+                    r#"Changed b: { c = "x"; d = [ 1 2 3 ]; } -> { c = "x"; d = [ 1 3 ]; e = null; }"#,
+                    "Changed g: «lambda» -> 1",
+                    "Removed gone: true -> -",
+                    "Changed l: [ 1 2 ] -> [ 2 1 ]",
+                ]
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn diff_values_lists_as_sets() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let opts = DiffOpts {
+                lists: ListDiff::Set,
+                ..Default::default()
+            };
+            assert_eq!(
+                diff(&es, OLD, NEW, opts),
+                [
+                    "Changed a: 1 -> 2",
+                    "Added added: - -> { z = 1; }",
+                    "Removed b.d.1: 2 -> -",
+                    "Added b.e: - -> null",
+                    "Changed g: «lambda» -> 1",
+                    "Removed gone: true -> -",
+                ]
+            );
+            assert_eq!(
+                diff(&es, "[ 1 2 3 ]", "[ 4 3 1 ]", opts),
+                ["Added 0: - -> 4", "Removed 1: 2 -> -"]
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn diff_values_context() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let old = r#""${builtins.toFile "diff.txt" "x"}""#;
+            let new = r#"builtins.unsafeDiscardStringContext "${builtins.toFile "diff.txt" "x"}""#;
+            assert_eq!(
+                diff(&es, old, new, DiffOpts::default()),
+                Vec::<String>::new()
+            );
+            let opts = DiffOpts {
+                compare_context: true,
+                ..Default::default()
+            };
+            let entries = diff(&es, old, new, opts);
+            assert_eq!(entries.len(), 1);
+            assert!(entries[0].starts_with("Changed : "), "{:?}", entries);
+        })
+        .unwrap();
+    }
+}
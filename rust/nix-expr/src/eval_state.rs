@@ -1,76 +1,657 @@
+use crate::print::PrintOptions;
+use crate::string_context::{StringContext, StringContextElement};
 use crate::value::{Value, ValueType};
+use crate::value_path::is_simple_attr_name;
 use anyhow::Context as _;
 use anyhow::{bail, Result};
 use lazy_static::lazy_static;
 use nix_c_raw as raw;
+use nix_store::path::StorePath;
 use nix_store::store::Store;
 use nix_util::context::Context;
-use std::ffi::CString;
-use std::ptr::null_mut;
+use nix_util::error::{strip_ansi_escapes, NixError};
+use nix_util::error_site;
+use nix_util::settings;
+use nix_util::version::{self, Capability};
+use std::cell::{OnceCell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{c_char, c_uint, CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr::null;
 use std::ptr::NonNull;
+use std::rc::{Rc, Weak};
+
+/// Options for [`init_with`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct InitOptions {
+    /// Read `nix.conf` and `NIX_CONFIG`.
+    ///
+    /// Nix reads them when the store library is initialized, which opening a [`Store`] also does, so `false` only has an effect if nothing opened a store yet.
+    pub load_config: bool,
+    /// Plugins to load, like the `plugin-files` setting. Directories are searched for plugins.
+    pub plugin_files: Vec<PathBuf>,
+    /// Settings to apply after reading the configuration and before loading plugins, as with `--option`.
+    pub settings: Vec<(String, String)>,
+}
+impl Default for InitOptions {
+    fn default() -> Self {
+        InitOptions {
+            load_config: true,
+            plugin_files: Vec::new(),
+            settings: Vec::new(),
+        }
+    }
+}
 
 lazy_static! {
-    static ref INIT: Result<()> = {
+    /// The options that Nix was initialized with.
+    static ref INIT: std::sync::Mutex<Option<InitOptions>> = std::sync::Mutex::new(None);
+}
+/// Initialize Nix with the default [`InitOptions`], unless it is initialized already, with whichever options.
+pub fn init() -> Result<()> {
+    let mut init = INIT.lock().unwrap();
+    if init.is_none() {
+        let options = InitOptions::default();
+        initialize(&options)?;
+        *init = Some(options);
+    }
+    Ok(())
+}
+/// Initialize Nix with options that must be applied before anything else uses Nix.
+///
+/// Call this before creating stores or `EvalState`s, which call [`init`].
+/// Initializing again with the same options does nothing, while different options are an error.
+/// If initialization fails, it may be retried.
+pub fn init_with(options: InitOptions) -> Result<()> {
+    let mut init = INIT.lock().unwrap();
+    match &*init {
+        None => {
+            initialize(&options)?;
+            *init = Some(options);
+            Ok(())
+        }
+        Some(applied) if *applied == options => Ok(()),
+        Some(applied) => bail!(
+            "Nix was already initialized with different options: {:?}",
+            applied
+        ),
+    }
+}
+fn initialize(options: &InitOptions) -> Result<()> {
+    unsafe {
+        raw::GC_allow_register_threads();
+    }
+    let context: Context = Context::new();
+    if options.load_config {
         unsafe {
-            raw::GC_allow_register_threads();
+            raw::nix_libstore_init(context.ptr());
         }
-        let context: Context = Context::new();
+        context.check_err(error_site!("nix_libstore_init"))?;
+    } else {
         unsafe {
-            raw::nix_libexpr_init(context.ptr());
+            raw::nix_libstore_init_no_load_config(context.ptr());
         }
-        context.check_err()?;
-        Ok(())
-    };
+        context.check_err(error_site!("nix_libstore_init_no_load_config"))?;
+    }
+    for (key, value) in &options.settings {
+        settings::set(key, value)?;
+    }
+    if !options.plugin_files.is_empty() {
+        let plugin_files = options
+            .plugin_files
+            .iter()
+            .map(|path| match path.to_str() {
+                Some(s) if !s.contains(char::is_whitespace) => Ok(s),
+                _ => bail!("plugin path {:?} can't be passed to Nix", path),
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join(" ");
+        settings::set("plugin-files", &plugin_files)?;
+        unsafe {
+            raw::nix_init_plugins(context.ptr());
+        }
+        context
+            .check_err(error_site!("nix_init_plugins"))
+            .with_context(|| format!("while loading plugins {}", plugin_files))?;
+    }
+    unsafe {
+        raw::nix_libexpr_init(context.ptr());
+    }
+    context.check_err(error_site!("nix_libexpr_init"))?;
+    Ok(())
 }
-pub fn init() -> Result<()> {
-    let x = INIT.as_ref();
-    match x {
-        Ok(_) => Ok(()),
-        Err(e) => {
-            // Couldn't just clone the error, so we have to print it here.
-            Err(anyhow::format_err!("nix_libstore_init error: {}", e))
+
+/// Where an expression passed to [`EvalState::eval_from_string`] comes from.
+///
+/// This determines the directory that relative paths in the expression are resolved against.
+/// It does not name the expression in error positions: the C API parses the expression as a string, so Nix shows its positions as `«string»`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum SourceName {
+    /// The expression was read from this file. As for a file that Nix reads itself, relative paths resolve against its directory, so `./foo` becomes `<dir>/foo`.
+    File(PathBuf),
+    /// The expression was constructed by nixops4 itself, e.g. glue code.
+    ///
+    /// There is no directory for relative paths. Nix resolves them as if `/«name»` were one, so using such a path fails because it doesn't exist, with an error that shows `/«name»`.
+    Synthetic(&'static str),
+}
+impl SourceName {
+    /// The base directory for relative paths, as Nix takes it.
+    fn base_dir(&self) -> Result<CString> {
+        match self {
+            SourceName::File(path) => {
+                let dir = path.parent().unwrap_or(Path::new("/"));
+                CString::new(dir.as_os_str().as_bytes())
+                    .with_context(|| format!("source path contains null byte: {:?}", path))
+            }
+            SourceName::Synthetic(name) => CString::new(format!("«{}»", name))
+                .with_context(|| format!("source name contains null byte: {:?}", name)),
         }
     }
 }
 
-pub struct EvalState {
+/// Options for [`EvalState::coerce_to_string`].
+///
+/// The [`Default`] corresponds to string interpolation, `"${v}"`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CoerceOpts {
+    /// Also accept ints, floats, booleans, null and lists, rendering them like `builtins.toString` does.
+    /// Otherwise only strings, paths and attribute sets with `__toString` or `outPath` can be coerced.
+    pub coerce_more: bool,
+    /// Copy paths to the store and return the store path, with context, as interpolation does.
+    /// Otherwise paths are returned as they are, without context, as `builtins.toString` does.
+    pub copy_to_store: bool,
+}
+impl Default for CoerceOpts {
+    fn default() -> Self {
+        CoerceOpts {
+            coerce_more: false,
+            copy_to_store: true,
+        }
+    }
+}
+
+/// The state that the [`EvalState`] handles and the [`Value`]s of an evaluator share; freed when all of them are gone.
+pub(crate) struct EvalStateRef {
     eval_state: NonNull<raw::EvalState>,
     store: Store,
+    offline: bool,
+    /// The `builtins` attribute set, see [`EvalState::builtins`]. A raw value with a reference of its own, because a [`Value`] would keep this alive.
+    builtins: OnceCell<NonNull<raw::Value>>,
+    /// The values of [`EvalState::glue`], by expression, with references of their own like `builtins`.
+    glue: RefCell<HashMap<&'static str, NonNull<raw::Value>>>,
+    eval_cache: RefCell<EvalCache>,
+    /// Released after the state is freed.
+    _settings: EvalSettingsLease,
+}
+impl Drop for EvalStateRef {
+    fn drop(&mut self) {
+        // Fails during thread exit, when the whole map is being dropped anyway
+        let _ =
+            LIVE_STATES.try_with(|states| states.borrow_mut().remove(&self.eval_state.as_ptr()));
+        unsafe {
+            if let Some(builtins) = self.builtins.get() {
+                raw::nix_gc_decref(Context::new().ptr(), builtins.as_ptr());
+            }
+            for (_, v) in self.glue.get_mut().drain() {
+                raw::nix_gc_decref(Context::new().ptr(), v.as_ptr());
+            }
+            self.eval_cache.get_mut().clear();
+            raw::nix_state_free(self.eval_state.as_ptr());
+        }
+    }
+}
+
+const DEFAULT_EVAL_CACHE_CAPACITY: usize = 64;
+
+/// The results of [`EvalState::eval_cached`], as raw values with a reference of their own, like [`EvalStateRef::builtins`].
+struct EvalCache {
+    capacity: usize,
+    /// The values, and when they were last used.
+    entries: HashMap<(String, SourceName), (NonNull<raw::Value>, u64)>,
+    tick: u64,
+}
+impl EvalCache {
+    fn get(&mut self, key: &(String, SourceName)) -> Option<NonNull<raw::Value>> {
+        self.tick += 1;
+        let (v, used) = self.entries.get_mut(key)?;
+        *used = self.tick;
+        Some(*v)
+    }
+    /// Insert a value, to which the cache takes a reference. Evicts the least recently used entry if the cache is full.
+    fn insert(&mut self, key: (String, SourceName), v: NonNull<raw::Value>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            if let Some(lru) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone())
+            {
+                let (evicted, _) = self.entries.remove(&lru).unwrap();
+                unsafe {
+                    raw::nix_gc_decref(Context::new().ptr(), evicted.as_ptr());
+                }
+            }
+        }
+        unsafe {
+            raw::nix_gc_incref(Context::new().ptr(), v.as_ptr());
+        }
+        self.tick += 1;
+        if let Some((old, _)) = self.entries.insert(key, (v, self.tick)) {
+            unsafe {
+                raw::nix_gc_decref(Context::new().ptr(), old.as_ptr());
+            }
+        }
+    }
+    fn clear(&mut self) {
+        for (_, (v, _)) in self.entries.drain() {
+            unsafe {
+                raw::nix_gc_decref(Context::new().ptr(), v.as_ptr());
+            }
+        }
+    }
+}
+
+/// A handle to a Nix evaluator.
+///
+/// Clones share the evaluator, which is freed once all handles and all [`Value`]s that it created are dropped.
+pub struct EvalState {
+    inner: Rc<EvalStateRef>,
     context: Context,
 }
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CaughtErrorKind {
+    /// `throw`
+    Throw,
+    /// A failed `assert`
+    Assert,
+}
+/// An evaluation error caught by [`EvalState::try_force`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CaughtError {
+    pub kind: CaughtErrorKind,
+    /// The message, e.g. the thrown string, without trace or position.
+    pub message: String,
+}
+impl CaughtError {
+    /// Recognize an error that `builtins.tryEval` would catch, among the errors returned by this crate.
+    pub fn from_error(e: &anyhow::Error) -> Option<CaughtError> {
+        let e = e.downcast_ref::<NixError>()?;
+        let kind = match e.name()? {
+            "nix::ThrownError" => CaughtErrorKind::Throw,
+            "nix::AssertionError" => CaughtErrorKind::Assert,
+            _ => return None,
+        };
+        Some(CaughtError {
+            kind,
+            message: strip_ansi_escapes(e.info_msg()?),
+        })
+    }
+}
+impl std::fmt::Display for CaughtError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+impl std::error::Error for CaughtError {}
+
+/// A string whose store paths are present in the store, see [`EvalState::realise_string`].
+#[derive(Debug)]
+pub struct RealisedString {
+    /// The string, in which placeholders for content-addressed outputs are replaced by their paths.
+    pub string: String,
+    /// The store paths that the string refers to, sorted.
+    pub paths: Vec<StorePath>,
+}
+
+/// The store paths and basic attributes of a derivation, see [`EvalState::require_derivation`].
+#[derive(Debug)]
+pub struct DerivationInfo {
+    pub drv_path: StorePath,
+    /// The outputs in the order of the `outputs` attribute, with their paths if they are known before building.
+    ///
+    /// The paths of content-addressed outputs are `None`; build the derivation with [`Store::realise`] or [`Store::build`] to learn them.
+    pub outputs: Vec<(String, Option<StorePath>)>,
+    pub name: String,
+    pub system: String,
+}
+
+/// The formal arguments of a function, see [`EvalState::require_function_formals`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Formals {
+    /// The names of the arguments in lexicographic order, and whether they have a default value.
+    pub args: Vec<(String, bool)>,
+    /// Whether the function accepts other attributes too, `...`.
+    pub ellipsis: bool,
+}
+
+/// Builds an [`EvalState`] with evaluator settings.
+///
+/// Nix keeps `pure-eval`, `restrict-eval`, `allow-import-from-derivation` and `nix-path` in its global settings, and consults some of them during evaluation.
+/// Therefore all `EvalState`s that are alive at the same time, on any thread, must have the same values for them.
+/// `build` fails if an `EvalState` with other values is alive, rather than take away e.g. the restrictions of an `EvalState` for untrusted code; build states with other settings one after the other.
+/// Settings that are not set on the builder revert to their configured values, e.g. from `nix.conf`.
+#[derive(Default)]
+pub struct EvalStateBuilder {
+    store: Option<Store>,
+    lookup_path: Vec<String>,
+    pure_eval: Option<bool>,
+    restrict_eval: Option<bool>,
+    allow_import_from_derivation: Option<bool>,
+    allowed_paths: Vec<PathBuf>,
+    nix_path: Option<String>,
+    offline: bool,
+    eval_cache_capacity: Option<usize>,
+}
+impl EvalStateBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn store(mut self, store: Store) -> Self {
+        self.store = Some(store);
+        self
+    }
+    /// Add lookup path entries for `<name>` expressions, like `-I`.
+    ///
+    /// Entries have the form `name=path` or `path`.
+    /// They take precedence over the `nix-path` setting, which Nix initializes from `NIX_PATH`.
+    pub fn lookup_path(mut self, entries: &[&str]) -> Self {
+        self.lookup_path
+            .extend(entries.iter().map(|e| e.to_string()));
+        self
+    }
+    /// Disallow impure operations, such as reading files outside the store, `builtins.getEnv` and `builtins.currentTime`.
+    pub fn pure_eval(mut self, pure_eval: bool) -> Self {
+        self.pure_eval = Some(pure_eval);
+        self
+    }
+    /// Only allow access to files in the store and in the lookup path.
+    pub fn restrict_eval(mut self, restrict_eval: bool) -> Self {
+        self.restrict_eval = Some(restrict_eval);
+        self
+    }
+    /// Whether evaluation may build derivations, for `import` and `readFile` of their outputs, or for [`EvalState::realise_string`] with `is_ifd`.
+    ///
+    /// Nix checks this setting during evaluation; see above.
+    pub fn allow_import_from_derivation(mut self, allow: bool) -> Self {
+        self.allow_import_from_derivation = Some(allow);
+        self
+    }
+    /// Allow access to these paths under [`EvalStateBuilder::restrict_eval`].
+    ///
+    /// Like in Nix, they are appended to the lookup path, so `<name>` may also find files in them.
+    pub fn allowed_paths(mut self, paths: &[&str]) -> Self {
+        self.allowed_paths.extend(paths.iter().map(PathBuf::from));
+        self
+    }
+    /// Allow access to a file or directory, and everything in it, under [`EvalStateBuilder::restrict_eval`], see [`EvalStateBuilder::allowed_paths`].
+    ///
+    /// A denied access fails with a [`NixError`] whose [`forbidden_path`](NixError::forbidden_path) is the path.
+    pub fn allow_path(mut self, path: &Path) -> Self {
+        self.allowed_paths.push(path.to_path_buf());
+        self
+    }
+    /// Restrict evaluation to the files in the store, for expressions that are not trusted.
+    ///
+    /// Enables [`EvalStateBuilder::restrict_eval`], and drops the paths allowed so far and the `nix-path` setting, which would allow their paths too.
+    /// Paths allowed after this call and entries added with [`EvalStateBuilder::lookup_path`] remain accessible.
+    pub fn deny_all_reads(mut self) -> Self {
+        self.restrict_eval = Some(true);
+        self.allowed_paths.clear();
+        self.nix_path = Some(String::new());
+        self
+    }
+    /// Make [`EvalState::fetch_tree`] fail with an [`OfflineError`](crate::fetch::OfflineError) instead of accessing the network.
+    ///
+    /// Evaluation itself is not affected; `builtins.fetchGit` and the like still use the network.
+    pub fn offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+    /// The number of results that [`EvalState::eval_cached`] keeps, 64 by default. With 0, nothing is cached.
+    pub fn eval_cache_capacity(mut self, capacity: usize) -> Self {
+        self.eval_cache_capacity = Some(capacity);
+        self
+    }
+    pub fn build(self) -> Result<EvalState> {
+        let store = match self.store {
+            Some(store) => store,
+            None => bail!("EvalStateBuilder: a store is required"),
+        };
+        init()?;
+        let mut lookup_path = self.lookup_path;
+        for path in self.allowed_paths {
+            match path.to_str() {
+                Some(p) => lookup_path.push(p.to_string()),
+                None => bail!(
+                    "EvalStateBuilder: allowed path is not valid UTF-8: {}",
+                    path.display()
+                ),
+            }
+        }
+
+        let mut in_use = EVAL_SETTINGS_IN_USE.lock().unwrap();
+        if in_use.defaults.is_none() {
+            in_use.defaults = Some(
+                EVAL_SETTINGS
+                    .iter()
+                    .map(|key| settings::get(key))
+                    .collect::<Result<Vec<_>>>()?,
+            );
+        }
+        let bool_setting = |b: Option<bool>| b.map(|b| if b { "true" } else { "false" });
+        let values = [
+            bool_setting(self.pure_eval),
+            bool_setting(self.restrict_eval),
+            bool_setting(self.allow_import_from_derivation),
+            self.nix_path.as_deref(),
+        ]
+        .iter()
+        .zip(in_use.defaults.as_ref().unwrap())
+        .map(|(value, default)| value.unwrap_or(default).to_string())
+        .collect::<Vec<_>>();
+        if in_use.states > 0 && in_use.values != values {
+            let conflicts = EVAL_SETTINGS
+                .iter()
+                .zip(&values)
+                .zip(&in_use.values)
+                .filter(|((_, value), live)| value != live)
+                .map(|((key, value), live)| format!("{} = {:?}, not {:?}", key, live, value))
+                .collect::<Vec<_>>();
+            bail!(
+                "EvalStateBuilder: Nix keeps the evaluator settings globally, and the EvalStates that are alive have {}",
+                conflicts.join(", ")
+            );
+        }
+        if in_use.states == 0 {
+            for (key, value) in EVAL_SETTINGS.iter().zip(&values) {
+                settings::set(key, value)?;
+            }
+            in_use.values = values;
+        }
+        in_use.states += 1;
+        let lease = EvalSettingsLease;
+        drop(in_use);
+        EvalState::create(
+            store,
+            &lookup_path,
+            self.offline,
+            self.eval_cache_capacity
+                .unwrap_or(DEFAULT_EVAL_CACHE_CAPACITY),
+            lease,
+        )
+    }
+}
+
+thread_local! {
+    /// The [`EvalState`]s on this thread, by their raw state, for [`EvalState::with_borrowed`].
+    static LIVE_STATES: RefCell<HashMap<*mut raw::EvalState, Weak<EvalStateRef>>> = RefCell::new(HashMap::new());
+}
+
+/// Words that look like variable names, but aren't.
+const NIX_KEYWORDS: [&str; 10] = [
+    "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+];
+
+/// The global settings that [`EvalStateBuilder`] manages, in the order of its fields.
+const EVAL_SETTINGS: [&str; 4] = [
+    "pure-eval",
+    "restrict-eval",
+    "allow-import-from-derivation",
+    "nix-path",
+];
+/// The [`EVAL_SETTINGS`] of the live [`EvalState`]s.
+#[derive(Default)]
+struct EvalSettingsInUse {
+    /// The configured values, before any [`EvalStateBuilder`] changed them.
+    defaults: Option<Vec<String>>,
+    /// The values of the live states, if there are any.
+    values: Vec<String>,
+    /// The number of live states.
+    states: usize,
+}
+lazy_static! {
+    static ref EVAL_SETTINGS_IN_USE: std::sync::Mutex<EvalSettingsInUse> = Default::default();
+}
+/// Counts an [`EvalState`] in [`EvalSettingsInUse::states`] while it is alive.
+struct EvalSettingsLease;
+impl Drop for EvalSettingsLease {
+    fn drop(&mut self) {
+        let mut in_use = EVAL_SETTINGS_IN_USE
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        in_use.states -= 1;
+    }
+}
 impl EvalState {
     pub fn new(store: Store) -> Result<Self> {
+        EvalStateBuilder::new().store(store).build()
+    }
+    /// Create an `EvalState` with additional lookup path entries. See [`EvalStateBuilder::lookup_path`].
+    pub fn new_with_lookup_path(store: Store, lookup_path: &[&str]) -> Result<Self> {
+        EvalStateBuilder::new()
+            .store(store)
+            .lookup_path(lookup_path)
+            .build()
+    }
+    fn create(
+        store: Store,
+        lookup_path: &[String],
+        offline: bool,
+        eval_cache_capacity: usize,
+        settings: EvalSettingsLease,
+    ) -> Result<Self> {
         let context = Context::new();
 
-        init()?;
+        let lookup_path = lookup_path
+            .iter()
+            .map(|entry| {
+                CString::new(entry.as_str()).with_context(|| {
+                    format!(
+                        "EvalState: lookup path entry contains null byte: {:?}",
+                        entry
+                    )
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut lookup_path_ptrs: Vec<*const c_char> =
+            lookup_path.iter().map(|s| s.as_ptr()).collect();
+        lookup_path_ptrs.push(null());
 
         let eval_state = unsafe {
             raw::nix_state_create(
                 context.ptr(),
-                /* searchPath */ null_mut(),
+                lookup_path_ptrs.as_mut_ptr(),
                 store.raw_ptr(),
             )
         };
+        context.check_err(error_site!("nix_state_create"))?;
         if eval_state.is_null() {
             bail!("nix_state_create returned a null pointer");
         }
-        Ok(EvalState {
+        let inner = Rc::new(EvalStateRef {
             eval_state: NonNull::new(eval_state).unwrap(),
             store,
-            context,
-        })
+            offline,
+            builtins: OnceCell::new(),
+            glue: RefCell::new(HashMap::new()),
+            eval_cache: RefCell::new(EvalCache {
+                capacity: eval_cache_capacity,
+                entries: HashMap::new(),
+                tick: 0,
+            }),
+            _settings: settings,
+        });
+        LIVE_STATES.with(|states| {
+            states
+                .borrow_mut()
+                .insert(eval_state, Rc::downgrade(&inner))
+        });
+        Ok(EvalState { inner, context })
+    }
+    /// Call `f` with the [`EvalState`] of a state that Nix passed to a callback, such as a primop, without taking ownership of it.
+    pub(crate) fn with_borrowed<R>(
+        eval_state: *mut raw::EvalState,
+        f: impl FnOnce(&EvalState) -> R,
+    ) -> Result<R> {
+        let inner = LIVE_STATES.with(|states| {
+            states
+                .borrow()
+                .get(&eval_state)
+                .and_then(|state| state.upgrade())
+        });
+        let inner = match inner {
+            Some(inner) => inner,
+            None => bail!("callback from an EvalState that was not created on this thread"),
+        };
+        Ok(f(&EvalState::from_ref(inner)))
+    }
+    pub(crate) fn from_ref(inner: Rc<EvalStateRef>) -> Self {
+        EvalState {
+            inner,
+            context: Context::new(),
+        }
+    }
+    pub(crate) fn state_ref(&self) -> &Rc<EvalStateRef> {
+        &self.inner
     }
     pub fn raw_ptr(&self) -> *mut raw::EvalState {
-        self.eval_state.as_ptr()
+        self.inner.eval_state.as_ptr()
     }
     pub fn store(&self) -> &Store {
-        &self.store
+        &self.inner.store
+    }
+    /// A clone of the store, e.g. to use the evaluator's store connection on another thread.
+    pub fn store_cloned(&self) -> Store {
+        self.inner.store.clone()
     }
-    pub fn eval_from_string(&self, expr: String, path: String) -> Result<Value> {
-        let expr_ptr =
-            CString::new(expr).with_context(|| "eval_from_string: expr contains null byte")?;
-        let path_ptr =
-            CString::new(path).with_context(|| "eval_from_string: path contains null byte")?;
+    /// See [`EvalStateBuilder::offline`].
+    pub(crate) fn is_offline(&self) -> bool {
+        self.inner.offline
+    }
+    /// Parse and evaluate an expression.
+    ///
+    /// In error positions within the expression itself, see [`NixError::positions`], Nix names the file `«string»` rather than `source`.
+    pub fn eval_from_string(&self, expr: impl AsRef<str>, source: SourceName) -> Result<Value> {
+        let base_dir = source.base_dir().with_context(|| "eval_from_string")?;
+        self.eval_from_string_in(expr.as_ref(), &base_dir)
+    }
+    fn eval_from_string_in(&self, expr: &str, base_dir: &CStr) -> Result<Value> {
+        let expr_ptr = CString::new(expr).map_err(|e| {
+            let offset = e.nul_position();
+            let before = &expr[..offset];
+            let line = before.matches('\n').count() + 1;
+            let column = offset - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+            anyhow::format_err!(
+                "eval_from_string: expr contains a null byte at byte {} (line {}, column {})",
+                offset,
+                line,
+                column
+            )
+        })?;
         let value = self.new_value_uninitialized();
         unsafe {
             let ctx_ptr = self.context.ptr();
@@ -78,289 +659,3873 @@ impl EvalState {
                 ctx_ptr,
                 self.raw_ptr(),
                 expr_ptr.as_ptr(),
-                path_ptr.as_ptr(),
+                base_dir.as_ptr(),
                 value.raw_ptr(),
             );
         };
-        self.context.check_err()?;
+        self.context
+            .check_err(error_site!("nix_expr_eval_from_string"))?;
+        Ok(value)
+    }
+    /// Like [`EvalState::eval_from_string`], but return the same value when the same expression is evaluated again from the same source, without parsing or evaluating it again.
+    ///
+    /// The evaluator keeps the most recently used results, up to [`EvalStateBuilder::eval_cache_capacity`]. Errors are not cached.
+    /// Because the value is shared, so is the evaluation of its attributes and list elements.
+    pub fn eval_cached(&self, expr: &str, source: SourceName) -> Result<Value> {
+        let key = (expr.to_string(), source);
+        if let Some(v) = self.inner.eval_cache.borrow_mut().get(&key) {
+            unsafe {
+                raw::nix_gc_incref(self.context.ptr(), v.as_ptr());
+            }
+            self.context.check_err(error_site!("nix_gc_incref"))?;
+            return Ok(Value::new(v.as_ptr(), &self.inner));
+        }
+        // Not borrowed while evaluating, which may call eval_cached again, e.g. from a primop
+        let v = self.eval_from_string(expr, key.1.clone())?;
+        self.inner
+            .eval_cache
+            .borrow_mut()
+            .insert(key, NonNull::new(v.raw_ptr()).unwrap());
+        Ok(v)
+    }
+    /// Forget the results of [`EvalState::eval_cached`].
+    pub fn clear_eval_cache(&self) {
+        self.inner.eval_cache.borrow_mut().clear();
+    }
+    /// Evaluate an expression in which `bindings` are variables, like `--arg` or `:a` in `nix repl`.
+    ///
+    /// The bindings are in scope like `let` bindings, so they take precedence over builtins such as `map`.
+    /// The expression is wrapped in a function of the bindings on its first line, which shifts columns there in error messages.
+    pub fn eval_with_bindings(
+        &self,
+        expr: &str,
+        source: SourceName,
+        bindings: &[(&str, &Value)],
+    ) -> Result<Value> {
+        for (name, _) in bindings {
+            if !is_simple_attr_name(name) || NIX_KEYWORDS.contains(name) {
+                bail!(
+                    "eval_with_bindings: {:?} is not a valid variable name",
+                    name
+                );
+            }
+        }
+        let names = bindings
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let f = self.eval_from_string(format!("{{ {} }}: {}", names, expr), source)?;
+        let args = self.new_value_attrs(
+            bindings
+                .iter()
+                .map(|(name, value)| (name.to_string(), (*value).clone())),
+        )?;
+        self.call(&f, &args)
+    }
+    /// Evaluate a Nix file, as `import path` does. Relative paths in the file resolve against its directory.
+    pub fn eval_from_file(&self, path: impl AsRef<Path>) -> Result<Value> {
+        let path = path.as_ref();
+        let path = if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            std::env::current_dir()
+                .with_context(|| "eval_from_file: getting the current directory")?
+                .join(path)
+        };
+        if !path.exists() {
+            bail!("eval_from_file: file does not exist: {}", path.display());
+        }
+        let path_value = self.new_value_path(&path)?;
+        self.call(&self.builtin("import")?, &path_value)
+            .with_context(|| format!("while evaluating {}", path.display()))
+    }
+    /// Evaluate a flake, like `builtins.getFlake`, and return its outputs.
+    ///
+    /// Flake references must be locked if the `EvalState` is pure, e.g. `path:/some/dir?narHash=...`.
+    /// The `flakes` experimental feature must be enabled with [`nix_util::settings::set`] before the `EvalState` is created, because that determines whether `builtins.getFlake` exists.
+    pub fn eval_flake(&self, flake_ref: &str) -> Result<Value> {
+        if !self.has_attr("eval_flake", &self.builtins()?, "getFlake")? {
+            bail!("eval_flake: the `flakes` experimental feature was not enabled when this EvalState was created");
+        }
+        let flake_ref_value = self.new_value_string(flake_ref)?;
+        self.call(&self.builtin("getFlake")?, &flake_ref_value)
+            .with_context(|| format!("while evaluating flake {}", flake_ref))
+    }
+    /// Like [`EvalState::eval_from_string`], with `path` as the base directory for relative paths.
+    #[deprecated(note = "use eval_from_string with a SourceName")]
+    pub fn eval_from_string_path(&self, expr: String, path: String) -> Result<Value> {
+        let base_dir =
+            CString::new(path).with_context(|| "eval_from_string_path: path contains null byte")?;
+        self.eval_from_string_in(&expr, &base_dir)
+    }
+    /** Try turn any Value into a Value that isn't a Thunk. */
+    pub fn force(&self, v: &Value) -> Result<()> {
+        unsafe {
+            raw::nix_value_force(self.context.ptr(), self.raw_ptr(), v.raw_ptr());
+        }
+        self.context.check_err(error_site!("nix_value_force"))?;
+        Ok(())
+    }
+    /// Evaluate a value completely, including the attributes of attribute sets and the elements of lists, like `builtins.deepSeq`.
+    ///
+    /// Functions are not called. Values that contain themselves, such as `let x = { inherit x; }; in x`, are fine,
+    /// but a value that is defined as itself, such as `rec { x = x; }.x`, fails with an infinite recursion error rather than hanging.
+    pub fn force_deep(&self, v: &Value) -> Result<()> {
+        unsafe {
+            raw::nix_value_force_deep(self.context.ptr(), self.raw_ptr(), v.raw_ptr());
+        }
+        self.context
+            .check_err(error_site!("nix_value_force_deep"))?;
+        Ok(())
+    }
+    /// Force a value, catching the errors that `builtins.tryEval` catches: `throw` and failed `assert`s.
+    ///
+    /// The outer `Result` is for other errors, including `abort`, which are not meant to be caught.
+    /// Note that most accessors already force the values they return; use [`CaughtError::from_error`] to catch errors from those.
+    pub fn try_force(&self, v: &Value) -> Result<std::result::Result<(), CaughtError>> {
+        match self.force(v) {
+            Ok(()) => Ok(Ok(())),
+            Err(e) => match CaughtError::from_error(&e) {
+                Some(caught) => Ok(Err(caught)),
+                None => Err(e),
+            },
+        }
+    }
+    pub fn value_is_thunk(&self, value: &Value) -> Result<bool> {
+        let r = unsafe {
+            raw::nix_get_type(self.context.ptr(), value.raw_ptr()) == raw::ValueType_NIX_TYPE_THUNK
+        };
+        self.context.check_err(error_site!("nix_get_type"))?;
+        Ok(r)
+    }
+    /// The type of a value, without evaluating it. Returns `None` if the value is a thunk, i.e. not evaluated yet.
+    pub fn value_type(&self, value: &Value) -> Result<Option<ValueType>> {
+        let r = unsafe { raw::nix_get_type(self.context.ptr(), value.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_type"))?;
+        Ok(match ValueType::from_raw(r) {
+            ValueType::Thunk => None,
+            t => Some(t),
+        })
+    }
+    /// The type of a value, evaluating it to weak head normal form if it is a thunk.
+    ///
+    /// If evaluation fails, the evaluation error is returned.
+    pub fn value_type_forced(&self, value: &Value) -> Result<ValueType> {
+        if self.value_is_thunk(value)? {
+            self.force(value)?;
+        }
+        let r = unsafe { raw::nix_get_type(self.context.ptr(), value.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_type"))?;
+        Ok(ValueType::from_raw(r))
+    }
+    /// An error about a value of the wrong type, showing the value, e.g. `expected a string, but got a Bool: true`.
+    pub(crate) fn type_error(&self, expected: &str, t: ValueType, v: &Value) -> anyhow::Error {
+        let shown = self
+            .value_to_display_string(v, PrintOptions::for_errors())
+            .unwrap_or_else(|_| "«error»".to_string());
+        anyhow::format_err!("expected {}, but got a {:?}: {}", expected, t, shown)
+    }
+    /// Not exposed, because the caller must always explicitly handle the context or not accept one at all.
+    fn get_string(&self, value: &Value) -> Result<String> {
+        String::from_utf8(self.get_string_bytes(value)?)
+            .map_err(|e| anyhow::format_err!("Nix string is not valid UTF-8: {}", e.utf8_error()))
+    }
+    /// `nix_get_string` returns a C string, without its length. That loses nothing, because this Nix stores strings as C strings too: a `\u0000` in `builtins.fromJSON` input ends the string for `builtins.stringLength` as well.
+    fn get_string_bytes(&self, value: &Value) -> Result<Vec<u8>> {
+        let c_str_raw = unsafe { raw::nix_get_string(self.context.ptr(), value.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_string"))?;
+        let cstring = unsafe { std::ffi::CStr::from_ptr(c_str_raw) };
+        Ok(cstring.to_bytes().to_vec())
+    }
+    /// Read a string as bytes, ignoring its context. Unlike [`EvalState::require_string`], this accepts strings that are not valid UTF-8, e.g. from `builtins.substring` in the middle of a character.
+    pub fn require_string_bytes(&self, value: &Value) -> Result<Vec<u8>> {
+        let t = self.value_type_forced(value)?;
+        if t != ValueType::String {
+            return Err(self.type_error("a string", t, value));
+        }
+        self.get_string_bytes(value)
+    }
+    /// Read a string, ignoring its context, and replace invalid UTF-8 with `U+FFFD`.
+    pub fn require_string_lossy(&self, value: &Value) -> Result<String> {
+        let bytes = self.require_string_bytes(value)?;
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+    /// Read a string, ignoring its context.
+    ///
+    /// Prefer [`EvalState::require_string_without_context`] or [`EvalState::require_string_with_context`], which do not silently drop the store paths that the string refers to.
+    pub fn require_string(&self, value: &Value) -> Result<String> {
+        let t = self.value_type_forced(value)?;
+        if t != ValueType::String {
+            return Err(self.type_error("a string", t, value));
+        }
+        self.get_string(value)
+    }
+    /// Read a string that must not have a context, such as a name or a plain setting.
+    ///
+    /// Fails if the string refers to any store path, e.g. because it contains an `outPath`.
+    pub fn require_string_without_context(&self, value: &Value) -> Result<String> {
+        let (s, context) = self.require_string_with_context(value)?;
+        if let Some(first) = context.elements().first() {
+            bail!(
+                "unexpected string context: expected a plain string, but it refers to {} store object(s), including {:?}",
+                context.elements().len(),
+                first
+            );
+        }
+        Ok(s)
+    }
+    /// Read a string along with its context: the store objects that must be realised for the string to be meaningful.
+    pub fn require_string_with_context(&self, value: &Value) -> Result<(String, StringContext)> {
+        let t = self.value_type_forced(value)?;
+        if t != ValueType::String {
+            return Err(self.type_error("a string", t, value));
+        }
+        let context = self.string_context(value)?;
+        Ok((self.get_string(value)?, context))
+    }
+
+    /// Read at most `max_bytes` bytes of a string, for previews.
+    ///
+    /// Returns the prefix, with invalid UTF-8 replaced, and the length of the whole string in bytes.
+    /// Unlike [`EvalState::require_string`], this does not copy the whole string, so it is suitable for strings of any size.
+    /// The string context is ignored.
+    pub fn require_string_lossy_truncated(
+        &self,
+        value: &Value,
+        max_bytes: usize,
+    ) -> Result<(String, usize)> {
+        let t = self.value_type_forced(value)?;
+        if t != ValueType::String {
+            return Err(self.type_error("a string", t, value));
+        }
+        let c_str_raw = unsafe { raw::nix_get_string(self.context.ptr(), value.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_string"))?;
+        let bytes = unsafe { std::ffi::CStr::from_ptr(c_str_raw) }.to_bytes();
+        let prefix = &bytes[..bytes.len().min(max_bytes)];
+        Ok((String::from_utf8_lossy(prefix).into_owned(), bytes.len()))
+    }
+
+    pub fn require_int(&self, v: &Value) -> Result<i64> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::Int {
+            return Err(self.type_error("an int", t, v));
+        }
+        let i = unsafe { raw::nix_get_int(self.context.ptr(), v.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_int"))?;
+        Ok(i)
+    }
+    pub fn require_bool(&self, v: &Value) -> Result<bool> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::Bool {
+            return Err(self.type_error("a bool", t, v));
+        }
+        let b = unsafe { raw::nix_get_bool(self.context.ptr(), v.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_bool"))?;
+        Ok(b)
+    }
+    pub fn require_float(&self, v: &Value) -> Result<f64> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::Float {
+            return Err(self.type_error("a float", t, v));
+        }
+        let f = unsafe { raw::nix_get_float(self.context.ptr(), v.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_float"))?;
+        Ok(f)
+    }
+
+    /// Evaluate, and require that the value is a path; return it as it is, without copying it to the store.
+    ///
+    /// Relative path literals have already been resolved at parse time. The path is not required to be valid UTF-8.
+    pub fn require_path(&self, v: &Value) -> Result<PathBuf> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::Path {
+            return Err(self.type_error("a path", t, v));
+        }
+        let c_str_raw = unsafe { raw::nix_get_path_string(self.context.ptr(), v.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_path_string"))?;
+        let bytes = unsafe { std::ffi::CStr::from_ptr(c_str_raw) }.to_bytes();
+        Ok(PathBuf::from(std::ffi::OsStr::from_bytes(bytes)))
+    }
+
+    /// Evaluate, and require that the value is an attribute set with the attribute `name`; return the attribute's value.
+    ///
+    /// The attribute value is not evaluated, so an attribute that fails to evaluate only returns its evaluation error when it is forced.
+    pub fn require_attrs_select(&self, v: &Value, name: &str) -> Result<Value> {
+        match self.attrs_select_opt("require_attrs_select", v, name)? {
+            Some(value) => Ok(value),
+            None => bail!("attribute `{}` not found", name),
+        }
+    }
+    /// Like [`EvalState::require_attrs_select`], but returns `None` when the attribute does not exist.
+    pub fn require_attrs_select_opt(&self, v: &Value, name: &str) -> Result<Option<Value>> {
+        self.attrs_select_opt("require_attrs_select_opt", v, name)
+    }
+    fn attrs_select_opt(&self, caller: &str, v: &Value, name: &str) -> Result<Option<Value>> {
+        if !self.has_attr(caller, v, name)? {
+            return Ok(None);
+        }
+        // nix_get_attr_byname forces the attribute, so select it with a thunk instead
+        let name = self.new_value_string(name)?;
+        let select = self.new_value_apply(&self.builtin("getAttr")?, &name)?;
+        Ok(Some(self.new_value_apply(&select, v)?))
+    }
+    /// Like [`EvalState::require_attrs_select`], but evaluates the attribute, and returns the value that is stored in the attribute set, e.g. to compare it by pointer.
+    pub(crate) fn require_attrs_select_forced(&self, v: &Value, name: &str) -> Result<Value> {
+        if !self.has_attr("require_attrs_select_forced", v, name)? {
+            bail!("attribute `{}` not found", name);
+        }
+        let name_ptr = CString::new(name).unwrap();
+        let value = unsafe {
+            raw::nix_get_attr_byname(
+                self.context.ptr(),
+                v.raw_ptr(),
+                self.raw_ptr(),
+                name_ptr.as_ptr(),
+            )
+        };
+        self.context
+            .check_err(error_site!("nix_get_attr_byname"))
+            .with_context(|| format!("while selecting attribute `{}`", name))?;
+        Ok(Value::new(value, &self.inner))
+    }
+    /// Whether the attribute set `v` has the attribute `name`. Errors are prefixed with `caller`, the public function that checks it.
+    fn has_attr(&self, caller: &str, v: &Value, name: &str) -> Result<bool> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::AttrSet {
+            return Err(self.type_error(
+                &format!("an attribute set to select `{}` from", name),
+                t,
+                v,
+            ));
+        }
+        let name_ptr = CString::new(name).with_context(|| {
+            format!("{}: attribute name contains null byte: {:?}", caller, name)
+        })?;
+        let has = unsafe {
+            raw::nix_has_attr_byname(
+                self.context.ptr(),
+                v.raw_ptr(),
+                self.raw_ptr(),
+                name_ptr.as_ptr(),
+            )
+        };
+        self.context.check_err(error_site!("nix_has_attr_byname"))?;
+        Ok(has)
+    }
+    /// Evaluate, and require that the value is an attribute set; return its attribute names in lexicographic order.
+    ///
+    /// The attribute values are not evaluated.
+    pub fn require_attrs_names(&self, v: &Value) -> Result<Vec<String>> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::AttrSet {
+            return Err(self.type_error("an attribute set", t, v));
+        }
+        let n = unsafe { raw::nix_get_attrs_size(self.context.ptr(), v.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_attrs_size"))?;
+        let mut names = Vec::with_capacity(n as usize);
+        for i in 0..n {
+            let name_raw = unsafe {
+                raw::nix_get_attr_name_byidx(self.context.ptr(), v.raw_ptr(), self.raw_ptr(), i)
+            };
+            self.context
+                .check_err(error_site!("nix_get_attr_name_byidx"))?;
+            let name = unsafe { std::ffi::CStr::from_ptr(name_raw) }
+                .to_str()
+                .map_err(|e| anyhow::format_err!("Nix attribute name is not valid UTF-8: {}", e))?;
+            names.push(name.to_owned());
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    /// Evaluate, and require that the value is an attribute set; iterate over its attributes in the order of [`EvalState::require_attrs_names`].
+    ///
+    /// The attribute values are not evaluated: `nix_get_attr_byidx` forces the values it returns, so instead each value is a thunk that selects the attribute when forced.
+    /// The names are read up front, so forcing values during the iteration is fine.
+    pub fn attrs_iter<'a>(&'a self, v: &'a Value) -> Result<AttrsIter<'a>> {
+        let names = self.require_attrs_names(v)?;
+        let select = self.glue("attrs: name: attrs.${name}")?;
+        let select = self.call(&select, v)?;
+        Ok(AttrsIter {
+            eval_state: self,
+            select,
+            names: names.into_iter(),
+        })
+    }
+
+    /// Evaluate, and require that the value is a list; return its length.
+    ///
+    /// The elements are not evaluated.
+    pub fn require_list_size(&self, v: &Value) -> Result<usize> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::List {
+            return Err(self.type_error("a list", t, v));
+        }
+        let n = unsafe { raw::nix_get_list_size(self.context.ptr(), v.raw_ptr()) };
+        self.context.check_err(error_site!("nix_get_list_size"))?;
+        Ok(n as usize)
+    }
+    /// Evaluate, and require that the value is a list with an element at index `i`; return that element.
+    ///
+    /// The element is not evaluated.
+    pub fn require_list_select_idx(&self, v: &Value, i: usize) -> Result<Value> {
+        let size = self.require_list_size(v)?;
+        if i >= size {
+            bail!(
+                "list index {} is out of bounds for a list of length {}",
+                i,
+                size
+            );
+        }
+        // nix_get_list_byidx forces the element, so select it with a thunk instead
+        let select = self.new_value_apply(&self.builtin("elemAt")?, v)?;
+        let i = self.new_value_int(i as i64)?;
+        self.new_value_apply(&select, &i)
+    }
+    /// Like [`EvalState::require_list_select_idx`], but evaluates the element, and returns the value that is stored in the list, e.g. to compare it by pointer.
+    pub(crate) fn require_list_select_idx_forced(&self, v: &Value, i: usize) -> Result<Value> {
+        let size = self.require_list_size(v)?;
+        if i >= size {
+            bail!(
+                "list index {} is out of bounds for a list of length {}",
+                i,
+                size
+            );
+        }
+        // i < size, and size came from a c_uint
+        let value = unsafe {
+            raw::nix_get_list_byidx(self.context.ptr(), v.raw_ptr(), self.raw_ptr(), i as c_uint)
+        };
+        self.context.check_err(error_site!("nix_get_list_byidx"))?;
+        Ok(Value::new(value, &self.inner))
+    }
+
+    /// Evaluate, and require that the value is a list; iterate over its elements, from either end.
+    ///
+    /// The elements are not evaluated: like the values of [`EvalState::attrs_iter`], each is a thunk that selects the element when forced.
+    pub fn list_iter<'a>(&'a self, v: &'a Value) -> Result<ListIter<'a>> {
+        let size = self.require_list_size(v)?;
+        let select = self.call(&self.builtin("elemAt")?, v)?;
+        Ok(ListIter {
+            eval_state: self,
+            select,
+            indices: 0..size,
+        })
+    }
+    /// Evaluate, and require that the value is a list; convert each element with `f`.
+    ///
+    /// An error of `f` says which element it is about.
+    pub fn require_list_of<T>(&self, v: &Value, f: impl Fn(&Value) -> Result<T>) -> Result<Vec<T>> {
+        self.list_iter(v)?
+            .enumerate()
+            .map(|(i, r)| {
+                r.and_then(|value| f(&value))
+                    .with_context(|| format!("while evaluating list element {}", i))
+            })
+            .collect()
+    }
+    /// Evaluate, and require that the value is a list of strings; see [`EvalState::require_string`].
+    pub fn require_list_of_strings(&self, v: &Value) -> Result<Vec<String>> {
+        self.require_list_of(v, |value| self.require_string(value))
+    }
+
+    pub fn new_value_int(&self, i: i64) -> Result<Value> {
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_init_int(self.context.ptr(), value.raw_ptr(), i);
+        }
+        self.context.check_err(error_site!("nix_init_int"))?;
+        Ok(value)
+    }
+    pub fn new_value_bool(&self, b: bool) -> Result<Value> {
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_init_bool(self.context.ptr(), value.raw_ptr(), b);
+        }
+        self.context.check_err(error_site!("nix_init_bool"))?;
+        Ok(value)
+    }
+    pub fn new_value_float(&self, f: f64) -> Result<Value> {
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_init_float(self.context.ptr(), value.raw_ptr(), f);
+        }
+        self.context.check_err(error_site!("nix_init_float"))?;
+        Ok(value)
+    }
+    /// Create a string value without context.
+    pub fn new_value_string(&self, s: &str) -> Result<Value> {
+        let s_ptr = CString::new(s)
+            .with_context(|| format!("new_value_string: string contains null byte: {:?}", s))?;
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_init_string(self.context.ptr(), value.raw_ptr(), s_ptr.as_ptr());
+        }
+        self.context.check_err(error_site!("nix_init_string"))?;
+        Ok(value)
+    }
+    pub fn new_value_null(&self) -> Result<Value> {
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_init_null(self.context.ptr(), value.raw_ptr());
+        }
+        self.context.check_err(error_site!("nix_init_null"))?;
         Ok(value)
     }
-    /** Try turn any Value into a Value that isn't a Thunk. */
-    pub fn force(&self, v: &Value) -> Result<()> {
-        unsafe {
-            raw::nix_value_force(self.context.ptr(), self.raw_ptr(), v.raw_ptr());
-        }
-        self.context.check_err()
+
+    /// Start building an attribute set with room for `capacity` attributes.
+    pub fn new_attrset_builder(&self, capacity: usize) -> Result<AttrsetBuilder<'_>> {
+        let builder =
+            unsafe { raw::nix_make_bindings_builder(self.context.ptr(), self.raw_ptr(), capacity) };
+        self.context
+            .check_err(error_site!("nix_make_bindings_builder"))?;
+        Ok(AttrsetBuilder {
+            eval_state: self,
+            builder: NonNull::new(builder).unwrap(),
+            names: BTreeSet::new(),
+            values: Vec::with_capacity(capacity),
+        })
+    }
+    /// Create an attribute set from name-value pairs. Duplicate names are an error.
+    pub fn new_value_attrs(
+        &self,
+        attrs: impl IntoIterator<Item = (String, Value)>,
+    ) -> Result<Value> {
+        let attrs: Vec<(String, Value)> = attrs.into_iter().collect();
+        let mut builder = self.new_attrset_builder(attrs.len())?;
+        for (name, value) in &attrs {
+            builder.insert(name, value)?;
+        }
+        builder.build()
+    }
+
+    /// Create a list value.
+    pub fn new_value_list(&self, items: &[Value]) -> Result<Value> {
+        let builder =
+            unsafe { raw::nix_make_list_builder(self.context.ptr(), self.raw_ptr(), items.len()) };
+        self.context
+            .check_err(error_site!("nix_make_list_builder"))?;
+        let builder = NonNull::new(builder).unwrap();
+        let r = (|| {
+            for (i, item) in items.iter().enumerate() {
+                let i: c_uint = i
+                    .try_into()
+                    .with_context(|| "new_value_list: list is too long")?;
+                unsafe {
+                    raw::nix_list_builder_insert(
+                        self.context.ptr(),
+                        builder.as_ptr(),
+                        i,
+                        item.raw_ptr(),
+                    );
+                }
+                self.context
+                    .check_err(error_site!("nix_list_builder_insert"))?;
+            }
+            let value = self.new_value_uninitialized();
+            unsafe {
+                raw::nix_make_list(self.context.ptr(), builder.as_ptr(), value.raw_ptr());
+            }
+            self.context.check_err(error_site!("nix_make_list"))?;
+            Ok(value)
+        })();
+        unsafe {
+            raw::nix_list_builder_free(builder.as_ptr());
+        }
+        r
+    }
+    /// Create a list of strings without context, e.g. resource names.
+    pub fn new_value_list_of_strings(
+        &self,
+        items: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> Result<Value> {
+        let items = items.into_iter();
+        let mut values = Vec::with_capacity(items.size_hint().0);
+        for item in items {
+            values.push(self.new_value_string(item.as_ref())?);
+        }
+        self.new_value_list(&values)
+    }
+    /// Create a list of integers.
+    pub fn new_value_list_of_ints(&self, items: impl IntoIterator<Item = i64>) -> Result<Value> {
+        let items = items.into_iter();
+        let mut values = Vec::with_capacity(items.size_hint().0);
+        for item in items {
+            values.push(self.new_value_int(item)?);
+        }
+        self.new_value_list(&values)
+    }
+    /// Create an attribute set of strings without context, e.g. tags. Duplicate names are an error, as in [`EvalState::new_value_attrs`].
+    pub fn new_value_attrs_of_strings<K: AsRef<str>, V: AsRef<str>>(
+        &self,
+        attrs: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<Value> {
+        let attrs = attrs.into_iter();
+        let mut values = Vec::with_capacity(attrs.size_hint().0);
+        for (name, value) in attrs {
+            values.push((name, self.new_value_string(value.as_ref())?));
+        }
+        // The builder needs the exact number of attributes up front
+        let mut builder = self.new_attrset_builder(values.len())?;
+        for (name, value) in &values {
+            builder.insert(name.as_ref(), value)?;
+        }
+        builder.build()
+    }
+
+    /// Create a path value, which behaves exactly like a path literal in a Nix expression.
+    ///
+    /// In particular, it is copied to the store when it is interpolated into a string or used in a derivation.
+    /// Use a string instead if the path should not be copied.
+    pub fn new_value_path(&self, path: &Path) -> Result<Value> {
+        if !path.is_absolute() {
+            bail!("new_value_path: path must be absolute, but got {:?}", path);
+        }
+        let path_ptr = CString::new(path.as_os_str().as_bytes())
+            .with_context(|| format!("new_value_path: path contains null byte: {:?}", path))?;
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_init_path_string(
+                self.context.ptr(),
+                self.raw_ptr(),
+                value.raw_ptr(),
+                path_ptr.as_ptr(),
+            );
+        }
+        self.context
+            .check_err(error_site!("nix_init_path_string"))?;
+        Ok(value)
+    }
+    /// Create the placeholder string for a derivation output, like `builtins.placeholder output_name`.
+    ///
+    /// The derivation builder replaces it by the actual output path.
+    pub fn new_value_placeholder(&self, output_name: &str) -> Result<Value> {
+        let name = self
+            .new_value_string(output_name)
+            .with_context(|| "new_value_placeholder")?;
+        self.call(&self.builtin("placeholder")?, &name)
+    }
+
+    /// Coerce a value to a string following Nix's rules, returning the string and its context.
+    ///
+    /// See [`CoerceOpts`] for the choice between interpolation and `builtins.toString` semantics.
+    /// Functions can never be coerced.
+    pub fn coerce_to_string(&self, v: &Value, opts: CoerceOpts) -> Result<(String, StringContext)> {
+        let glue = match (opts.coerce_more, opts.copy_to_store) {
+            (false, true) => "v: \"${v}\"",
+            (true, false) => "builtins.toString",
+            (false, false) => {
+                let t = self.value_type_forced(v)?;
+                match t {
+                    ValueType::String | ValueType::Path | ValueType::AttrSet | ValueType::External => {}
+                    _ => bail!("cannot coerce {} to a string", t.describe()),
+                }
+                "builtins.toString"
+            }
+            // builtins.toString never copies, so copy the paths first
+            (true, true) => {
+                "let go = v: if builtins.isPath v then \"${v}\" else if builtins.isList v then map go v else v; in v: builtins.toString (go v)"
+            }
+        };
+        let s = self.call(&self.glue(glue)?, v)?;
+        let context = self.string_context(&s)?;
+        Ok((self.get_string(&s)?, context))
+    }
+    /// Build or substitute the store paths that a string refers to, the paths in its context, so that the string can be used outside of Nix.
+    ///
+    /// Set `is_ifd` when realising on behalf of an expression that is being evaluated, which is then subject to `allow-import-from-derivation`.
+    /// A string without context is returned as is, without building anything.
+    pub fn realise_string(&self, v: &Value, is_ifd: bool) -> Result<RealisedString> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::String {
+            return Err(self.type_error("a string", t, v));
+        }
+        let context = self.string_context(v)?;
+        version::require(Capability::RealiseString, error_site!("nix_string_realise"))?;
+        let rs = unsafe {
+            raw::nix_string_realise(self.context.ptr(), self.raw_ptr(), v.raw_ptr(), is_ifd)
+        };
+        self.context.check_err(error_site!("nix_string_realise"))?;
+        let rs = match NonNull::new(rs) {
+            Some(rs) => rs,
+            None => bail!("nix_string_realise returned a null pointer"),
+        };
+        let bytes = unsafe {
+            let start = raw::nix_realised_string_get_buffer_start(rs.as_ptr());
+            let size = raw::nix_realised_string_get_buffer_size(rs.as_ptr());
+            std::slice::from_raw_parts(start as *const u8, size).to_vec()
+        };
+        unsafe {
+            raw::nix_realised_string_free(rs.as_ptr());
+        }
+        let string = String::from_utf8(bytes)
+            .map_err(|e| anyhow::format_err!("realised string is not valid UTF-8: {}", e))?;
+
+        // The realised string only exposes the paths as opaque pointers, so find them through the context.
+        // The derivations have been built, so realising them again only looks up their outputs.
+        let mut paths = BTreeMap::new();
+        for element in context.elements() {
+            match element {
+                StringContextElement::Opaque { path } => {
+                    paths.insert(path.clone(), self.store().parse_store_path(path)?);
+                }
+                StringContextElement::DerivationOutput { drv_path, output } => {
+                    let drv = self.store().parse_store_path(drv_path)?;
+                    let mut outputs = self.store().realise(&drv)?;
+                    match outputs.remove(output) {
+                        Some(out) => {
+                            paths.insert(out.as_str().to_string(), out);
+                        }
+                        None => bail!("derivation {} has no output `{}`", drv_path, output),
+                    }
+                }
+                StringContextElement::DerivationDeep { drv_path } => {
+                    let drv = self.store().parse_store_path(drv_path)?;
+                    for (_, out) in self.store().realise(&drv)? {
+                        paths.insert(out.as_str().to_string(), out);
+                    }
+                    paths.insert(drv_path.clone(), drv);
+                }
+            }
+        }
+        Ok(RealisedString {
+            string,
+            paths: paths.into_values().collect(),
+        })
+    }
+
+    /// Read the store paths and basic attributes of a derivation, which is an attribute set with `type = "derivation"`.
+    ///
+    /// Output paths are `None` for content-addressed derivations, whose outputs are not known until they are built.
+    pub fn require_derivation(&self, v: &Value) -> Result<DerivationInfo> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::AttrSet {
+            return Err(self.type_error("a derivation", t, v));
+        }
+        if !self.is_derivation(v)? {
+            bail!("expected a derivation, but got an attribute set");
+        }
+        let name = self.require_string(&self.require_attrs_select(v, "name")?)?;
+        let system = self.require_string(&self.require_attrs_select(v, "system")?)?;
+        let drv_path = self.require_string(&self.require_attrs_select(v, "drvPath")?)?;
+        let drv_path = self
+            .store()
+            .parse_store_path(&drv_path)
+            .with_context(|| format!("while reading the drvPath of derivation {}", name))?;
+
+        let output_names = self.require_attrs_select(v, "outputs")?;
+        let n = self.require_list_size(&output_names)?;
+        let mut outputs = Vec::with_capacity(n);
+        for i in 0..n {
+            let output = self.require_string(&self.require_list_select_idx(&output_names, i)?)?;
+            let out = self.require_attrs_select(v, &output)?;
+            let out_path = self.require_string(&self.require_attrs_select(&out, "outPath")?)?;
+            // Floating content-addressed outputs have a placeholder, `/<hash>`, instead of a store path.
+            let is_placeholder = out_path
+                .strip_prefix('/')
+                .is_some_and(|rest| !rest.contains('/'));
+            let out_path = if is_placeholder {
+                None
+            } else {
+                Some(self.store().parse_store_path(&out_path)?)
+            };
+            outputs.push((output, out_path));
+        }
+        Ok(DerivationInfo {
+            drv_path,
+            outputs,
+            name,
+            system,
+        })
+    }
+
+    /// Whether an attribute set is a derivation, i.e. has `type = "derivation"`. Only evaluates `type`.
+    pub(crate) fn is_derivation(&self, v: &Value) -> Result<bool> {
+        Ok(match self.require_attrs_select_opt(v, "type")? {
+            Some(ty) => {
+                self.value_type_forced(&ty)? == ValueType::String
+                    && self.require_string(&ty)? == "derivation"
+            }
+            None => false,
+        })
+    }
+
+    /// Evaluate a derivation and return its `.drv` path, like `nix-instantiate`.
+    ///
+    /// Evaluating `drvPath` writes the derivation to the store, but does not build it. Whether evaluating the derivation may build other derivations depends on [`EvalStateBuilder::allow_import_from_derivation`].
+    pub fn instantiate(&self, v: &Value) -> Result<StorePath> {
+        let d = self.require_derivation(v)?;
+        if !self.store().is_valid_path(&d.drv_path)? {
+            bail!(
+                "derivation {} was not written to the store, which may be read-only",
+                d.drv_path
+            );
+        }
+        Ok(d.drv_path)
+    }
+
+    /// Read the context of a string value.
+    pub(crate) fn string_context(&self, s: &Value) -> Result<StringContext> {
+        let json = self.call(&self.glue("s: builtins.toJSON (builtins.getContext s)")?, s)?;
+        StringContext::from_get_context_json(&self.get_string(&json)?)
+    }
+    /// The `builtins` attribute set. It is evaluated once per evaluator, and kept for as long as the evaluator lives.
+    pub fn builtins(&self) -> Result<Value> {
+        if let Some(builtins) = self.inner.builtins.get() {
+            unsafe {
+                raw::nix_gc_incref(self.context.ptr(), builtins.as_ptr());
+            }
+            self.context.check_err(error_site!("nix_gc_incref"))?;
+            return Ok(Value::new(builtins.as_ptr(), &self.inner));
+        }
+        let v = self.eval_from_string("builtins", SourceName::Synthetic("nixops4 glue"))?;
+        unsafe {
+            raw::nix_gc_incref(self.context.ptr(), v.raw_ptr());
+        }
+        self.context.check_err(error_site!("nix_gc_incref"))?;
+        if let Err(extra) = self.inner.builtins.set(NonNull::new(v.raw_ptr()).unwrap()) {
+            unsafe {
+                raw::nix_gc_decref(self.context.ptr(), extra.as_ptr());
+            }
+        }
+        Ok(v)
+    }
+    /// A builtin function, such as `toJSON`, from the cached [`EvalState::builtins`].
+    pub(crate) fn builtin(&self, name: &str) -> Result<Value> {
+        self.require_attrs_select_forced(&self.builtins()?, name)
+    }
+    /// The value of some glue code, such as a function that builtins alone don't provide. It is evaluated once per evaluator, like [`EvalState::builtins`].
+    pub(crate) fn glue(&self, expr: &'static str) -> Result<Value> {
+        if let Some(v) = self.inner.glue.borrow().get(expr) {
+            unsafe {
+                raw::nix_gc_incref(self.context.ptr(), v.as_ptr());
+            }
+            self.context.check_err(error_site!("nix_gc_incref"))?;
+            return Ok(Value::new(v.as_ptr(), &self.inner));
+        }
+        let v = self.eval_from_string(expr, SourceName::Synthetic("nixops4 glue"))?;
+        unsafe {
+            raw::nix_gc_incref(self.context.ptr(), v.raw_ptr());
+        }
+        self.context.check_err(error_site!("nix_gc_incref"))?;
+        let old = self
+            .inner
+            .glue
+            .borrow_mut()
+            .insert(expr, NonNull::new(v.raw_ptr()).unwrap());
+        if let Some(old) = old {
+            unsafe {
+                raw::nix_gc_decref(self.context.ptr(), old.as_ptr());
+            }
+        }
+        Ok(v)
+    }
+    /// `builtins.toJSON`, without the string context of the result.
+    pub fn builtin_to_json(&self, v: &Value) -> Result<String> {
+        let json = self.call(&self.builtin("toJSON")?, v)?;
+        self.get_string(&json)
+    }
+    /// `builtins.fromJSON`.
+    pub fn builtin_from_json(&self, s: &str) -> Result<Value> {
+        let s = self.new_value_string(s)?;
+        self.call(&self.builtin("fromJSON")?, &s)
+    }
+    /// `builtins.typeOf`, e.g. `"set"` or `"lambda"`. See [`EvalState::value_type_forced`] for the type as a [`ValueType`].
+    pub fn builtin_typeof(&self, v: &Value) -> Result<String> {
+        let t = self.call(&self.builtin("typeOf")?, v)?;
+        self.get_string(&t)
+    }
+    /// The context of a string: the store objects it refers to. See [`EvalState::require_string_with_context`] to read the string as well.
+    pub fn string_get_context(&self, v: &Value) -> Result<StringContext> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::String {
+            return Err(self.type_error("a string", t, v));
+        }
+        self.string_context(v)
+    }
+    /// The same string without its context, like `builtins.unsafeDiscardStringContext`.
+    ///
+    /// Nothing is built when such a string is realised, and derivations that use it don't depend on the store objects it mentions.
+    pub fn string_discard_context(&self, v: &Value) -> Result<Value> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::String {
+            return Err(self.type_error("a string", t, v));
+        }
+        self.call(&self.builtin("unsafeDiscardStringContext")?, v)
+    }
+    /// The same string with `ctx` added to its context, like `builtins.appendContext`, e.g. to declare a dependency that Nix can't see.
+    ///
+    /// Like `builtins.appendContext`, this fails if a path in `ctx` is not a store path, or if a derivation element is not a `.drv` path.
+    pub fn string_with_added_context(&self, v: &Value, ctx: &StringContext) -> Result<Value> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::String {
+            return Err(self.type_error("a string", t, v));
+        }
+        let f = self.glue("s: json: builtins.appendContext s (builtins.fromJSON json)")?;
+        let json = self.new_value_string(&ctx.to_get_context_json())?;
+        self.call_multi(&f, &[v.clone(), json])
+    }
+    /// Apply a function to an argument. The result is evaluated to weak head normal form.
+    ///
+    /// Besides functions, attribute sets with a `__functor` can be called.
+    pub fn call(&self, f: &Value, arg: &Value) -> Result<Value> {
+        let t = self.value_type_forced(f)?;
+        if t != ValueType::Function && t != ValueType::AttrSet {
+            return Err(self.type_error("a function", t, f));
+        }
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_value_call(
+                self.context.ptr(),
+                self.raw_ptr(),
+                f.raw_ptr(),
+                arg.raw_ptr(),
+                value.raw_ptr(),
+            );
+        }
+        self.context.check_err(error_site!("nix_value_call"))?;
+        Ok(value)
+    }
+    /// A value that applies `f` to `arg` when forced. Unlike [`EvalState::call`], nothing is evaluated yet.
+    pub fn new_value_apply(&self, f: &Value, arg: &Value) -> Result<Value> {
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_init_apply(
+                self.context.ptr(),
+                value.raw_ptr(),
+                f.raw_ptr(),
+                arg.raw_ptr(),
+            );
+        }
+        self.context.check_err(error_site!("nix_init_apply"))?;
+        Ok(value)
+    }
+    /// Apply a curried function to the arguments, from left to right, like `f a b c`.
+    ///
+    /// With no arguments, `f` is returned as is.
+    pub fn call_multi(&self, f: &Value, args: &[Value]) -> Result<Value> {
+        let mut r = f.clone();
+        for (i, arg) in args.iter().enumerate() {
+            r = self
+                .call(&r, arg)
+                .with_context(|| format!("while applying argument {}", i + 1))?;
+        }
+        Ok(r)
+    }
+
+    /// Call a function with automatic arguments, like `nix-build --arg` does.
+    ///
+    /// If `f` is a function with formal arguments, `{ a, b ? 1 }: ...`, it is called with the attributes of `args` that it accepts, or with all of them if it has an ellipsis.
+    /// Formals that `args` does not provide use their defaults; a formal without a default is an error.
+    /// Any other value, including a function with a plain argument, `x: ...`, is returned unchanged.
+    pub fn auto_call(&self, f: &Value, args: &Value) -> Result<Value> {
+        let t = self.value_type_forced(args)?;
+        if t != ValueType::AttrSet {
+            bail!(
+                "auto_call: {}",
+                self.type_error("an attribute set of arguments", t, args)
+            );
+        }
+        // The C API does not expose formals, but toXML renders them as <attrspat>, and its attributes in sorted order.
+        let glue = self.glue(
+            r#"
+            let
+              autoCall = f: args:
+                if builtins.isAttrs f && f ? __functor then autoCall (f.__functor f) args
+                else if !(builtins.isFunction f) then f
+                else
+                  let
+                    xml = builtins.toXML f;
+                    hasFormals = builtins.length (builtins.split "<attrspat" xml) > 1;
+                    ellipsis = builtins.length (builtins.split "<attrspat ellipsis=\"1\"" xml) > 1;
+                    formals = builtins.functionArgs f;
+                    missing = builtins.filter (n: !formals.${n} && !(args ? ${n})) (builtins.attrNames formals);
+                  in
+                  if !hasFormals then f
+                  else if missing != [ ] then
+                    throw "cannot evaluate a function that has an argument without a value ('${builtins.head missing}')"
+                  else f (if ellipsis then args else builtins.intersectAttrs formals args);
+            in autoCall
+            "#,
+        )?;
+        self.call_multi(&glue, &[f.clone(), args.clone()])
+    }
+
+    /// Evaluate, and require that the value is a function; return its formal arguments if it takes an attribute set pattern, like `{ a, b ? 1, ... }: ...`.
+    ///
+    /// Functions with a plain argument, `x: ...`, return `None`. So do primops, such as `builtins.map`, and partially applied primops, because Nix does not know the names of their arguments.
+    /// A partially applied function with formals is the function that it returns, which has formals of its own, or not.
+    pub fn require_function_formals(&self, v: &Value) -> Result<Option<Formals>> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::Function {
+            return Err(self.type_error("a function", t, v));
+        }
+        // See auto_call. functionArgs alone returns { } for both `{ }: ...` and `x: ...`.
+        let glue = self.glue(
+            r#"
+            f:
+              let xml = builtins.toXML f;
+              in
+              if builtins.length (builtins.split "<attrspat" xml) > 1 then {
+                ellipsis = builtins.length (builtins.split "<attrspat ellipsis=\"1\"" xml) > 1;
+                args = builtins.functionArgs f;
+              }
+              else null
+            "#,
+        )?;
+        let r = self.call(&glue, v)?;
+        if self.value_type_forced(&r)? == ValueType::Null {
+            return Ok(None);
+        }
+        let ellipsis = self.require_attrs_select(&r, "ellipsis")?;
+        let ellipsis = self.require_bool(&ellipsis)?;
+        let args_value = self.require_attrs_select(&r, "args")?;
+        let args = self
+            .attrs_iter(&args_value)?
+            .map(|r| {
+                let (name, has_default) = r?;
+                let has_default = self.require_bool(&has_default)?;
+                Ok((name, has_default))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(Formals { args, ellipsis }))
+    }
+
+    pub(crate) fn new_value_uninitialized(&self) -> Value {
+        let value = unsafe { raw::nix_alloc_value(self.context.ptr(), self.raw_ptr()) };
+        Value::new(value, &self.inner)
+    }
+}
+
+/// The attributes of an attribute set, as `(name, value)` pairs. Created by [`EvalState::attrs_iter`].
+pub struct AttrsIter<'a> {
+    eval_state: &'a EvalState,
+    /// The attribute set, applied to the selector function
+    select: Value,
+    names: std::vec::IntoIter<String>,
+}
+impl Iterator for AttrsIter<'_> {
+    type Item = Result<(String, Value)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let name = self.names.next()?;
+        let r = self
+            .eval_state
+            .new_value_string(&name)
+            .and_then(|n| self.eval_state.new_value_apply(&self.select, &n))
+            .with_context(|| format!("while iterating over attribute `{}`", name));
+        match r {
+            Ok(value) => Some(Ok((name, value))),
+            Err(e) => {
+                // Don't continue after an error
+                self.names = Vec::new().into_iter();
+                Some(Err(e))
+            }
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.names.size_hint()
+    }
+}
+impl ExactSizeIterator for AttrsIter<'_> {}
+
+/// The elements of a list. Created by [`EvalState::list_iter`].
+pub struct ListIter<'a> {
+    eval_state: &'a EvalState,
+    /// The list, applied to the selector function
+    select: Value,
+    indices: std::ops::Range<usize>,
+}
+impl ListIter<'_> {
+    fn element(&mut self, i: usize) -> Result<Value> {
+        let r = self
+            .eval_state
+            .new_value_int(i as i64)
+            .and_then(|i| self.eval_state.new_value_apply(&self.select, &i))
+            .with_context(|| format!("while iterating over list element {}", i));
+        if r.is_err() {
+            // Don't continue after an error
+            self.indices = 0..0;
+        }
+        r
+    }
+}
+impl Iterator for ListIter<'_> {
+    type Item = Result<Value>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let i = self.indices.next()?;
+        Some(self.element(i))
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.indices.size_hint()
+    }
+}
+impl DoubleEndedIterator for ListIter<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let i = self.indices.next_back()?;
+        Some(self.element(i))
+    }
+}
+impl ExactSizeIterator for ListIter<'_> {}
+
+/// Builds an attribute set value. Created by [`EvalState::new_attrset_builder`].
+pub struct AttrsetBuilder<'a> {
+    eval_state: &'a EvalState,
+    builder: NonNull<raw::BindingsBuilder>,
+    names: BTreeSet<String>,
+    /// Keep the inserted values referenced until the attribute set is built.
+    values: Vec<Value>,
+}
+impl AttrsetBuilder<'_> {
+    /// Add an attribute. Inserting the same name twice is an error, rather than the last or first one winning.
+    pub fn insert(&mut self, name: &str, value: &Value) -> Result<()> {
+        if self.names.contains(name) {
+            bail!("duplicate attribute `{}` in attribute set", name);
+        }
+        let name_ptr = CString::new(name).with_context(|| {
+            format!(
+                "AttrsetBuilder: attribute name contains null byte: {:?}",
+                name
+            )
+        })?;
+        let es = self.eval_state;
+        unsafe {
+            raw::nix_bindings_builder_insert(
+                es.context.ptr(),
+                self.builder.as_ptr(),
+                name_ptr.as_ptr(),
+                value.raw_ptr(),
+            );
+        }
+        es.context
+            .check_err(error_site!("nix_bindings_builder_insert"))?;
+        self.names.insert(name.to_owned());
+        self.values.push(value.clone());
+        Ok(())
+    }
+    pub fn build(self) -> Result<Value> {
+        let es = self.eval_state;
+        let value = es.new_value_uninitialized();
+        unsafe {
+            raw::nix_make_attrs(es.context.ptr(), value.raw_ptr(), self.builder.as_ptr());
+        }
+        es.context.check_err(error_site!("nix_make_attrs"))?;
+        Ok(value)
+    }
+}
+impl Drop for AttrsetBuilder<'_> {
+    fn drop(&mut self) {
+        unsafe {
+            raw::nix_bindings_builder_free(self.builder.as_ptr());
+        }
+    }
+}
+
+pub fn gc_now() {
+    unsafe {
+        raw::nix_gc_now();
+    }
+}
+
+/// Statistics of the garbage collector, see [`gc_stats`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GcStats {
+    /// The size of the heap, in bytes.
+    pub heap_size: usize,
+    /// The free part of the heap, in bytes.
+    pub free_bytes: usize,
+    /// The number of collections since the process started.
+    pub total_collections: u64,
+    /// The number of bytes allocated since the last collection.
+    pub bytes_since_gc: usize,
+}
+
+pub fn gc_stats() -> GcStats {
+    unsafe {
+        GcStats {
+            heap_size: raw::GC_get_heap_size(),
+            free_bytes: raw::GC_get_free_bytes(),
+            total_collections: raw::GC_get_gc_no() as u64,
+            bytes_since_gc: raw::GC_get_bytes_since_gc(),
+        }
+    }
+}
+
+/// Keeps the garbage collector from running, in all threads, until it is dropped. See [`gc_disable_scope`].
+///
+/// Guards nest: collection resumes when the last one is dropped.
+pub struct GcDisableGuard {
+    _private: (),
+}
+impl GcDisableGuard {
+    pub fn new() -> Self {
+        unsafe {
+            raw::GC_disable();
+        }
+        GcDisableGuard { _private: () }
+    }
+}
+impl Default for GcDisableGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Drop for GcDisableGuard {
+    fn drop(&mut self) {
+        unsafe {
+            raw::GC_enable();
+        }
+    }
+}
+
+/// Run `f` without garbage collection pauses, e.g. for latency sensitive output. Has no effect on [`gc_now`] calls outside `f`.
+///
+/// The heap grows instead, so keep `f` short.
+pub fn gc_disable_scope<R>(f: impl FnOnce() -> R) -> R {
+    let _guard = GcDisableGuard::new();
+    f()
+}
+
+/// Registers the current thread with the garbage collector, and unregisters it when dropped, also when unwinding from a panic.
+///
+/// If the thread is registered already, this does nothing, so registrations nest.
+pub struct GcThreadRegistration {
+    unregister: bool,
+    // Unregistering has to happen on the same thread
+    _not_send: std::marker::PhantomData<*const ()>,
+}
+impl GcThreadRegistration {
+    pub fn new() -> Result<Self> {
+        init()?;
+        let unregister = unsafe { raw::GC_thread_is_registered() } == 0;
+        if unregister {
+            gc_register_my_thread()?;
+        }
+        Ok(GcThreadRegistration {
+            unregister,
+            _not_send: std::marker::PhantomData,
+        })
+    }
+}
+impl Drop for GcThreadRegistration {
+    fn drop(&mut self) {
+        if self.unregister {
+            unsafe {
+                raw::GC_unregister_my_thread();
+            }
+        }
+    }
+}
+
+/// Run `f` with the current thread registered with the garbage collector; see [`GcThreadRegistration`].
+pub fn gc_registering_current_thread<F, R>(f: F) -> Result<R>
+where
+    F: FnOnce() -> R,
+{
+    let _registration = GcThreadRegistration::new()?;
+    Ok(f())
+}
+pub fn gc_register_my_thread() -> Result<()> {
+    unsafe {
+        let already_done = raw::GC_thread_is_registered();
+        if already_done != 0 {
+            return Ok(());
+        }
+        let mut sb: raw::GC_stack_base = raw::GC_stack_base {
+            mem_base: 0 as *mut _,
+        };
+        let r = raw::GC_get_stack_base(&mut sb);
+        if r as u32 != raw::GC_SUCCESS {
+            Err(anyhow::format_err!("GC_get_stack_base failed: {}", r))?;
+        }
+        raw::GC_register_my_thread(&sb);
+        Ok(())
+    }
+}
+
+impl Clone for EvalState {
+    fn clone(&self) -> Self {
+        EvalState::from_ref(self.inner.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ctor::ctor;
+    use nix_store::derivation::Derivation;
+    use nix_store::store::BuildStatus;
+    use nix_util::error::NixError;
+
+    use super::*;
+    use crate::string_context::StringContextElement;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_and_drop() {
+        gc_registering_current_thread(|| {
+            // very basic test: make sure initialization doesn't crash
+            let store = Store::open("auto").unwrap();
+            let _e = EvalState::new(store).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_lookup_path() {
+        gc_registering_current_thread(|| {
+            // Nix only resolves lookup paths that exist
+            let dir = std::env::temp_dir()
+                .join(format!("nix-expr-test-lookup-path-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("bar")).unwrap();
+            let entry = format!("foo={}", dir.display());
+
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new_with_lookup_path(store, &[entry.as_str()]).unwrap();
+            let v = es
+                .eval_from_string("<foo>", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_path(&v).unwrap(), dir);
+            let v = es
+                .eval_from_string("<foo/bar>", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_path(&v).unwrap(), dir.join("bar"));
+            std::fs::remove_dir_all(dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_lookup_path_missing() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.eval_from_string(
+                "<nixops4-test-does-not-exist>",
+                SourceName::Synthetic("test"),
+            );
+            let e = r.err().unwrap();
+            assert!(
+                e.to_string().contains("nixops4-test-does-not-exist"),
+                "{}",
+                e
+            );
+            assert!(e.downcast_ref::<NixError>().is_some());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_builder_requires_store() {
+        let r = EvalStateBuilder::new().pure_eval(true).build();
+        assert_eq!(
+            r.err().unwrap().to_string(),
+            "EvalStateBuilder: a store is required"
+        );
+    }
+
+    #[test]
+    fn eval_state_eval_from_string() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("1", SourceName::Synthetic("test"))
+                .unwrap();
+            let v2 = v.clone();
+            es.force(&v).unwrap();
+            let t = es.value_type_forced(&v).unwrap();
+            assert!(t == ValueType::Int);
+            let t2 = es.value_type_forced(&v2).unwrap();
+            assert!(t2 == ValueType::Int);
+            gc_now();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_with_bindings() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let n = es.new_value_int(41).unwrap();
+            let v = es
+                .eval_with_bindings("n + 1", SourceName::Synthetic("test"), &[("n", &n)])
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 42);
+
+            let config = es
+                .eval_from_string("{ host.port = 22; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let map = es.new_value_string("shadowed").unwrap();
+            let v = es
+                .eval_with_bindings(
+                    "\"${map}:${toString config.host.port}\"",
+                    SourceName::Synthetic("test"),
+                    &[("config", &config), ("map", &map)],
+                )
+                .unwrap();
+            assert_eq!(es.require_string(&v).unwrap(), "shadowed:22");
+
+            let v = es
+                .eval_with_bindings("builtins.length [ 1 ]", SourceName::Synthetic("test"), &[])
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 1);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_with_bindings_errors() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let n = es.new_value_int(41).unwrap();
+            let e = es
+                .eval_with_bindings("m + 1", SourceName::Synthetic("test"), &[("n", &n)])
+                .err()
+                .unwrap();
+            assert!(e.to_string().contains("undefined variable 'm'"), "{}", e);
+            let e = es
+                .eval_with_bindings("1", SourceName::Synthetic("test"), &[("in", &n)])
+                .err()
+                .unwrap();
+            assert_eq!(
+                e.to_string(),
+                "eval_with_bindings: \"in\" is not a valid variable name"
+            );
+            let e = es
+                .eval_with_bindings("1", SourceName::Synthetic("test"), &[("n", &n), ("n", &n)])
+                .err()
+                .unwrap();
+            assert!(e.to_string().contains("duplicate"), "{}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_try_force() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("1 + 1", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.try_force(&v).unwrap(), Ok(()));
+            assert_eq!(es.require_int(&v).unwrap(), 2);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_caught_error() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "{ thrown = throw \"nope\"; asserted = assert 1 == 2; 3; aborted = abort \"x\"; ok = 1; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let e = es.force(&es.require_attrs_select(&v, "thrown").unwrap()).err().unwrap();
+            assert_eq!(
+                CaughtError::from_error(&e),
+                Some(CaughtError {
+                    kind: CaughtErrorKind::Throw,
+                    message: "nope".to_string()
+                })
+            );
+            let e = es.force(&es.require_attrs_select(&v, "asserted").unwrap()).err().unwrap();
+            let caught = CaughtError::from_error(&e).unwrap();
+            assert_eq!(caught.kind, CaughtErrorKind::Assert);
+            assert!(caught.message.contains("1 == 2"), "{}", caught.message);
+            let e = es.force(&es.require_attrs_select(&v, "aborted").unwrap()).err().unwrap();
+            assert_eq!(CaughtError::from_error(&e), None);
+            assert!(es.require_attrs_select(&v, "ok").is_ok());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_force_deep() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("{ a = 1; b = [ 2 3 ]; }", SourceName::Synthetic("test"))
+                .unwrap();
+            es.force_deep(&v).unwrap();
+            let v = es
+                .eval_from_string(
+                    "let x = { a = 1; inherit x; }; in x",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            es.force_deep(&v).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_force_deep_nested_throw() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "{ a = { b = [ { c = throw \"deep down\"; } ]; }; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            // Shallow forcing does not reach it
+            es.force(&v).unwrap();
+            let e = es.force_deep(&v).unwrap_err();
+            assert!(e.to_string().contains("deep down"), "{}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_force_deep_infinite_recursion() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("{ a = rec { x = x; }; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let e = es.force_deep(&v).unwrap_err();
+            assert!(e.to_string().contains("infinite recursion"), "{}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_bool() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("true", SourceName::Synthetic("test"))
+                .unwrap();
+            es.force(&v).unwrap();
+            let t = es.value_type_forced(&v).unwrap();
+            assert!(t == ValueType::Bool);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_int() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("6 * 7", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 42);
+            let v = es
+                .eval_from_string("true", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.require_int(&v).unwrap_err().to_string(),
+                "expected an int, but got a Bool: true"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_float() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("1.5 + 1.5", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_float(&v).unwrap(), 3.0);
+            let v = es
+                .eval_from_string("3", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.require_float(&v).unwrap_err().to_string(),
+                "expected a float, but got a Int: 3"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_bool() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("!false", SourceName::Synthetic("test"))
+                .unwrap();
+            assert!(es.require_bool(&v).unwrap());
+            let v = es
+                .eval_from_string("\"true\"", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.require_bool(&v).unwrap_err().to_string(),
+                "expected a bool, but got a String: \"true\""
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_string() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("\"hello\"", SourceName::Synthetic("test"))
+                .unwrap();
+            es.force(&v).unwrap();
+            let t = es.value_type(&v).unwrap();
+            assert!(t == Some(ValueType::String));
+            let t = es.value_type_forced(&v).unwrap();
+            assert!(t == ValueType::String);
+            let s = es.require_string(&v).unwrap();
+            assert!(s == "hello");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_string_unexpected_bool() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("true", SourceName::Synthetic("test"))
+                .unwrap();
+            es.force(&v).unwrap();
+            let r = es.require_string(&v);
+            assert!(r.is_err());
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "expected a string, but got a Bool: true"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_value_string_unexpected_path_value() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("/foo", SourceName::Synthetic("test"))
+                .unwrap();
+            es.force(&v).unwrap();
+            let r = es.require_string(&v);
+            assert!(r.is_err());
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "expected a string, but got a Path: /foo"
+            );
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn eval_state_value_string_bad_utf() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "builtins.substring 0 1 \"ü\"",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            es.force(&v).unwrap();
+            let t = es.value_type_forced(&v).unwrap();
+            assert!(t == ValueType::String);
+            let r = es.require_string(&v);
+            assert!(r.is_err());
+            assert!(r
+                .unwrap_err()
+                .to_string()
+                .contains("Nix string is not valid UTF-8"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_string_unexpected_context() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("(derivation { name = \"hello\"; system = \"dummy\"; builder = \"cmd.exe\"; }).outPath", SourceName::Synthetic("test"))
+                .unwrap();
+            es.force(&v).unwrap();
+            let t = es.value_type_forced(&v).unwrap();
+            assert!(t == ValueType::String);
+            let r = es.require_string_without_context(&v);
+            assert!(r.is_err());
+            assert!(r
+                .unwrap_err()
+                .to_string()
+                .contains("unexpected string context"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_string_without_context() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("\"hello\"", SourceName::Synthetic("test"))
+                .unwrap();
+            let s = es.require_string_without_context(&v).unwrap();
+            assert_eq!(s, "hello");
+            let (s, context) = es.require_string_with_context(&v).unwrap();
+            assert_eq!(s, "hello");
+            assert!(context.is_empty());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_string_with_context_out_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("(derivation { name = \"hello\"; system = \"dummy\"; builder = \"cmd.exe\"; }).outPath", SourceName::Synthetic("test"))
+                .unwrap();
+            let (s, context) = es.require_string_with_context(&v).unwrap();
+            assert!(s.ends_with("-hello"));
+            match context.elements() {
+                [StringContextElement::DerivationOutput { drv_path, output }] => {
+                    assert!(drv_path.ends_with("-hello.drv"));
+                    assert_eq!(output, "out");
+                }
+                _ => panic!("unexpected context: {:?}", context),
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_string_with_context_concatenated() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    let
+                      a = derivation { name = "a"; system = "dummy"; builder = "cmd.exe"; outputs = [ "out" "dev" ]; };
+                      b = derivation { name = "b"; system = "dummy"; builder = "cmd.exe"; };
+                    in "${a.dev}:${b}"
+                    "#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let (s, context) = es.require_string_with_context(&v).unwrap();
+            assert!(s.ends_with("-b"));
+            let mut outputs: Vec<(String, String)> = context
+                .elements()
+                .iter()
+                .map(|e| match e {
+                    StringContextElement::DerivationOutput { drv_path, output } => {
+                        (drv_path.clone(), output.clone())
+                    }
+                    _ => panic!("unexpected context element: {:?}", e),
+                })
+                .collect();
+            outputs.sort_by(|x, y| x.1.cmp(&y.1));
+            assert_eq!(outputs.len(), 2);
+            assert!(outputs[0].0.ends_with("-a.drv"));
+            assert_eq!(outputs[0].1, "dev");
+            assert!(outputs[1].0.ends_with("-b.drv"));
+            assert_eq!(outputs[1].1, "out");
+            let r = es.require_string_without_context(&v);
+            assert!(r.is_err());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_attrset() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("{ }", SourceName::Synthetic("test"))
+                .unwrap();
+            es.force(&v).unwrap();
+            let t = es.value_type_forced(&v).unwrap();
+            assert!(t == ValueType::AttrSet);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "{ a = \"aye\"; \"b.c\" = \"dotted\"; \"with space\" = \"spaced\"; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            for (name, expected) in [("a", "aye"), ("b.c", "dotted"), ("with space", "spaced")] {
+                let a = es.require_attrs_select(&v, name).unwrap();
+                assert_eq!(es.require_string(&a).unwrap(), expected);
+            }
+            assert!(es.require_attrs_select_opt(&v, "b").unwrap().is_none());
+            let r = es.require_attrs_select(&v, "b");
+            assert_eq!(r.err().unwrap().to_string(), "attribute `b` not found");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_lazy() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("{ a = throw \"x\"; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let a = es.require_attrs_select(&v, "a").unwrap();
+            assert_eq!(es.value_type(&a).unwrap(), None);
+            let a = es.require_attrs_select_opt(&v, "a").unwrap().unwrap();
+            let e = es.force(&a).unwrap_err();
+            assert!(format!("{:#}", e).contains("x"), "{:#}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_not_attrs() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("[ ]", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.require_attrs_select(&v, "foo");
+            assert_eq!(
+                r.err().unwrap().to_string(),
+                "expected an attribute set to select `foo` from, but got a List: [ ]"
+            );
+            let r = es.require_attrs_select_opt(&v, "foo");
+            assert!(r.is_err());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_attrs_names() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            // The values must not be evaluated
+            let v = es
+                .eval_from_string(
+                    "{ zzz = throw \"z\"; a = throw \"a\"; \"m.n\" = throw \"m\"; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let names = es.require_attrs_names(&v).unwrap();
+            assert_eq!(names, vec!["a", "m.n", "zzz"]);
+            let v = es
+                .eval_from_string("{ }", SourceName::Synthetic("test"))
+                .unwrap();
+            assert!(es.require_attrs_names(&v).unwrap().is_empty());
+            let v = es
+                .eval_from_string("1", SourceName::Synthetic("test"))
+                .unwrap();
+            assert!(es.require_attrs_names(&v).is_err());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_value_list() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("[ ]", SourceName::Synthetic("test"))
+                .unwrap();
+            es.force(&v).unwrap();
+            let t = es.value_type_forced(&v).unwrap();
+            assert!(t == ValueType::List);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_list_select_idx() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("[ 1 \"two\" { } ]", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_list_size(&v).unwrap(), 3);
+            let types: Vec<ValueType> = (0..3)
+                .map(|i| {
+                    let e = es.require_list_select_idx(&v, i).unwrap();
+                    es.value_type_forced(&e).unwrap()
+                })
+                .collect();
+            assert_eq!(
+                types,
+                vec![ValueType::Int, ValueType::String, ValueType::AttrSet]
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_list_select_idx_lazy() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("[ 1 (throw \"x\") ]", SourceName::Synthetic("test"))
+                .unwrap();
+            let e = es.require_list_select_idx(&v, 1).unwrap();
+            assert_eq!(es.value_type(&e).unwrap(), None);
+            assert!(es.force(&e).is_err());
+            let e = es.require_list_select_idx(&v, 0).unwrap();
+            assert_eq!(es.require_int(&e).unwrap(), 1);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_list_select_idx_out_of_bounds() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("[ 1 2 ]", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.require_list_select_idx(&v, 2);
+            assert_eq!(
+                r.err().unwrap().to_string(),
+                "list index 2 is out of bounds for a list of length 2"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_list_size_lazy() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "builtins.genList (i: throw \"element ${toString i}\") 100000",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            assert_eq!(es.require_list_size(&v).unwrap(), 100000);
+            let v = es
+                .eval_from_string("{ }", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.require_list_size(&v).err().unwrap().to_string(),
+                "expected a list, but got a AttrSet: { }"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_attrs_iter_lazy() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    builtins.listToAttrs (builtins.genList (i: {
+                      name = "a" + builtins.substring 0 (4 - builtins.stringLength (toString i)) "000" + toString i;
+                      value = if builtins.bitAnd i 127 == 0 then i else throw "forced ${toString i}";
+                    }) 1000)
+                    "#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let mut iter = es.attrs_iter(&v).unwrap();
+            assert_eq!(iter.len(), 1000);
+            let mut forced = Vec::new();
+            let mut i = 0;
+            while let Some(r) = iter.next() {
+                let (name, value) = r.unwrap();
+                assert_eq!(name, format!("a{:04}", i));
+                if i % 128 == 0 {
+                    // Forcing values during the iteration
+                    forced.push(es.require_int(&value).unwrap());
+                }
+                i += 1;
+                assert_eq!(iter.len(), 1000 - i);
+            }
+            assert_eq!(i, 1000);
+            assert_eq!(forced, [0, 128, 256, 384, 512, 640, 768, 896]);
+
+            // The other values throw, when forced
+            let (name, value) = es.attrs_iter(&v).unwrap().nth(1).unwrap().unwrap();
+            assert_eq!(name, "a0001");
+            let e = es.force(&value).unwrap_err();
+            assert!(format!("{:#}", e).contains("forced 1"), "{:#}", e);
+
+            let v = es
+                .eval_from_string("[ ]", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.attrs_iter(&v).err().unwrap().to_string(),
+                "expected an attribute set, but got a List: [ ]"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_list_iter() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "[ 1 (throw \"not forced\") 3 4 ]",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let mut iter = es.list_iter(&v).unwrap();
+            assert_eq!(iter.len(), 4);
+            let first = iter.next().unwrap().unwrap();
+            assert_eq!(es.require_int(&first).unwrap(), 1);
+            let last = iter.next_back().unwrap().unwrap();
+            assert_eq!(es.require_int(&last).unwrap(), 4);
+            assert_eq!(iter.len(), 2);
+            let third = iter.next_back().unwrap().unwrap();
+            assert_eq!(es.require_int(&third).unwrap(), 3);
+            let second = iter.next().unwrap().unwrap();
+            assert!(es.force(&second).is_err());
+            assert!(iter.next().is_none());
+            assert!(iter.next_back().is_none());
+
+            let reversed = es
+                .list_iter(&v)
+                .unwrap()
+                .rev()
+                .step_by(2)
+                .map(|r| es.require_int(&r.unwrap()).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(reversed, [4, 1]);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_list_of_strings() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("[ \"a\" \"b\" ]", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_list_of_strings(&v).unwrap(), ["a", "b"]);
+            let lengths = es
+                .require_list_of(&v, |s| Ok(es.require_string(s)?.len()))
+                .unwrap();
+            assert_eq!(lengths, [1, 1]);
+
+            let v = es
+                .eval_from_string("[ \"a\" \"b\" 3 ]", SourceName::Synthetic("test"))
+                .unwrap();
+            let e = es.require_list_of_strings(&v).unwrap_err();
+            assert_eq!(
+                format!("{:#}", e),
+                "while evaluating list element 2: expected a string, but got a Int: 3"
+            );
+            let v = es
+                .eval_from_string("{ }", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.require_list_of_strings(&v).unwrap_err().to_string(),
+                "expected a list, but got a AttrSet: { }"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_round_trip() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.new_value_int(-42).unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), -42);
+            let v = es.new_value_bool(true).unwrap();
+            assert!(es.require_bool(&v).unwrap());
+            let v = es.new_value_float(0.25).unwrap();
+            assert_eq!(es.require_float(&v).unwrap(), 0.25);
+            let v = es.new_value_string("hello ü").unwrap();
+            assert_eq!(es.require_string_without_context(&v).unwrap(), "hello ü");
+            let v = es.new_value_null().unwrap();
+            assert_eq!(es.value_type_forced(&v).unwrap(), ValueType::Null);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_string_null_byte() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.new_value_string("a\0b");
+            assert!(r
+                .err()
+                .unwrap()
+                .to_string()
+                .contains("new_value_string: string contains null byte"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_attrs_select_null_byte() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("{ a = 1; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let e = es.require_attrs_select(&v, "a\0b").err().unwrap();
+            assert_eq!(
+                e.to_string(),
+                "require_attrs_select: attribute name contains null byte: \"a\\0b\""
+            );
+            let e = es.require_attrs_select_opt(&v, "a\0b").err().unwrap();
+            assert_eq!(
+                e.to_string(),
+                "require_attrs_select_opt: attribute name contains null byte: \"a\\0b\""
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_attrs() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .new_value_attrs([
+                    ("a".to_string(), es.new_value_int(1).unwrap()),
+                    ("b".to_string(), es.new_value_string("x").unwrap()),
+                ])
+                .unwrap();
+            assert_eq!(es.require_attrs_names(&v).unwrap(), vec!["a", "b"]);
+            let a = es.require_attrs_select(&v, "a").unwrap();
+            assert_eq!(es.require_int(&a).unwrap(), 1);
+            let b = es.require_attrs_select(&v, "b").unwrap();
+            assert_eq!(es.require_string(&b).unwrap(), "x");
+            let f = es
+                .eval_from_string("x: x.a + 1", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.call(&f, &v).unwrap();
+            assert_eq!(es.require_int(&r).unwrap(), 2);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_attrset_builder_duplicate() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let one = es.new_value_int(1).unwrap();
+            let mut builder = es.new_attrset_builder(2).unwrap();
+            builder.insert("a", &one).unwrap();
+            let r = builder.insert("a", &one);
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "duplicate attribute `a` in attribute set"
+            );
+            // The builder is still usable
+            let v = builder.build().unwrap();
+            assert_eq!(es.require_attrs_names(&v).unwrap(), vec!["a"]);
+            let r = es.new_value_attrs([("b".to_string(), one.clone()), ("b".to_string(), one)]);
+            assert!(r.is_err());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_call() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let f = es
+                .eval_from_string("x: x + 1", SourceName::Synthetic("test"))
+                .unwrap();
+            let v = es.new_value_int(41).unwrap();
+            let r = es.call(&f, &v).unwrap();
+            assert_eq!(es.require_int(&r).unwrap(), 42);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_call_multi() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let f = es
+                .eval_from_string("a: b: a + \"-\" + b", SourceName::Synthetic("test"))
+                .unwrap();
+            let args = [
+                es.new_value_string("left").unwrap(),
+                es.new_value_string("right").unwrap(),
+            ];
+            let r = es.call_multi(&f, &args).unwrap();
+            assert_eq!(es.require_string(&r).unwrap(), "left-right");
+            // A partial application is still a function
+            let r = es.call_multi(&f, &args[..1]).unwrap();
+            assert_eq!(es.value_type_forced(&r).unwrap(), ValueType::Function);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_call_not_a_function() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let f = es.new_value_int(1).unwrap();
+            let v = es.new_value_int(2).unwrap();
+            let r = es.call(&f, &v);
+            assert_eq!(
+                r.err().unwrap().to_string(),
+                "expected a function, but got a Int: 1"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_call_throws() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let f = es
+                .eval_from_string(
+                    "x: throw \"oh no, ${toString x}\"",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let v = es.new_value_int(3).unwrap();
+            let r = es.call(&f, &v);
+            let e = r.err().unwrap();
+            assert!(e.to_string().contains("oh no, 3"), "{}", e);
+            assert!(e.downcast_ref::<NixError>().is_some());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_auto_call_formals() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let args = es
+                .eval_from_string("{ a = 1; c = 5; }", SourceName::Synthetic("test"))
+                .unwrap();
+            // c is not passed, so this would fail if it were
+            let f = es
+                .eval_from_string("{ a, b ? 10 }: a + b", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.auto_call(&f, &args).unwrap();
+            assert_eq!(es.require_int(&r).unwrap(), 11);
+            let f = es
+                .eval_from_string(
+                    "{ __functor = self: { a }: a + 2; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let r = es.auto_call(&f, &args).unwrap();
+            assert_eq!(es.require_int(&r).unwrap(), 3);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_auto_call_ellipsis() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let args = es
+                .eval_from_string("{ a = 1; c = 5; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let f = es
+                .eval_from_string("{ a, ... }@all: a + all.c", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.auto_call(&f, &args).unwrap();
+            assert_eq!(es.require_int(&r).unwrap(), 6);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_function_formals() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let f = es
+                .eval_from_string("{ a, b ? 1, ... }: a", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.require_function_formals(&f).unwrap(),
+                Some(Formals {
+                    args: vec![("a".to_string(), false), ("b".to_string(), true)],
+                    ellipsis: true,
+                })
+            );
+            let f = es
+                .eval_from_string("{ }@args: args", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.require_function_formals(&f).unwrap(),
+                Some(Formals {
+                    args: vec![],
+                    ellipsis: false,
+                })
+            );
+            for expr in ["x: x", "builtins.map", "builtins.map (x: x)"] {
+                let f = es
+                    .eval_from_string(expr, SourceName::Synthetic("test"))
+                    .unwrap();
+                assert_eq!(es.require_function_formals(&f).unwrap(), None, "{}", expr);
+            }
+            let v = es.new_value_int(1).unwrap();
+            assert_eq!(
+                es.require_function_formals(&v).unwrap_err().to_string(),
+                "expected a function, but got a Int: 1"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_auto_call_not_called() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let args = es
+                .eval_from_string("{ a = 1; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let v = es.new_value_int(42).unwrap();
+            let r = es.auto_call(&v, &args).unwrap();
+            assert_eq!(es.require_int(&r).unwrap(), 42);
+            let f = es
+                .eval_from_string("x: x", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.auto_call(&f, &args).unwrap();
+            assert_eq!(es.value_type_forced(&r).unwrap(), ValueType::Function);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_auto_call_missing_argument() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let args = es
+                .eval_from_string("{ a = 1; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let f = es
+                .eval_from_string("{ a, pkgs }: a", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.auto_call(&f, &args);
+            let e = format!("{:#}", r.err().unwrap());
+            assert!(
+                e.contains(
+                    "cannot evaluate a function that has an argument without a value ('pkgs')"
+                ),
+                "{}",
+                e
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_list() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .new_value_list(&[
+                    es.new_value_int(1).unwrap(),
+                    es.new_value_string("two").unwrap(),
+                ])
+                .unwrap();
+            assert_eq!(es.require_list_size(&v).unwrap(), 2);
+            let e = es.require_list_select_idx(&v, 1).unwrap();
+            assert_eq!(es.require_string(&e).unwrap(), "two");
+            let v = es.new_value_list(&[]).unwrap();
+            assert_eq!(es.require_list_size(&v).unwrap(), 0);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_list_and_attrs_of_primitives() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let names = es
+                .new_value_list_of_strings(["web", "db", "cache"])
+                .unwrap();
+            assert_eq!(
+                es.require_list_of_strings(&names).unwrap(),
+                ["web", "db", "cache"]
+            );
+            let ports = es
+                .new_value_list_of_ints((1..=3).map(|i| i * 1000))
+                .unwrap();
+            assert_eq!(
+                es.require_list_of(&ports, |v| es.require_int(v)).unwrap(),
+                [1000, 2000, 3000]
+            );
+            let tags = es
+                .new_value_attrs_of_strings(vec![
+                    ("env".to_string(), "prod"),
+                    ("team".to_string(), "ops"),
+                ])
+                .unwrap();
+            assert_eq!(es.require_attrs_names(&tags).unwrap(), ["env", "team"]);
+            let env = es.require_attrs_select(&tags, "env").unwrap();
+            assert_eq!(es.require_string(&env).unwrap(), "prod");
+
+            let f = es
+                .eval_from_string(
+                    r#"names: ports: tags:
+                      builtins.concatStringsSep "," (builtins.sort builtins.lessThan names)
+                      + " " + toString (builtins.foldl' builtins.add 0 ports)
+                      + " " + builtins.concatStringsSep "," (builtins.attrValues tags)"#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let r = es.call_multi(&f, &[names, ports, tags]).unwrap();
+            assert_eq!(es.require_string(&r).unwrap(), "cache,db,web 6000 prod,ops");
+
+            let empty = es.new_value_list_of_strings(Vec::<String>::new()).unwrap();
+            assert_eq!(es.require_list_size(&empty).unwrap(), 0);
+            let e = es
+                .new_value_attrs_of_strings([("a", "1"), ("a", "2")])
+                .err()
+                .unwrap();
+            assert_eq!(e.to_string(), "duplicate attribute `a` in attribute set");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_path() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.new_value_path(Path::new("/foo/bar.nix")).unwrap();
+            let t = es.value_type_forced(&v).unwrap();
+            assert!(t == ValueType::Path);
+            let base_name_of = es
+                .eval_from_string("builtins.baseNameOf", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.call(&base_name_of, &v).unwrap();
+            assert_eq!(es.require_string(&r).unwrap(), "bar.nix");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_path_relative() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "./rel",
+                    SourceName::File(PathBuf::from("/some/dir/default.nix")),
+                )
+                .unwrap();
+            assert_eq!(es.require_path(&v).unwrap(), Path::new("/some/dir/rel"));
+            // The old signature took the directory
+            #[allow(deprecated)]
+            let v = es
+                .eval_from_string_path("./rel".to_string(), "/some/dir".to_string())
+                .unwrap();
+            assert_eq!(es.require_path(&v).unwrap(), Path::new("/some/dir/rel"));
+            let v = es
+                .eval_from_string("\"/some/dir\"", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.require_path(&v).unwrap_err().to_string(),
+                "expected a path, but got a String: \"/some/dir\""
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_relative_synthetic() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("./rel", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_path(&v).unwrap(), Path::new("/«test»/rel"));
+            let e = es
+                .eval_from_string("builtins.readFile ./rel", SourceName::Synthetic("test"))
+                .err()
+                .unwrap();
+            assert!(e.to_string().contains("/«test»/rel"), "{}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_string_null_byte() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            for source in [
+                SourceName::Synthetic("test"),
+                SourceName::File(PathBuf::from("/some/dir/default.nix")),
+            ] {
+                let e = es
+                    .eval_from_string("{\n  a = \"x\0\";\n}", source)
+                    .err()
+                    .unwrap();
+                assert_eq!(
+                    e.to_string(),
+                    "eval_from_string: expr contains a null byte at byte 10 (line 2, column 9)"
+                );
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_path_not_utf8() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let path = PathBuf::from(std::ffi::OsStr::from_bytes(b"/tmp/caf\xe9"));
+            let v = es.new_value_path(&path).unwrap();
+            assert_eq!(es.require_path(&v).unwrap(), path);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_path_relative() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.new_value_path(Path::new("foo/bar.nix"));
+            assert!(r.is_err());
+            assert!(r.err().unwrap().to_string().contains("must be absolute"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_path_copied_to_store() {
+        gc_registering_current_thread(|| {
+            let dir = std::env::temp_dir().join(format!(
+                "nix-expr-test-new-value-path-{}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let file = dir.join("config.txt");
+            std::fs::write(&file, "hello").unwrap();
+
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let p = es.new_value_path(&file).unwrap();
+            // Same coercion as for derivation attributes
+            let f = es
+                .eval_from_string("p: \"${p}\"", SourceName::Synthetic("test"))
+                .unwrap();
+            let s = es.call(&f, &p).unwrap();
+            let s = es.require_string(&s).unwrap();
+            assert!(s.ends_with("-config.txt"));
+            assert_ne!(s, file.to_str().unwrap());
+            std::fs::remove_dir_all(dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_file() {
+        gc_registering_current_thread(|| {
+            let dir = std::env::temp_dir().join(format!(
+                "nix-expr-test-eval-from-file-{}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(dir.join("data.txt"), "some data").unwrap();
+            std::fs::write(dir.join("default.nix"), "builtins.readFile ./data.txt").unwrap();
+
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.eval_from_file(dir.join("default.nix")).unwrap();
+            assert_eq!(es.require_string(&v).unwrap(), "some data");
+            std::fs::remove_dir_all(dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_file_parse_error() {
+        gc_registering_current_thread(|| {
+            let dir = std::env::temp_dir().join(format!(
+                "nix-expr-test-eval-from-file-parse-error-{}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let file = dir.join("broken.nix");
+            std::fs::write(&file, "{\n  a = 1;\n  b = ;\n}\n").unwrap();
+
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.eval_from_file(&file);
+            let e = format!("{:#}", r.err().unwrap());
+            assert!(e.contains(&format!("{}:3:", file.display())), "{}", e);
+            std::fs::remove_dir_all(dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_from_file_missing() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.eval_from_file("/nonexistent/nixops4/test.nix");
+            assert_eq!(
+                r.err().unwrap().to_string(),
+                "eval_from_file: file does not exist: /nonexistent/nixops4/test.nix"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_eval_flake() {
+        gc_registering_current_thread(|| {
+            let dir = std::env::temp_dir()
+                .join(format!("nix-expr-test-eval-flake-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            std::fs::write(
+                dir.join("flake.nix"),
+                "{ outputs = { self }: { hello = \"world\"; }; }",
+            )
+            .unwrap();
+
+            settings::set("experimental-features", "flakes nix-command").unwrap();
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let outputs = es.eval_flake(&format!("path:{}", dir.display())).unwrap();
+            let hello = es.require_attrs_select(&outputs, "hello").unwrap();
+            assert_eq!(es.require_string(&hello).unwrap(), "world");
+
+            let r = es.eval_flake(&format!("path:{}", dir.join("missing").display()));
+            let e = format!("{:#}", r.err().unwrap());
+            assert!(e.contains("while evaluating flake path:"), "{}", e);
+            std::fs::remove_dir_all(dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_new_value_placeholder() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.new_value_placeholder("out").unwrap();
+            let expected = es
+                .eval_from_string(
+                    "builtins.placeholder \"out\"",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            assert_eq!(
+                es.require_string(&v).unwrap(),
+                es.require_string(&expected).unwrap()
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_error_kind_key() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("{ a = 1; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let name = CString::new("b").unwrap();
+            unsafe {
+                raw::nix_get_attr_byname(
+                    es.context.ptr(),
+                    v.raw_ptr(),
+                    es.raw_ptr(),
+                    name.as_ptr(),
+                );
+            }
+            let e = es
+                .context
+                .check_err(error_site!("nix_get_attr_byname"))
+                .unwrap_err();
+            assert!(matches!(e, NixError::Key { .. }), "{:?}", e);
+            assert_eq!(e.code(), raw::NIX_ERR_KEY);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_error_kind_throw() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.eval_from_string("throw \"boom\"", SourceName::Synthetic("test"));
+            let err = r.err().unwrap();
+            match err.downcast_ref::<NixError>().unwrap() {
+                NixError::Exception { name, .. } => {
+                    assert_eq!(name.as_deref(), Some("nix::ThrownError"))
+                }
+                e => panic!("expected an exception, got {:?}", e),
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_error_positions() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.eval_from_string(
+                "let\n  x = 1;\n  y = throw \"boom\";\nin\n  x + y\n",
+                SourceName::Synthetic("test"),
+            );
+            let err = r.err().unwrap();
+            let nix_err = err.downcast_ref::<NixError>().unwrap();
+            assert!(
+                nix_err.positions().iter().any(|p| p.line == 3),
+                "{:?}",
+                nix_err
+            );
+            assert!(!nix_err.traces().is_empty(), "{:?}", nix_err);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_error_positions_parse_error() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.eval_from_string("{\n  a = 1\n}", SourceName::Synthetic("test"));
+            let err = r.err().unwrap();
+            let nix_err = err.downcast_ref::<NixError>().unwrap();
+            let pos = nix_err.positions().last().unwrap();
+            // Nix attributes positions in an expression string to the string, not to its base path
+            assert_eq!(pos.file, "«string»");
+            assert_eq!(pos.line, 3);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_error_site() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let r = es.eval_from_string("throw \"boom\"", SourceName::Synthetic("test"));
+            let err = r.err().unwrap();
+            let msg = err.to_string();
+            assert!(msg.starts_with("in nix_expr_eval_from_string (eval_state.rs:"));
+            assert!(msg.contains("boom"));
+            let nix_err = err.downcast_ref::<NixError>().unwrap();
+            assert_eq!(nix_err.site().call, "nix_expr_eval_from_string");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_coerce_to_string_like_to_string() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let opts = CoerceOpts {
+                coerce_more: true,
+                copy_to_store: false,
+            };
+            for expr in [
+                "\"str\"",
+                "42",
+                "-1",
+                "1.5",
+                "1.0",
+                "true",
+                "false",
+                "null",
+                "[ 1 [ ] \"a\" [ 2 null ] true ]",
+                "/some/path",
+                "{ __toString = self: \"custom\"; }",
+                "{ outPath = \"out\"; }",
+            ] {
+                let v = es
+                    .eval_from_string(expr, SourceName::Synthetic("test"))
+                    .unwrap();
+                let (s, context) = es.coerce_to_string(&v, opts).unwrap();
+                let expected = es
+                    .eval_from_string(
+                        format!("builtins.toString ({})", expr),
+                        SourceName::Synthetic("test"),
+                    )
+                    .unwrap();
+                assert_eq!(s, es.require_string(&expected).unwrap(), "for {}", expr);
+                assert!(context.is_empty());
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_coerce_to_string_strict() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            for copy_to_store in [false, true] {
+                let opts = CoerceOpts {
+                    coerce_more: false,
+                    copy_to_store,
+                };
+                let v = es
+                    .eval_from_string("8080", SourceName::Synthetic("test"))
+                    .unwrap();
+                let r = es.coerce_to_string(&v, opts);
+                assert!(r.is_err());
+                assert!(r
+                    .unwrap_err()
+                    .to_string()
+                    .contains("cannot coerce an integer to a string"));
+
+                let v = es
+                    .eval_from_string("\"str\"", SourceName::Synthetic("test"))
+                    .unwrap();
+                let (s, _) = es.coerce_to_string(&v, opts).unwrap();
+                assert_eq!(s, "str");
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_coerce_to_string_path() {
+        gc_registering_current_thread(|| {
+            let dir = std::env::temp_dir().join(format!(
+                "nix-expr-test-coerce-to-string-{}",
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let file = dir.join("config.txt");
+            std::fs::write(&file, "hello").unwrap();
+
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let p = es.new_value_path(&file).unwrap();
+
+            let no_copy = CoerceOpts {
+                coerce_more: false,
+                copy_to_store: false,
+            };
+            let (s, context) = es.coerce_to_string(&p, no_copy).unwrap();
+            assert_eq!(s, file.to_str().unwrap());
+            assert!(context.is_empty());
+
+            let (s, context) = es.coerce_to_string(&p, CoerceOpts::default()).unwrap();
+            assert!(s.ends_with("-config.txt"));
+            // Added to the store, not just hashed
+            assert_eq!(std::fs::read_to_string(&s).unwrap(), "hello");
+            assert_eq!(
+                context.elements(),
+                &[StringContextElement::Opaque { path: s.clone() }]
+            );
+
+            // coerce_more also copies paths inside lists
+            let f = es
+                .eval_from_string("p: [ p 1 ]", SourceName::Synthetic("test"))
+                .unwrap();
+            let l = es.call(&f, &p).unwrap();
+            let more = CoerceOpts {
+                coerce_more: true,
+                copy_to_store: true,
+            };
+            let (s2, context2) = es.coerce_to_string(&l, more).unwrap();
+            assert_eq!(s2, format!("{} 1", s));
+            assert_eq!(context2, context);
+            std::fs::remove_dir_all(dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_coerce_to_string_derivation() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "derivation { name = \"hello\"; system = \"dummy\"; builder = \"cmd.exe\"; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let (s, context) = es.coerce_to_string(&v, CoerceOpts::default()).unwrap();
+            assert!(s.ends_with("-hello"));
+            match context.elements() {
+                [StringContextElement::DerivationOutput { drv_path, output }] => {
+                    assert!(drv_path.ends_with("-hello.drv"));
+                    assert_eq!(output, "out");
+                }
+                _ => panic!("unexpected context: {:?}", context),
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_realise_string_no_context() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("\"hello\"", SourceName::Synthetic("test"))
+                .unwrap();
+            let rs = es.realise_string(&v, false).unwrap();
+            assert_eq!(rs.string, "hello");
+            assert!(rs.paths.is_empty());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_realise_string_text_file() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "\"${builtins.toFile \"hello.txt\" \"hello\"}/\"",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let rs = es.realise_string(&v, false).unwrap();
+            assert!(rs.string.ends_with("-hello.txt/"));
+            assert_eq!(rs.paths.len(), 1);
+            assert_eq!(rs.paths[0].name(), "hello.txt");
+            assert_eq!(format!("{}/", rs.paths[0]), rs.string);
+            assert_eq!(
+                std::fs::read_to_string(rs.paths[0].as_str()).unwrap(),
+                "hello"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_realise_string_build_failure() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    "${derivation {
+                        name = "nixops4-test-fail";
+                        system = builtins.currentSystem;
+                        builder = "/bin/sh";
+                        args = [ "-c" "echo failing on purpose >&2; exit 1" ];
+                    }}"
+                    "#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let r = es.realise_string(&v, false);
+            match r {
+                Ok(_) => panic!("expected the build to fail"),
+                Err(e) => {
+                    let msg = format!("{:#}", e);
+                    assert!(msg.contains("nixops4-test-fail.drv"), "{}", msg);
+                }
+            }
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_string_context_discard_and_add() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    (derivation {
+                        name = "nixops4-test-context";
+                        system = builtins.currentSystem;
+                        builder = "/bin/sh";
+                        args = [ "-c" "echo hello > $out" ];
+                    }).outPath
+                    "#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let out_path = es.get_string(&v).unwrap();
+            let ctx = es.string_get_context(&v).unwrap();
+            match ctx.elements() {
+                [StringContextElement::DerivationOutput { drv_path, output }] => {
+                    assert!(drv_path.ends_with("-nixops4-test-context.drv"));
+                    assert_eq!(output, "out");
+                }
+                _ => panic!("unexpected context: {:?}", ctx),
+            }
+
+            let plain = es.string_discard_context(&v).unwrap();
+            assert_eq!(es.get_string(&plain).unwrap(), out_path);
+            assert!(es.string_get_context(&plain).unwrap().is_empty());
+            let rs = es.realise_string(&plain, false).unwrap();
+            assert_eq!(rs.string, out_path);
+            assert!(rs.paths.is_empty());
+
+            let readded = es.string_with_added_context(&plain, &ctx).unwrap();
+            assert_eq!(es.string_get_context(&readded).unwrap(), ctx);
+            let rs = es.realise_string(&readded, false).unwrap();
+            assert_eq!(rs.string, out_path);
+            assert_eq!(
+                rs.paths.iter().map(|p| p.as_str()).collect::<Vec<_>>(),
+                [out_path.as_str()]
+            );
+            assert_eq!(std::fs::read_to_string(&out_path).unwrap(), "hello\n");
+
+            let e = es
+                .string_with_added_context(
+                    &plain,
+                    &StringContext::new(vec![StringContextElement::DerivationDeep {
+                        drv_path: "/tmp/not-a-store-path.drv".to_string(),
+                    }]),
+                )
+                .err()
+                .unwrap();
+            assert!(format!("{:#}", e).contains("not a store path"), "{:#}", e);
+            let e = es
+                .string_discard_context(&es.new_value_int(1).unwrap())
+                .err()
+                .unwrap();
+            assert_eq!(e.to_string(), "expected a string, but got a Int: 1");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    #[ignore] // Needs a store that builds content-addressed derivations, which a daemon only does with the ca-derivations experimental feature
+    fn eval_state_build_content_addressed() {
+        gc_registering_current_thread(|| {
+            settings::set("experimental-features", "ca-derivations flakes nix-command").unwrap();
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    derivation {
+                        name = "nixops4-test-ca";
+                        system = builtins.currentSystem;
+                        builder = "/bin/sh";
+                        args = [ "-c" "echo hello > $out" ];
+                        __contentAddressed = true;
+                        outputHashMode = "recursive";
+                        outputHashAlgo = "sha256";
+                    }
+                    "#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let d = es.require_derivation(&v).unwrap();
+            assert_eq!(d.outputs.len(), 1);
+            assert_eq!(d.outputs[0].0, "out");
+            assert_eq!(d.outputs[0].1, None);
+
+            let r = es.store().build(&d.drv_path).unwrap();
+            assert_eq!(r.status, BuildStatus::Built, "{:?}", r.error);
+            let out = &r.outputs["out"];
+            assert_eq!(out.name(), "nixops4-test-ca");
+            assert_eq!(std::fs::read_to_string(out.as_str()).unwrap(), "hello\n");
+
+            // The placeholder in outPath is replaced by the realised path
+            let out_path = es.require_attrs_select(&v, "outPath").unwrap();
+            let rs = es.realise_string(&out_path, false).unwrap();
+            assert_eq!(rs.string, out.as_str());
+            assert_eq!(
+                rs.paths.iter().map(|p| p.as_str()).collect::<Vec<_>>(),
+                [out.as_str()]
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_builtins() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let builtins = es.builtins().unwrap();
+            assert_eq!(es.value_type(&builtins).unwrap(), Some(ValueType::AttrSet));
+            let again = es.builtins().unwrap();
+            assert_eq!(builtins.raw_ptr(), again.raw_ptr());
+            drop(builtins);
+            drop(again);
+            gc_now();
+            // Still usable after the handed out values are gone
+            let length = es.builtin("length").unwrap();
+            assert_eq!(es.value_type(&length).unwrap(), Some(ValueType::Function));
+
+            let v = es
+                .builtin_from_json(r#"{"a":[1,2.5,null,true,"s"]}"#)
+                .unwrap();
+            assert_eq!(
+                es.builtin_to_json(&v).unwrap(),
+                r#"{"a":[1,2.5,null,true,"s"]}"#
+            );
+            let e = es.builtin_from_json("{").err().unwrap();
+            assert!(format!("{:#}", e).contains("JSON"), "{:#}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_glue() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let inc = es.glue("x: x + 1").unwrap();
+            let again = es.glue("x: x + 1").unwrap();
+            assert_eq!(inc.raw_ptr(), again.raw_ptr());
+            drop(inc);
+            drop(again);
+            gc_now();
+            let one = es.new_value_int(1).unwrap();
+            let r = es.call(&es.glue("x: x + 1").unwrap(), &one).unwrap();
+            assert_eq!(es.require_int(&r).unwrap(), 2);
+            // Errors are not kept
+            es.glue("throw \"no\"").err().unwrap();
+            es.glue("throw \"no\"").err().unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_builtin_typeof() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let cases = [
+                (ValueType::AttrSet, "{ }", "set"),
+                (ValueType::Bool, "true", "bool"),
+                (ValueType::Float, "1.5", "float"),
+                (ValueType::Function, "x: x", "lambda"),
+                (ValueType::Function, "builtins.map", "lambda"),
+                (ValueType::Int, "1", "int"),
+                (ValueType::List, "[ ]", "list"),
+                (ValueType::Null, "null", "null"),
+                (ValueType::Path, "/tmp", "path"),
+                (ValueType::String, "\"s\"", "string"),
+            ];
+            for (t, expr, name) in cases {
+                let v = es
+                    .eval_from_string(expr, SourceName::Synthetic("test"))
+                    .unwrap();
+                assert_eq!(es.value_type_forced(&v).unwrap(), t, "{}", expr);
+                assert_eq!(es.builtin_typeof(&v).unwrap(), name, "{}", expr);
+            }
+            let v = es.new_value_external(1u32).unwrap();
+            assert_eq!(es.value_type_forced(&v).unwrap(), ValueType::External);
+            assert_eq!(es.builtin_typeof(&v).unwrap(), "external");
+        })
+        .unwrap();
+    }
+
+    static EVAL_CACHED_COUNT: std::sync::atomic::AtomicUsize =
+        std::sync::atomic::AtomicUsize::new(0);
+
+    #[test]
+    fn eval_state_eval_cached() {
+        use std::sync::atomic::Ordering::SeqCst;
+        gc_registering_current_thread(|| {
+            // Counts evaluations, where builtins.trace would only print them
+            EvalState::register_primop(
+                "rustCountEvalCached",
+                1,
+                "Count the calls, and return the argument.",
+                |_es, args| {
+                    EVAL_CACHED_COUNT.fetch_add(1, SeqCst);
+                    Ok(args[0].clone())
+                },
+            )
+            .unwrap();
+            let count = || EVAL_CACHED_COUNT.load(SeqCst);
+            let source = || SourceName::Synthetic("test");
+            let store = Store::open("auto").unwrap();
+
+            let es = EvalState::new(store.clone()).unwrap();
+            let mut ints = Vec::new();
+            for _ in 0..3 {
+                let v = es.eval_cached("rustCountEvalCached 1", source()).unwrap();
+                ints.push(es.require_int(&v).unwrap());
+            }
+            assert_eq!(ints, [1, 1, 1]);
+            assert_eq!(count(), 1);
+            // A different expression or source is evaluated on its own
+            es.eval_cached("rustCountEvalCached 2", source()).unwrap();
+            es.eval_cached("rustCountEvalCached 1", SourceName::Synthetic("other"))
+                .unwrap();
+            assert_eq!(count(), 3);
+            es.clear_eval_cache();
+            es.eval_cached("rustCountEvalCached 1", source()).unwrap();
+            assert_eq!(count(), 4);
+            // Errors are not cached
+            es.eval_cached("throw \"no\"", source()).err().unwrap();
+            es.eval_cached("throw \"no\"", source()).err().unwrap();
+
+            let es = EvalStateBuilder::new()
+                .store(store)
+                .eval_cache_capacity(1)
+                .build()
+                .unwrap();
+            es.eval_cached("rustCountEvalCached 1", source()).unwrap();
+            es.eval_cached("rustCountEvalCached 1", source()).unwrap();
+            assert_eq!(count(), 5);
+            gc_now();
+            let v = es.eval_cached("rustCountEvalCached 1", source()).unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 1);
+            assert_eq!(count(), 5);
+            // Evicts the first
+            es.eval_cached("rustCountEvalCached 2", source()).unwrap();
+            es.eval_cached("rustCountEvalCached 1", source()).unwrap();
+            assert_eq!(count(), 7);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_require_string_bytes() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "builtins.substring 0 1 \"ü\"",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            assert_eq!(es.require_string_bytes(&v).unwrap(), [0xc3]);
+            assert_eq!(es.require_string_lossy(&v).unwrap(), "\u{fffd}");
+            let v = es
+                .eval_from_string("\"ü\"", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_string_bytes(&v).unwrap(), "ü".as_bytes());
+            assert_eq!(es.require_string_lossy(&v).unwrap(), "ü");
+
+            // As long as Nix agrees about the length, no bytes are lost
+            let v = es
+                .eval_from_string(
+                    r#"let s = builtins.fromJSON "\"a\\u0000b\""; in { inherit s; len = builtins.stringLength s; }"#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let s = es.require_attrs_select(&v, "s").unwrap();
+            let len = es.require_attrs_select(&v, "len").unwrap();
+            let bytes = es.require_string_bytes(&s).unwrap();
+            assert_eq!(bytes.len() as i64, es.require_int(&len).unwrap());
+            assert!(bytes.starts_with(b"a"));
+
+            let i = es.new_value_int(1).unwrap();
+            let e = es.require_string_lossy(&i).unwrap_err();
+            assert_eq!(e.to_string(), "expected a string, but got a Int: 1");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_store_shared_with_threads() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "builtins.toFile \"shared.txt\" \"shared\"",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let path = es.require_string(&v).unwrap();
+            let threads = (0..2)
+                .map(|_| {
+                    let store = es.store_cloned();
+                    let path = path.clone();
+                    std::thread::spawn(move || {
+                        let path = store.parse_store_path(&path).unwrap();
+                        let fake = store
+                            .parse_store_path(
+                                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixops4-test-fake",
+                            )
+                            .unwrap();
+                        for _ in 0..100 {
+                            assert!(store.is_valid_path(&path).unwrap());
+                            assert!(!store.is_valid_path(&fake).unwrap());
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for t in threads {
+                t.join().unwrap();
+            }
+            // The evaluator's store still works
+            let path = es.store().parse_store_path(&path).unwrap();
+            assert!(es.store().is_valid_path(&path).unwrap());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn eval_state_realise_string_type_error() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.new_value_int(1).unwrap();
+            let e = es.realise_string(&v, false).unwrap_err();
+            assert_eq!(e.to_string(), "expected a string, but got a Int: 1");
+        })
+        .unwrap();
     }
-    pub fn value_is_thunk(&self, value: &Value) -> bool {
-        let r = unsafe {
-            raw::nix_get_type(self.context.ptr(), value.raw_ptr()) == raw::ValueType_NIX_TYPE_THUNK
-        };
-        self.context.check_err().unwrap();
-        r
+
+    #[test]
+    fn eval_state_require_derivation() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "derivation { name = \"hello\"; system = \"dummy\"; builder = \"cmd.exe\"; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let d = es.require_derivation(&v).unwrap();
+            assert_eq!(d.name, "hello");
+            assert_eq!(d.system, "dummy");
+            assert_eq!(d.drv_path.name(), "hello.drv");
+            match d.outputs.as_slice() {
+                [(name, Some(path))] => {
+                    assert_eq!(name, "out");
+                    assert_eq!(path.name(), "hello");
+                }
+                _ => panic!("unexpected outputs: {:?}", d.outputs),
+            }
+        })
+        .unwrap();
     }
-    pub fn value_type(&self, value: &Value) -> Result<ValueType> {
-        if self.value_is_thunk(value) {
-            self.force(value)?;
-        }
-        let r = unsafe { raw::nix_get_type(self.context.ptr(), value.raw_ptr()) };
-        Ok(ValueType::from_raw(r))
+
+    #[test]
+    fn eval_state_instantiate() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "derivation { name = \"instantiated\"; system = \"dummy\"; builder = \"cmd.exe\"; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let drv = es.instantiate(&v).unwrap();
+            assert!(drv.to_string().ends_with("-instantiated.drv"), "{}", drv);
+            assert!(es.store().is_valid_path(&drv).unwrap());
+
+            let v = es.new_value_int(1).unwrap();
+            assert_eq!(
+                es.instantiate(&v).unwrap_err().to_string(),
+                "expected a derivation, but got a Int: 1"
+            );
+        })
+        .unwrap();
     }
-    /// Not exposed, because the caller must always explicitly handle the context or not accept one at all.
-    fn get_string(&self, value: &Value) -> Result<String> {
-        let c_str_raw = unsafe { raw::nix_get_string(self.context.ptr(), value.raw_ptr()) };
-        self.context.check_err()?;
-        let cstring = unsafe { std::ffi::CStr::from_ptr(c_str_raw) };
-        let str = cstring
-            .to_str()
-            .map_err(|e| anyhow::format_err!("Nix string is not valid UTF-8: {}", e))?;
-        Ok(str.to_owned())
+
+    #[test]
+    fn eval_state_instantiate_parse_derivation() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    let dep = derivation { name = "dep"; system = "dummy"; builder = "/bin/sh"; outputs = [ "out" "dev" ]; };
+                    in derivation {
+                      name = "parsed";
+                      system = "dummy";
+                      builder = "/bin/sh";
+                      args = [ "-c" "echo \"$greeting\" > $out" ];
+                      greeting = "hello\nworld";
+                      inherit dep;
+                    }
+                    "#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let drv_path = es.instantiate(&v).unwrap();
+            let d = Derivation::parse(es.store(), &drv_path).unwrap();
+            assert_eq!(d.builder, "/bin/sh");
+            assert_eq!(d.platform, "dummy");
+            assert_eq!(d.args, ["-c", "echo \"$greeting\" > $out"]);
+            assert_eq!(d.env["greeting"], "hello\nworld");
+            assert_eq!(d.env["name"], "parsed");
+            let out = es.require_derivation(&v).unwrap().outputs.remove(0).1.unwrap();
+            assert_eq!(d.env["out"], out.as_str());
+            assert_eq!(d.outputs["out"].path.as_ref(), Some(&out));
+            match d.input_drvs.as_slice() {
+                [(path, outputs)] => {
+                    assert_eq!(path.name(), "dep.drv");
+                    assert_eq!(outputs.iter().collect::<Vec<_>>(), ["out"]);
+                }
+                _ => panic!("unexpected input_drvs: {:?}", d.input_drvs),
+            }
+        })
+        .unwrap();
     }
-    /// NOTE: this will be replaced by two methods, one that also returns the context, and one that checks that the context is empty
-    pub fn require_string(&self, value: &Value) -> Result<String> {
-        let t = self.value_type(value)?;
-        if t != ValueType::String {
-            bail!("expected a string, but got a {:?}", t);
-        }
-        self.get_string(value)
+
+    #[test]
+    fn eval_state_require_derivation_multiple_outputs() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "derivation { name = \"hello\"; system = \"dummy\"; builder = \"cmd.exe\"; outputs = [ \"out\" \"dev\" \"doc\" ]; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let d = es.require_derivation(&v).unwrap();
+            let names: Vec<_> = d.outputs.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(names, ["out", "dev", "doc"]);
+            let paths: Vec<_> = d
+                .outputs
+                .iter()
+                .map(|(_, path)| path.as_ref().unwrap().name())
+                .collect();
+            assert_eq!(paths, ["hello", "hello-dev", "hello-doc"]);
+        })
+        .unwrap();
     }
 
-    fn new_value_uninitialized(&self) -> Value {
-        let value = unsafe { raw::nix_alloc_value(self.context.ptr(), self.raw_ptr()) };
-        Value::new(value)
+    #[test]
+    fn eval_state_require_derivation_not_a_derivation() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "{ name = \"hello\"; type = \"package\"; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let e = es.require_derivation(&v).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "expected a derivation, but got an attribute set"
+            );
+            let v = es.new_value_int(1).unwrap();
+            let e = es.require_derivation(&v).unwrap_err();
+            assert_eq!(e.to_string(), "expected a derivation, but got a Int: 1");
+        })
+        .unwrap();
     }
-}
 
-pub fn gc_now() {
-    unsafe {
-        raw::nix_gc_now();
+    #[test]
+    fn store_build_already_valid() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "builtins.toFile \"hello.txt\" \"hello\"",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let p = es
+                .store()
+                .parse_store_path(&es.require_string(&v).unwrap())
+                .unwrap();
+            let r = es.store().build(&p).unwrap();
+            assert_eq!(r.status, BuildStatus::AlreadyValid);
+            assert!(r.error.is_none());
+        })
+        .unwrap();
     }
-}
 
-/** Run a function while making sure that the current thread is registered with the GC. */
-pub fn gc_registering_current_thread<F, R>(f: F) -> Result<R>
-where
-    F: FnOnce() -> R,
-{
-    init()?;
-    if unsafe { raw::GC_thread_is_registered() } != 0 {
-        return Ok(f());
-    } else {
-        gc_register_my_thread().unwrap();
-        let r = f();
-        unsafe {
-            raw::GC_unregister_my_thread();
-        }
-        return Ok(r);
+    #[test]
+    #[ignore] // Runs a build, which needs /bin/sh
+    fn store_build_derivation() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    derivation {
+                        name = "nixops4-test-build";
+                        system = builtins.currentSystem;
+                        builder = "/bin/sh";
+                        args = [ "-c" "echo hello > $out" ];
+                    }
+                    "#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let d = es.require_derivation(&v).unwrap();
+            let r = es.store().build(&d.drv_path).unwrap();
+            assert_eq!(r.status, BuildStatus::Built);
+            let out = &r.outputs["out"];
+            assert_eq!(Some(out), d.outputs[0].1.as_ref());
+            assert_eq!(std::fs::read_to_string(out.as_str()).unwrap(), "hello\n");
+        })
+        .unwrap();
     }
-}
 
-pub fn gc_register_my_thread() -> Result<()> {
-    unsafe {
-        let already_done = raw::GC_thread_is_registered();
-        if already_done != 0 {
-            return Ok(());
-        }
-        let mut sb: raw::GC_stack_base = raw::GC_stack_base {
-            mem_base: 0 as *mut _,
-        };
-        let r = raw::GC_get_stack_base(&mut sb);
-        if r as u32 != raw::GC_SUCCESS {
-            Err(anyhow::format_err!("GC_get_stack_base failed: {}", r))?;
-        }
-        raw::GC_register_my_thread(&sb);
-        Ok(())
+    #[test]
+    fn store_build_failure() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"
+                    derivation {
+                        name = "nixops4-test-build-fail";
+                        system = builtins.currentSystem;
+                        builder = "/bin/sh";
+                        args = [ "-c" "exit 1" ];
+                    }
+                    "#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let d = es.require_derivation(&v).unwrap();
+            let r = es.store().build(&d.drv_path).unwrap();
+            assert_eq!(r.status, BuildStatus::Failed);
+            let error = r.error.unwrap();
+            assert!(error.contains("nixops4-test-build-fail.drv"), "{}", error);
+        })
+        .unwrap();
     }
-}
 
-impl Drop for EvalState {
-    fn drop(&mut self) {
-        unsafe {
-            raw::nix_state_free(self.raw_ptr());
-        }
+    /// Held by the tests that disable the garbage collector, which is global.
+    static GC_DISABLE_TESTS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn gc_disable_scope_no_collections() {
+        let _lock = GC_DISABLE_TESTS.lock().unwrap();
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            gc_disable_scope(|| {
+                let before = gc_stats();
+                for i in 0..100_000 {
+                    es.new_value_int(i).unwrap();
+                }
+                gc_now();
+                let after = gc_stats();
+                assert_eq!(after.total_collections, before.total_collections);
+                assert!(after.bytes_since_gc >= before.bytes_since_gc);
+            });
+        })
+        .unwrap();
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use ctor::ctor;
+    #[test]
+    fn gc_disable_scope_nested_and_unwinding() {
+        let _lock = GC_DISABLE_TESTS.lock().unwrap();
+        gc_registering_current_thread(|| {
+            let r = std::panic::catch_unwind(|| {
+                gc_disable_scope(|| {
+                    gc_disable_scope(|| {
+                        assert_ne!(unsafe { raw::GC_is_disabled() }, 0);
+                    });
+                    assert_ne!(unsafe { raw::GC_is_disabled() }, 0);
+                    panic!("unwinding out of the scope");
+                })
+            });
+            assert!(r.is_err());
+            assert_eq!(unsafe { raw::GC_is_disabled() }, 0);
+        })
+        .unwrap();
+    }
 
-    use super::*;
+    #[test]
+    fn gc_registering_current_thread_panic() {
+        std::thread::spawn(|| {
+            let r = std::panic::catch_unwind(|| {
+                gc_registering_current_thread(|| {
+                    assert_ne!(unsafe { raw::GC_thread_is_registered() }, 0);
+                    panic!("unwinding out of the registration");
+                })
+            });
+            assert!(r.is_err());
+            assert_eq!(unsafe { raw::GC_thread_is_registered() }, 0);
+            // Registering again works
+            let v = gc_registering_current_thread(|| {
+                let store = Store::open("auto").unwrap();
+                let es = EvalState::new(store).unwrap();
+                let v = es.new_value_int(1).unwrap();
+                gc_now();
+                es.require_int(&v).unwrap()
+            })
+            .unwrap();
+            assert_eq!(v, 1);
+            assert_eq!(unsafe { raw::GC_thread_is_registered() }, 0);
+        })
+        .join()
+        .unwrap();
+    }
 
-    #[ctor]
-    fn setup() {
-        init().unwrap();
+    #[test]
+    fn gc_registering_current_thread_nested() {
+        std::thread::spawn(|| {
+            gc_registering_current_thread(|| {
+                gc_registering_current_thread(|| {
+                    assert_ne!(unsafe { raw::GC_thread_is_registered() }, 0);
+                })
+                .unwrap();
+                // The inner call didn't unregister
+                assert_ne!(unsafe { raw::GC_thread_is_registered() }, 0);
+            })
+            .unwrap();
+            assert_eq!(unsafe { raw::GC_thread_is_registered() }, 0);
+        })
+        .join()
+        .unwrap();
     }
 
     #[test]
-    fn eval_state_new_and_drop() {
+    fn gc_stats_monotone() {
         gc_registering_current_thread(|| {
-            // very basic test: make sure initialization doesn't crash
-            let store = Store::open("auto").unwrap();
-            let _e = EvalState::new(store).unwrap();
+            let before = gc_stats();
+            assert!(before.heap_size > 0);
+            assert!(before.free_bytes <= before.heap_size);
+            gc_now();
+            let after = gc_stats();
+            assert!(after.total_collections >= before.total_collections);
         })
         .unwrap();
     }
 
     #[test]
-    fn eval_state_eval_from_string() {
+    fn value_soak_gc() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
-            let v = es
-                .eval_from_string("1".to_string(), "<test>".to_string())
-                .unwrap();
-            let v2 = v.clone();
-            es.force(&v).unwrap();
-            let t = es.value_type(&v).unwrap();
-            assert!(t == ValueType::Int);
-            let t2 = es.value_type(&v2).unwrap();
-            assert!(t2 == ValueType::Int);
+            let mut kept = Vec::new();
+            for i in 0..100_000 {
+                let v = es.new_value_int(i).unwrap();
+                if i % 100 == 0 {
+                    kept.push(v.clone());
+                }
+                if i % 10_000 == 0 {
+                    gc_now();
+                }
+            }
+            for _ in 0..3 {
+                gc_now();
+            }
+            assert_eq!(kept.len(), 1000);
+            for (n, v) in kept.iter().enumerate() {
+                assert_eq!(es.require_int(v).unwrap(), n as i64 * 100);
+            }
+            // Dropping clones leaves the others alive
+            let survivors: Vec<_> = kept.iter().step_by(2).cloned().collect();
+            drop(kept);
             gc_now();
+            for (n, v) in survivors.iter().enumerate() {
+                assert_eq!(es.require_int(v).unwrap(), n as i64 * 200);
+            }
         })
         .unwrap();
     }
 
     #[test]
-    fn eval_state_value_bool() {
+    fn value_leak_from_raw() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
-            let v = es
-                .eval_from_string("true".to_string(), "<test>".to_string())
-                .unwrap();
-            es.force(&v).unwrap();
-            let t = es.value_type(&v).unwrap();
-            assert!(t == ValueType::Bool);
+            let ptr = es.new_value_int(42).unwrap().leak();
+            gc_now();
+            let v = unsafe { Value::from_raw(&es, ptr) };
+            assert_eq!(es.require_int(&v).unwrap(), 42);
+
+            // The round trip doesn't keep the state alive
+            let raw_state = es.raw_ptr();
+            let v = unsafe { Value::from_raw(&es, v.leak()) };
+            drop(es);
+            drop(v);
+            assert!(!LIVE_STATES.with(|states| states.borrow().contains_key(&raw_state)));
         })
         .unwrap();
     }
 
     #[test]
-    fn eval_state_value_string() {
+    fn value_keeps_eval_state_alive() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
+            let raw_state = es.raw_ptr();
             let v = es
-                .eval_from_string("\"hello\"".to_string(), "<test>".to_string())
+                .eval_from_string("{ a = 1 + 1; }", SourceName::Synthetic("test"))
                 .unwrap();
-            es.force(&v).unwrap();
-            let t = es.value_type(&v).unwrap();
-            assert!(t == ValueType::String);
-            let s = es.require_string(&v).unwrap();
-            assert!(s == "hello");
+            let a = es.require_attrs_select_opt(&v, "a").unwrap().unwrap();
+            drop(es);
+            gc_now();
+            let es = a.eval_state();
+            assert_eq!(es.raw_ptr(), raw_state);
+            es.force(&a).unwrap();
+            assert_eq!(es.require_int(&a).unwrap(), 2);
+            drop(es);
+            drop(v);
+            assert!(LIVE_STATES.with(|states| states.borrow().contains_key(&raw_state)));
+            // The last reference frees the state
+            drop(a);
+            assert!(!LIVE_STATES.with(|states| states.borrow().contains_key(&raw_state)));
         })
         .unwrap();
     }
 
     #[test]
-    fn eval_state_value_string_unexpected_bool() {
+    fn eval_state_clone() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
-            let v = es
-                .eval_from_string("true".to_string(), "<test>".to_string())
+            let es2 = es.clone();
+            assert_eq!(es.raw_ptr(), es2.raw_ptr());
+            drop(es);
+            let v = es2
+                .eval_from_string("1 + 1", SourceName::Synthetic("test"))
                 .unwrap();
-            es.force(&v).unwrap();
-            let r = es.require_string(&v);
-            assert!(r.is_err());
-            // TODO: safe print value (like Nix would)
-            assert_eq!(
-                r.unwrap_err().to_string(),
-                "expected a string, but got a Bool"
-            );
+            assert_eq!(es2.require_int(&v).unwrap(), 2);
         })
-        .unwrap()
+        .unwrap();
     }
 
     #[test]
-    fn eval_state_value_string_unexpected_path_value() {
+    fn value_dropped_after_eval_state() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
             let v = es
-                .eval_from_string("/foo".to_string(), "<test>".to_string())
+                .eval_from_string("{ a = 1; }", SourceName::Synthetic("test"))
                 .unwrap();
-            es.force(&v).unwrap();
-            let r = es.require_string(&v);
-            assert!(r.is_err());
-            assert_eq!(
-                r.unwrap_err().to_string(),
-                "expected a string, but got a Path"
-            );
+            drop(es);
+            drop(v);
+            gc_now();
         })
-        .unwrap()
+        .unwrap();
     }
 
     #[test]
-    fn eval_state_value_string_bad_utf() {
+    fn eval_state_require_string_lossy_truncated_large() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
             let v = es
                 .eval_from_string(
-                    "builtins.substring 0 1 \"ü\"".to_string(),
-                    "<test>".to_string(),
+                    "builtins.concatStringsSep \"\" (builtins.genList (x: \"abcdefghij\") 1000000)",
+                    SourceName::Synthetic("test"),
                 )
                 .unwrap();
-            es.force(&v).unwrap();
-            let t = es.value_type(&v).unwrap();
-            assert!(t == ValueType::String);
-            let r = es.require_string(&v);
-            assert!(r.is_err());
-            assert!(r
-                .unwrap_err()
-                .to_string()
-                .contains("Nix string is not valid UTF-8"));
+            let (prefix, len) = es.require_string_lossy_truncated(&v, 16).unwrap();
+            assert_eq!(prefix, "abcdefghijabcdef");
+            assert_eq!(len, 10_000_000);
         })
         .unwrap();
     }
 
     #[test]
-    fn eval_state_value_string_unexpected_context() {
+    fn eval_state_require_string_lossy_truncated_short() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
             let v = es
-                .eval_from_string("(derivation { name = \"hello\"; system = \"dummy\"; builder = \"cmd.exe\"; }).outPath".to_string(), "<test>".to_string())
+                .eval_from_string("\"hello\"", SourceName::Synthetic("test"))
                 .unwrap();
-            es.force(&v).unwrap();
-            let t = es.value_type(&v).unwrap();
-            assert!(t == ValueType::String);
-            // TODO
-            // let r = es.require_string_without_context(&v);
-            // assert!(r.is_err());
-            // assert!(r.unwrap_err().to_string().contains("unexpected context"));
+            let (prefix, len) = es.require_string_lossy_truncated(&v, 16).unwrap();
+            assert_eq!(prefix, "hello");
+            assert_eq!(len, 5);
         })
         .unwrap();
     }
 
     #[test]
-    fn eval_state_value_attrset() {
+    fn eval_state_require_string_lossy_truncated_split_char() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
             let v = es
-                .eval_from_string("{ }".to_string(), "<test>".to_string())
+                .eval_from_string("\"aü\"", SourceName::Synthetic("test"))
                 .unwrap();
-            es.force(&v).unwrap();
-            let t = es.value_type(&v).unwrap();
-            assert!(t == ValueType::AttrSet);
+            let (prefix, len) = es.require_string_lossy_truncated(&v, 2).unwrap();
+            assert_eq!(prefix, "a\u{FFFD}");
+            assert_eq!(len, 3);
         })
         .unwrap();
     }
 
     #[test]
-    fn eval_state_value_list() {
+    fn eval_state_require_string_lossy_truncated_not_a_string() {
         gc_registering_current_thread(|| {
             let store = Store::open("auto").unwrap();
             let es = EvalState::new(store).unwrap();
             let v = es
-                .eval_from_string("[ ]".to_string(), "<test>".to_string())
+                .eval_from_string("1", SourceName::Synthetic("test"))
                 .unwrap();
-            es.force(&v).unwrap();
-            let t = es.value_type(&v).unwrap();
-            assert!(t == ValueType::List);
+            let r = es.require_string_lossy_truncated(&v, 2);
+            assert_eq!(
+                r.unwrap_err().to_string(),
+                "expected a string, but got a Int: 1"
+            );
         })
         .unwrap();
     }
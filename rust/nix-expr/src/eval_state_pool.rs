@@ -0,0 +1,261 @@
+//! A pool of [`EvalState`]s on threads of their own, for evaluating independent expressions in parallel.
+
+use crate::eval_state::{
+    gc_registering_current_thread, EvalState, EvalStateBuilder, RealisedString, SourceName,
+};
+use crate::json::StringContextPolicy;
+use crate::oneshot::{self, Call};
+use anyhow::{bail, format_err, Result};
+use nix_store::store::Store;
+use std::future::Future;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+
+type Job = Box<dyn FnOnce(&EvalState) + Send>;
+
+/// Runs a number of worker threads, each registered with the garbage collector and with its own `EvalState`, that take calls from a shared queue.
+///
+/// The workers don't share values, so calls return owned data, such as JSON, rather than [`Value`](crate::value::Value)s.
+/// A call that panics fails, and stops its worker.
+/// Dropping the pool lets the workers finish the queued calls, then joins them and frees their `EvalState`s.
+pub struct EvalStatePool {
+    sender: Option<mpsc::Sender<Job>>,
+    threads: Vec<JoinHandle<()>>,
+}
+impl EvalStatePool {
+    /// Start `size` workers, which open the store at `store_uri` and build their `EvalState` with the settings that `configure` adds to the builder.
+    pub fn new(
+        store_uri: &str,
+        size: usize,
+        configure: impl Fn(EvalStateBuilder) -> EvalStateBuilder + Send + Sync + 'static,
+    ) -> Result<Self> {
+        if size == 0 {
+            bail!("EvalStatePool: size must be at least 1");
+        }
+        let configure = Arc::new(configure);
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (ready_sender, ready_receiver) = mpsc::channel::<Result<()>>();
+        let mut pool = EvalStatePool {
+            sender: Some(sender),
+            threads: Vec::with_capacity(size),
+        };
+        for i in 0..size {
+            let store_uri = store_uri.to_string();
+            let configure = configure.clone();
+            let receiver = receiver.clone();
+            let ready_sender = ready_sender.clone();
+            let thread = std::thread::Builder::new()
+                .name(format!("nix-eval-{}", i))
+                .spawn(move || {
+                    let r = gc_registering_current_thread(|| {
+                        let es = match Store::open(&store_uri).and_then(|store| {
+                            configure(EvalStateBuilder::new().store(store)).build()
+                        }) {
+                            Ok(es) => es,
+                            Err(e) => {
+                                let _ = ready_sender.send(Err(e));
+                                return;
+                            }
+                        };
+                        let _ = ready_sender.send(Ok(()));
+                        loop {
+                            // Only hold the lock while waiting, so that the other workers can take the next call
+                            let job = receiver.lock().unwrap().recv();
+                            match job {
+                                Ok(job) => job(&es),
+                                // The pool was dropped and the queue is empty
+                                Err(_) => break,
+                            }
+                        }
+                    });
+                    if let Err(e) = r {
+                        let _ = ready_sender.send(Err(e));
+                    }
+                })?;
+            pool.threads.push(thread);
+        }
+        drop(ready_sender);
+        for _ in 0..size {
+            match ready_receiver.recv() {
+                Ok(Ok(())) => {}
+                // Dropping the pool stops the workers that did start
+                Ok(Err(e)) => return Err(e),
+                Err(_) => bail!("evaluation thread stopped while creating the EvalState"),
+            }
+        }
+        Ok(pool)
+    }
+
+    /// The number of worker threads.
+    pub fn size(&self) -> usize {
+        self.threads.len()
+    }
+
+    /// Run `f` on the first worker that is available.
+    pub fn with<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&EvalState) -> Result<R> + Send + 'static,
+    ) -> impl Future<Output = Result<R>> {
+        let (call, completer) = oneshot::call();
+        let sender = self.sender.as_ref().unwrap();
+        match sender.send(Box::new(move |es| completer.complete(f(es)))) {
+            Ok(()) => call,
+            Err(_) => Call::ready(Err(format_err!("evaluation threads have stopped"))),
+        }
+    }
+
+    /// Evaluate `expr` completely and convert it to JSON. See [`EvalState::value_to_json`].
+    pub fn eval_json(
+        &self,
+        expr: impl Into<String>,
+        source: SourceName,
+        context: StringContextPolicy,
+    ) -> impl Future<Output = Result<serde_json::Value>> {
+        let expr = expr.into();
+        self.with(move |es| {
+            let v = es.eval_from_string(expr, source)?;
+            es.value_to_json(&v, context)
+        })
+    }
+
+    /// Evaluate `expr` to a string and build the store paths it refers to. See [`EvalState::realise_string`].
+    pub fn eval_realised_string(
+        &self,
+        expr: impl Into<String>,
+        source: SourceName,
+        is_ifd: bool,
+    ) -> impl Future<Output = Result<RealisedString>> {
+        let expr = expr.into();
+        self.with(move |es| {
+            let v = es.eval_from_string(expr, source)?;
+            es.realise_string(&v, is_ifd)
+        })
+    }
+}
+impl Drop for EvalStatePool {
+    fn drop(&mut self) {
+        // Closing the queue stops the workers once they have emptied it
+        drop(self.sender.take());
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::init;
+    use ctor::ctor;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    fn fib_expr(n: u64) -> String {
+        format!(
+            "let fib = n: if n < 2 then n else fib (n - 1) + fib (n - 2); in {{ n = {}; fib = fib {}; }}",
+            n, n
+        )
+    }
+
+    fn fib(n: u64) -> u64 {
+        if n < 2 {
+            n
+        } else {
+            fib(n - 1) + fib(n - 2)
+        }
+    }
+
+    #[test]
+    fn eval_state_pool_parallel() {
+        let pool = Arc::new(EvalStatePool::new("auto", 4, |b| b).unwrap());
+        assert_eq!(pool.size(), 4);
+        let rt = runtime();
+        let results = rt.block_on(async {
+            let tasks = (0..20)
+                .map(|i| {
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        pool.eval_json(
+                            fib_expr(10 + i % 8),
+                            SourceName::Synthetic("test"),
+                            StringContextPolicy::Reject,
+                        )
+                        .await
+                    })
+                })
+                .collect::<Vec<_>>();
+            let mut results = Vec::new();
+            for task in tasks {
+                results.push(task.await.unwrap().unwrap());
+            }
+            results
+        });
+        for (i, r) in (0..20).zip(results) {
+            let n = 10 + i % 8;
+            assert_eq!(r, serde_json::json!({ "n": n, "fib": fib(n) }));
+        }
+    }
+
+    #[test]
+    fn eval_state_pool_shutdown_under_load() {
+        let pool = EvalStatePool::new("auto", 3, |b| b).unwrap();
+        let calls = (0..20)
+            .map(|i| {
+                pool.eval_json(
+                    fib_expr(12 + i % 4),
+                    SourceName::Synthetic("test"),
+                    StringContextPolicy::Reject,
+                )
+            })
+            .collect::<Vec<_>>();
+        // Waits for the queued calls, then joins the workers
+        drop(pool);
+        let rt = runtime();
+        for (i, call) in (0..20).zip(calls) {
+            let r = rt.block_on(call).unwrap();
+            assert_eq!(r["fib"], fib(12 + i % 4));
+        }
+    }
+
+    #[test]
+    fn eval_state_pool_worker_panic() {
+        let pool = EvalStatePool::new("auto", 2, |b| b).unwrap();
+        let rt = runtime();
+        let e = rt
+            .block_on(pool.with(|_es| -> Result<()> { panic!("panicking on purpose") }))
+            .unwrap_err();
+        assert_eq!(e.to_string(), "evaluation thread stopped during the call");
+        // The other worker still takes calls
+        let r = rt
+            .block_on(pool.with(|es| {
+                let v = es.eval_from_string("1 + 1", SourceName::Synthetic("test"))?;
+                es.require_int(&v)
+            }))
+            .unwrap();
+        assert_eq!(r, 2);
+    }
+
+    #[test]
+    fn eval_state_pool_new_error() {
+        match EvalStatePool::new("nonexistent-scheme://", 2, |b| b) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => assert!(!e.to_string().is_empty()),
+        }
+        match EvalStatePool::new("auto", 0, |b| b) {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => assert_eq!(e.to_string(), "EvalStatePool: size must be at least 1"),
+        }
+    }
+}
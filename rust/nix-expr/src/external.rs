@@ -0,0 +1,246 @@
+//! External values: Rust objects inside Nix values.
+
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use anyhow::{bail, Result};
+use nix_c_raw as raw;
+use nix_util::context::Context;
+use nix_util::error_site;
+use nix_util::version::{self, Capability};
+use std::any::{type_name, Any};
+use std::ffi::{c_void, CString};
+
+/// Marks the contents of external values that were created by [`EvalState::new_value_external`], as opposed to other users of the C API.
+const EXTERNAL_MAGIC: u64 = 0x6e69_786f_7073_3465;
+
+#[repr(C)]
+struct ExternalData {
+    magic: u64,
+    type_name: CString,
+    value: Box<dyn Any + Send>,
+}
+
+static EXTERNAL_DESC: raw::NixCExternalValueDesc = raw::NixCExternalValueDesc {
+    print: Some(external_print),
+    showType: Some(external_show_type),
+    typeOf: Some(external_type_of),
+    // Nix's defaults: coercion to a string throws, external values are never equal, and there is no JSON or XML representation
+    coerceToString: None,
+    equal: None,
+    printValueAsJSON: None,
+    printValueAsXML: None,
+};
+
+impl EvalState {
+    /// Wrap `t` in a Nix value, for example to pass it through an expression to a primop.
+    ///
+    /// `t` is dropped when the garbage collector frees the value, which may happen on another thread.
+    pub fn new_value_external<T: Any + Send>(&self, t: T) -> Result<Value> {
+        version::require(
+            Capability::ExternalValues,
+            error_site!("nix_create_external_value"),
+        )?;
+        let data = Box::into_raw(Box::new(ExternalData {
+            magic: EXTERNAL_MAGIC,
+            type_name: CString::new(type_name::<T>()).unwrap(),
+            value: Box::new(t),
+        }));
+        let context = Context::new();
+        let external = unsafe {
+            raw::nix_create_external_value(
+                context.ptr(),
+                &EXTERNAL_DESC as *const _ as *mut _,
+                data as *mut c_void,
+            )
+        };
+        if let Err(e) = context.check_err(error_site!("nix_create_external_value")) {
+            drop(unsafe { Box::from_raw(data) });
+            return Err(e.into());
+        }
+        unsafe {
+            raw::nix_gc_register_finalizer(
+                external as *mut c_void,
+                data as *mut c_void,
+                Some(finalize_external),
+            );
+        }
+        let value = self.new_value_uninitialized();
+        unsafe {
+            raw::nix_init_external(context.ptr(), value.raw_ptr(), external);
+        }
+        let r = context.check_err(error_site!("nix_init_external"));
+        // The value holds the external value now
+        unsafe {
+            raw::nix_gc_decref(context.ptr(), external as *const c_void);
+        }
+        r?;
+        Ok(value)
+    }
+
+    /// Get the Rust object out of a value created by [`new_value_external`](Self::new_value_external).
+    pub fn require_external<'v, T: Any>(&self, v: &'v Value) -> Result<&'v T> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::External {
+            return Err(self.type_error("an external value", t, v));
+        }
+        let context = Context::new();
+        let data = unsafe {
+            let external = raw::nix_get_external(context.ptr(), v.raw_ptr());
+            context.check_err(error_site!("nix_get_external"))?;
+            let content = raw::nix_get_external_value_content(context.ptr(), external);
+            context.check_err(error_site!("nix_get_external_value_content"))?;
+            content as *const ExternalData
+        };
+        // The magic number is read as the first field of ExternalData, which is repr(C)
+        if data.is_null() || unsafe { (*data).magic } != EXTERNAL_MAGIC {
+            bail!("expected an external value created by nix-expr, but got a foreign one");
+        }
+        // Valid for as long as v keeps the external value alive
+        let data: &'v ExternalData = unsafe { &*data };
+        match data.value.downcast_ref::<T>() {
+            Some(t) => Ok(t),
+            None => bail!(
+                "expected an external {}, but got an external {}",
+                type_name::<T>(),
+                data.type_name.to_string_lossy()
+            ),
+        }
+    }
+}
+
+unsafe extern "C" fn finalize_external(_obj: *mut c_void, cd: *mut c_void) {
+    drop(Box::from_raw(cd as *mut ExternalData));
+}
+
+unsafe extern "C" fn external_print(self_: *mut c_void, printer: *mut raw::nix_printer) {
+    let data = &*(self_ as *const ExternalData);
+    let s = CString::new(format!("«external {}»", data.type_name.to_string_lossy())).unwrap();
+    let context = Context::new();
+    raw::nix_external_print(context.ptr(), printer, s.as_ptr());
+}
+
+unsafe extern "C" fn external_show_type(self_: *mut c_void, res: *mut raw::nix_string_return) {
+    let data = &*(self_ as *const ExternalData);
+    let s = CString::new(format!("an external {}", data.type_name.to_string_lossy())).unwrap();
+    raw::nix_set_string_return(res, s.as_ptr());
+}
+
+unsafe extern "C" fn external_type_of(_self: *mut c_void, res: *mut raw::nix_string_return) {
+    let s = CString::new("external").unwrap();
+    raw::nix_set_string_return(res, s.as_ptr());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_now, gc_registering_current_thread, init, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    struct DropCounter(Arc<AtomicUsize>);
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn external_round_trip() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.new_value_external(String::from("handle")).unwrap();
+            assert_eq!(es.value_type_forced(&v).unwrap(), ValueType::External);
+            let s: &String = es.require_external(&v).unwrap();
+            assert_eq!(s, "handle");
+
+            // Through an expression
+            let f = es
+                .eval_from_string("x: { inner = x; }", SourceName::Synthetic("test"))
+                .unwrap();
+            let r = es.call(&f, &v).unwrap();
+            let inner = es.require_attrs_select(&r, "inner").unwrap();
+            let s: &String = es.require_external(&inner).unwrap();
+            assert_eq!(s, "handle");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn external_wrong_type() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.new_value_external(1u32).unwrap();
+            let e = es.require_external::<String>(&v).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "expected an external alloc::string::String, but got an external u32"
+            );
+            let i = es.new_value_int(1).unwrap();
+            let e = es.require_external::<u32>(&i).unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "expected an external value, but got a Int: 1"
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn external_not_coercible() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.new_value_external(1u32).unwrap();
+            let f = es
+                .eval_from_string("x: \"${x}\"", SourceName::Synthetic("test"))
+                .unwrap();
+            let e = match es.call(&f, &v) {
+                Ok(_) => panic!("expected an error"),
+                Err(e) => e,
+            };
+            assert!(e.to_string().contains("cannot coerce"), "{}", e);
+            let f = es
+                .eval_from_string("builtins.typeOf", SourceName::Synthetic("test"))
+                .unwrap();
+            let t = es.call(&f, &v).unwrap();
+            assert_eq!(es.require_string(&t).unwrap(), "external");
+        })
+        .unwrap();
+    }
+
+    fn make_external(es: &EvalState, drops: &Arc<AtomicUsize>) -> Value {
+        es.new_value_external(DropCounter(drops.clone())).unwrap()
+    }
+
+    #[test]
+    fn external_dropped_by_gc() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let drops = Arc::new(AtomicUsize::new(0));
+            let v = make_external(&es, &drops);
+            gc_now();
+            assert_eq!(drops.load(Ordering::SeqCst), 0);
+            assert!(es.require_external::<DropCounter>(&v).is_ok());
+            drop(v);
+            // The collector is conservative, so a stray pointer may keep the value alive for a few more cycles
+            for _ in 0..10 {
+                gc_now();
+                if drops.load(Ordering::SeqCst) > 0 {
+                    break;
+                }
+            }
+            assert_eq!(drops.load(Ordering::SeqCst), 1);
+        })
+        .unwrap();
+    }
+}
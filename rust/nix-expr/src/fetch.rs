@@ -0,0 +1,334 @@
+//! Fetching source trees into the store, through `builtins.fetchGit`, `builtins.fetchTarball` and `builtins.path`.
+//!
+//! `builtins.fetchTree` would cover all of them, but it requires the `fetch-tree` experimental feature.
+
+use crate::eval_state::EvalState;
+use crate::value::Value;
+use anyhow::{Context as _, Result};
+use nix_store::path::StorePath;
+use nix_util::hash::Hash;
+use std::path::PathBuf;
+
+/// What to fetch with [`EvalState::fetch_tree`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum FetchInput {
+    Git {
+        /// A URL such as `https://github.com/NixOS/nix`, `file:///some/repo` or `git+file:///some/repo`, or a local path.
+        url: String,
+        /// The branch or tag, e.g. `main` or `refs/tags/v1.0`. Defaults to `HEAD`.
+        r#ref: Option<String>,
+        /// The commit to fetch. Evaluation in pure mode requires one.
+        rev: Option<String>,
+        /// Fetch without the history, which leaves out the `revCount`.
+        shallow: bool,
+        submodules: bool,
+    },
+    Tarball {
+        url: String,
+        /// The expected hash of the unpacked tree. Evaluation in pure mode requires one.
+        nar_hash: Option<Hash>,
+    },
+    /// A local file or directory, copied to the store as is.
+    Path { path: PathBuf },
+}
+impl FetchInput {
+    /// The URL that needs the network, if any.
+    fn remote_url(&self) -> Option<&str> {
+        let url = match self {
+            FetchInput::Git { url, .. } => url.strip_prefix("git+").unwrap_or(url),
+            FetchInput::Tarball { url, .. } => url,
+            FetchInput::Path { .. } => return None,
+        };
+        if url.starts_with('/') || url.starts_with("file://") {
+            None
+        } else {
+            Some(url)
+        }
+    }
+}
+
+/// A tree fetched by [`EvalState::fetch_tree`], and the attributes that lock it.
+#[derive(Debug)]
+pub struct FetchedTree {
+    pub store_path: StorePath,
+    /// The commit of a Git input.
+    pub rev: Option<String>,
+    /// The commit time of a Git input, in seconds since the epoch.
+    pub last_modified: Option<i64>,
+    /// The hash of the tree's NAR serialization. Nix only reports it for Git inputs; for tarballs this is the expected hash.
+    pub nar_hash: Option<Hash>,
+}
+
+/// The error of [`EvalState::fetch_tree`] for inputs that need the network, when the `EvalState` is [`offline`](crate::eval_state::EvalStateBuilder::offline).
+///
+/// Inside an `anyhow::Error` it can be recovered with `downcast_ref`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OfflineError {
+    pub url: String,
+}
+impl std::fmt::Display for OfflineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cannot fetch {} in offline mode", self.url)
+    }
+}
+impl std::error::Error for OfflineError {}
+
+impl EvalState {
+    /// Fetch a source tree into the store.
+    ///
+    /// Offline, inputs that are not on the local file system fail with an [`OfflineError`], even if Nix has them in its cache.
+    pub fn fetch_tree(&self, input: &FetchInput) -> Result<FetchedTree> {
+        if self.is_offline() {
+            if let Some(url) = input.remote_url() {
+                return Err(OfflineError {
+                    url: url.to_string(),
+                }
+                .into());
+            }
+        }
+        self.fetch_tree_online(input)
+            .with_context(|| format!("while fetching {:?}", input))
+    }
+
+    fn fetch_tree_online(&self, input: &FetchInput) -> Result<FetchedTree> {
+        match input {
+            FetchInput::Git {
+                url,
+                r#ref,
+                rev,
+                shallow,
+                submodules,
+            } => {
+                let url = url.strip_prefix("git+").unwrap_or(url);
+                let mut args = vec![
+                    ("url".to_string(), self.new_value_string(url)?),
+                    ("shallow".to_string(), self.new_value_bool(*shallow)?),
+                    ("submodules".to_string(), self.new_value_bool(*submodules)?),
+                ];
+                if let Some(r#ref) = r#ref {
+                    args.push(("ref".to_string(), self.new_value_string(r#ref)?));
+                }
+                if let Some(rev) = rev {
+                    args.push(("rev".to_string(), self.new_value_string(rev)?));
+                }
+                let r = self.call_builtin("fetchGit", args)?;
+                let out_path = self.require_attrs_select(&r, "outPath")?;
+                let nar_hash = self.require_attrs_select(&r, "narHash")?;
+                Ok(FetchedTree {
+                    store_path: self.store_path_of(&out_path)?,
+                    rev: Some(self.require_string(&self.require_attrs_select(&r, "rev")?)?),
+                    last_modified: Some(
+                        self.require_int(&self.require_attrs_select(&r, "lastModified")?)?,
+                    ),
+                    nar_hash: Some(Hash::parse(&self.require_string(&nar_hash)?)?),
+                })
+            }
+            FetchInput::Tarball { url, nar_hash } => {
+                let mut args = vec![("url".to_string(), self.new_value_string(url)?)];
+                if let Some(nar_hash) = nar_hash {
+                    args.push((
+                        "sha256".to_string(),
+                        self.new_value_string(&nar_hash.to_sri())?,
+                    ));
+                }
+                let r = self.call_builtin("fetchTarball", args)?;
+                Ok(FetchedTree {
+                    store_path: self.store_path_of(&r)?,
+                    rev: None,
+                    last_modified: None,
+                    nar_hash: nar_hash.clone(),
+                })
+            }
+            FetchInput::Path { path } => {
+                let args = vec![("path".to_string(), self.new_value_path(path)?)];
+                let r = self.call_builtin("path", args)?;
+                Ok(FetchedTree {
+                    store_path: self.store_path_of(&r)?,
+                    rev: None,
+                    last_modified: None,
+                    nar_hash: None,
+                })
+            }
+        }
+    }
+
+    fn call_builtin(&self, name: &str, args: Vec<(String, Value)>) -> Result<Value> {
+        let f = self.builtin(name)?;
+        let args = self.new_value_attrs(args)?;
+        self.call(&f, &args)
+    }
+
+    fn store_path_of(&self, v: &Value) -> Result<StorePath> {
+        self.store().parse_store_path(&self.require_string(v)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init, EvalStateBuilder, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+    use nix_util::hash::Algo;
+    use std::path::Path;
+    use std::process::Command;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("nix-expr-fetch-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let out = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git {:?}: {:?}", args, out);
+        String::from_utf8(out.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn fetch_tree_path() {
+        gc_registering_current_thread(|| {
+            let dir = temp_dir("path");
+            std::fs::write(dir.join("default.nix"), "42").unwrap();
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let tree = es
+                .fetch_tree(&FetchInput::Path { path: dir.clone() })
+                .unwrap();
+            let contents =
+                std::fs::read_to_string(Path::new(tree.store_path.as_str()).join("default.nix"))
+                    .unwrap();
+            assert_eq!(contents, "42");
+            assert_eq!(tree.rev, None);
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn fetch_tree_path_nar_hash() {
+        gc_registering_current_thread(|| {
+            let dir = temp_dir("nar");
+            std::fs::create_dir_all(dir.join("empty")).unwrap();
+            std::fs::write(dir.join("script"), "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(
+                dir.join("script"),
+                std::os::unix::fs::PermissionsExt::from_mode(0o755),
+            )
+            .unwrap();
+            std::os::unix::fs::symlink("script", dir.join("link")).unwrap();
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let tree = es
+                .fetch_tree(&FetchInput::Path { path: dir.clone() })
+                .unwrap();
+
+            let nar_file =
+                std::env::temp_dir().join(format!("nix-expr-fetch-{}.nar", std::process::id()));
+            let file = std::fs::File::create(&nar_file).unwrap();
+            es.store().nar_from_path(&tree.store_path, file).unwrap();
+            let nar_hash = es.hash_file(Algo::Sha256, &nar_file).unwrap();
+            std::fs::remove_file(&nar_file).unwrap();
+
+            // Nix checks the hash when the store path it implies doesn't exist yet
+            let f = es
+                .eval_from_string(
+                    "path: sha256: builtins.path { inherit path sha256; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let path = es.new_value_path(&dir).unwrap();
+            let hash = es.new_value_string(&nar_hash.to_sri()).unwrap();
+            let r = es.call_multi(&f, &[path, hash]).unwrap();
+            assert_eq!(es.require_string(&r).unwrap(), tree.store_path.as_str());
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn fetch_tree_git() {
+        gc_registering_current_thread(|| {
+            let dir = temp_dir("git");
+            git(&dir, &["init", "-q", "-b", "main"]);
+            std::fs::write(dir.join("file"), "hello").unwrap();
+            git(&dir, &["add", "file"]);
+            git(&dir, &["commit", "-q", "-m", "init"]);
+            let rev = git(&dir, &["rev-parse", "HEAD"]);
+            let time = git(&dir, &["log", "-1", "--format=%ct"]);
+
+            let store = Store::open("auto").unwrap();
+            let es = EvalStateBuilder::new()
+                .store(store)
+                .offline(true)
+                .build()
+                .unwrap();
+            let tree = es
+                .fetch_tree(&FetchInput::Git {
+                    url: format!("git+file://{}", dir.display()),
+                    r#ref: Some("main".to_string()),
+                    rev: Some(rev.clone()),
+                    shallow: true,
+                    submodules: false,
+                })
+                .unwrap();
+            assert_eq!(tree.rev.as_deref(), Some(rev.as_str()));
+            assert_eq!(tree.last_modified, Some(time.parse().unwrap()));
+            assert!(tree.nar_hash.is_some());
+            let contents =
+                std::fs::read_to_string(Path::new(tree.store_path.as_str()).join("file")).unwrap();
+            assert_eq!(contents, "hello");
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn fetch_tree_offline() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalStateBuilder::new()
+                .store(store)
+                .offline(true)
+                .build()
+                .unwrap();
+            let e = es
+                .fetch_tree(&FetchInput::Tarball {
+                    url: "https://example.com/source.tar.gz".to_string(),
+                    nar_hash: None,
+                })
+                .unwrap_err();
+            assert_eq!(
+                e.downcast_ref::<OfflineError>(),
+                Some(&OfflineError {
+                    url: "https://example.com/source.tar.gz".to_string()
+                })
+            );
+            let e = es
+                .fetch_tree(&FetchInput::Git {
+                    url: "git+https://github.com/NixOS/nix".to_string(),
+                    r#ref: None,
+                    rev: None,
+                    shallow: false,
+                    submodules: false,
+                })
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "cannot fetch https://github.com/NixOS/nix in offline mode"
+            );
+        })
+        .unwrap();
+    }
+}
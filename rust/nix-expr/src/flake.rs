@@ -0,0 +1,282 @@
+//! Flake lock files, `flake.lock`.
+//!
+//! The C API has no flake functions, so this reads lock files but can't lock flakes or fetch their inputs.
+
+use anyhow::{bail, Context as _, Result};
+use nix_util::hash::Hash;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// How a node refers to one of its inputs.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum InputRef {
+    /// The id of a node in the lock file.
+    Node(String),
+    /// `inputs.<name>.follows`: a path of input names, starting at the root flake.
+    Follows(Vec<String>),
+}
+
+/// A node of the lock file's graph: the root flake, or a locked input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub inputs: BTreeMap<String, InputRef>,
+    /// The locked reference as attributes, e.g. `type`, `owner`, `repo`, `rev`, `narHash` and `lastModified`. `None` for the root.
+    pub locked: Option<serde_json::Map<String, serde_json::Value>>,
+    /// The reference as written in `flake.nix`. `None` for the root.
+    pub original: Option<serde_json::Map<String, serde_json::Value>>,
+    /// `false` for inputs with `flake = false`.
+    pub flake: bool,
+}
+impl Node {
+    pub fn nar_hash(&self) -> Result<Option<Hash>> {
+        match self.locked.as_ref().and_then(|l| l.get("narHash")) {
+            None => Ok(None),
+            Some(serde_json::Value::String(s)) => Ok(Some(Hash::parse(s)?)),
+            Some(v) => bail!("narHash should be a string, but is {}", v),
+        }
+    }
+    /// The time of the locked revision, in seconds since the epoch.
+    pub fn last_modified(&self) -> Result<Option<i64>> {
+        match self.locked.as_ref().and_then(|l| l.get("lastModified")) {
+            None => Ok(None),
+            Some(v) => match v.as_i64() {
+                Some(t) => Ok(Some(t)),
+                None => bail!("lastModified should be an integer, but is {}", v),
+            },
+        }
+    }
+}
+
+/// The contents of a `flake.lock`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlakeLock {
+    pub nodes: BTreeMap<String, Node>,
+    /// The id of the root flake's node.
+    pub root: String,
+}
+impl FlakeLock {
+    pub fn read(path: &Path) -> Result<FlakeLock> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("while reading lock file {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("while parsing lock file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<FlakeLock> {
+        let j: serde_json::Value = serde_json::from_str(text)?;
+        match j.get("version").and_then(|v| v.as_u64()) {
+            Some(5..=7) => {}
+            Some(v) => bail!("unsupported lock file version {}", v),
+            None => bail!("lock file has no version"),
+        }
+        let root = match j.get("root").and_then(|v| v.as_str()) {
+            Some(root) => root.to_string(),
+            None => bail!("lock file has no root"),
+        };
+        let mut nodes = BTreeMap::new();
+        let json_nodes = match j.get("nodes").and_then(|v| v.as_object()) {
+            Some(nodes) => nodes,
+            None => bail!("lock file has no nodes"),
+        };
+        for (id, n) in json_nodes {
+            let node = parse_node(n).with_context(|| format!("while parsing node {:?}", id))?;
+            nodes.insert(id.clone(), node);
+        }
+        if !nodes.contains_key(&root) {
+            bail!("root node {:?} is missing", root);
+        }
+        Ok(FlakeLock { nodes, root })
+    }
+
+    /// The names of the root flake's inputs.
+    pub fn root_inputs(&self) -> Vec<&str> {
+        self.nodes[&self.root]
+            .inputs
+            .keys()
+            .map(|s| s.as_str())
+            .collect()
+    }
+
+    /// Find the node of an input by its path from the root, e.g. `["utils", "nixpkgs"]`, resolving `follows`.
+    /// Returns the node's id and the node.
+    pub fn input(&self, path: &[&str]) -> Result<(&str, &Node)> {
+        let path = path.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let id = self
+            .resolve(&path, 0)?
+            .with_context(|| format!("input {:?} follows itself", path.join("/")))?;
+        Ok((id, &self.nodes[id]))
+    }
+
+    /// `None` if `follows` leads to a cycle.
+    fn resolve(&self, path: &[String], depth: usize) -> Result<Option<&str>> {
+        // Nix rejects cycles when locking, but a lock file can be edited by hand
+        if depth > self.nodes.len() {
+            return Ok(None);
+        }
+        let mut id = self.root.as_str();
+        for (i, name) in path.iter().enumerate() {
+            id = match self.nodes[id].inputs.get(name) {
+                Some(InputRef::Node(next)) => match self.nodes.get_key_value(next) {
+                    Some((next, _)) => next.as_str(),
+                    None => bail!("input {:?} refers to missing node {:?}", name, next),
+                },
+                Some(InputRef::Follows(follows)) => match self.resolve(follows, depth + 1)? {
+                    Some(id) => id,
+                    None => return Ok(None),
+                },
+                None => bail!("input {:?} does not exist", path[..=i].join("/")),
+            };
+        }
+        Ok(Some(id))
+    }
+}
+
+fn parse_node(n: &serde_json::Value) -> Result<Node> {
+    let mut inputs = BTreeMap::new();
+    if let Some(json_inputs) = n.get("inputs") {
+        let json_inputs = match json_inputs.as_object() {
+            Some(inputs) => inputs,
+            None => bail!("inputs should be an object, but is {}", json_inputs),
+        };
+        for (name, r) in json_inputs {
+            let r = match r {
+                serde_json::Value::String(id) => InputRef::Node(id.clone()),
+                serde_json::Value::Array(path) => InputRef::Follows(
+                    path.iter()
+                        .map(|s| match s.as_str() {
+                            Some(s) => Ok(s.to_string()),
+                            None => bail!("input path of {:?} should contain strings", name),
+                        })
+                        .collect::<Result<_>>()?,
+                ),
+                _ => bail!(
+                    "input {:?} should be a node id or a path, but is {}",
+                    name,
+                    r
+                ),
+            };
+            inputs.insert(name.clone(), r);
+        }
+    }
+    let object = |key: &str| match n.get(key) {
+        None => Ok(None),
+        Some(serde_json::Value::Object(o)) => Ok(Some(o.clone())),
+        Some(v) => bail!("{} should be an object, but is {}", key, v),
+    };
+    Ok(Node {
+        inputs,
+        locked: object("locked")?,
+        original: object("original")?,
+        flake: n.get("flake").and_then(|f| f.as_bool()).unwrap_or(true),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCK: &str = r#"{
+  "nodes": {
+    "flake-utils": {
+      "inputs": {
+        "nixpkgs": [
+          "nixpkgs"
+        ]
+      },
+      "locked": {
+        "lastModified": 1710146030,
+        "narHash": "sha256-SZ5L6eA7HJ/nmkzGG7/ISclqe6oZdOZTNoesiInkXPQ=",
+        "owner": "numtide",
+        "repo": "flake-utils",
+        "rev": "b1d9ab70662946ef0850d488da1c9019f3a9752a",
+        "type": "github"
+      },
+      "original": {
+        "owner": "numtide",
+        "repo": "flake-utils",
+        "type": "github"
+      }
+    },
+    "nixpkgs": {
+      "locked": {
+        "lastModified": 1711001935,
+        "narHash": "sha256-URtGpHue7HHZK0mrHnSf8wJ6OmMKYSsoLmJybrOLFSQ=",
+        "owner": "NixOS",
+        "repo": "nixpkgs",
+        "rev": "20f77aa09916374aa3141cbc605c955626762c9a",
+        "type": "github"
+      },
+      "original": {
+        "owner": "NixOS",
+        "ref": "nixos-unstable",
+        "repo": "nixpkgs",
+        "type": "github"
+      }
+    },
+    "root": {
+      "inputs": {
+        "flake-utils": "flake-utils",
+        "nixpkgs": "nixpkgs"
+      }
+    }
+  },
+  "root": "root",
+  "version": 7
+}
+"#;
+
+    #[test]
+    fn flake_lock_read() {
+        let dir = std::env::temp_dir().join(format!("nix-expr-flake-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("flake.lock");
+        std::fs::write(&path, LOCK).unwrap();
+        let lock = FlakeLock::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(lock.root_inputs(), ["flake-utils", "nixpkgs"]);
+        let (id, nixpkgs) = lock.input(&["nixpkgs"]).unwrap();
+        assert_eq!(id, "nixpkgs");
+        assert_eq!(nixpkgs.last_modified().unwrap(), Some(1711001935));
+        assert_eq!(
+            nixpkgs.nar_hash().unwrap().unwrap().to_sri(),
+            "sha256-URtGpHue7HHZK0mrHnSf8wJ6OmMKYSsoLmJybrOLFSQ="
+        );
+        let locked = nixpkgs.locked.as_ref().unwrap();
+        assert_eq!(locked["rev"], "20f77aa09916374aa3141cbc605c955626762c9a");
+        assert_eq!(nixpkgs.original.as_ref().unwrap()["ref"], "nixos-unstable");
+        assert!(nixpkgs.flake);
+
+        let (id, _) = lock.input(&["flake-utils"]).unwrap();
+        assert_eq!(id, "flake-utils");
+        assert_eq!(
+            lock.nodes["flake-utils"].inputs["nixpkgs"],
+            InputRef::Follows(vec!["nixpkgs".to_string()])
+        );
+        let (id, followed) = lock.input(&["flake-utils", "nixpkgs"]).unwrap();
+        assert_eq!(id, "nixpkgs");
+        assert_eq!(followed, nixpkgs);
+    }
+
+    #[test]
+    fn flake_lock_errors() {
+        let lock = FlakeLock::parse(LOCK).unwrap();
+        let e = lock.input(&["flake-utils", "systems"]).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "input \"flake-utils/systems\" does not exist"
+        );
+
+        let cyclic = LOCK.replace(
+            r#""nixpkgs": "nixpkgs"
+      }"#,
+            r#""nixpkgs": [ "flake-utils", "nixpkgs" ]
+      }"#,
+        );
+        let lock = FlakeLock::parse(&cyclic).unwrap();
+        let e = lock.input(&["nixpkgs"]).unwrap_err();
+        assert_eq!(e.to_string(), "input \"nixpkgs\" follows itself");
+
+        let e = FlakeLock::parse(&LOCK.replace("\"version\": 7", "\"version\": 4")).unwrap_err();
+        assert_eq!(e.to_string(), "unsupported lock file version 4");
+    }
+}
@@ -0,0 +1,89 @@
+//! Computing hashes with Nix's own implementation, through `builtins.hashString` and `builtins.hashFile`.
+
+use crate::eval_state::EvalState;
+use anyhow::{Context as _, Result};
+use nix_util::hash::{Algo, Hash};
+use std::path::Path;
+
+impl EvalState {
+    /// Hash a string, like `builtins.hashString`.
+    pub fn hash_string(&self, algo: Algo, s: &str) -> Result<Hash> {
+        let f = self.builtin("hashString")?;
+        let algo_value = self.new_value_string(algo.name())?;
+        let s_value = self.new_value_string(s)?;
+        let r = self.call_multi(&f, &[algo_value, s_value])?;
+        Hash::parse_digest(algo, &self.require_string(&r)?)
+    }
+
+    /// Hash the contents of a file, like `builtins.hashFile` and `nix hash file`.
+    ///
+    /// Reading the file is subject to the evaluator's settings, such as [`pure_eval`](crate::eval_state::EvalStateBuilder::pure_eval).
+    pub fn hash_file(&self, algo: Algo, path: &Path) -> Result<Hash> {
+        let f = self.builtin("hashFile")?;
+        let algo_value = self.new_value_string(algo.name())?;
+        let path_value = self.new_value_path(path)?;
+        let r = self
+            .call_multi(&f, &[algo_value, path_value])
+            .with_context(|| format!("while hashing {}", path.display()))?;
+        Hash::parse_digest(algo, &self.require_string(&r)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    #[test]
+    fn hash_string() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let h = es.hash_string(Algo::Sha256, "hello").unwrap();
+            assert_eq!(
+                h.to_base16(),
+                "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+            );
+            assert_eq!(
+                h.to_base32(),
+                "094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic"
+            );
+            assert_eq!(
+                h.to_sri(),
+                "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+            );
+            let h = es.hash_string(Algo::Md5, "hello").unwrap();
+            assert_eq!(h.to_base16(), "5d41402abc4b2a76b9719d911017c592");
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn hash_file() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let dir =
+                std::env::temp_dir().join(format!("nix-expr-hash-file-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("hello.txt");
+            std::fs::write(&path, "hello").unwrap();
+            let h = es.hash_file(Algo::Sha256, &path).unwrap();
+            // nix hash file hello.txt
+            assert_eq!(
+                h.to_sri(),
+                "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+            );
+            assert_eq!(h, es.hash_string(Algo::Sha256, "hello").unwrap());
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+        .unwrap();
+    }
+}
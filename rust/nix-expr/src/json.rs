@@ -0,0 +1,390 @@
+//! Conversion between Nix values and JSON.
+
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use crate::value_path::ValuePath;
+use anyhow::{bail, Context as _, Result};
+
+/// How deeply nested a JSON document may be for [`EvalState::json_to_value`].
+///
+/// `serde_json` itself refuses to parse documents nested more than 128 levels deep by default.
+pub const JSON_TO_VALUE_MAX_DEPTH: usize = 1000;
+
+/// How deeply nested a value may be for [`EvalState::value_to_json`], so that cyclic values fail rather than overflow the stack.
+pub const VALUE_TO_JSON_MAX_DEPTH: usize = 1000;
+
+/// What [`EvalState::value_to_json`] does with strings that have a context.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StringContextPolicy {
+    /// Fail, so that store paths that may not have been built do not end up in the JSON unnoticed.
+    Reject,
+    /// Drop the context and keep only the string.
+    Strip,
+}
+
+impl EvalState {
+    /// Evaluate a value completely and convert it to JSON.
+    ///
+    /// Attributes are in sorted order. Paths become strings, without being copied to the store.
+    /// Like in `builtins.toJSON`, attribute sets with `__toString` become that string, and other attribute sets with an `outPath`, such as derivations, become their `outPath`.
+    /// Functions and external values can not be converted; the error names the attribute path of the offending value.
+    /// Values nested more than [`VALUE_TO_JSON_MAX_DEPTH`] levels deep, such as cyclic ones, are rejected.
+    pub fn value_to_json(
+        &self,
+        v: &Value,
+        context: StringContextPolicy,
+    ) -> Result<serde_json::Value> {
+        self.value_to_json_at(v, context, &ValuePath::default())
+    }
+    fn value_to_json_at(
+        &self,
+        v: &Value,
+        context: StringContextPolicy,
+        path: &ValuePath,
+    ) -> Result<serde_json::Value> {
+        if path.0.len() > VALUE_TO_JSON_MAX_DEPTH {
+            bail!(
+                "cannot convert {} to JSON: nested more than {} levels deep",
+                path,
+                VALUE_TO_JSON_MAX_DEPTH
+            );
+        }
+        let t = self
+            .value_type_forced(v)
+            .with_context(|| format!("while converting {} to JSON", path))?;
+        let json = match t {
+            ValueType::Null => serde_json::Value::Null,
+            ValueType::Bool => serde_json::Value::Bool(self.require_bool(v)?),
+            ValueType::Int => serde_json::Value::from(self.require_int(v)?),
+            ValueType::Float => {
+                let f = self.require_float(v)?;
+                match serde_json::Number::from_f64(f) {
+                    Some(n) => serde_json::Value::Number(n),
+                    None => bail!("cannot convert the float {} at {} to JSON", f, path),
+                }
+            }
+            ValueType::String => self.string_to_json(v, context, path)?,
+            ValueType::Path => {
+                let p = self.require_path(v)?;
+                match p.to_str() {
+                    Some(s) => serde_json::Value::String(s.to_string()),
+                    None => bail!(
+                        "cannot convert the path at {} to JSON: it is not valid UTF-8: {:?}",
+                        path,
+                        p
+                    ),
+                }
+            }
+            ValueType::List => {
+                let n = self.require_list_size(v)?;
+                let mut elems = Vec::with_capacity(n);
+                for i in 0..n {
+                    let elem_path = path.index(i);
+                    let elem = self
+                        .require_list_select_idx(v, i)
+                        .with_context(|| format!("while converting {} to JSON", elem_path))?;
+                    elems.push(self.value_to_json_at(&elem, context, &elem_path)?);
+                }
+                serde_json::Value::Array(elems)
+            }
+            ValueType::AttrSet if self.require_attrs_select_opt(v, "__toString")?.is_some() => {
+                let s = self
+                    .call(&self.builtin("toString")?, v)
+                    .with_context(|| format!("while converting {} to JSON", path))?;
+                self.string_to_json(&s, context, path)?
+            }
+            ValueType::AttrSet => {
+                if let Some(out_path) = self.require_attrs_select_opt(v, "outPath")? {
+                    return self.value_to_json_at(&out_path, context, &path.attr("outPath"));
+                }
+                let mut obj = serde_json::Map::new();
+                for name in self.require_attrs_names(v)? {
+                    let attr_path = path.attr(&name);
+                    let attr = self
+                        .require_attrs_select(v, &name)
+                        .with_context(|| format!("while converting {} to JSON", attr_path))?;
+                    let json = self.value_to_json_at(&attr, context, &attr_path)?;
+                    obj.insert(name, json);
+                }
+                serde_json::Value::Object(obj)
+            }
+            ValueType::Function | ValueType::External | ValueType::Thunk | ValueType::Unknown => {
+                bail!("cannot convert {} at {} to JSON", t.describe(), path)
+            }
+        };
+        Ok(json)
+    }
+    fn string_to_json(
+        &self,
+        v: &Value,
+        context: StringContextPolicy,
+        path: &ValuePath,
+    ) -> Result<serde_json::Value> {
+        let s = match context {
+            StringContextPolicy::Reject => self
+                .require_string_without_context(v)
+                .with_context(|| format!("while converting {} to JSON", path))?,
+            StringContextPolicy::Strip => self.require_string(v)?,
+        };
+        Ok(serde_json::Value::String(s))
+    }
+}
+
+impl EvalState {
+    /// Convert JSON to a Nix value, like `builtins.fromJSON`.
+    ///
+    /// Numbers written without a fraction or exponent become integers, and other numbers floats.
+    /// Documents nested more than [`JSON_TO_VALUE_MAX_DEPTH`] levels deep are rejected.
+    pub fn json_to_value(&self, j: &serde_json::Value) -> Result<Value> {
+        self.json_to_value_at(j, &ValuePath::default())
+    }
+    fn json_to_value_at(&self, j: &serde_json::Value, path: &ValuePath) -> Result<Value> {
+        if path.0.len() > JSON_TO_VALUE_MAX_DEPTH {
+            bail!(
+                "cannot convert JSON to a Nix value: nested more than {} levels deep",
+                JSON_TO_VALUE_MAX_DEPTH
+            );
+        }
+        match j {
+            serde_json::Value::Null => self.new_value_null(),
+            serde_json::Value::Bool(b) => self.new_value_bool(*b),
+            serde_json::Value::Number(n) => {
+                if let Some(i) = n.as_i64() {
+                    self.new_value_int(i)
+                } else if n.is_u64() {
+                    bail!(
+                        "the integer {} at {} does not fit in a Nix integer",
+                        n,
+                        path
+                    )
+                } else {
+                    // Always Some without the arbitrary_precision feature
+                    self.new_value_float(n.as_f64().unwrap())
+                }
+            }
+            serde_json::Value::String(s) => self
+                .new_value_string(s)
+                .with_context(|| format!("while converting {} from JSON", path)),
+            serde_json::Value::Array(elems) => {
+                let values = elems
+                    .iter()
+                    .enumerate()
+                    .map(|(i, elem)| self.json_to_value_at(elem, &path.index(i)))
+                    .collect::<Result<Vec<_>>>()?;
+                self.new_value_list(&values)
+            }
+            serde_json::Value::Object(obj) => {
+                let mut builder = self.new_attrset_builder(obj.len())?;
+                for (name, elem) in obj {
+                    let value = self.json_to_value_at(elem, &path.attr(name))?;
+                    builder
+                        .insert(name, &value)
+                        .with_context(|| format!("while converting {} from JSON", path))?;
+                }
+                builder.build()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, SourceName};
+    use nix_store::store::Store;
+    use serde_json::json;
+
+    #[test]
+    fn value_to_json_nested() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"{ z = null; a = { b = [ 1 2.5 true "x" ]; "c.d" = { }; }; p = /some/path; }"#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let j = es.value_to_json(&v, StringContextPolicy::Reject).unwrap();
+            assert_eq!(
+                j,
+                json!({ "z": null, "a": { "b": [1, 2.5, true, "x"], "c.d": {} }, "p": "/some/path" })
+            );
+            // Sorted order
+            let keys: Vec<&String> = j.as_object().unwrap().keys().collect();
+            assert_eq!(keys, vec!["a", "p", "z"]);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn value_to_json_string_context() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"{ drv = { out = "${derivation { name = "hello"; system = "dummy"; builder = "cmd.exe"; }}"; }; }"#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let e = es
+                .value_to_json(&v, StringContextPolicy::Reject)
+                .unwrap_err();
+            let e = format!("{:#}", e);
+            assert!(e.contains("`drv.out`"), "{}", e);
+            assert!(e.contains("unexpected string context"), "{}", e);
+            let j = es.value_to_json(&v, StringContextPolicy::Strip).unwrap();
+            assert!(j["drv"]["out"].as_str().unwrap().ends_with("-hello"));
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn value_to_json_derivation() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    r#"{
+                      drv = derivation { name = "hello"; system = "dummy"; builder = "cmd.exe"; };
+                      s = { __toString = self: "to${self.x}"; x = "String"; outPath = "ignored"; };
+                      o = { outPath = "/some/path"; };
+                    }"#,
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let j = es.value_to_json(&v, StringContextPolicy::Strip).unwrap();
+            assert!(j["drv"].as_str().unwrap().ends_with("-hello"), "{}", j);
+            assert_eq!(j["s"], json!("toString"));
+            assert_eq!(j["o"], json!("/some/path"));
+            let e = es
+                .value_to_json(&v, StringContextPolicy::Reject)
+                .unwrap_err();
+            let e = format!("{:#}", e);
+            assert!(e.contains("`drv.outPath`"), "{}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn value_to_json_too_deep() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("let x = { y = x; }; in x", SourceName::Synthetic("test"))
+                .unwrap();
+            let e = es
+                .value_to_json(&v, StringContextPolicy::Reject)
+                .unwrap_err();
+            assert!(e.to_string().contains("nested more than"), "{}", e);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn value_to_json_function() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "{ a = { b = [ 1 (x: x) ]; }; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            let e = es
+                .value_to_json(&v, StringContextPolicy::Reject)
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "cannot convert a function at `a.b[1]` to JSON"
+            );
+            let v = es
+                .eval_from_string("x: x", SourceName::Synthetic("test"))
+                .unwrap();
+            let e = es
+                .value_to_json(&v, StringContextPolicy::Reject)
+                .unwrap_err();
+            assert_eq!(
+                e.to_string(),
+                "cannot convert a function at the top level to JSON"
+            );
+        })
+        .unwrap();
+    }
+
+    fn round_trip(j: serde_json::Value) {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es.json_to_value(&j).unwrap();
+            let j2 = es.value_to_json(&v, StringContextPolicy::Reject).unwrap();
+            assert_eq!(j2, j);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn json_to_value_round_trip() {
+        round_trip(json!({
+            "a": [1, -2, 2.5, true, false, null, "x"],
+            "b": { "c": {} },
+            "": "empty name",
+            "with.dots and spaces": "unusual",
+            "${interpolation}": "not interpolated",
+            "ünïcødé": []
+        }));
+        round_trip(json!(42));
+        round_trip(json!("top level string"));
+    }
+
+    #[test]
+    fn json_to_value_deep() {
+        let mut j = json!("bottom");
+        for _ in 0..100 {
+            j = json!([j]);
+        }
+        round_trip(j);
+    }
+
+    #[test]
+    fn json_to_value_types() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let j: serde_json::Value = serde_json::from_str("[1, 1.0, 1e3]").unwrap();
+            let v = es.json_to_value(&j).unwrap();
+            let types: Vec<ValueType> = (0..3)
+                .map(|i| {
+                    es.value_type_forced(&es.require_list_select_idx(&v, i).unwrap())
+                        .unwrap()
+                })
+                .collect();
+            assert_eq!(
+                types,
+                vec![ValueType::Int, ValueType::Float, ValueType::Float]
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn json_to_value_too_deep() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let mut j = json!(1);
+            for _ in 0..(JSON_TO_VALUE_MAX_DEPTH + 1) {
+                j = json!([j]);
+            }
+            let e = es.json_to_value(&j).err().unwrap();
+            assert!(e.to_string().contains("nested more than"), "{}", e);
+            let e = es.json_to_value(&json!(u64::MAX)).err().unwrap();
+            assert!(e.to_string().contains("does not fit"), "{}", e);
+        })
+        .unwrap();
+    }
+}
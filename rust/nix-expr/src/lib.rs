@@ -1,2 +1,25 @@
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod attr_path;
+pub mod compare;
+pub mod de;
+pub mod diff;
 pub mod eval_state;
+pub mod eval_state_pool;
+pub mod external;
+pub mod fetch;
+pub mod flake;
+pub mod hash;
+pub mod json;
+mod oneshot;
+pub mod position;
+pub mod primop;
+pub mod print;
+pub mod realise;
+pub mod send_eval_state;
+pub mod ser;
+pub mod string_context;
 pub mod value;
+mod value_path;
+pub mod walk;
+pub mod xml;
@@ -0,0 +1,84 @@
+//! A future for the result of a call that runs on another thread.
+
+use anyhow::{format_err, Result};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+struct Shared<R> {
+    result: Option<Result<R>>,
+    waker: Option<Waker>,
+}
+
+/// Resolves to the result that the [`Completer`] provides.
+pub(crate) struct Call<R> {
+    shared: Arc<Mutex<Shared<R>>>,
+}
+impl<R> Call<R> {
+    pub(crate) fn ready(r: Result<R>) -> Self {
+        Call {
+            shared: Arc::new(Mutex::new(Shared {
+                result: Some(r),
+                waker: None,
+            })),
+        }
+    }
+}
+impl<R> Future for Call<R> {
+    type Output = Result<R>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<R>> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(r) => Poll::Ready(r),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Provides the result of a [`Call`]. When it is dropped without a result, e.g. because the thread panicked, the call fails.
+pub(crate) struct Completer<R> {
+    shared: Arc<Mutex<Shared<R>>>,
+    completed: bool,
+}
+impl<R> Completer<R> {
+    pub(crate) fn complete(mut self, r: Result<R>) {
+        self.set(r);
+    }
+    fn set(&mut self, r: Result<R>) {
+        self.completed = true;
+        let mut shared = self.shared.lock().unwrap();
+        shared.result = Some(r);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+impl<R> Drop for Completer<R> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.set(Err(format_err!(
+                "evaluation thread stopped during the call"
+            )));
+        }
+    }
+}
+
+pub(crate) fn call<R>() -> (Call<R>, Completer<R>) {
+    let shared = Arc::new(Mutex::new(Shared {
+        result: None,
+        waker: None,
+    }));
+    (
+        Call {
+            shared: shared.clone(),
+        },
+        Completer {
+            shared,
+            completed: false,
+        },
+    )
+}
@@ -0,0 +1,137 @@
+//! Where attributes are defined, for error messages.
+//!
+//! There is no `lambda_position` for functions: neither the C API nor `builtins` expose the position of a lambda, and `builtins.toXML` leaves it out.
+//! Recovering it from an error trace would require calling the function, which evaluates its body.
+//! For a function that is an attribute, such as a deployment, [`EvalState::attr_position`] of that attribute gives where it is defined.
+
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use anyhow::{bail, Result};
+use std::fmt;
+use std::path::PathBuf;
+
+/// A position in a Nix file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pos {
+    pub file: PathBuf,
+    pub line: u32,
+    pub column: u32,
+}
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.line, self.column)
+    }
+}
+
+impl EvalState {
+    /// Where the attribute `name` of an attribute set is defined, like `builtins.unsafeGetAttrPos`. The attribute's value is not evaluated.
+    ///
+    /// Attributes that don't come from a file, such as those of expressions from [`EvalState::eval_from_string`] or of `builtins.listToAttrs`, have no position, and return `None`.
+    /// Nix doesn't keep the [`SourceName`](crate::eval_state::SourceName) of `eval_from_string` for positions.
+    pub fn attr_position(&self, attrset: &Value, name: &str) -> Result<Option<Pos>> {
+        let t = self.value_type_forced(attrset)?;
+        if t != ValueType::AttrSet {
+            return Err(self.type_error("an attribute set", t, attrset));
+        }
+        let glue = self.glue(
+            "attrs: name: if attrs ? ${name} then builtins.unsafeGetAttrPos name attrs else false",
+        )?;
+        let name_value = self.new_value_string(name)?;
+        let pos = self.call_multi(&glue, &[attrset.clone(), name_value])?;
+        match self.value_type_forced(&pos)? {
+            ValueType::Bool => bail!("attribute `{}` not found", name),
+            ValueType::Null => Ok(None),
+            _ => {
+                let file = self.require_string(&self.require_attrs_select(&pos, "file")?)?;
+                let line = self.require_int(&self.require_attrs_select(&pos, "line")?)?;
+                let column = self.require_int(&self.require_attrs_select(&pos, "column")?)?;
+                Ok(Some(Pos {
+                    file: PathBuf::from(file),
+                    line: line.try_into()?,
+                    column: column.try_into()?,
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    const EXPR: &str = r#"{
+  web = throw "not evaluated";
+  db = {
+    port = 5432;
+  };
+  generated = builtins.listToAttrs [ { name = "x"; value = 1; } ];
+}
+"#;
+
+    fn with_file(f: impl FnOnce(&EvalState, &std::path::Path)) {
+        gc_registering_current_thread(|| {
+            let dir = std::env::temp_dir().join(format!(
+                "nix-expr-position-{}-{:?}",
+                std::process::id(),
+                std::thread::current().id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            let path = dir.join("prod.nix");
+            std::fs::write(&path, EXPR).unwrap();
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            f(&es, &path);
+            std::fs::remove_dir_all(&dir).unwrap();
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn attr_position() {
+        with_file(|es, path| {
+            let v = es.eval_from_file(path).unwrap();
+            let pos = es.attr_position(&v, "web").unwrap().unwrap();
+            assert_eq!(pos.file, path);
+            assert_eq!((pos.line, pos.column), (2, 3));
+            assert_eq!(pos.to_string(), format!("{}:2:3", path.display()));
+            let db = es.require_attrs_select(&v, "db").unwrap();
+            let pos = es.attr_position(&db, "port").unwrap().unwrap();
+            assert_eq!((pos.line, pos.column), (4, 5));
+
+            let generated = es.require_attrs_select(&v, "generated").unwrap();
+            assert_eq!(es.attr_position(&generated, "x").unwrap(), None);
+            let e = es.attr_position(&v, "missing").unwrap_err();
+            assert_eq!(e.to_string(), "attribute `missing` not found");
+        });
+    }
+
+    #[test]
+    fn attr_position_from_string() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(EXPR, SourceName::File("/fake/prod.nix".into()))
+                .unwrap();
+            assert_eq!(es.attr_position(&v, "web").unwrap(), None);
+            let builtins = es
+                .eval_from_string("builtins", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.attr_position(&builtins, "map").unwrap(), None);
+            let i = es.new_value_int(1).unwrap();
+            assert_eq!(
+                es.attr_position(&i, "x").unwrap_err().to_string(),
+                "expected an attribute set, but got a Int: 1"
+            );
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,239 @@
+//! Builtin functions implemented in Rust.
+
+use crate::eval_state::{init, EvalState};
+use crate::value::Value;
+use anyhow::{bail, Context as _, Result};
+use nix_c_raw as raw;
+use nix_util::context::Context;
+use nix_util::error_site;
+use std::any::Any;
+use std::ffi::{c_char, c_int, c_void, CString};
+use std::panic::{self, AssertUnwindSafe};
+
+type PrimOpFn = dyn Fn(&EvalState, &[Value]) -> Result<Value> + Send + Sync;
+
+struct PrimOpData {
+    name: String,
+    arity: usize,
+    f: Box<PrimOpFn>,
+}
+
+impl EvalState {
+    /// Add a builtin function, available as `name` and `builtins.name`, that calls `f` with its `arity` arguments.
+    ///
+    /// The primop is only available in `EvalState`s that are created after registering it; existing ones are not affected.
+    /// Registered primops live until the process exits, and are shared by the `EvalState`s of all threads, so `f` must be `Send` and `Sync`.
+    ///
+    /// When `f` returns an error, the primop call throws it as with `builtins.throw`, so that `builtins.tryEval` can catch it.
+    /// When `f` panics, the evaluation fails with the panic message.
+    pub fn register_primop(
+        name: &str,
+        arity: usize,
+        doc: &str,
+        f: impl Fn(&EvalState, &[Value]) -> Result<Value> + Send + Sync + 'static,
+    ) -> Result<()> {
+        init()?;
+        if arity == 0 {
+            bail!(
+                "register_primop: primop {} must take at least one argument",
+                name
+            );
+        }
+        let context = Context::new();
+        let c_name = CString::new(name)
+            .with_context(|| format!("register_primop: name contains null byte: {:?}", name))?;
+        let c_doc = CString::new(doc)
+            .with_context(|| format!("register_primop: doc of {} contains null byte", name))?;
+        let arg_names = (1..=arity)
+            .map(|i| CString::new(format!("arg{}", i)).unwrap())
+            .collect::<Vec<_>>();
+        let mut arg_ptrs: Vec<*const c_char> = arg_names.iter().map(|s| s.as_ptr()).collect();
+        arg_ptrs.push(std::ptr::null());
+
+        let data = Box::into_raw(Box::new(PrimOpData {
+            name: name.to_string(),
+            arity,
+            f: Box::new(f),
+        }));
+        let primop = unsafe {
+            raw::nix_alloc_primop(
+                context.ptr(),
+                Some(call_primop),
+                arity as c_int,
+                c_name.as_ptr(),
+                arg_ptrs.as_mut_ptr(),
+                // Nix keeps the doc pointer rather than a copy
+                c_doc.into_raw(),
+                data as *mut c_void,
+            )
+        };
+        if let Err(e) = context.check_err(error_site!("nix_alloc_primop")) {
+            drop(unsafe { Box::from_raw(data) });
+            return Err(e.into());
+        }
+        unsafe {
+            raw::nix_register_primop(context.ptr(), primop);
+        }
+        context.check_err(error_site!("nix_register_primop"))?;
+        Ok(())
+    }
+
+    /// A value that throws `message` when forced, like `builtins.throw message`.
+    fn new_value_throw(&self, message: &str) -> Result<Value> {
+        let message = self.new_value_string(message)?;
+        self.new_value_apply(&self.builtin("throw")?, &message)
+    }
+}
+
+unsafe extern "C" fn call_primop(
+    user_data: *mut c_void,
+    context: *mut raw::nix_c_context,
+    state: *mut raw::EvalState,
+    args: *mut *mut raw::Value,
+    ret: *mut raw::Value,
+) {
+    let data = &*(user_data as *const PrimOpData);
+    // Unwinding into Nix would abort the process
+    let r = panic::catch_unwind(AssertUnwindSafe(|| {
+        EvalState::with_borrowed(state, |es| -> Result<()> {
+            let args = (0..data.arity)
+                .map(|i| Value::new_borrowed(*args.add(i), es))
+                .collect::<Vec<_>>();
+            let v = match (data.f)(es, &args) {
+                Ok(v) => v,
+                Err(e) => es.new_value_throw(&format!("{}: {:#}", data.name, e))?,
+            };
+            let copy_context = Context::new();
+            raw::nix_copy_value(copy_context.ptr(), ret, v.raw_ptr());
+            copy_context.check_err(error_site!("nix_copy_value"))?;
+            Ok(())
+        })
+    }));
+    let r = match r {
+        Ok(r) => r.and_then(|r| r),
+        Err(payload) => Err(anyhow::format_err!(
+            "panicked: {}",
+            panic_message(&*payload)
+        )),
+    };
+    if let Err(e) = r {
+        // Not catchable by tryEval, but these are bugs rather than errors of the primop
+        let msg = CString::new(format!("{}: {:#}", data.name, e).replace('\0', "\\0")).unwrap();
+        raw::nix_set_err_msg(context, raw::NIX_ERR_UNKNOWN, msg.as_ptr());
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+        EvalState::register_primop("rustAdd", 2, "Add two integers.", |es, args| {
+            let a = es.require_int(&args[0])?;
+            let b = es.require_int(&args[1])?;
+            es.new_value_int(a + b)
+        })
+        .unwrap();
+        EvalState::register_primop("rustFail", 1, "Always fails.", |_es, _args| {
+            bail!("failing on purpose")
+        })
+        .unwrap();
+        EvalState::register_primop("rustPanic", 1, "Always panics.", |_es, _args| {
+            panic!("panicking on purpose")
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn register_primop() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string("builtins.rustAdd 20 22", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 42);
+            let v = es
+                .eval_from_string("rustAdd 1 (rustAdd 2 3)", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 6);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn register_primop_type_error() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let e = match es
+                .eval_from_string("builtins.rustAdd 20 true", SourceName::Synthetic("test"))
+            {
+                Ok(_) => panic!("expected an error"),
+                Err(e) => e,
+            };
+            let msg = format!("{:#}", e);
+            assert!(
+                msg.contains("rustAdd: expected an int, but got a Bool: true"),
+                "{}",
+                msg
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn register_primop_error_is_catchable() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "(builtins.tryEval (builtins.rustFail null)).success",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            assert!(!es.require_bool(&v).unwrap());
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn register_primop_panic_is_an_error() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let e = es
+                .eval_from_string("builtins.rustPanic null", SourceName::Synthetic("test"))
+                .err()
+                .unwrap();
+            let msg = format!("{:#}", e);
+            assert!(
+                msg.contains("rustPanic: panicked: panicking on purpose"),
+                "{}",
+                msg
+            );
+            // The state is still usable
+            let v = es
+                .eval_from_string("builtins.rustAdd 1 2", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(es.require_int(&v).unwrap(), 3);
+        })
+        .unwrap();
+    }
+}
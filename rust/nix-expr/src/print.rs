@@ -0,0 +1,463 @@
+//! Printing values in Nix syntax, for messages.
+
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use crate::value_path::is_simple_attr_name;
+use anyhow::Result;
+use nix_c_raw as raw;
+
+/// Options for [`EvalState::value_to_display_string`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PrintOptions {
+    /// How many levels of nested attribute sets and lists to print the contents of; deeper ones print as `{ ... }` and `[ ... ]`.
+    pub max_depth: usize,
+    /// How many attributes of an attribute set to print, in order; the rest print as `«N attributes elided»`.
+    pub max_attrs: usize,
+    /// How many elements of a list to print; the rest print as `«N items elided»`.
+    pub max_list_items: usize,
+    /// How many bytes of a string to print; the rest print as `«N bytes elided»`.
+    ///
+    /// Strings that refer to store paths are marked `«with context»`.
+    pub max_string_length: usize,
+    /// Evaluate values in order to print them.
+    ///
+    /// Otherwise a thunk prints as `«thunk»`. The C API evaluates attribute values and list elements when they are accessed, so without forcing those are not accessed at all, and print as `…`.
+    pub force: bool,
+}
+impl Default for PrintOptions {
+    fn default() -> Self {
+        PrintOptions {
+            max_depth: 10,
+            max_attrs: 10,
+            max_list_items: 10,
+            max_string_length: 1024,
+            force: true,
+        }
+    }
+}
+impl PrintOptions {
+    /// For showing a value in an error message: short, and without evaluating anything.
+    pub fn for_errors() -> Self {
+        PrintOptions {
+            max_depth: 1,
+            max_attrs: 10,
+            max_list_items: 10,
+            max_string_length: 80,
+            force: false,
+        }
+    }
+    /// For debugging and log lines: a bounded preview that does not evaluate anything.
+    pub fn preview() -> Self {
+        PrintOptions {
+            max_depth: 2,
+            max_attrs: 8,
+            max_list_items: 8,
+            max_string_length: 64,
+            force: false,
+        }
+    }
+}
+
+impl EvalState {
+    /// A short rendering of a value for log lines, see [`PrintOptions::preview`]. Evaluates nothing, and does not fail.
+    pub fn value_summary(&self, v: &Value) -> String {
+        self.value_to_display_string(v, PrintOptions::preview())
+            .unwrap_or_else(|_| "«error»".to_string())
+    }
+    /// Render a value in Nix syntax, e.g. `{ a = 1; b = [ "x" ]; }`, like Nix prints values in the REPL and in errors.
+    ///
+    /// Printing does not fail: values that fail to evaluate print as `«error»`.
+    /// A value that contains itself prints as `«repeated»` where it recurs, so printing terminates.
+    pub fn value_to_display_string(&self, v: &Value, opts: PrintOptions) -> Result<String> {
+        let mut printer = Printer {
+            es: self,
+            opts,
+            out: String::new(),
+            ancestors: Vec::new(),
+        };
+        printer.print(v, 0);
+        Ok(printer.out)
+    }
+}
+
+impl Value {
+    /// Format the value with [`EvalState::value_summary`], e.g. `tracing::debug!(value = ?v.debug_with(&es))`.
+    pub fn debug_with<'a>(&'a self, es: &'a EvalState) -> ValueDebug<'a> {
+        ValueDebug { value: self, es }
+    }
+}
+
+/// A [`Value`] with the [`EvalState`] to print it with. See [`Value::debug_with`].
+pub struct ValueDebug<'a> {
+    value: &'a Value,
+    es: &'a EvalState,
+}
+impl std::fmt::Display for ValueDebug<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.es.value_summary(self.value))
+    }
+}
+impl std::fmt::Debug for ValueDebug<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.es.value_summary(self.value))
+    }
+}
+
+struct Printer<'a> {
+    es: &'a EvalState,
+    opts: PrintOptions,
+    out: String,
+    /// The attribute sets and lists being printed, to detect cycles.
+    ancestors: Vec<*mut raw::Value>,
+}
+impl Printer<'_> {
+    fn print(&mut self, v: &Value, depth: usize) {
+        if !self.opts.force && self.es.value_is_thunk(v).unwrap_or(false) {
+            self.out.push_str("«thunk»");
+            return;
+        }
+        let t = match self.es.value_type_forced(v) {
+            Ok(t) => t,
+            Err(_) => {
+                self.out.push_str("«error»");
+                return;
+            }
+        };
+        match t {
+            ValueType::Null => self.out.push_str("null"),
+            ValueType::Bool => match self.es.require_bool(v) {
+                Ok(b) => self.out.push_str(if b { "true" } else { "false" }),
+                Err(_) => self.out.push_str("«error»"),
+            },
+            ValueType::Int => match self.es.require_int(v) {
+                Ok(i) => self.out.push_str(&i.to_string()),
+                Err(_) => self.out.push_str("«error»"),
+            },
+            ValueType::Float => match self.es.require_float(v) {
+                Ok(f) => self.out.push_str(&f.to_string()),
+                Err(_) => self.out.push_str("«error»"),
+            },
+            ValueType::String => {
+                match self
+                    .es
+                    .require_string_lossy_truncated(v, self.opts.max_string_length)
+                {
+                    Ok((s, len)) => {
+                        self.out.push_str(&quote_string(&s));
+                        if len > s.len() {
+                            self.out
+                                .push_str(&format!(" «{} bytes elided»", len - s.len()));
+                        }
+                        if self.es.string_context(v).is_ok_and(|c| !c.is_empty()) {
+                            self.out.push_str(" «with context»");
+                        }
+                    }
+                    Err(_) => self.out.push_str("«error»"),
+                }
+            }
+            ValueType::Path => match self.es.require_path(v) {
+                Ok(p) => self.out.push_str(&p.to_string_lossy()),
+                Err(_) => self.out.push_str("«error»"),
+            },
+            ValueType::AttrSet => self.print_attrs(v, depth),
+            ValueType::List => self.print_list(v, depth),
+            ValueType::Function => self.out.push_str("«lambda»"),
+            ValueType::External => self.out.push_str("«external»"),
+            ValueType::Thunk => self.out.push_str("«thunk»"),
+            ValueType::Unknown => self.out.push_str("«unknown»"),
+        }
+    }
+
+    fn print_attrs(&mut self, v: &Value, depth: usize) {
+        if self.ancestors.contains(&v.raw_ptr()) {
+            self.out.push_str("«repeated»");
+            return;
+        }
+        let names = match self.es.require_attrs_names(v) {
+            Ok(names) => names,
+            Err(_) => {
+                self.out.push_str("«error»");
+                return;
+            }
+        };
+        if names.is_empty() {
+            self.out.push_str("{ }");
+            return;
+        }
+        if self.opts.force {
+            if let Some(drv_path) = self.derivation_path(v, &names) {
+                self.out.push_str(&format!("«derivation {}»", drv_path));
+                return;
+            }
+        }
+        if depth >= self.opts.max_depth {
+            self.out.push_str("{ ... }");
+            return;
+        }
+        self.ancestors.push(v.raw_ptr());
+        self.out.push_str("{ ");
+        for name in names.iter().take(self.opts.max_attrs) {
+            self.out.push_str(&quote_attr_name(name));
+            self.out.push_str(" = ");
+            if self.opts.force {
+                // The stored value, so that cycles are detected
+                match self.es.require_attrs_select_forced(v, name) {
+                    Ok(value) => self.print(&value, depth + 1),
+                    Err(_) => self.out.push_str("«error»"),
+                }
+            } else {
+                self.out.push('…');
+            }
+            self.out.push_str("; ");
+        }
+        if names.len() > self.opts.max_attrs {
+            self.out.push_str(&format!(
+                "«{} attributes elided» ",
+                names.len() - self.opts.max_attrs
+            ));
+        }
+        self.out.push('}');
+        self.ancestors.pop();
+    }
+
+    /// The `drvPath` of a derivation, which Nix prints instead of its attributes.
+    fn derivation_path(&self, v: &Value, names: &[String]) -> Option<String> {
+        if !names.iter().any(|n| n == "type") || !names.iter().any(|n| n == "drvPath") {
+            return None;
+        }
+        let t = self.es.require_attrs_select(v, "type").ok()?;
+        if self.es.require_string(&t).ok()? != "derivation" {
+            return None;
+        }
+        let drv_path = self.es.require_attrs_select(v, "drvPath").ok()?;
+        self.es.require_string(&drv_path).ok()
+    }
+
+    fn print_list(&mut self, v: &Value, depth: usize) {
+        if self.ancestors.contains(&v.raw_ptr()) {
+            self.out.push_str("«repeated»");
+            return;
+        }
+        let len = match self.es.require_list_size(v) {
+            Ok(len) => len,
+            Err(_) => {
+                self.out.push_str("«error»");
+                return;
+            }
+        };
+        if len == 0 {
+            self.out.push_str("[ ]");
+            return;
+        }
+        if depth >= self.opts.max_depth {
+            self.out.push_str("[ ... ]");
+            return;
+        }
+        self.ancestors.push(v.raw_ptr());
+        self.out.push_str("[ ");
+        for i in 0..len.min(self.opts.max_list_items) {
+            if self.opts.force {
+                match self.es.require_list_select_idx_forced(v, i) {
+                    Ok(value) => self.print(&value, depth + 1),
+                    Err(_) => self.out.push_str("«error»"),
+                }
+            } else {
+                self.out.push('…');
+            }
+            self.out.push(' ');
+        }
+        if len > self.opts.max_list_items {
+            self.out.push_str(&format!(
+                "«{} items elided» ",
+                len - self.opts.max_list_items
+            ));
+        }
+        self.out.push(']');
+        self.ancestors.pop();
+    }
+}
+
+/// A string literal that evaluates to `s`.
+fn quote_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn quote_attr_name(name: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with",
+    ];
+    if is_simple_attr_name(name) && !KEYWORDS.contains(&name) {
+        name.to_string()
+    } else {
+        quote_string(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, SourceName};
+    use nix_store::store::Store;
+
+    #[test]
+    fn quote() {
+        assert_eq!(quote_string("plain"), "\"plain\"");
+        assert_eq!(
+            quote_string("a \"b\" \\ ${c} $d\n\t"),
+            "\"a \\\"b\\\" \\\\ \\${c} $d\\n\\t\""
+        );
+        assert_eq!(quote_attr_name("foo-bar'"), "foo-bar'");
+        assert_eq!(quote_attr_name("a.b"), "\"a.b\"");
+        assert_eq!(quote_attr_name("let"), "\"let\"");
+        assert_eq!(quote_attr_name(""), "\"\"");
+    }
+
+    fn display(expr: &str, opts: PrintOptions) -> String {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(expr, SourceName::Synthetic("test"))
+                .unwrap();
+            es.value_to_display_string(&v, opts).unwrap()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn display_scalars() {
+        let d = PrintOptions::default();
+        assert_eq!(display("null", d), "null");
+        assert_eq!(display("true", d), "true");
+        assert_eq!(display("-3", d), "-3");
+        assert_eq!(display("2.5", d), "2.5");
+        assert_eq!(display(r#""a\"${"b"}\n""#, d), r#""a\"b\n""#);
+        assert_eq!(display("/some/path", d), "/some/path");
+        assert_eq!(display("x: x", d), "«lambda»");
+        assert_eq!(display("builtins.map", d), "«lambda»");
+    }
+
+    #[test]
+    fn display_containers() {
+        let d = PrintOptions::default();
+        assert_eq!(display("{ }", d), "{ }");
+        assert_eq!(display("[ ]", d), "[ ]");
+        assert_eq!(
+            display(r#"{ b = [ 1 2 ]; a = { c = "x"; }; "d.e" = null; }"#, d),
+            r#"{ a = { c = "x"; }; b = [ 1 2 ]; "d.e" = null; }"#
+        );
+    }
+
+    #[test]
+    fn display_limits() {
+        let opts = PrintOptions {
+            max_depth: 1,
+            max_attrs: 2,
+            max_list_items: 3,
+            max_string_length: 4,
+            force: true,
+        };
+        assert_eq!(
+            display("{ a = { b = 1; }; l = [ [ 1 ] ]; }", opts),
+            "{ a = { ... }; l = [ ... ]; }"
+        );
+        assert_eq!(
+            display("{ a = 1; b = 2; c = 3; }", opts),
+            "{ a = 1; b = 2; «1 attributes elided» }"
+        );
+        assert_eq!(display("[ 1 2 3 4 5 ]", opts), "[ 1 2 3 «2 items elided» ]");
+        assert_eq!(display(r#""abcdefg""#, opts), r#""abcd" «3 bytes elided»"#);
+    }
+
+    #[test]
+    fn display_errors() {
+        let d = PrintOptions::default();
+        assert_eq!(
+            display(r#"{ a = throw "no"; b = 1; }"#, d),
+            "{ a = «error»; b = 1; }"
+        );
+        assert_eq!(display(r#"[ (abort "no") 1 ]"#, d), "[ «error» 1 ]");
+    }
+
+    #[test]
+    fn display_without_forcing() {
+        assert_eq!(
+            display(
+                r#"{ a = throw "no"; b = [ 1 ]; }"#,
+                PrintOptions::for_errors()
+            ),
+            "{ a = …; b = …; }"
+        );
+        assert_eq!(
+            display("[ 1 (throw \"no\") ]", PrintOptions::for_errors()),
+            "[ … … ]"
+        );
+        assert_eq!(display("true", PrintOptions::for_errors()), "true");
+    }
+
+    #[test]
+    fn display_cycle() {
+        let s = display(
+            "let x = { inherit x; y = 1; }; in x",
+            PrintOptions::default(),
+        );
+        // The top-level value is a copy of `x`, so the cycle may be detected one level down
+        assert!(s.starts_with("{ x = "), "{}", s);
+        assert!(s.contains("x = «repeated»; y = 1; }"), "{}", s);
+    }
+
+    #[test]
+    fn display_derivation() {
+        let s = display(
+            r#"{ d = derivation { name = "x"; builder = "/bin/sh"; system = "x86_64-linux"; }; }"#,
+            PrintOptions::default(),
+        );
+        assert!(s.starts_with("{ d = «derivation /"), "{}", s);
+        assert!(s.ends_with("-x.drv»; }"), "{}", s);
+    }
+
+    #[test]
+    fn value_summary_preview() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let eval = |expr: &str| {
+                es.eval_from_string(expr, SourceName::Synthetic("test"))
+                    .unwrap()
+            };
+            let v = eval("builtins.genList (x: x) 10001");
+            assert_eq!(
+                es.value_summary(&v),
+                "[ … … … … … … … … «9993 items elided» ]"
+            );
+            let v = eval(r#"{ a = throw "not evaluated"; b = 1; }"#);
+            assert_eq!(es.value_summary(&v), "{ a = …; b = …; }");
+            let v = eval(
+                r#""${derivation { name = "x"; builder = "/bin/sh"; system = "x86_64-linux"; }}""#,
+            );
+            let s = es.value_summary(&v);
+            assert!(s.starts_with("\"/"), "{}", s);
+            assert!(s.ends_with("-x\" «with context»"), "{}", s);
+            let v = eval(r#""plain""#);
+            assert_eq!(es.value_summary(&v), "\"plain\"");
+            assert_eq!(format!("{}", v.debug_with(&es)), "\"plain\"");
+            assert_eq!(format!("{:?}", v.debug_with(&es)), "\"plain\"");
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! Realising many strings at once, e.g. the configurations of all the machines in a deployment.
+
+use crate::eval_state::{EvalState, RealisedString};
+use crate::value::Value;
+use anyhow::Result;
+use nix_util::error::{strip_ansi_escapes, NixError};
+use nix_util::settings;
+use std::fmt;
+
+/// Options for [`EvalState::realise_many`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RealiseOpts {
+    /// How many builds to run in parallel, the `max-jobs` setting. By default, the configured value applies.
+    pub max_jobs: Option<usize>,
+    /// Keep building after a build fails, the `keep-going` setting. Without it, the values after a failure are not realised.
+    pub keep_going: bool,
+}
+
+/// Why [`EvalState::realise_many`] did not realise a value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BuildFailure {
+    /// A build failed.
+    Failed {
+        /// The derivation that failed to build, if Nix named one.
+        drv_path: Option<String>,
+        /// The last lines of its build log that Nix reported, without the `> ` prefix.
+        log_tail: Vec<String>,
+        /// The error as Nix reported it, without highlighting.
+        message: String,
+    },
+    /// Not attempted, because an earlier value failed and [`RealiseOpts::keep_going`] was not set.
+    Cancelled,
+}
+
+impl fmt::Display for BuildFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildFailure::Failed { message, .. } => f.write_str(message),
+            BuildFailure::Cancelled => f.write_str("cancelled after an earlier build failed"),
+        }
+    }
+}
+
+impl std::error::Error for BuildFailure {}
+
+impl BuildFailure {
+    fn from_nix_error(e: &NixError) -> BuildFailure {
+        let message = strip_ansi_escapes(e.message());
+        BuildFailure::Failed {
+            drv_path: parse_failed_drv(&message),
+            log_tail: parse_log_tail(&message),
+            message,
+        }
+    }
+}
+
+impl EvalState {
+    /// Realise strings, like [`EvalState::realise_string`] without `is_ifd`, and return the result for each of them in the same order.
+    ///
+    /// A failing build is reported as the [`BuildFailure`] of its value, rather than as an error of the whole call, which is reserved for e.g. values that are not strings.
+    /// The C API realises one path at a time, so the values are realised in turn; `max_jobs` still applies to the builds of each of them.
+    /// The `max-jobs` and `keep-going` settings are global, and are restored afterwards.
+    pub fn realise_many(
+        &self,
+        values: &[&Value],
+        opts: RealiseOpts,
+    ) -> Result<Vec<Result<RealisedString, BuildFailure>>> {
+        let mut overrides = vec![(
+            "keep-going",
+            if opts.keep_going { "true" } else { "false" }.to_string(),
+        )];
+        if let Some(max_jobs) = opts.max_jobs {
+            overrides.push(("max-jobs", max_jobs.to_string()));
+        }
+        let saved = overrides
+            .iter()
+            .map(|(key, _)| Ok((*key, settings::get(key)?)))
+            .collect::<Result<Vec<_>>>()?;
+        for (key, value) in &overrides {
+            settings::set(key, value)?;
+        }
+        let r = self.realise_each(values, opts.keep_going);
+        for (key, value) in &saved {
+            settings::set(key, value)?;
+        }
+        r
+    }
+
+    fn realise_each(
+        &self,
+        values: &[&Value],
+        keep_going: bool,
+    ) -> Result<Vec<Result<RealisedString, BuildFailure>>> {
+        let mut results = Vec::with_capacity(values.len());
+        let mut failed = false;
+        for v in values {
+            if failed && !keep_going {
+                results.push(Err(BuildFailure::Cancelled));
+                continue;
+            }
+            // Evaluation errors are not build failures
+            self.force(v)?;
+            match self.realise_string(v, false) {
+                Ok(rs) => results.push(Ok(rs)),
+                Err(e) => match e.downcast_ref::<NixError>() {
+                    Some(nix_error @ NixError::Exception { .. }) => {
+                        failed = true;
+                        results.push(Err(BuildFailure::from_nix_error(nix_error)));
+                    }
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// The first derivation that the message quotes, e.g. in `builder for '/nix/store/…-x.drv' failed with exit code 1`.
+fn parse_failed_drv(message: &str) -> Option<String> {
+    message
+        .split('\'')
+        .skip(1)
+        .step_by(2)
+        .find(|quoted| quoted.starts_with('/') && quoted.ends_with(".drv"))
+        .map(|drv| drv.to_string())
+}
+
+/// The lines after `last 10 log lines:`, which Nix prefixes with `> `.
+fn parse_log_tail(message: &str) -> Vec<String> {
+    message
+        .lines()
+        .skip_while(|line| !line.to_lowercase().contains("log lines:"))
+        .skip(1)
+        .map(|line| line.trim_start())
+        .map_while(|line| line.strip_prefix('>'))
+        .map(|line| line.strip_prefix(' ').unwrap_or(line).to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    #[test]
+    fn parse_build_failure() {
+        let message = "error: builder for '/nix/store/abc-fail.drv' failed with exit code 1;
+       last 2 log lines:
+       > first
+       >
+       For full logs, run 'nix log /nix/store/abc-fail.drv'.";
+        assert_eq!(
+            parse_failed_drv(message).as_deref(),
+            Some("/nix/store/abc-fail.drv")
+        );
+        assert_eq!(parse_log_tail(message), ["first", ""]);
+        let message = "error: Cannot build '/nix/store/abc-fail.drv'.
+       Reason: builder failed with exit code 1.
+       Last 1 log lines:
+       > oops";
+        assert_eq!(
+            parse_failed_drv(message).as_deref(),
+            Some("/nix/store/abc-fail.drv")
+        );
+        assert_eq!(parse_log_tail(message), ["oops"]);
+        assert_eq!(parse_failed_drv("error: 'x' is not a 'drv'"), None);
+        assert!(parse_log_tail("error: boom").is_empty());
+    }
+
+    const DRVS: &str = r#"
+      let drv = name: script: "${derivation {
+        inherit name;
+        system = builtins.currentSystem;
+        builder = "/bin/sh";
+        args = [ "-c" script ];
+      }}";
+      in [
+        (drv "nixops4-test-realise-fail" "echo failing on purpose >&2; exit 1")
+        (drv "nixops4-test-realise-ok" "echo ok > $out")
+      ]
+    "#;
+
+    #[test]
+    fn realise_many() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(DRVS, SourceName::Synthetic("test"))
+                .unwrap();
+            let fail = es.require_list_select_idx(&v, 0).unwrap();
+            let ok = es.require_list_select_idx(&v, 1).unwrap();
+            let keep_going = settings::get("keep-going").unwrap();
+
+            let rs = es
+                .realise_many(
+                    &[&fail, &ok],
+                    RealiseOpts {
+                        max_jobs: Some(2),
+                        keep_going: true,
+                    },
+                )
+                .unwrap();
+            assert_eq!(rs.len(), 2);
+            match &rs[0] {
+                Err(BuildFailure::Failed {
+                    drv_path, log_tail, ..
+                }) => {
+                    let drv_path = drv_path.as_deref().unwrap();
+                    assert!(
+                        drv_path.ends_with("-nixops4-test-realise-fail.drv"),
+                        "{}",
+                        drv_path
+                    );
+                    assert!(
+                        log_tail.iter().any(|l| l == "failing on purpose"),
+                        "{:?}",
+                        log_tail
+                    );
+                }
+                r => panic!("expected a build failure, got {:?}", r.as_ref().err()),
+            }
+            let out = rs[1].as_ref().unwrap();
+            assert_eq!(std::fs::read_to_string(&out.string).unwrap(), "ok\n");
+
+            let rs = es
+                .realise_many(&[&fail, &ok], RealiseOpts::default())
+                .unwrap();
+            assert!(matches!(rs[0], Err(BuildFailure::Failed { .. })));
+            assert!(matches!(rs[1], Err(BuildFailure::Cancelled)));
+
+            assert_eq!(settings::get("keep-going").unwrap(), keep_going);
+
+            let s = es.new_value_int(1).unwrap();
+            assert!(es.realise_many(&[&s], RealiseOpts::default()).is_err());
+        })
+        .unwrap();
+    }
+}
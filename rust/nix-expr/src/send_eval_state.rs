@@ -0,0 +1,346 @@
+//! An [`EvalState`] that can be shared between threads, by running it on a thread of its own.
+
+use crate::eval_state::{gc_registering_current_thread, EvalState, SourceName};
+use crate::value::{Value, ValueType};
+use anyhow::{bail, format_err, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(0);
+
+enum Command {
+    Run(Box<dyn FnOnce(&mut Worker) + Send>),
+    DropValue(u64),
+    Shutdown,
+}
+
+/// The state of the evaluation thread.
+pub struct Worker {
+    es: EvalState,
+    owner: u64,
+    values: HashMap<u64, Value>,
+    next_id: u64,
+}
+impl Worker {
+    pub fn eval_state(&self) -> &EvalState {
+        &self.es
+    }
+    /// Look up a value that was handed out as a [`SendValue`].
+    pub fn get(&self, v: &SendValueRef) -> Result<&Value> {
+        if v.owner != self.owner {
+            bail!("value belongs to a different SendEvalState");
+        }
+        match self.values.get(&v.id) {
+            Some(v) => Ok(v),
+            None => bail!("value was already dropped"),
+        }
+    }
+    /// The number of values that are handed out, for checking that they are released.
+    #[cfg(test)]
+    pub(crate) fn value_count(&self) -> usize {
+        self.values.len()
+    }
+    pub(crate) fn insert(&mut self, v: Value) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.values.insert(id, v);
+        id
+    }
+}
+
+/// A way to refer to a [`SendValue`] inside [`SendEvalState::with`], where the value itself can't go.
+#[derive(Clone, Copy, Debug)]
+pub struct SendValueRef {
+    owner: u64,
+    id: u64,
+}
+
+/// A [`Value`] that lives on the thread of a [`SendEvalState`]. Dropping it releases the value.
+pub struct SendValue {
+    r: SendValueRef,
+    sender: mpsc::Sender<Command>,
+}
+impl SendValue {
+    pub fn value_ref(&self) -> SendValueRef {
+        self.r
+    }
+}
+impl Drop for SendValue {
+    fn drop(&mut self) {
+        // Fails when the thread has stopped, which has dropped all values already
+        let _ = self.sender.send(Command::DropValue(self.r.id));
+    }
+}
+
+/// Runs an [`EvalState`] on a dedicated thread, which is registered with the garbage collector, and forwards method calls to it.
+///
+/// `EvalState` and [`Value`] hold pointers into the Nix heap, so they stay on that thread; other threads get [`SendValue`] handles instead.
+/// Calls are handled one at a time, in the order they arrive.
+/// Dropping the `SendEvalState` waits for the current call to finish, then stops the thread.
+pub struct SendEvalState {
+    owner: u64,
+    sender: mpsc::Sender<Command>,
+    thread: Option<JoinHandle<()>>,
+}
+impl SendEvalState {
+    /// Start the thread, and create its `EvalState` there with `make`, since an `EvalState` can't be sent to it.
+    pub fn spawn(make: impl FnOnce() -> Result<EvalState> + Send + 'static) -> Result<Self> {
+        let owner = NEXT_OWNER.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel::<Command>();
+        let (ready_sender, ready_receiver) = mpsc::channel::<Result<()>>();
+        let thread = std::thread::Builder::new()
+            .name("nix-eval".to_string())
+            .spawn(move || {
+                let r = gc_registering_current_thread(|| {
+                    let es = match make() {
+                        Ok(es) => es,
+                        Err(e) => {
+                            let _ = ready_sender.send(Err(e));
+                            return;
+                        }
+                    };
+                    let _ = ready_sender.send(Ok(()));
+                    let mut worker = Worker {
+                        es,
+                        owner,
+                        values: HashMap::new(),
+                        next_id: 0,
+                    };
+                    for command in receiver {
+                        match command {
+                            Command::Run(f) => f(&mut worker),
+                            Command::DropValue(id) => {
+                                worker.values.remove(&id);
+                            }
+                            Command::Shutdown => break,
+                        }
+                    }
+                    // Drop the values and the EvalState while the thread is still registered
+                    drop(worker);
+                });
+                if let Err(e) = r {
+                    let _ = ready_sender.send(Err(e));
+                }
+            })?;
+        match ready_receiver.recv() {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                let _ = thread.join();
+                return Err(e);
+            }
+            Err(_) => {
+                let _ = thread.join();
+                bail!("evaluation thread stopped while creating the EvalState");
+            }
+        }
+        Ok(SendEvalState {
+            owner,
+            sender,
+            thread: Some(thread),
+        })
+    }
+
+    /// Queue `f` to run on the evaluation thread, without waiting for it.
+    pub(crate) fn submit(&self, f: Box<dyn FnOnce(&mut Worker) + Send>) -> Result<()> {
+        self.sender
+            .send(Command::Run(f))
+            .map_err(|_| format_err!("evaluation thread has stopped"))
+    }
+
+    /// Make handles for the values that [`Worker::insert`] returns an id for, also on the evaluation thread.
+    pub(crate) fn send_value_maker(&self) -> impl Fn(u64) -> SendValue + Send + 'static {
+        let owner = self.owner;
+        let sender = self.sender.clone();
+        move |id| SendValue {
+            r: SendValueRef { owner, id },
+            sender: sender.clone(),
+        }
+    }
+
+    /// Run `f` on the evaluation thread, for anything that the forwarding methods don't cover.
+    pub fn with<R: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Worker) -> Result<R> + Send + 'static,
+    ) -> Result<R> {
+        let (result_sender, result_receiver) = mpsc::channel();
+        self.submit(Box::new(move |worker| {
+            let _ = result_sender.send(f(worker));
+        }))?;
+        result_receiver
+            .recv()
+            .map_err(|_| format_err!("evaluation thread stopped during the call"))?
+    }
+
+    fn with_new_value(
+        &self,
+        f: impl FnOnce(&mut Worker) -> Result<Value> + Send + 'static,
+    ) -> Result<SendValue> {
+        let id = self.with(move |worker| {
+            let v = f(worker)?;
+            Ok(worker.insert(v))
+        })?;
+        Ok((self.send_value_maker())(id))
+    }
+
+    /// See [`EvalState::eval_from_string`].
+    pub fn eval_from_string(
+        &self,
+        expr: impl Into<String>,
+        source: SourceName,
+    ) -> Result<SendValue> {
+        let expr = expr.into();
+        self.with_new_value(move |w| w.es.eval_from_string(expr, source))
+    }
+    /// See [`EvalState::force`].
+    pub fn force(&self, v: &SendValue) -> Result<()> {
+        let v = v.value_ref();
+        self.with(move |w| w.es.force(w.get(&v)?))
+    }
+    /// See [`EvalState::value_type_forced`].
+    pub fn value_type_forced(&self, v: &SendValue) -> Result<ValueType> {
+        let v = v.value_ref();
+        self.with(move |w| w.es.value_type_forced(w.get(&v)?))
+    }
+    /// See [`EvalState::require_string`].
+    pub fn require_string(&self, v: &SendValue) -> Result<String> {
+        let v = v.value_ref();
+        self.with(move |w| w.es.require_string(w.get(&v)?))
+    }
+    /// See [`EvalState::require_int`].
+    pub fn require_int(&self, v: &SendValue) -> Result<i64> {
+        let v = v.value_ref();
+        self.with(move |w| w.es.require_int(w.get(&v)?))
+    }
+    /// See [`EvalState::require_bool`].
+    pub fn require_bool(&self, v: &SendValue) -> Result<bool> {
+        let v = v.value_ref();
+        self.with(move |w| w.es.require_bool(w.get(&v)?))
+    }
+    /// See [`EvalState::require_attrs_names`].
+    pub fn require_attrs_names(&self, v: &SendValue) -> Result<Vec<String>> {
+        let v = v.value_ref();
+        self.with(move |w| w.es.require_attrs_names(w.get(&v)?))
+    }
+    /// See [`EvalState::require_attrs_select`].
+    pub fn require_attrs_select(&self, v: &SendValue, name: &str) -> Result<SendValue> {
+        let v = v.value_ref();
+        let name = name.to_string();
+        self.with_new_value(move |w| w.es.require_attrs_select(w.get(&v)?, &name))
+    }
+    /// See [`EvalState::call`].
+    pub fn call(&self, f: &SendValue, arg: &SendValue) -> Result<SendValue> {
+        let f = f.value_ref();
+        let arg = arg.value_ref();
+        self.with_new_value(move |w| w.es.call(w.get(&f)?, w.get(&arg)?))
+    }
+}
+impl Drop for SendEvalState {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Command::Shutdown);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::init;
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    fn spawn() -> SendEvalState {
+        SendEvalState::spawn(|| EvalState::new(Store::open("auto")?)).unwrap()
+    }
+
+    #[test]
+    fn send_eval_state_two_threads() {
+        let es = spawn();
+        let f = es
+            .eval_from_string("x: x * 2", SourceName::Synthetic("test"))
+            .unwrap();
+        std::thread::scope(|s| {
+            let handles = (0..2)
+                .map(|i| {
+                    let es = &es;
+                    let f = &f;
+                    s.spawn(move || {
+                        let arg = es
+                            .eval_from_string(format!("{}", i + 20), SourceName::Synthetic("test"))
+                            .unwrap();
+                        let r = es.call(f, &arg).unwrap();
+                        es.require_int(&r).unwrap()
+                    })
+                })
+                .collect::<Vec<_>>();
+            let results = handles
+                .into_iter()
+                .map(|h| h.join().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(results, [40, 42]);
+        });
+        // The handles that the threads dropped have been released
+        assert_eq!(es.with(|w| Ok(w.value_count())).unwrap(), 1);
+        drop(f);
+        assert_eq!(es.with(|w| Ok(w.value_count())).unwrap(), 0);
+        // Stops and joins the thread
+        drop(es);
+    }
+
+    #[test]
+    fn send_eval_state_attrs() {
+        let es = spawn();
+        let v = es
+            .eval_from_string("{ a = \"x\"; b = true; }", SourceName::Synthetic("test"))
+            .unwrap();
+        assert_eq!(es.value_type_forced(&v).unwrap(), ValueType::AttrSet);
+        assert_eq!(es.require_attrs_names(&v).unwrap(), ["a", "b"]);
+        let a = es.require_attrs_select(&v, "a").unwrap();
+        assert_eq!(es.require_string(&a).unwrap(), "x");
+        let b = es.require_attrs_select(&v, "b").unwrap();
+        assert!(es.require_bool(&b).unwrap());
+        let r = v.value_ref();
+        let n = es
+            .with(move |w| {
+                let v = w.get(&r)?;
+                w.eval_state()
+                    .require_attrs_names(v)
+                    .map(|names| names.len())
+            })
+            .unwrap();
+        assert_eq!(n, 2);
+    }
+
+    #[test]
+    fn send_eval_state_errors() {
+        let es = spawn();
+        let other = spawn();
+        let v = es
+            .eval_from_string("1", SourceName::Synthetic("test"))
+            .unwrap();
+        let e = other.require_int(&v).unwrap_err();
+        assert_eq!(e.to_string(), "value belongs to a different SendEvalState");
+        let e = es.require_string(&v).unwrap_err();
+        assert_eq!(e.to_string(), "expected a string, but got a Int: 1");
+        // Values may outlive the state
+        drop(es);
+        drop(v);
+    }
+
+    #[test]
+    fn send_eval_state_spawn_error() {
+        let r = SendEvalState::spawn(|| bail!("no store today"));
+        match r {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => assert_eq!(e.to_string(), "no store today"),
+        }
+    }
+}
@@ -0,0 +1,516 @@
+//! Serialize Rust data structures to Nix values with `serde`.
+//!
+//! ```ignore
+//! let arg = nix_expr::ser::to_value(&es, &state)?;
+//! let result = es.auto_call(&f, &arg)?;
+//! ```
+//!
+//! Structs and maps become attribute sets, sequences and tuples become lists, and `None`, `()` and unit structs become `null`.
+//! Enums are externally tagged, like [`crate::de`] expects: a unit variant is a string, and other variants are an attribute set with a single attribute.
+
+use crate::eval_state::{AttrsetBuilder, EvalState};
+use crate::value::{Value, ValueType};
+use crate::value_path::ValuePath;
+use serde::ser::{self, Serialize};
+use std::fmt::Display;
+
+pub use crate::de::Error;
+
+/// Convert a Rust value to a Nix value.
+pub fn to_value<T: Serialize + ?Sized>(es: &EvalState, v: &T) -> Result<Value, Error> {
+    v.serialize(NixSerializer::new(es))
+}
+
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        <Error as serde::de::Error>::custom(msg)
+    }
+}
+
+/// A [`serde::Serializer`] that produces a Nix value.
+pub struct NixSerializer<'a> {
+    es: &'a EvalState,
+    path: ValuePath,
+}
+impl<'a> NixSerializer<'a> {
+    pub fn new(es: &'a EvalState) -> Self {
+        NixSerializer {
+            es,
+            path: ValuePath::default(),
+        }
+    }
+    fn check<T>(&self, r: anyhow::Result<T>) -> Result<T, Error> {
+        r.map_err(|e| Error::at(&self.path, e))
+    }
+    fn variant(&self, variant: &str, value: Value) -> Result<Value, Error> {
+        self.check(
+            self.es
+                .new_value_attrs(std::iter::once((variant.to_string(), value))),
+        )
+    }
+    fn list(self, len: Option<usize>, variant: Option<&'static str>) -> ListSerializer<'a> {
+        ListSerializer {
+            items: Vec::with_capacity(len.unwrap_or(0)),
+            serializer: self,
+            variant,
+        }
+    }
+    fn attrs(
+        self,
+        len: Option<usize>,
+        variant: Option<&'static str>,
+    ) -> Result<AttrsSerializer<'a>, Error> {
+        let builder = self.check(self.es.new_attrset_builder(len.unwrap_or(0)))?;
+        Ok(AttrsSerializer {
+            builder,
+            key: None,
+            serializer: self,
+            variant,
+        })
+    }
+    fn child(&self, path: ValuePath) -> NixSerializer<'a> {
+        NixSerializer { es: self.es, path }
+    }
+}
+
+impl<'a> ser::Serializer for NixSerializer<'a> {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = ListSerializer<'a>;
+    type SerializeTuple = ListSerializer<'a>;
+    type SerializeTupleStruct = ListSerializer<'a>;
+    type SerializeTupleVariant = ListSerializer<'a>;
+    type SerializeMap = AttrsSerializer<'a>;
+    type SerializeStruct = AttrsSerializer<'a>;
+    type SerializeStructVariant = AttrsSerializer<'a>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value, Error> {
+        self.check(self.es.new_value_bool(v))
+    }
+    fn serialize_i8(self, v: i8) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i16(self, v: i16) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i32(self, v: i32) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_i64(self, v: i64) -> Result<Value, Error> {
+        self.check(self.es.new_value_int(v))
+    }
+    fn serialize_u8(self, v: u8) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_u16(self, v: u16) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_u32(self, v: u32) -> Result<Value, Error> {
+        self.serialize_i64(v.into())
+    }
+    fn serialize_u64(self, v: u64) -> Result<Value, Error> {
+        match i64::try_from(v) {
+            Ok(i) => self.serialize_i64(i),
+            Err(_) => Err(Error::at(
+                &self.path,
+                anyhow::format_err!("the integer {} does not fit in a Nix integer", v),
+            )),
+        }
+    }
+    fn serialize_f32(self, v: f32) -> Result<Value, Error> {
+        self.serialize_f64(v.into())
+    }
+    fn serialize_f64(self, v: f64) -> Result<Value, Error> {
+        self.check(self.es.new_value_float(v))
+    }
+    fn serialize_char(self, v: char) -> Result<Value, Error> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+    fn serialize_str(self, v: &str) -> Result<Value, Error> {
+        self.check(self.es.new_value_string(v))
+    }
+    /// Bytes become a list of integers, as `serde_json` does, because Nix strings are not meant for binary data.
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value, Error> {
+        let items = v
+            .iter()
+            .map(|b| self.check(self.es.new_value_int((*b).into())))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.check(self.es.new_value_list(&items))
+    }
+
+    fn serialize_none(self) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<Value, Error> {
+        self.check(self.es.new_value_null())
+    }
+    /// Unit structs become `null`, like `()`, so that they round-trip through [`crate::de`].
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value, Error> {
+        self.serialize_unit()
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Value, Error> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value, Error> {
+        let value = value.serialize(self.child(self.path.attr(variant)))?;
+        self.variant(variant, value)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer<'a>, Error> {
+        Ok(self.list(len, None))
+    }
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer<'a>, Error> {
+        Ok(self.list(Some(len), None))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer<'a>, Error> {
+        Ok(self.list(Some(len), None))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer<'a>, Error> {
+        Ok(self.list(Some(len), Some(variant)))
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<AttrsSerializer<'a>, Error> {
+        self.attrs(len, None)
+    }
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<AttrsSerializer<'a>, Error> {
+        self.attrs(Some(len), None)
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<AttrsSerializer<'a>, Error> {
+        self.attrs(Some(len), Some(variant))
+    }
+}
+
+/// Collects the elements of a list, or of a tuple variant.
+pub struct ListSerializer<'a> {
+    serializer: NixSerializer<'a>,
+    items: Vec<Value>,
+    variant: Option<&'static str>,
+}
+impl ListSerializer<'_> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let path = match self.variant {
+            Some(variant) => self.serializer.path.attr(variant),
+            None => self.serializer.path.clone(),
+        }
+        .index(self.items.len());
+        let value = value
+            .serialize(self.serializer.child(path.clone()))
+            .map_err(|e| e.or_at(&path))?;
+        self.items.push(value);
+        Ok(())
+    }
+    fn finish(self) -> Result<Value, Error> {
+        let list = self
+            .serializer
+            .check(self.serializer.es.new_value_list(&self.items))?;
+        match self.variant {
+            Some(variant) => self.serializer.variant(variant, list),
+            None => Ok(list),
+        }
+    }
+}
+impl ser::SerializeSeq for ListSerializer<'_> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+impl ser::SerializeTuple for ListSerializer<'_> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+impl ser::SerializeTupleStruct for ListSerializer<'_> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+impl ser::SerializeTupleVariant for ListSerializer<'_> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.push(value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+/// Collects the attributes of an attribute set, or of a struct variant.
+pub struct AttrsSerializer<'a> {
+    serializer: NixSerializer<'a>,
+    builder: AttrsetBuilder<'a>,
+    key: Option<String>,
+    variant: Option<&'static str>,
+}
+impl AttrsSerializer<'_> {
+    fn base_path(&self) -> ValuePath {
+        match self.variant {
+            Some(variant) => self.serializer.path.attr(variant),
+            None => self.serializer.path.clone(),
+        }
+    }
+    fn insert<T: Serialize + ?Sized>(&mut self, name: &str, value: &T) -> Result<(), Error> {
+        let base = self.base_path();
+        let path = base.attr(name);
+        let value = value
+            .serialize(self.serializer.child(path.clone()))
+            .map_err(|e| e.or_at(&path))?;
+        self.builder
+            .insert(name, &value)
+            .map_err(|e| Error::at(&base, e))
+    }
+    fn finish(self) -> Result<Value, Error> {
+        let ser = self.serializer;
+        let attrs = ser.check(self.builder.build())?;
+        match self.variant {
+            Some(variant) => ser.variant(variant, attrs),
+            None => Ok(attrs),
+        }
+    }
+}
+impl ser::SerializeMap for AttrsSerializer<'_> {
+    type Ok = Value;
+    type Error = Error;
+    /// Keys must serialize to strings, e.g. `String` or a unit enum variant; other keys are an error.
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        let path = self.base_path();
+        let es = self.serializer.es;
+        let key = key.serialize(self.serializer.child(path.clone()))?;
+        let t = es
+            .value_type_forced(&key)
+            .map_err(|e| Error::at(&path, e))?;
+        if t != ValueType::String {
+            return Err(Error::at(
+                &path,
+                anyhow::format_err!("attribute names must be strings, but got a {:?}", t),
+            ));
+        }
+        self.key = Some(es.require_string(&key).map_err(|e| Error::at(&path, e))?);
+        Ok(())
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        let key = self
+            .key
+            .take()
+            .expect("serialize_value called before serialize_key");
+        self.insert(&key, value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+impl ser::SerializeStruct for AttrsSerializer<'_> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key, value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+impl ser::SerializeStructVariant for AttrsSerializer<'_> {
+    type Ok = Value;
+    type Error = Error;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.insert(key, value)
+    }
+    fn end(self) -> Result<Value, Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::de::from_value;
+    use crate::eval_state::gc_registering_current_thread;
+    use nix_store::store::Store;
+    use serde::{Deserialize, Serialize};
+    use std::collections::{BTreeMap, HashMap};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct State {
+        id: String,
+        size: u64,
+        ratio: f64,
+        tags: Vec<String>,
+        parent: Option<String>,
+        labels: BTreeMap<String, String>,
+        kind: Kind,
+        others: Vec<Kind>,
+        marker: Marker,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    enum Kind {
+        Host,
+        Vm(String),
+        Pair(i64, bool),
+        Container { image: String },
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Marker;
+
+    fn with_es<R>(f: impl FnOnce(&EvalState) -> R) -> R {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            f(&es)
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn to_value_round_trip() {
+        with_es(|es| {
+            let state = State {
+                id: "i-123".to_string(),
+                size: 42,
+                ratio: 0.25,
+                tags: vec!["a".to_string(), "b".to_string()],
+                parent: None,
+                labels: BTreeMap::from([("env".to_string(), "prod".to_string())]),
+                kind: Kind::Container {
+                    image: "nginx".to_string(),
+                },
+                others: vec![
+                    Kind::Host,
+                    Kind::Vm("nixos".to_string()),
+                    Kind::Pair(1, true),
+                ],
+                marker: Marker,
+            };
+            let v = to_value(es, &state).unwrap();
+            let parent = es.require_attrs_select(&v, "parent").unwrap();
+            assert_eq!(es.value_type_forced(&parent).unwrap(), ValueType::Null);
+            let marker = es.require_attrs_select(&v, "marker").unwrap();
+            assert_eq!(es.value_type_forced(&marker).unwrap(), ValueType::Null);
+            let kind = es.require_attrs_select(&v, "kind").unwrap();
+            assert_eq!(es.require_attrs_names(&kind).unwrap(), vec!["container"]);
+            let back: State = from_value(es, &v).unwrap();
+            assert_eq!(back, state);
+        });
+    }
+
+    #[test]
+    fn to_value_flatten() {
+        #[derive(Serialize)]
+        struct Outer {
+            name: String,
+            #[serde(flatten)]
+            inner: Inner,
+        }
+        #[derive(Serialize)]
+        struct Inner {
+            a: i64,
+            b: bool,
+        }
+        with_es(|es| {
+            let v = to_value(
+                es,
+                &Outer {
+                    name: "x".to_string(),
+                    inner: Inner { a: 1, b: true },
+                },
+            )
+            .unwrap();
+            assert_eq!(es.require_attrs_names(&v).unwrap(), vec!["a", "b", "name"]);
+            let a = es.require_attrs_select(&v, "a").unwrap();
+            assert_eq!(es.require_int(&a).unwrap(), 1);
+        });
+    }
+
+    #[test]
+    fn to_value_errors() {
+        with_es(|es| {
+            let e = to_value(es, &HashMap::from([(1, "one")])).err().unwrap();
+            assert_eq!(
+                e.to_string(),
+                "attribute names must be strings, but got a Int"
+            );
+
+            #[derive(Serialize)]
+            struct Big {
+                huge: Vec<u64>,
+            }
+            let e = to_value(
+                es,
+                &Big {
+                    huge: vec![u64::MAX],
+                },
+            )
+            .err()
+            .unwrap();
+            assert_eq!(
+                e.to_string(),
+                "huge[0]: the integer 18446744073709551615 does not fit in a Nix integer"
+            );
+        });
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{bail, Context as _, Result};
+
+/// One store object that a Nix string refers to.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum StringContextElement {
+    /// A plain store path, such as a source file that was copied to the store.
+    Opaque { path: String },
+    /// One output of a derivation, such as the `outPath` of a derivation.
+    DerivationOutput { drv_path: String, output: String },
+    /// A derivation itself, including all of its outputs, such as the `drvPath` of a derivation.
+    DerivationDeep { drv_path: String },
+}
+
+/// The string context of a Nix string: the store objects that must be present for the string to be meaningful.
+///
+/// Elements are sorted, so that two contexts can be compared with `==`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct StringContext {
+    elements: Vec<StringContextElement>,
+}
+
+impl StringContext {
+    pub fn new(mut elements: Vec<StringContextElement>) -> Self {
+        elements.sort();
+        elements.dedup();
+        StringContext { elements }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+    pub fn elements(&self) -> &[StringContextElement] {
+        &self.elements
+    }
+
+    /// Parse the JSON rendering of `builtins.getContext`.
+    pub(crate) fn from_get_context_json(json: &str) -> Result<Self> {
+        let v: serde_json::Value =
+            serde_json::from_str(json).with_context(|| "parsing builtins.getContext output")?;
+        let obj = match v.as_object() {
+            Some(obj) => obj,
+            None => bail!("builtins.getContext did not return an attribute set"),
+        };
+        let mut elements = Vec::new();
+        for (path, info) in obj {
+            if info.get("path").and_then(|p| p.as_bool()) == Some(true) {
+                elements.push(StringContextElement::Opaque { path: path.clone() });
+            }
+            if info.get("allOutputs").and_then(|p| p.as_bool()) == Some(true) {
+                elements.push(StringContextElement::DerivationDeep {
+                    drv_path: path.clone(),
+                });
+            }
+            if let Some(outputs) = info.get("outputs") {
+                let outputs = match outputs.as_array() {
+                    Some(outputs) => outputs,
+                    None => bail!("builtins.getContext: outputs of {} is not a list", path),
+                };
+                for output in outputs {
+                    let output = match output.as_str() {
+                        Some(output) => output,
+                        None => bail!("builtins.getContext: output of {} is not a string", path),
+                    };
+                    elements.push(StringContextElement::DerivationOutput {
+                        drv_path: path.clone(),
+                        output: output.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(StringContext::new(elements))
+    }
+
+    /// Render the context as the attribute set that `builtins.getContext` returns and `builtins.appendContext` takes, as JSON.
+    pub(crate) fn to_get_context_json(&self) -> String {
+        let mut obj = serde_json::Map::new();
+        for element in &self.elements {
+            let (path, key, value) = match element {
+                StringContextElement::Opaque { path } => {
+                    (path, "path", serde_json::Value::Bool(true))
+                }
+                StringContextElement::DerivationDeep { drv_path } => {
+                    (drv_path, "allOutputs", serde_json::Value::Bool(true))
+                }
+                StringContextElement::DerivationOutput { drv_path, output } => (
+                    drv_path,
+                    "outputs",
+                    serde_json::Value::String(output.clone()),
+                ),
+            };
+            let info = obj
+                .entry(path.clone())
+                .or_insert_with(|| serde_json::Value::Object(serde_json::Map::new()));
+            let info = info.as_object_mut().unwrap();
+            if key == "outputs" {
+                let outputs = info
+                    .entry("outputs")
+                    .or_insert_with(|| serde_json::Value::Array(Vec::new()));
+                outputs.as_array_mut().unwrap().push(value);
+            } else {
+                info.insert(key.to_string(), value);
+            }
+        }
+        serde_json::Value::Object(obj).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_get_context_json_empty() {
+        let c = StringContext::from_get_context_json("{}").unwrap();
+        assert!(c.is_empty());
+    }
+
+    #[test]
+    fn from_get_context_json_elements() {
+        let c = StringContext::from_get_context_json(
+            r#"{
+                "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-src": { "path": true },
+                "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv": { "outputs": [ "out", "dev" ] },
+                "/nix/store/cccccccccccccccccccccccccccccccc-world.drv": { "allOutputs": true }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            c.elements(),
+            &[
+                StringContextElement::Opaque {
+                    path: "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-src".to_string()
+                },
+                StringContextElement::DerivationOutput {
+                    drv_path: "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv".to_string(),
+                    output: "dev".to_string()
+                },
+                StringContextElement::DerivationOutput {
+                    drv_path: "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv".to_string(),
+                    output: "out".to_string()
+                },
+                StringContextElement::DerivationDeep {
+                    drv_path: "/nix/store/cccccccccccccccccccccccccccccccc-world.drv".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn to_get_context_json_roundtrip() {
+        let c = StringContext::new(vec![
+            StringContextElement::DerivationOutput {
+                drv_path: "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv".to_string(),
+                output: "out".to_string(),
+            },
+            StringContextElement::DerivationOutput {
+                drv_path: "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv".to_string(),
+                output: "dev".to_string(),
+            },
+            StringContextElement::DerivationDeep {
+                drv_path: "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv".to_string(),
+            },
+            StringContextElement::Opaque {
+                path: "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-src".to_string(),
+            },
+        ]);
+        let json = c.to_get_context_json();
+        assert_eq!(
+            json,
+            r#"{"/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-src":{"path":true},"/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-hello.drv":{"allOutputs":true,"outputs":["dev","out"]}}"#
+        );
+        assert_eq!(StringContext::from_get_context_json(&json).unwrap(), c);
+        assert_eq!(StringContext::default().to_get_context_json(), "{}");
+    }
+
+    #[test]
+    fn from_get_context_json_not_attrs() {
+        let r = StringContext::from_get_context_json("[]");
+        assert!(r.is_err());
+    }
+}
@@ -1,11 +1,15 @@
+use crate::eval_state::{EvalState, EvalStateRef};
 use nix_c_raw as raw;
 use nix_util::context::Context;
+use nix_util::error_site;
+use std::mem::ManuallyDrop;
 use std::ptr::NonNull;
+use std::rc::Rc;
 
 // TODO: test: cloning a thunk does not duplicate the evaluation.
 
 /** The type of a value (or thunk) */
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
 pub enum ValueType {
     AttrSet,
     Bool,
@@ -22,6 +26,23 @@ pub enum ValueType {
 }
 
 impl ValueType {
+    /// A description of the type for use in error messages, e.g. "an integer".
+    pub fn describe(&self) -> &'static str {
+        match self {
+            ValueType::AttrSet => "a set",
+            ValueType::Bool => "a Boolean",
+            ValueType::External => "an external value",
+            ValueType::Float => "a float",
+            ValueType::Function => "a function",
+            ValueType::Int => "an integer",
+            ValueType::List => "a list",
+            ValueType::Null => "null",
+            ValueType::Path => "a path",
+            ValueType::String => "a string",
+            ValueType::Thunk => "a thunk",
+            ValueType::Unknown => "an unknown value",
+        }
+    }
     pub(crate) fn from_raw(raw: raw::ValueType) -> ValueType {
         match raw {
             raw::ValueType_NIX_TYPE_ATTRS => ValueType::AttrSet,
@@ -40,19 +61,54 @@ impl ValueType {
     }
 }
 
-/* A pointer to a value or thunk, to be used with EvalState methods. */
+/// A pointer to a value or thunk, to be used with EvalState methods.
+///
+/// It keeps the Nix value alive: each `Value` holds one reference count, which [`Clone`] increments and [`Drop`] decrements.
+/// The garbage collector does not free a value while any `Value` refers to it.
+///
+/// It also keeps the [`EvalState`] that created it alive, so the evaluator is only freed after its last `Value`.
+/// Only use a `Value` with that `EvalState`, or a clone of it.
 pub struct Value {
     inner: NonNull<raw::Value>,
+    state: Rc<EvalStateRef>,
 }
 impl Value {
-    pub(crate) fn new(inner: *mut raw::Value) -> Self {
+    pub(crate) fn new(inner: *mut raw::Value, state: &Rc<EvalStateRef>) -> Self {
         Value {
             inner: NonNull::new(inner).unwrap(),
+            state: state.clone(),
         }
     }
+    /// Take a reference to a value that is owned by Nix, such as a primop argument.
+    pub(crate) fn new_borrowed(inner: *mut raw::Value, es: &EvalState) -> Self {
+        let context = Context::new();
+        unsafe { raw::nix_gc_incref(context.ptr(), inner) };
+        context.check_err(error_site!("nix_gc_incref")).unwrap();
+        unsafe { Value::from_raw(es, inner) }
+    }
+    /// The evaluator that this value belongs to.
+    pub fn eval_state(&self) -> EvalState {
+        EvalState::from_ref(self.state.clone())
+    }
     pub(crate) fn raw_ptr(&self) -> *mut raw::Value {
         self.inner.as_ptr()
     }
+    /// Give up the reference without decrementing it, e.g. to hand the value to C code.
+    /// Use [`from_raw`](Self::from_raw) to take it back. The raw value does not keep the `EvalState` alive.
+    pub fn leak(self) -> *mut raw::Value {
+        let this = ManuallyDrop::new(self);
+        // Release the state, but not the reference count of the value
+        drop(unsafe { std::ptr::read(&this.state) });
+        this.raw_ptr()
+    }
+    /// Take ownership of one reference to a value of `es`, such as one returned by [`leak`](Self::leak).
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a live Nix value, and the caller must own a reference count on it, which the `Value` will release.
+    pub unsafe fn from_raw(es: &EvalState, ptr: *mut raw::Value) -> Self {
+        Value::new(ptr, es.state_ref())
+    }
 }
 impl Drop for Value {
     fn drop(&mut self) {
@@ -67,8 +123,11 @@ impl Clone for Value {
     fn clone(&self) -> Self {
         let context = Context::new();
         unsafe { raw::nix_gc_incref(context.ptr(), self.inner.as_ptr()) };
-        context.check_err().unwrap();
-        Value { inner: self.inner }
+        context.check_err(error_site!("nix_gc_incref")).unwrap();
+        Value {
+            inner: self.inner,
+            state: self.state.clone(),
+        }
     }
 }
 
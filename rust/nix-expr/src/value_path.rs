@@ -0,0 +1,76 @@
+//! Locations inside a Nix value, for error messages.
+
+/// A location inside a value, for error messages, e.g. `` `a.b[2]` ``.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ValuePath(pub(crate) Vec<PathElem>);
+#[derive(Clone, Debug)]
+pub(crate) enum PathElem {
+    Attr(String),
+    Index(usize),
+}
+impl ValuePath {
+    pub(crate) fn attr(&self, name: &str) -> ValuePath {
+        let mut p = self.clone();
+        p.0.push(PathElem::Attr(name.to_string()));
+        p
+    }
+    pub(crate) fn index(&self, i: usize) -> ValuePath {
+        let mut p = self.clone();
+        p.0.push(PathElem::Index(i));
+        p
+    }
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// The path without quotes, e.g. `a.b[2]`, or the empty string for the top level.
+    pub(crate) fn dotted(&self) -> String {
+        let mut s = String::new();
+        for (i, elem) in self.0.iter().enumerate() {
+            match elem {
+                PathElem::Attr(name) => {
+                    if i > 0 {
+                        s.push('.');
+                    }
+                    if is_simple_attr_name(name) {
+                        s.push_str(name);
+                    } else {
+                        s.push_str(&format!("{:?}", name));
+                    }
+                }
+                PathElem::Index(i) => s.push_str(&format!("[{}]", i)),
+            }
+        }
+        s
+    }
+}
+impl std::fmt::Display for ValuePath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_empty() {
+            return f.write_str("the top level");
+        }
+        write!(f, "`{}`", self.dotted())
+    }
+}
+pub(crate) fn is_simple_attr_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '\'')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_path_display() {
+        let p = ValuePath::default();
+        assert_eq!(p.to_string(), "the top level");
+        assert_eq!(p.dotted(), "");
+        let p = p.attr("a").index(2).attr("b.c").attr("d-e");
+        assert_eq!(p.to_string(), "`a[2].\"b.c\".d-e`");
+        assert_eq!(p.dotted(), "a[2].\"b.c\".d-e");
+    }
+}
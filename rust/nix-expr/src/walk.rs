@@ -0,0 +1,260 @@
+//! Listing the attributes of a tree of attribute sets, stopping at derivations, like `nix search` and `nix flake show`.
+
+use crate::attr_path::AttrPath;
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use anyhow::{Context as _, Result};
+
+/// Options for [`EvalState::walk_attrs`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct WalkOpts {
+    /// How many levels of attribute sets to descend into. Attribute sets below are reported as [`LeafKind::Value`].
+    pub max_depth: Option<usize>,
+    /// Descend into nested attribute sets that lack `recurseForDerivations = true`. The root is always descended into.
+    pub descend_without_recurse: bool,
+    /// Report evaluation errors as [`LeafKind::Error`] leaves, instead of failing.
+    pub record_errors: bool,
+}
+
+/// What [`EvalState::walk_attrs`] found at an attribute path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum LeafKind {
+    Derivation {
+        name: String,
+        system: String,
+    },
+    /// Any other value, including attribute sets that were not descended into.
+    Value(ValueType),
+    /// The error of evaluating the attribute, with [`WalkOpts::record_errors`].
+    Error(String),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AttrLeaf {
+    pub path: AttrPath,
+    pub kind: LeafKind,
+}
+
+impl EvalState {
+    /// List the leaves of a tree of attribute sets, in lexicographic order of their paths.
+    ///
+    /// Each attribute is evaluated to weak head normal form, but the attributes of derivations and of attribute sets that are not descended into are not.
+    /// An error in one attribute doesn't affect its siblings. `recurseForDerivations` attributes themselves are not listed.
+    pub fn walk_attrs(&self, root: &Value, opts: WalkOpts) -> Result<Vec<AttrLeaf>> {
+        let mut leaves = Vec::new();
+        self.walk(root, &mut Vec::new(), &opts, &mut leaves)?;
+        Ok(leaves)
+    }
+
+    fn walk(
+        &self,
+        v: &Value,
+        path: &mut Vec<String>,
+        opts: &WalkOpts,
+        leaves: &mut Vec<AttrLeaf>,
+    ) -> Result<()> {
+        let kind = match self.walk_node(v, path, opts) {
+            Ok(Some(kind)) => kind,
+            Ok(None) => return self.walk_children(v, path, opts, leaves),
+            Err(e) => return walk_error(e, path, opts, leaves),
+        };
+        leaves.push(AttrLeaf {
+            path: AttrPath {
+                segments: path.clone(),
+            },
+            kind,
+        });
+        Ok(())
+    }
+
+    /// The kind of leaf at `path`, or `None` to descend into it.
+    fn walk_node(&self, v: &Value, path: &[String], opts: &WalkOpts) -> Result<Option<LeafKind>> {
+        let t = self.value_type_forced(v)?;
+        if t != ValueType::AttrSet {
+            return Ok(Some(LeafKind::Value(t)));
+        }
+        if self.is_derivation(v)? {
+            return Ok(Some(LeafKind::Derivation {
+                name: self.require_string(&self.require_attrs_select(v, "name")?)?,
+                system: self.require_string(&self.require_attrs_select(v, "system")?)?,
+            }));
+        }
+        if path.is_empty() {
+            return Ok(None);
+        }
+        if opts.max_depth.is_some_and(|max| path.len() >= max) {
+            return Ok(Some(LeafKind::Value(t)));
+        }
+        let recurse = match self.require_attrs_select_opt(v, "recurseForDerivations")? {
+            Some(r) => self.value_type_forced(&r)? == ValueType::Bool && self.require_bool(&r)?,
+            None => false,
+        };
+        if recurse || opts.descend_without_recurse {
+            Ok(None)
+        } else {
+            Ok(Some(LeafKind::Value(t)))
+        }
+    }
+
+    fn walk_children(
+        &self,
+        v: &Value,
+        path: &mut Vec<String>,
+        opts: &WalkOpts,
+        leaves: &mut Vec<AttrLeaf>,
+    ) -> Result<()> {
+        for name in self.require_attrs_names(v)? {
+            if name == "recurseForDerivations" {
+                continue;
+            }
+            // Selecting doesn't evaluate the attribute; walk does, so its errors belong to its leaf
+            let child = self.require_attrs_select(v, &name)?;
+            path.push(name);
+            let r = self.walk(&child, path, opts, leaves);
+            path.pop();
+            r?;
+        }
+        Ok(())
+    }
+}
+
+fn walk_error(
+    e: anyhow::Error,
+    path: &[String],
+    opts: &WalkOpts,
+    leaves: &mut Vec<AttrLeaf>,
+) -> Result<()> {
+    let path = AttrPath {
+        segments: path.to_vec(),
+    };
+    if !opts.record_errors {
+        return Err(e).with_context(|| format!("while evaluating `{}`", path));
+    }
+    leaves.push(AttrLeaf {
+        path,
+        kind: LeafKind::Error(format!("{:#}", e)),
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    const TREE: &str = r#"
+      let drv = name: derivation { inherit name; system = "x86_64-linux"; builder = "/bin/sh"; };
+      in {
+        hello = drv "hello";
+        nested = {
+          recurseForDerivations = true;
+          a = drv "a";
+          deeper = { recurseForDerivations = true; b = drv "b"; };
+          n = 1;
+        };
+        plain = { c = drv "c"; d = throw "not forced"; };
+        broken = { recurseForDerivations = true; x = throw "broken x"; y = drv "y"; };
+        bad = throw "bad";
+        str = "s";
+      }
+    "#;
+
+    fn show(leaves: &[AttrLeaf]) -> Vec<String> {
+        leaves
+            .iter()
+            .map(|leaf| {
+                let kind = match &leaf.kind {
+                    LeafKind::Derivation { name, system } => format!("{} ({})", name, system),
+                    LeafKind::Value(t) => format!("{:?}", t),
+                    LeafKind::Error(_) => "error".to_string(),
+                };
+                format!("{}: {}", leaf.path, kind)
+            })
+            .collect()
+    }
+
+    fn error_at<'a>(leaves: &'a [AttrLeaf], path: &str) -> &'a str {
+        match leaves.iter().find(|leaf| leaf.path.to_string() == path) {
+            Some(AttrLeaf {
+                kind: LeafKind::Error(e),
+                ..
+            }) => e,
+            leaf => panic!("expected an error at {}, got {:?}", path, leaf),
+        }
+    }
+
+    #[test]
+    fn walk_attrs() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(TREE, SourceName::Synthetic("test"))
+                .unwrap();
+            let opts = WalkOpts {
+                record_errors: true,
+                ..Default::default()
+            };
+            let leaves = es.walk_attrs(&v, opts).unwrap();
+            assert_eq!(
+                show(&leaves),
+                [
+                    "bad: error",
+                    "broken.x: error",
+                    "broken.y: y (x86_64-linux)",
+                    "hello: hello (x86_64-linux)",
+                    "nested.a: a (x86_64-linux)",
+                    "nested.deeper.b: b (x86_64-linux)",
+                    "nested.n: Int",
+                    "plain: AttrSet",
+                    "str: String",
+                ]
+            );
+            assert!(error_at(&leaves, "bad").contains("bad"));
+            assert!(error_at(&leaves, "broken.x").contains("broken x"));
+
+            let opts = WalkOpts {
+                max_depth: Some(1),
+                descend_without_recurse: true,
+                record_errors: true,
+            };
+            let leaves = es.walk_attrs(&v, opts).unwrap();
+            assert_eq!(
+                show(&leaves),
+                [
+                    "bad: error",
+                    "broken: AttrSet",
+                    "hello: hello (x86_64-linux)",
+                    "nested: AttrSet",
+                    "plain: AttrSet",
+                    "str: String",
+                ]
+            );
+
+            let opts = WalkOpts {
+                descend_without_recurse: true,
+                record_errors: true,
+                ..Default::default()
+            };
+            let leaves = es.walk_attrs(&v, opts).unwrap();
+            assert!(show(&leaves).contains(&"plain.c: c (x86_64-linux)".to_string()));
+            assert!(error_at(&leaves, "plain.d").contains("not forced"));
+
+            let e = es.walk_attrs(&v, WalkOpts::default()).unwrap_err();
+            assert_eq!(e.to_string(), "while evaluating `bad`");
+            assert!(format!("{:#}", e).contains("bad"));
+
+            let drv = es.require_attrs_select(&v, "hello").unwrap();
+            let leaves = es.walk_attrs(&drv, opts).unwrap();
+            assert_eq!(show(&leaves), [": hello (x86_64-linux)"]);
+        })
+        .unwrap();
+    }
+}
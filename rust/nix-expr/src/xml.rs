@@ -0,0 +1,197 @@
+//! Rendering values as XML, like `builtins.toXML` and `nix-instantiate --eval --xml`.
+
+use crate::eval_state::EvalState;
+use crate::value::{Value, ValueType};
+use anyhow::Result;
+
+const XML_HEADER: &str = "<?xml version='1.0' encoding='utf-8'?>\n<expr>\n";
+const XML_FOOTER: &str = "</expr>\n";
+
+impl EvalState {
+    /// Render a value as XML, in the format of `builtins.toXML`.
+    ///
+    /// With `strict`, the value is evaluated completely, and the document is the one `builtins.toXML` returns, without its string context.
+    /// Unlike `nix-instantiate --xml`, it has no source locations, which `builtins.toXML` leaves out.
+    ///
+    /// Otherwise nothing is evaluated: a thunk renders as `<unevaluated />`.
+    /// The C API evaluates attribute values and list elements when they are accessed, so those always render as `<unevaluated />`, and derivations render as attribute sets.
+    pub fn value_to_xml(&self, v: &Value, strict: bool) -> Result<String> {
+        if strict {
+            return self.to_xml(v);
+        }
+        let mut out = XML_HEADER.to_string();
+        if self.value_is_thunk(v)? {
+            out.push_str("  <unevaluated />\n");
+        } else {
+            match self.value_type_forced(v)? {
+                ValueType::AttrSet => {
+                    out.push_str("  <attrs>\n");
+                    for name in self.require_attrs_names(v)? {
+                        out.push_str(&format!("    <attr name=\"{}\">\n", escape_attr(&name)));
+                        out.push_str("      <unevaluated />\n");
+                        out.push_str("    </attr>\n");
+                    }
+                    out.push_str("  </attrs>\n");
+                }
+                ValueType::List => {
+                    out.push_str("  <list>\n");
+                    for _ in 0..self.require_list_size(v)? {
+                        out.push_str("    <unevaluated />\n");
+                    }
+                    out.push_str("  </list>\n");
+                }
+                // Already evaluated, and no contents that toXML would evaluate
+                _ => {
+                    let xml = self.to_xml(v)?;
+                    out.push_str(
+                        xml.strip_prefix(XML_HEADER)
+                            .and_then(|xml| xml.strip_suffix(XML_FOOTER))
+                            .unwrap_or(&xml),
+                    );
+                }
+            }
+        }
+        out.push_str(XML_FOOTER);
+        Ok(out)
+    }
+
+    fn to_xml(&self, v: &Value) -> Result<String> {
+        let xml = self.call(&self.builtin("toXML")?, v)?;
+        self.require_string(&xml)
+    }
+}
+
+/// Escape an attribute value as Nix's XML writer does.
+fn escape_attr(s: &str) -> String {
+    let mut r = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => r.push_str("&quot;"),
+            '<' => r.push_str("&lt;"),
+            '>' => r.push_str("&gt;"),
+            '&' => r.push_str("&amp;"),
+            '\n' => r.push_str("&#xA;"),
+            c => r.push(c),
+        }
+    }
+    r
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval_state::{gc_registering_current_thread, init, SourceName};
+    use ctor::ctor;
+    use nix_store::store::Store;
+
+    #[ctor]
+    fn setup() {
+        init().unwrap();
+    }
+
+    const EXPR: &str =
+        r#"{ a = 1; b = [ true null ]; "c&d" = "x<y"; f = { x, y ? 1, ... }@args: x; g = z: z; }"#;
+
+    #[test]
+    fn value_to_xml_strict() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(EXPR, SourceName::Synthetic("test"))
+                .unwrap();
+            let xml = es.value_to_xml(&v, true).unwrap();
+            assert_eq!(
+                xml,
+                r#"<?xml version='1.0' encoding='utf-8'?>
+<expr>
+  <attrs>
+    <attr name="a">
+      <int value="1" />
+    </attr>
+    <attr name="b">
+      <list>
+        <bool value="true" />
+        <null />
+      </list>
+    </attr>
+    <attr name="c&amp;d">
+      <string value="x&lt;y" />
+    </attr>
+    <attr name="f">
+      <function>
+        <attrspat ellipsis="1" name="args">
+          <attr name="x" />
+          <attr name="y" />
+        </attrspat>
+      </function>
+    </attr>
+    <attr name="g">
+      <function>
+        <varpat name="z" />
+      </function>
+    </attr>
+  </attrs>
+</expr>
+"#
+            );
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn value_to_xml_lazy() {
+        gc_registering_current_thread(|| {
+            let store = Store::open("auto").unwrap();
+            let es = EvalState::new(store).unwrap();
+            let v = es
+                .eval_from_string(
+                    "{ a = throw \"not evaluated\"; \"b\\n\" = 2; }",
+                    SourceName::Synthetic("test"),
+                )
+                .unwrap();
+            assert_eq!(
+                es.value_to_xml(&v, false).unwrap(),
+                r#"<?xml version='1.0' encoding='utf-8'?>
+<expr>
+  <attrs>
+    <attr name="a">
+      <unevaluated />
+    </attr>
+    <attr name="b&#xA;">
+      <unevaluated />
+    </attr>
+  </attrs>
+</expr>
+"#
+            );
+            let v = es
+                .eval_from_string("[ (throw \"not evaluated\") ]", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.value_to_xml(&v, false).unwrap(),
+                "<?xml version='1.0' encoding='utf-8'?>\n<expr>\n  <list>\n    <unevaluated />\n  </list>\n</expr>\n"
+            );
+
+            // Scalars are the same either way
+            let v = es
+                .eval_from_string("\"hello\"", SourceName::Synthetic("test"))
+                .unwrap();
+            assert_eq!(
+                es.value_to_xml(&v, false).unwrap(),
+                es.value_to_xml(&v, true).unwrap()
+            );
+
+            let f = es
+                .eval_from_string("builtins.throw", SourceName::Synthetic("test"))
+                .unwrap();
+            let msg = es.new_value_string("not evaluated").unwrap();
+            let thunk = es.new_value_apply(&f, &msg).unwrap();
+            assert_eq!(
+                es.value_to_xml(&thunk, false).unwrap(),
+                "<?xml version='1.0' encoding='utf-8'?>\n<expr>\n  <unevaluated />\n</expr>\n"
+            );
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+//! The evaluator settings are global to the process, and `EvalStateBuilder::build` refuses to build a state whose settings differ from those of a live state.
+//! The tests that need other than the configured settings are in their own test binary, so that they don't conflict with the states of the other tests, and take [`SETTINGS`] so that they don't conflict with each other.
+
+use ctor::ctor;
+use nix_expr::eval_state::{
+    gc_registering_current_thread, init, EvalState, EvalStateBuilder, SourceName,
+};
+use nix_expr::eval_state_pool::EvalStatePool;
+use nix_expr::json::StringContextPolicy;
+use nix_store::store::Store;
+use nix_util::error::NixError;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+#[ctor]
+fn setup() {
+    init().unwrap();
+}
+
+static SETTINGS: Mutex<()> = Mutex::new(());
+
+fn lock_settings() -> MutexGuard<'static, ()> {
+    // A failed test does not affect the others, because it dropped its states while unwinding
+    SETTINGS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[test]
+fn eval_state_builder_pure_eval() {
+    let _settings = lock_settings();
+    gc_registering_current_thread(|| {
+        let store = Store::open("auto").unwrap();
+        let es = EvalStateBuilder::new()
+            .store(store)
+            .pure_eval(true)
+            .build()
+            .unwrap();
+        let r = es.eval_from_string("builtins.currentTime", SourceName::Synthetic("test"));
+        assert!(r.is_err());
+        // Other settings require that the state is gone
+        drop(es);
+
+        let store = Store::open("auto").unwrap();
+        let es = EvalStateBuilder::new()
+            .store(store)
+            .pure_eval(false)
+            .build()
+            .unwrap();
+        let v = es
+            .eval_from_string("builtins.currentTime", SourceName::Synthetic("test"))
+            .unwrap();
+        assert!(es.require_int(&v).unwrap() > 0);
+    })
+    .unwrap();
+}
+
+#[test]
+fn eval_state_builder_allow_path() {
+    let _settings = lock_settings();
+    let dir = std::env::temp_dir().join(format!("nix-expr-allow-{}", std::process::id()));
+    let allowed = dir.join("allowed");
+    let denied = dir.join("denied");
+    std::fs::create_dir_all(&allowed).unwrap();
+    std::fs::create_dir_all(&denied).unwrap();
+    std::fs::write(allowed.join("file"), "ok").unwrap();
+    std::fs::write(denied.join("file"), "secret").unwrap();
+    let read = |p: &Path| format!("builtins.readFile {}", p.display());
+    gc_registering_current_thread(|| {
+        let store = Store::open("auto").unwrap();
+        let es = EvalStateBuilder::new()
+            .store(store)
+            .restrict_eval(true)
+            .allow_path(&allowed)
+            .build()
+            .unwrap();
+        let v = es
+            .eval_from_string(read(&allowed.join("file")), SourceName::Synthetic("test"))
+            .unwrap();
+        assert_eq!(es.require_string(&v).unwrap(), "ok");
+
+        let e = es
+            .eval_from_string(read(&denied.join("file")), SourceName::Synthetic("test"))
+            .err()
+            .unwrap();
+        let e = e.downcast_ref::<NixError>().unwrap();
+        assert_eq!(e.forbidden_path(), Some(denied.join("file").as_path()));
+        drop(v);
+        drop(es);
+
+        let store = Store::open("auto").unwrap();
+        let es = EvalStateBuilder::new()
+            .store(store)
+            .allow_path(&allowed)
+            .deny_all_reads()
+            .build()
+            .unwrap();
+        let e = es
+            .eval_from_string(read(&allowed.join("file")), SourceName::Synthetic("test"))
+            .err()
+            .unwrap();
+        let e = e.downcast_ref::<NixError>().unwrap();
+        assert_eq!(e.forbidden_path(), Some(allowed.join("file").as_path()));
+        drop(es);
+
+        // The next EvalState gets the configured settings back
+        let store = Store::open("auto").unwrap();
+        let es = EvalState::new(store).unwrap();
+        let v = es
+            .eval_from_string(read(&denied.join("file")), SourceName::Synthetic("test"))
+            .unwrap();
+        assert_eq!(es.require_string(&v).unwrap(), "secret");
+    })
+    .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn eval_state_builder_conflicting_settings() {
+    let _settings = lock_settings();
+    let dir = std::env::temp_dir().join(format!("nix-expr-conflict-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("file");
+    std::fs::write(&file, "secret").unwrap();
+    let read = format!("builtins.readFile {}", file.display());
+    gc_registering_current_thread(|| {
+        let untrusted = EvalStateBuilder::new()
+            .store(Store::open("auto").unwrap())
+            .deny_all_reads()
+            .build()
+            .unwrap();
+        let e = EvalState::new(Store::open("auto").unwrap()).err().unwrap();
+        assert!(
+            e.to_string()
+                .contains("restrict-eval = \"true\", not \"false\""),
+            "{}",
+            e
+        );
+        // The same settings are fine
+        let also_untrusted = EvalStateBuilder::new()
+            .store(Store::open("auto").unwrap())
+            .deny_all_reads()
+            .build()
+            .unwrap();
+
+        // Also from another thread, like an EvalStatePool worker
+        let e = std::thread::spawn(|| {
+            gc_registering_current_thread(|| {
+                EvalState::new(Store::open("auto").unwrap())
+                    .err()
+                    .unwrap()
+                    .to_string()
+            })
+            .unwrap()
+        })
+        .join()
+        .unwrap();
+        assert!(
+            e.contains("restrict-eval = \"true\", not \"false\""),
+            "{}",
+            e
+        );
+
+        for es in [&untrusted, &also_untrusted] {
+            let e = es
+                .eval_from_string(&read, SourceName::Synthetic("test"))
+                .err()
+                .unwrap();
+            let e = e.downcast_ref::<NixError>().unwrap();
+            assert_eq!(e.forbidden_path(), Some(file.as_path()));
+        }
+        drop(untrusted);
+        drop(also_untrusted);
+
+        let permissive = EvalState::new(Store::open("auto").unwrap()).unwrap();
+        let v = permissive
+            .eval_from_string(&read, SourceName::Synthetic("test"))
+            .unwrap();
+        assert_eq!(permissive.require_string(&v).unwrap(), "secret");
+    })
+    .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn eval_state_instantiate_no_ifd() {
+    let _settings = lock_settings();
+    gc_registering_current_thread(|| {
+        let store = Store::open("auto").unwrap();
+        let es = EvalStateBuilder::new()
+            .store(store)
+            .allow_import_from_derivation(false)
+            .build()
+            .unwrap();
+        let v = es
+            .eval_from_string(
+                r#"
+                let dep = derivation { name = "dep"; system = "dummy"; builder = "cmd.exe"; };
+                in derivation { name = "ifd"; system = "dummy"; builder = "cmd.exe"; x = import dep; }
+                "#,
+                SourceName::Synthetic("test"),
+            )
+            .unwrap();
+        let e = es.instantiate(&v).unwrap_err();
+        assert!(
+            format!("{:#}", e).contains("allow-import-from-derivation"),
+            "{:#}",
+            e
+        );
+    })
+    .unwrap();
+}
+
+#[test]
+fn eval_state_pool_settings_and_errors() {
+    let _settings = lock_settings();
+    let pool = EvalStatePool::new("auto", 2, |b| b.pure_eval(true)).unwrap();
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+    let e = rt
+        .block_on(pool.eval_json(
+            "builtins.currentTime",
+            SourceName::Synthetic("test"),
+            StringContextPolicy::Reject,
+        ))
+        .unwrap_err();
+    assert!(format!("{:#}", e).contains("currentTime"), "{:#}", e);
+    let r = rt
+        .block_on(pool.eval_realised_string(
+            "\"${builtins.toFile \"hello.txt\" \"hello\"}\"",
+            SourceName::Synthetic("test"),
+            false,
+        ))
+        .unwrap();
+    assert_eq!(r.paths.len(), 1);
+    assert_eq!(r.string, r.paths[0].to_string());
+}
@@ -0,0 +1,37 @@
+//! Initialization is global to the process, so it is tested in its own test binary, in a single test to control the order.
+
+use nix_expr::eval_state::{init, init_with, InitOptions};
+use nix_util::settings;
+use std::path::PathBuf;
+
+#[test]
+fn init_with_options() {
+    let bogus = InitOptions {
+        plugin_files: vec![PathBuf::from("/nonexistent/nixops4-plugin.so")],
+        ..InitOptions::default()
+    };
+    let e = init_with(bogus).unwrap_err();
+    let msg = format!("{:#}", e);
+    assert!(
+        msg.starts_with("while loading plugins /nonexistent/nixops4-plugin.so: "),
+        "{}",
+        msg
+    );
+
+    let options = InitOptions {
+        settings: vec![("cores".to_string(), "3".to_string())],
+        ..InitOptions::default()
+    };
+    init_with(options.clone()).unwrap();
+    assert_eq!(settings::get("cores").unwrap(), "3");
+    init_with(options.clone()).unwrap();
+    init().unwrap();
+
+    let e = init_with(InitOptions::default()).unwrap_err();
+    assert!(
+        e.to_string()
+            .starts_with("Nix was already initialized with different options: "),
+        "{}",
+        e
+    );
+}
@@ -0,0 +1,256 @@
+//! Derivations, as read from `.drv` files.
+
+use crate::path::StorePath;
+use crate::store::Store;
+use anyhow::{bail, Context as _, Result};
+use nix_util::hash::{Algo, Hash};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// An output of a [`Derivation`]. Which fields are set depends on the kind of output:
+///
+/// - input addressed: `path`
+/// - fixed-output: `path`, `hash_algo` and `hash`
+/// - floating content addressed: `hash_algo`
+/// - impure: `hash_algo`, while the `.drv` has `impure` for the hash
+/// - deferred, when an input is floating content addressed: none
+#[derive(Debug, PartialEq, Eq)]
+pub struct Output {
+    pub path: Option<StorePath>,
+    /// The algorithm with the ingestion method, e.g. `r:sha256` for a NAR hash or `sha256` for a flat one.
+    pub hash_algo: Option<String>,
+    pub hash: Option<Hash>,
+}
+
+/// A derivation, as in `nix derivation show`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Derivation {
+    pub outputs: BTreeMap<String, Output>,
+    /// The derivations that this one depends on, with the outputs that it uses.
+    pub input_drvs: Vec<(StorePath, BTreeSet<String>)>,
+    pub input_srcs: Vec<StorePath>,
+    /// The system to build on, e.g. `x86_64-linux`.
+    pub platform: String,
+    pub builder: String,
+    pub args: Vec<String>,
+    pub env: BTreeMap<String, String>,
+}
+impl Derivation {
+    /// Read a derivation from the store.
+    ///
+    /// The C API can't read derivations, so this reads the `.drv` file itself, which requires a store that is on the local file system.
+    pub fn parse(store: &Store, drv_path: &StorePath) -> Result<Derivation> {
+        let text = std::fs::read_to_string(drv_path.as_str())
+            .with_context(|| format!("while reading derivation {}", drv_path))?;
+        Self::from_aterm(store, &text)
+            .with_context(|| format!("while parsing derivation {}", drv_path))
+    }
+
+    /// Parse the contents of a `.drv` file, in the ATerm format.
+    pub fn from_aterm(store: &Store, text: &str) -> Result<Derivation> {
+        let mut p = Parser { s: text, pos: 0 };
+        p.expect("Derive(")?;
+        let outputs = p.list(|p| {
+            p.expect("(")?;
+            let name = p.string()?;
+            p.expect(",")?;
+            let path = p.string()?;
+            p.expect(",")?;
+            let hash_algo = p.string()?;
+            p.expect(",")?;
+            let hash = p.string()?;
+            p.expect(")")?;
+            let hash = if hash.is_empty() || hash == "impure" {
+                None
+            } else {
+                let algo = hash_algo.rsplit(':').next().unwrap_or_default();
+                Some(Hash::parse_digest(Algo::parse(algo)?, &hash)?)
+            };
+            let output = Output {
+                path: non_empty(path)
+                    .map(|path| store.parse_store_path(&path))
+                    .transpose()?,
+                hash_algo: non_empty(hash_algo),
+                hash,
+            };
+            Ok((name, output))
+        })?;
+        p.expect(",")?;
+        let input_drvs = p.list(|p| {
+            p.expect("(")?;
+            let path = store.parse_store_path(&p.string()?)?;
+            p.expect(",")?;
+            let outputs = p.list(|p| p.string())?;
+            p.expect(")")?;
+            Ok((path, outputs.into_iter().collect()))
+        })?;
+        p.expect(",")?;
+        let input_srcs = p.list(|p| store.parse_store_path(&p.string()?))?;
+        p.expect(",")?;
+        let platform = p.string()?;
+        p.expect(",")?;
+        let builder = p.string()?;
+        p.expect(",")?;
+        let args = p.list(|p| p.string())?;
+        p.expect(",")?;
+        let env = p.list(|p| {
+            p.expect("(")?;
+            let name = p.string()?;
+            p.expect(",")?;
+            let value = p.string()?;
+            p.expect(")")?;
+            Ok((name, value))
+        })?;
+        p.expect(")")?;
+        if p.pos != text.len() {
+            bail!("unexpected text after the derivation at byte {}", p.pos);
+        }
+        Ok(Derivation {
+            outputs: outputs.into_iter().collect(),
+            input_drvs,
+            input_srcs,
+            platform,
+            builder,
+            args,
+            env: env.into_iter().collect(),
+        })
+    }
+}
+
+fn non_empty(s: String) -> Option<String> {
+    if s.is_empty() {
+        None
+    } else {
+        Some(s)
+    }
+}
+
+struct Parser<'a> {
+    s: &'a str,
+    pos: usize,
+}
+impl Parser<'_> {
+    fn expect(&mut self, lit: &str) -> Result<()> {
+        if !self.s[self.pos..].starts_with(lit) {
+            bail!("expected {:?} at byte {} of the derivation", lit, self.pos);
+        }
+        self.pos += lit.len();
+        Ok(())
+    }
+    fn string(&mut self) -> Result<String> {
+        self.expect("\"")?;
+        let mut r = String::new();
+        let mut chars = self.s[self.pos..].char_indices();
+        loop {
+            let (i, c) = match chars.next() {
+                Some(ic) => ic,
+                None => bail!("unterminated string at byte {} of the derivation", self.pos),
+            };
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(r);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => r.push('\n'),
+                    Some((_, 'r')) => r.push('\r'),
+                    Some((_, 't')) => r.push('\t'),
+                    Some((_, c)) => r.push(c),
+                    None => bail!("unterminated string at byte {} of the derivation", self.pos),
+                },
+                c => r.push(c),
+            }
+        }
+    }
+    fn list<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        self.expect("[")?;
+        let mut items = Vec::new();
+        if self.s[self.pos..].starts_with(']') {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(item(self)?);
+            if self.s[self.pos..].starts_with(',') {
+                self.pos += 1;
+            } else {
+                self.expect("]")?;
+                return Ok(items);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEP: &str = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-dep.drv";
+    const SRC: &str = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-builder.sh";
+    const OUT: &str = "/nix/store/cccccccccccccccccccccccccccccccc-hello";
+
+    #[test]
+    fn derivation_from_aterm() {
+        let store = Store::open("auto").unwrap();
+        let text = format!(
+            r#"Derive([("doc","","r:sha256",""),("out","{}","","")],[("{}",["dev","out"])],["{}"],"x86_64-linux","/bin/sh",["-e","{}"],[("name","hello"),("script","echo \"hi\"\n\\")])"#,
+            OUT, DEP, SRC, SRC
+        );
+        let d = Derivation::from_aterm(&store, &text).unwrap();
+        assert_eq!(d.platform, "x86_64-linux");
+        assert_eq!(d.builder, "/bin/sh");
+        assert_eq!(d.args, ["-e", SRC]);
+        assert_eq!(d.env["name"], "hello");
+        assert_eq!(d.env["script"], "echo \"hi\"\n\\");
+        assert_eq!(d.outputs["out"].path.as_ref().unwrap().as_str(), OUT);
+        assert_eq!(d.outputs["out"].hash_algo, None);
+        assert_eq!(d.outputs["doc"].path, None);
+        assert_eq!(d.outputs["doc"].hash_algo.as_deref(), Some("r:sha256"));
+        assert_eq!(d.outputs["doc"].hash, None);
+        match d.input_drvs.as_slice() {
+            [(path, outputs)] => {
+                assert_eq!(path.as_str(), DEP);
+                assert_eq!(outputs.iter().collect::<Vec<_>>(), ["dev", "out"]);
+            }
+            _ => panic!("unexpected input_drvs: {:?}", d.input_drvs),
+        }
+        assert_eq!(d.input_srcs.len(), 1);
+        assert_eq!(d.input_srcs[0].as_str(), SRC);
+    }
+
+    #[test]
+    fn derivation_fixed_output() {
+        let store = Store::open("auto").unwrap();
+        let text = format!(
+            r#"Derive([("out","{}","sha256","2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")],[],[],"builtin","builtin:fetchurl",[],[])"#,
+            OUT
+        );
+        let d = Derivation::from_aterm(&store, &text).unwrap();
+        let out = &d.outputs["out"];
+        assert_eq!(out.hash_algo.as_deref(), Some("sha256"));
+        assert_eq!(
+            out.hash.as_ref().unwrap().to_sri(),
+            "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ="
+        );
+        assert!(d.args.is_empty() && d.env.is_empty() && d.input_drvs.is_empty());
+    }
+
+    #[test]
+    fn derivation_invalid() {
+        let store = Store::open("auto").unwrap();
+        let e = Derivation::from_aterm(&store, "DrvWithVersion(\"xp-dyn-drv\",").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "expected \"Derive(\" at byte 0 of the derivation"
+        );
+        let e = Derivation::from_aterm(&store, "Derive([(\"out").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "unterminated string at byte 10 of the derivation"
+        );
+        let e = Derivation::from_aterm(&store, "Derive([],[],[],\"x\",\"y\",[],[])x").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "unexpected text after the derivation at byte 30"
+        );
+    }
+}
@@ -1 +1,6 @@
+pub mod derivation;
+pub mod nar;
+pub mod path;
+pub mod profile;
+pub mod ssh;
 pub mod store;
@@ -0,0 +1,194 @@
+//! Serializing store paths as NARs, Nix's archive format.
+//!
+//! The C API has no NAR functions, so this reads the store path from the file system, which requires a store that is on the local file system.
+//! Importing NARs needs the store to add the path and its info, which the C API doesn't support yet.
+
+use crate::path::StorePath;
+use crate::store::Store;
+use anyhow::{bail, Context as _, Result};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+impl Store {
+    /// Write the NAR serialization of a store path to `sink`, like `nix-store --dump`.
+    ///
+    /// File contents are streamed, not read into memory.
+    pub fn nar_from_path(&self, path: &StorePath, sink: impl Write) -> Result<()> {
+        dump_path(Path::new(path.as_str()), sink)
+            .with_context(|| format!("while serializing {} as a NAR", path))
+    }
+}
+
+/// Write the NAR serialization of any file, directory or symlink to `sink`.
+pub fn dump_path(path: &Path, sink: impl Write) -> Result<()> {
+    let mut sink = io::BufWriter::new(sink);
+    write_str(&mut sink, b"nix-archive-1")?;
+    dump(path, &mut sink)?;
+    sink.flush()?;
+    Ok(())
+}
+
+fn dump(path: &Path, sink: &mut impl Write) -> Result<()> {
+    let metadata =
+        fs::symlink_metadata(path).with_context(|| format!("reading {}", path.display()))?;
+    write_str(sink, b"(")?;
+    write_str(sink, b"type")?;
+    let file_type = metadata.file_type();
+    if file_type.is_file() {
+        write_str(sink, b"regular")?;
+        if metadata.permissions().mode() & 0o100 != 0 {
+            write_str(sink, b"executable")?;
+            write_str(sink, b"")?;
+        }
+        write_str(sink, b"contents")?;
+        let len = metadata.len();
+        sink.write_all(&len.to_le_bytes())?;
+        let mut file =
+            fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let copied =
+            io::copy(&mut file, sink).with_context(|| format!("reading {}", path.display()))?;
+        if copied != len {
+            bail!("{} changed size while it was being read", path.display());
+        }
+        write_padding(sink, len)?;
+    } else if file_type.is_symlink() {
+        write_str(sink, b"symlink")?;
+        write_str(sink, b"target")?;
+        let target =
+            fs::read_link(path).with_context(|| format!("reading link {}", path.display()))?;
+        write_str(sink, target.as_os_str().as_bytes())?;
+    } else if file_type.is_dir() {
+        write_str(sink, b"directory")?;
+        let mut names = fs::read_dir(path)
+            .with_context(|| format!("reading directory {}", path.display()))?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<Result<Vec<_>>>()?;
+        // Byte order, like Nix
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+        for name in names {
+            write_str(sink, b"entry")?;
+            write_str(sink, b"(")?;
+            write_str(sink, b"name")?;
+            write_str(sink, name.as_bytes())?;
+            write_str(sink, b"node")?;
+            dump(&path.join(&name), sink)?;
+            write_str(sink, b")")?;
+        }
+    } else {
+        bail!("{} has an unsupported file type", path.display());
+    }
+    write_str(sink, b")")?;
+    Ok(())
+}
+
+/// A NAR string: its length as a 64-bit little-endian integer, then the bytes, padded with zeros to a multiple of 8 bytes.
+fn write_str(sink: &mut impl Write, s: &[u8]) -> Result<()> {
+    sink.write_all(&(s.len() as u64).to_le_bytes())?;
+    sink.write_all(s)?;
+    write_padding(sink, s.len() as u64)
+}
+
+fn write_padding(sink: &mut impl Write, len: u64) -> Result<()> {
+    let padding = (8 - len % 8) % 8;
+    sink.write_all(&[0u8; 8][..padding as usize])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nar_str(s: &[u8]) -> Vec<u8> {
+        let mut v = Vec::new();
+        write_str(&mut v, s).unwrap();
+        v
+    }
+
+    #[test]
+    fn nar_string_padding() {
+        assert_eq!(nar_str(b""), [0; 8]);
+        assert_eq!(
+            nar_str(b"("),
+            [1, 0, 0, 0, 0, 0, 0, 0, b'(', 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(nar_str(b"12345678").len(), 16);
+    }
+
+    #[test]
+    fn dump_path_tree() {
+        let dir = std::env::temp_dir().join(format!("nix-store-nar-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("empty")).unwrap();
+        fs::write(dir.join("hello"), "hi").unwrap();
+        fs::write(dir.join("run"), "").unwrap();
+        fs::set_permissions(dir.join("run"), fs::Permissions::from_mode(0o755)).unwrap();
+        std::os::unix::fs::symlink("hello", dir.join("link")).unwrap();
+
+        let mut nar = Vec::new();
+        dump_path(&dir, &mut nar).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut expected = Vec::new();
+        for s in [
+            &b"nix-archive-1"[..],
+            b"(",
+            b"type",
+            b"directory",
+            b"entry",
+            b"(",
+            b"name",
+            b"empty",
+            b"node",
+            b"(",
+            b"type",
+            b"directory",
+            b")",
+            b")",
+            b"entry",
+            b"(",
+            b"name",
+            b"hello",
+            b"node",
+            b"(",
+            b"type",
+            b"regular",
+            b"contents",
+            b"hi",
+            b")",
+            b")",
+            b"entry",
+            b"(",
+            b"name",
+            b"link",
+            b"node",
+            b"(",
+            b"type",
+            b"symlink",
+            b"target",
+            b"hello",
+            b")",
+            b")",
+            b"entry",
+            b"(",
+            b"name",
+            b"run",
+            b"node",
+            b"(",
+            b"type",
+            b"regular",
+            b"executable",
+            b"",
+            b"contents",
+            b"",
+            b")",
+            b")",
+            b")",
+        ] {
+            expected.extend(nar_str(s));
+        }
+        assert_eq!(nar, expected);
+    }
+}
@@ -0,0 +1,177 @@
+use anyhow::{bail, Result};
+use nix_c_raw as raw;
+use std::fmt;
+use std::ptr::NonNull;
+
+/// A path in the Nix store, e.g. `/nix/store/<hash>-hello`, as parsed by a [`Store`](crate::store::Store).
+///
+/// The C API can parse store paths, but not print them, so the path is kept as text alongside, in the canonical form that Nix parsed.
+pub struct StorePath {
+    raw: NonNull<raw::StorePath>,
+    path: String,
+}
+impl StorePath {
+    /// Take ownership of a store path that was parsed from `path`, which must be canonical.
+    pub(crate) fn new(raw: NonNull<raw::StorePath>, path: String) -> Self {
+        StorePath { raw, path }
+    }
+    pub fn raw_ptr(&self) -> *mut raw::StorePath {
+        self.raw.as_ptr()
+    }
+    /// The path, including the store directory.
+    pub fn as_str(&self) -> &str {
+        &self.path
+    }
+    /// The hash part of the path, e.g. `<hash>` for `/nix/store/<hash>-hello`.
+    pub fn hash_part(&self) -> &str {
+        let base = self.path.rsplit('/').next().unwrap_or_default();
+        base.split_once('-').map(|(hash, _)| hash).unwrap_or(base)
+    }
+    /// The name part of the path, e.g. `hello` for `/nix/store/<hash>-hello`.
+    pub fn name(&self) -> &str {
+        let base = self.path.rsplit('/').next().unwrap_or_default();
+        base.split_once('-')
+            .map(|(_, name)| name)
+            .unwrap_or_default()
+    }
+}
+// A StorePath is an immutable C++ value, not managed by the garbage collector, so it can be used and freed on any thread.
+unsafe impl Send for StorePath {}
+unsafe impl Sync for StorePath {}
+impl Drop for StorePath {
+    fn drop(&mut self) {
+        unsafe {
+            raw::nix_store_path_free(self.raw.as_ptr());
+        }
+    }
+}
+impl PartialEq for StorePath {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path
+    }
+}
+impl Eq for StorePath {}
+impl fmt::Debug for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("StorePath").field(&self.path).finish()
+    }
+}
+impl fmt::Display for StorePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.path)
+    }
+}
+
+/// The length of the hash part of a store path.
+const HASH_LEN: usize = 32;
+/// The maximum length of the name part of a store path.
+const MAX_NAME_LEN: usize = 211;
+
+/// The name part of a store path, e.g. `hello-2.12` in `/nix/store/<hash>-hello-2.12`.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct StorePathName(String);
+impl StorePathName {
+    pub fn new(name: &str) -> Result<StorePathName> {
+        Self::validate(name)?;
+        Ok(StorePathName(name.to_string()))
+    }
+    /// Check a name with the rules of Nix: at most 211 characters out of `A-Za-z0-9+-._?=`, and not `.`, `..` or starting with `.-` or `..-`.
+    pub fn validate(name: &str) -> Result<()> {
+        if name.is_empty() {
+            bail!("store path name is empty");
+        }
+        if name.len() > MAX_NAME_LEN {
+            bail!(
+                "store path name {:?} is longer than {} characters",
+                name,
+                MAX_NAME_LEN
+            );
+        }
+        if name == "." || name == ".." || name.starts_with(".-") || name.starts_with("..-") {
+            bail!("store path name {:?} is not valid", name);
+        }
+        if let Some(c) = name
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || "+-._?=".contains(*c)))
+        {
+            bail!(
+                "store path name {:?} contains illegal character {:?}",
+                name,
+                c
+            );
+        }
+        Ok(())
+    }
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+impl fmt::Display for StorePathName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Split a store path into its hash part and name, with the same rules as [`Store::parse_store_path`](crate::store::Store::parse_store_path), but without a store.
+///
+/// `store_dir` is the store directory, e.g. `/nix/store`. As in Nix, `path` is made canonical first, so `/nix//store/<hash>-hello/` is accepted.
+pub fn parse_store_path_components(store_dir: &str, path: &str) -> Result<(String, StorePathName)> {
+    let canonical = canon_path(path)?;
+    let (dir, base) = match canonical.rsplit_once('/') {
+        Some(("", base)) => ("/", base),
+        Some((dir, base)) => (dir, base),
+        None => unreachable!("canonical paths are absolute"),
+    };
+    if dir != store_dir {
+        bail!("path {:?} is not in the Nix store", canonical);
+    }
+    parse_base_name(base)
+}
+
+/// Whether a string has the form of a store path in some store directory, `<dir>/<hash>-<name>`.
+pub fn is_store_path_like(path: &str) -> bool {
+    match path.rsplit_once('/') {
+        Some((dir, base)) => dir.starts_with('/') && parse_base_name(base).is_ok(),
+        None => false,
+    }
+}
+
+fn parse_base_name(base: &str) -> Result<(String, StorePathName)> {
+    if base.len() < HASH_LEN + 1 {
+        bail!("{:?} is too short to be a valid store path", base);
+    }
+    let (hash, rest) = base.split_at(HASH_LEN);
+    if let Some(c) = hash
+        .chars()
+        .find(|c| !(c.is_ascii_digit() || c.is_ascii_lowercase()) || "eout".contains(*c))
+    {
+        bail!(
+            "store path {:?} contains illegal base-32 character {:?}",
+            base,
+            c
+        );
+    }
+    // Nix doesn't check the separator either
+    let name = StorePathName::new(&rest[1..])?;
+    Ok((hash.to_string(), name))
+}
+
+/// Like Nix's `canonPath`: an absolute path without `.`, `..`, repeated or trailing slashes.
+pub(crate) fn canon_path(path: &str) -> Result<String> {
+    if !path.starts_with('/') {
+        bail!("not an absolute path: {:?}", path);
+    }
+    let mut components = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            c => components.push(c),
+        }
+    }
+    Ok(format!("/{}", components.join("/")))
+}
+
+// Tested in store.rs
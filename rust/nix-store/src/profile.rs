@@ -0,0 +1,348 @@
+//! Profiles and their generations, as managed by `nix-env`.
+//!
+//! A profile is a symlink `<profile>` to a generation link `<profile>-<number>-link` in the same directory, which points to a store path.
+//! The C API has no profile functions, so this manages the links itself, the same way Nix does.
+//!
+//! Nix registers generation links as GC roots. The C API can't register roots yet, so generations only protect their store paths from garbage collection if the profile is in a directory that is already a root, such as `/nix/var/nix/profiles`.
+
+use crate::path::StorePath;
+use crate::store::Store;
+use anyhow::{bail, Context as _, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// A generation of a profile.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Generation {
+    pub number: u64,
+    /// The modification time of the generation link.
+    pub creation_time: SystemTime,
+    pub store_path: StorePath,
+    /// Whether the profile points to this generation.
+    pub current: bool,
+}
+
+impl Store {
+    /// Make `path` the current generation of a profile, like `nix-env --set`.
+    ///
+    /// Like Nix, this adds a generation, unless the last generation already points to `path`.
+    pub fn profile_set(&self, profile: &Path, path: &StorePath) -> Result<Generation> {
+        let f = || -> Result<Generation> {
+            let generations = self.profile_list_generations(profile)?;
+            let number = match generations.last() {
+                Some(last) if &last.store_path == path => last.number,
+                Some(last) => {
+                    let number = last.number + 1;
+                    replace_symlink(Path::new(path.as_str()), &generation_link(profile, number)?)?;
+                    number
+                }
+                None => {
+                    replace_symlink(Path::new(path.as_str()), &generation_link(profile, 1)?)?;
+                    1
+                }
+            };
+            self.switch_generation(profile, number)?;
+            self.profile_generation(profile, number, Some(number))
+        };
+        f().with_context(|| format!("while setting profile {}", profile.display()))
+    }
+
+    /// The generations of a profile, oldest first. A profile that doesn't exist has none.
+    pub fn profile_list_generations(&self, profile: &Path) -> Result<Vec<Generation>> {
+        let current = current_generation(profile)?;
+        generation_numbers(profile)?
+            .into_iter()
+            .map(|number| self.profile_generation(profile, number, current))
+            .collect()
+    }
+
+    /// Switch a profile to an older generation, like `nix-env --rollback` and `nix-env --switch-generation`.
+    ///
+    /// With `to` `None`, this switches to the newest generation before the current one.
+    pub fn profile_rollback(&self, profile: &Path, to: Option<u64>) -> Result<Generation> {
+        let f = || -> Result<Generation> {
+            let numbers = generation_numbers(profile)?;
+            let number = match to {
+                Some(to) => {
+                    if !numbers.contains(&to) {
+                        bail!("generation {} does not exist", to);
+                    }
+                    to
+                }
+                None => {
+                    let current = match current_generation(profile)? {
+                        Some(current) => current,
+                        None => bail!("the profile has no current generation"),
+                    };
+                    match numbers.iter().rev().find(|n| **n < current) {
+                        Some(n) => *n,
+                        None => bail!("no generation older than the current ({}) exists", current),
+                    }
+                }
+            };
+            self.switch_generation(profile, number)?;
+            self.profile_generation(profile, number, Some(number))
+        };
+        f().with_context(|| format!("while rolling back profile {}", profile.display()))
+    }
+
+    /// Delete the generations that were replaced more than `older_than` ago, like `nix-env --delete-generations <days>d`.
+    ///
+    /// Like Nix, this keeps the generation that was current `older_than` ago, so that the profile can still be rolled back to it, and never deletes the current generation.
+    pub fn profile_delete_generations(&self, profile: &Path, older_than: Duration) -> Result<()> {
+        let f = || -> Result<()> {
+            let cutoff = SystemTime::now()
+                .checked_sub(older_than)
+                .unwrap_or(SystemTime::UNIX_EPOCH);
+            let generations = self.profile_list_generations(profile)?;
+            let mut older = generations
+                .iter()
+                .rev()
+                .skip_while(|g| g.creation_time >= cutoff);
+            // It was current at the cutoff
+            older.next();
+            for g in older {
+                if g.current {
+                    continue;
+                }
+                let link = generation_link(profile, g.number)?;
+                fs::remove_file(&link)
+                    .with_context(|| format!("while deleting {}", link.display()))?;
+            }
+            Ok(())
+        };
+        f().with_context(|| {
+            format!(
+                "while deleting old generations of profile {}",
+                profile.display()
+            )
+        })
+    }
+
+    fn profile_generation(
+        &self,
+        profile: &Path,
+        number: u64,
+        current: Option<u64>,
+    ) -> Result<Generation> {
+        let link = generation_link(profile, number)?;
+        let metadata = fs::symlink_metadata(&link)
+            .with_context(|| format!("while reading {}", link.display()))?;
+        let target = fs::read_link(&link)
+            .with_context(|| format!("while reading link {}", link.display()))?;
+        let target = match target.to_str() {
+            Some(target) => target,
+            None => bail!("{} does not point to a store path", link.display()),
+        };
+        Ok(Generation {
+            number,
+            creation_time: metadata.modified()?,
+            store_path: self.parse_store_path(target)?,
+            current: current == Some(number),
+        })
+    }
+
+    fn switch_generation(&self, profile: &Path, number: u64) -> Result<()> {
+        let link = generation_link(profile, number)?;
+        // Like Nix, point to the generation link relative to the profile's directory
+        let target = link.file_name().map(PathBuf::from).unwrap_or(link);
+        replace_symlink(&target, profile)
+    }
+}
+
+fn profile_name(profile: &Path) -> Result<&str> {
+    match profile.file_name().and_then(|n| n.to_str()) {
+        Some(name) => Ok(name),
+        None => bail!("invalid profile path {}", profile.display()),
+    }
+}
+
+fn generation_link(profile: &Path, number: u64) -> Result<PathBuf> {
+    Ok(profile.with_file_name(format!("{}-{}-link", profile_name(profile)?, number)))
+}
+
+/// Parse `<name>-<number>-link`.
+fn parse_generation_link(name: &str, file_name: &str) -> Option<u64> {
+    let number = file_name
+        .strip_prefix(name)?
+        .strip_prefix('-')?
+        .strip_suffix("-link")?;
+    if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// The generation numbers of a profile, in ascending order.
+fn generation_numbers(profile: &Path) -> Result<Vec<u64>> {
+    let name = profile_name(profile)?;
+    let dir = match profile.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => bail!("invalid profile path {}", profile.display()),
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("while reading directory {}", dir.display()))
+        }
+    };
+    let mut numbers = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if let Some(number) = entry
+            .file_name()
+            .to_str()
+            .and_then(|f| parse_generation_link(name, f))
+        {
+            numbers.push(number);
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+fn current_generation(profile: &Path) -> Result<Option<u64>> {
+    let target = match fs::read_link(profile) {
+        Ok(target) => target,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("while reading link {}", profile.display()))
+        }
+    };
+    Ok(target
+        .file_name()
+        .and_then(|f| f.to_str())
+        .and_then(|f| parse_generation_link(profile_name(profile).ok()?, f)))
+}
+
+/// Atomically make `link` a symlink to `target`, by creating a temporary link next to it and renaming it, like Nix's `replaceSymlink`.
+fn replace_symlink(target: &Path, link: &Path) -> Result<()> {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let tmp = link.with_file_name(format!(
+        "{}.tmp-{}-{}",
+        profile_name(link)?,
+        std::process::id(),
+        nanos
+    ));
+    std::os::unix::fs::symlink(target, &tmp)
+        .with_context(|| format!("while creating symlink {}", tmp.display()))?;
+    fs::rename(&tmp, link).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        anyhow::Error::new(e).context(format!(
+            "while renaming {} to {}",
+            tmp.display(),
+            link.display()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH_1: &str = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixops4-test-state-1";
+    const PATH_2: &str = "/nix/store/bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb-nixops4-test-state-2";
+
+    fn targets(profile: &Path) -> (String, Vec<(u64, String)>) {
+        let profile_target = fs::read_link(profile).unwrap();
+        let mut links = generation_numbers(profile)
+            .unwrap()
+            .into_iter()
+            .map(|n| {
+                let link = fs::read_link(generation_link(profile, n).unwrap()).unwrap();
+                (n, link.to_str().unwrap().to_string())
+            })
+            .collect::<Vec<_>>();
+        links.sort();
+        (profile_target.to_str().unwrap().to_string(), links)
+    }
+
+    #[test]
+    fn profile_generations() {
+        let store = Store::open("auto").unwrap();
+        let dir = std::env::temp_dir().join(format!("nix-store-profile-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let profile = dir.join("deployment");
+        let path_1 = store.parse_store_path(PATH_1).unwrap();
+        let path_2 = store.parse_store_path(PATH_2).unwrap();
+        assert!(store.profile_list_generations(&profile).unwrap().is_empty());
+
+        let g = store.profile_set(&profile, &path_1).unwrap();
+        assert_eq!((g.number, g.current), (1, true));
+        let g = store.profile_set(&profile, &path_2).unwrap();
+        assert_eq!((g.number, g.current), (2, true));
+        // Setting the same path again doesn't add a generation
+        let g = store.profile_set(&profile, &path_2).unwrap();
+        assert_eq!(g.number, 2);
+        assert_eq!(
+            targets(&profile),
+            (
+                "deployment-2-link".to_string(),
+                vec![(1, PATH_1.to_string()), (2, PATH_2.to_string())]
+            )
+        );
+
+        let generations = store.profile_list_generations(&profile).unwrap();
+        assert_eq!(
+            generations
+                .iter()
+                .map(|g| (g.number, g.store_path.as_str(), g.current))
+                .collect::<Vec<_>>(),
+            [(1, PATH_1, false), (2, PATH_2, true)]
+        );
+
+        let g = store.profile_rollback(&profile, None).unwrap();
+        assert_eq!((g.number, g.store_path.as_str()), (1, PATH_1));
+        assert_eq!(targets(&profile).0, "deployment-1-link");
+        let e = store.profile_rollback(&profile, None).unwrap_err();
+        assert_eq!(
+            e.root_cause().to_string(),
+            "no generation older than the current (1) exists"
+        );
+        let e = store.profile_rollback(&profile, Some(3)).unwrap_err();
+        assert_eq!(e.root_cause().to_string(), "generation 3 does not exist");
+        store.profile_rollback(&profile, Some(2)).unwrap();
+        assert_eq!(targets(&profile).0, "deployment-2-link");
+
+        // Both generations were created within the hour
+        store
+            .profile_delete_generations(&profile, Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(
+            targets(&profile),
+            (
+                "deployment-2-link".to_string(),
+                vec![(1, PATH_1.to_string()), (2, PATH_2.to_string())]
+            )
+        );
+        let g = store.profile_set(&profile, &path_1).unwrap();
+        assert_eq!(g.number, 3);
+        std::thread::sleep(Duration::from_millis(10));
+        // Generation 3 was current at the cutoff, and the older ones are deleted
+        store
+            .profile_delete_generations(&profile, Duration::ZERO)
+            .unwrap();
+        assert_eq!(
+            targets(&profile),
+            (
+                "deployment-3-link".to_string(),
+                vec![(3, PATH_1.to_string())]
+            )
+        );
+        assert!(fs::read_dir(&dir).unwrap().all(|e| !e
+            .unwrap()
+            .file_name()
+            .to_str()
+            .unwrap()
+            .contains(".tmp-")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+//! Stores on other machines, through `ssh-ng://`.
+
+use crate::store::Store;
+use anyhow::{bail, Context as _, Result};
+use std::path::PathBuf;
+
+/// Connection options for [`Store::open_ssh`]. The defaults are those of `ssh` and Nix.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SshStoreOpts {
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    /// The private key to log in with, the `ssh-key` store parameter.
+    pub identity_file: Option<PathBuf>,
+    /// The `nix-daemon` to run on the remote machine, the `remote-program` store parameter, e.g. when Nix is not on its `PATH`.
+    pub remote_program: Option<String>,
+    /// Compress the connection, the `compress` store parameter.
+    pub compress: bool,
+}
+impl SshStoreOpts {
+    /// The store URI and parameters for [`Store::open_with_params`].
+    pub fn uri_and_params(&self, host: &str) -> Result<(String, Vec<(String, String)>)> {
+        if host.is_empty() {
+            bail!("ssh store host is empty");
+        }
+        if host.contains('@') {
+            bail!(
+                "ssh store host {:?} contains '@'; set the user in SshStoreOpts instead of writing user@host",
+                host
+            );
+        }
+        if let Some(c) = host
+            .chars()
+            .find(|c| c.is_whitespace() || c.is_control() || "/?#".contains(*c))
+        {
+            bail!("ssh store host {:?} contains {:?}", host, c);
+        }
+        let mut uri = "ssh-ng://".to_string();
+        if let Some(user) = &self.user {
+            if user.is_empty() {
+                bail!("ssh store user is empty");
+            }
+            uri.push_str(&percent_encode(user));
+            uri.push('@');
+        }
+        // IPv6 literals are bracketed, so that their colons are not taken for a port
+        if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+            uri.push('[');
+            uri.push_str(host);
+            uri.push(']');
+        } else {
+            uri.push_str(host);
+        }
+        if let Some(port) = self.port {
+            uri.push_str(&format!(":{}", port));
+        }
+
+        let mut params = Vec::new();
+        if let Some(identity_file) = &self.identity_file {
+            let identity_file = match identity_file.to_str() {
+                Some(f) => f,
+                None => bail!(
+                    "ssh identity file {} is not valid UTF-8",
+                    identity_file.display()
+                ),
+            };
+            params.push(("ssh-key".to_string(), identity_file.to_string()));
+        }
+        if let Some(remote_program) = &self.remote_program {
+            params.push(("remote-program".to_string(), remote_program.clone()));
+        }
+        if self.compress {
+            params.push(("compress".to_string(), "true".to_string()));
+        }
+        Ok((uri, params))
+    }
+}
+
+impl Store {
+    /// Open the store of another machine over SSH, with the `ssh-ng` protocol.
+    ///
+    /// Nix connects when the store is first used; call [`Store::ping`] to detect connection problems right away.
+    pub fn open_ssh(host: &str, opts: &SshStoreOpts) -> Result<Store> {
+        let (uri, params) = opts.uri_and_params(host)?;
+        let params = params
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+        Store::open_with_params(&uri, &params).with_context(|| format!("while opening {}", uri))
+    }
+}
+
+/// Percent-encode the characters that are not allowed in the user info of a URI.
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::new();
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || b"-._~".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", b));
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn ssh_store_uri() {
+        let opts = SshStoreOpts::default();
+        assert_eq!(
+            opts.uri_and_params("example.com").unwrap(),
+            ("ssh-ng://example.com".to_string(), vec![])
+        );
+        let opts = SshStoreOpts {
+            user: Some("deploy".to_string()),
+            port: Some(2222),
+            identity_file: Some(PathBuf::from("/run/keys/deploy")),
+            remote_program: Some("/run/current-system/sw/bin/nix-daemon".to_string()),
+            compress: true,
+        };
+        assert_eq!(
+            opts.uri_and_params("10.0.0.1").unwrap(),
+            (
+                "ssh-ng://deploy@10.0.0.1:2222".to_string(),
+                params(&[
+                    ("ssh-key", "/run/keys/deploy"),
+                    ("remote-program", "/run/current-system/sw/bin/nix-daemon"),
+                    ("compress", "true"),
+                ])
+            )
+        );
+        assert_eq!(
+            opts.uri_and_params("::1").unwrap().0,
+            "ssh-ng://deploy@[::1]:2222"
+        );
+        assert_eq!(
+            opts.uri_and_params("[fe80::1]").unwrap().0,
+            "ssh-ng://deploy@[fe80::1]:2222"
+        );
+        let opts = SshStoreOpts {
+            user: Some("ad@corp user".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            opts.uri_and_params("host").unwrap().0,
+            "ssh-ng://ad%40corp%20user@host"
+        );
+    }
+
+    #[test]
+    fn ssh_store_uri_invalid() {
+        let opts = SshStoreOpts::default();
+        let e = opts.uri_and_params("root@host").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "ssh store host \"root@host\" contains '@'; set the user in SshStoreOpts instead of writing user@host"
+        );
+        assert!(opts.uri_and_params("").is_err());
+        assert!(opts.uri_and_params("host/path").is_err());
+        assert!(opts.uri_and_params("host name").is_err());
+    }
+}
@@ -1,11 +1,16 @@
-use anyhow::{bail, Result};
+use crate::path::{canon_path, StorePath};
+use anyhow::{bail, Context as _, Result};
 use lazy_static::lazy_static;
 use nix_c_raw as raw;
 use nix_util::context::Context;
+use nix_util::error::{strip_ansi_escapes, NixError};
+use nix_util::error_site;
 use nix_util::string_return::callback_get_vec_u8;
-use std::ffi::CString;
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CStr, CString};
 use std::ptr::null_mut;
 use std::ptr::NonNull;
+use std::sync::Arc;
 
 /* TODO make Nix itself thread safe */
 lazy_static! {
@@ -13,8 +18,9 @@ lazy_static! {
         unsafe {
             let context: Context = Context::new();
             raw::nix_libstore_init(context.ptr());
-            context.check_err()
+            context.check_err(error_site!("nix_libstore_init"))?;
         }
+        Ok(())
     };
 }
 
@@ -33,14 +39,28 @@ impl Drop for StoreRef {
         }
     }
 }
+// Nix's stores are thread safe; Nix itself queries and builds from several threads at once, and remote stores keep a pool of connections.
+unsafe impl Send for StoreRef {}
+unsafe impl Sync for StoreRef {}
 
+/// A connection to a Nix store.
+///
+/// Clones share the connection and are cheap, so clone a `Store` to use it on another thread.
+/// A `Store` is `Send` but not `Sync`, because each handle has its own error context; the clones of a store can be used concurrently.
 pub struct Store {
-    inner: StoreRef,
+    inner: Arc<StoreRef>,
     /* An error context to reuse. This way we don't have to allocate them for each store operation. */
     context: Context,
 }
 impl Store {
     pub fn open(url: &str) -> Result<Self> {
+        Self::open_with_params(url, &[])
+    }
+
+    /// Open a store with [store parameters](https://nixos.org/manual/nix/stable/store/types/), e.g. `root` for a chroot store.
+    ///
+    /// Nix only warns about parameters that the store type does not support.
+    pub fn open_with_params(url: &str, params: &[(&str, &str)]) -> Result<Self> {
         let x = INIT.as_ref();
         match x {
             Ok(_) => {}
@@ -53,21 +73,39 @@ impl Store {
         let context: Context = Context::new();
 
         let uri_ptr = CString::new(url)?;
-        let store = unsafe {
-            raw::nix_store_open(
-                context.ptr(),
-                uri_ptr.as_ptr(),
-                null_mut::<*mut *const i8>(),
-            )
+        let params = params
+            .iter()
+            .map(|(k, v)| {
+                let k = CString::new(*k)
+                    .with_context(|| format!("store parameter name contains null byte: {:?}", k))?;
+                let v = CString::new(*v).with_context(|| {
+                    format!("value of store parameter {:?} contains null byte", k)
+                })?;
+                Ok((k, v))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // nix_store_open takes a null-terminated array of key-value pairs.
+        let mut pairs: Vec<[*const c_char; 2]> = params
+            .iter()
+            .map(|(k, v)| [k.as_ptr(), v.as_ptr()])
+            .collect();
+        let mut pair_ptrs: Vec<*mut *const c_char> =
+            pairs.iter_mut().map(|pair| pair.as_mut_ptr()).collect();
+        pair_ptrs.push(null_mut());
+        let params_ptr = if params.is_empty() {
+            null_mut()
+        } else {
+            pair_ptrs.as_mut_ptr()
         };
-        context.check_err()?;
+        let store = unsafe { raw::nix_store_open(context.ptr(), uri_ptr.as_ptr(), params_ptr) };
+        context.check_err(error_site!("nix_store_open"))?;
         if store.is_null() {
             bail!("nix_c_store_open returned a null pointer");
         }
         let store = Store {
-            inner: StoreRef {
+            inner: Arc::new(StoreRef {
                 inner: NonNull::new(store).unwrap(),
-            },
+            }),
             context,
         };
         Ok(store)
@@ -77,7 +115,8 @@ impl Store {
         self.inner.ptr()
     }
 
-    pub fn get_uri(&self) -> Result<String> {
+    /// The URI of the store, e.g. `daemon` or `local?root=/tmp/root`.
+    pub fn uri(&self) -> Result<String> {
         let mut raw_buffer: Vec<u8> = Vec::new();
         unsafe {
             raw::nix_store_get_uri(
@@ -87,14 +126,180 @@ impl Store {
                 &mut raw_buffer as *mut Vec<u8> as *mut std::ffi::c_void,
             )
         };
-        self.context.check_err()?;
+        self.context.check_err(error_site!("nix_store_get_uri"))?;
         String::from_utf8(raw_buffer).map_err(|e| e.into())
     }
+
+    /// The version of Nix that the store runs, e.g. that of the daemon, or of this library for a local store.
+    pub fn version(&self) -> Result<String> {
+        let mut raw_buffer: Vec<u8> = Vec::new();
+        unsafe {
+            raw::nix_store_get_version(
+                self.context.ptr(),
+                self.inner.ptr(),
+                callback_get_vec_u8 as *mut std::ffi::c_void,
+                &mut raw_buffer as *mut Vec<u8> as *mut std::ffi::c_void,
+            )
+        };
+        self.context
+            .check_err(error_site!("nix_store_get_version"))?;
+        String::from_utf8(raw_buffer).map_err(|e| e.into())
+    }
+
+    /// Check that the store can be reached, e.g. that an SSH connection can be made, by asking for its [`version`](Self::version).
+    ///
+    /// The error includes the output of `ssh`, if that is what failed.
+    pub fn ping(&self) -> Result<()> {
+        self.version().with_context(|| match self.uri() {
+            Ok(uri) => format!("while connecting to store {}", uri),
+            Err(_) => "while connecting to the store".to_string(),
+        })?;
+        Ok(())
+    }
+
+    /// Parse a store path, e.g. `/nix/store/<hash>-hello`. The path does not need to exist.
+    ///
+    /// Paths outside the store directory, and paths with a malformed hash or name, are rejected with the error message from Nix.
+    /// Like Nix, the path is made canonical, so `/nix//store/<hash>-hello/` yields `/nix/store/<hash>-hello`.
+    pub fn parse_store_path(&self, path: &str) -> Result<StorePath> {
+        let path_ptr = CString::new(path)
+            .with_context(|| format!("parse_store_path: path contains null byte: {:?}", path))?;
+        let raw_path = unsafe {
+            raw::nix_store_parse_path(self.context.ptr(), self.inner.ptr(), path_ptr.as_ptr())
+        };
+        self.context
+            .check_err(error_site!("nix_store_parse_path"))?;
+        match NonNull::new(raw_path) {
+            Some(raw_path) => Ok(StorePath::new(raw_path, canon_path(path)?)),
+            None => bail!("nix_store_parse_path returned a null pointer"),
+        }
+    }
+
+    /// Whether the path exists in the store.
+    pub fn is_valid_path(&self, path: &StorePath) -> Result<bool> {
+        let r = unsafe {
+            raw::nix_store_is_valid_path(self.context.ptr(), self.inner.ptr(), path.raw_ptr())
+        };
+        self.context
+            .check_err(error_site!("nix_store_is_valid_path"))?;
+        Ok(r)
+    }
+
+    /// Build or substitute all outputs of a derivation, and return them by output name.
+    ///
+    /// Outputs that are already valid are not built again.
+    pub fn realise(&self, drv_path: &StorePath) -> Result<BTreeMap<String, StorePath>> {
+        let outputs = self
+            .realise_raw(drv_path)
+            .with_context(|| format!("while building {}", drv_path))?;
+        self.parse_outputs(outputs)
+    }
+
+    /// Build a derivation, or substitute a path, and report the outcome.
+    ///
+    /// Unlike [`realise`](Self::realise), a failing build is not an error, but a [`BuildResult`] with [`BuildStatus::Failed`].
+    /// A valid path that is not a derivation is not built again, and is reported as [`BuildStatus::AlreadyValid`].
+    /// The C API does not tell whether the outputs of a derivation were built or already valid, so those are always [`BuildStatus::Built`].
+    pub fn build(&self, path: &StorePath) -> Result<BuildResult> {
+        if !path.name().ends_with(".drv") && self.is_valid_path(path)? {
+            return Ok(BuildResult {
+                status: BuildStatus::AlreadyValid,
+                outputs: BTreeMap::new(),
+                error: None,
+            });
+        }
+        match self.realise_raw(path) {
+            Ok(outputs) => Ok(BuildResult {
+                status: BuildStatus::Built,
+                outputs: self.parse_outputs(outputs)?,
+                error: None,
+            }),
+            Err(e) => Ok(BuildResult {
+                status: BuildStatus::Failed,
+                outputs: BTreeMap::new(),
+                error: Some(strip_ansi_escapes(e.message())),
+            }),
+        }
+    }
+
+    /// Build several paths, returning their results in the same order.
+    ///
+    /// The C API realises one path at a time, so the paths are built in turn rather than in a single scheduler pass.
+    pub fn build_many(&self, paths: &[StorePath]) -> Result<Vec<BuildResult>> {
+        paths.iter().map(|path| self.build(path)).collect()
+    }
+
+    #[allow(clippy::result_large_err)] // Converted to anyhow::Error right away
+    fn realise_raw(&self, path: &StorePath) -> Result<Vec<(String, String)>, NixError> {
+        let mut outputs: Vec<(String, String)> = Vec::new();
+        unsafe {
+            raw::nix_store_realise(
+                self.context.ptr(),
+                self.inner.ptr(),
+                path.raw_ptr(),
+                &mut outputs as *mut Vec<(String, String)> as *mut c_void,
+                Some(callback_realise_output),
+            );
+        }
+        self.context.check_err(error_site!("nix_store_realise"))?;
+        Ok(outputs)
+    }
+
+    fn parse_outputs(&self, outputs: Vec<(String, String)>) -> Result<BTreeMap<String, StorePath>> {
+        outputs
+            .into_iter()
+            .map(|(name, path)| Ok((name, self.parse_store_path(&path)?)))
+            .collect()
+    }
+}
+
+// The context is owned by this handle, and the store is shared with Arc
+unsafe impl Send for Store {}
+
+impl Clone for Store {
+    fn clone(&self) -> Self {
+        Store {
+            inner: self.inner.clone(),
+            context: Context::new(),
+        }
+    }
+}
+
+/// The outcome of [`Store::build`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStatus {
+    Built,
+    AlreadyValid,
+    Failed,
+}
+
+/// The result of building a single path, see [`Store::build`].
+#[derive(Debug)]
+pub struct BuildResult {
+    pub status: BuildStatus,
+    /// The outputs of a derivation by name. Empty unless the status is [`BuildStatus::Built`].
+    ///
+    /// These are the realised paths, so they include the outputs of content-addressed derivations, which are only known after the build.
+    pub outputs: BTreeMap<String, StorePath>,
+    /// The error reported by Nix, including the tail of the build log, if the build failed.
+    pub error: Option<String>,
+}
+
+unsafe extern "C" fn callback_realise_output(
+    userdata: *mut c_void,
+    outname: *const c_char,
+    out: *const c_char,
+) {
+    let outputs = userdata as *mut Vec<(String, String)>;
+    let name = CStr::from_ptr(outname).to_string_lossy().into_owned();
+    let path = CStr::from_ptr(out).to_string_lossy().into_owned();
+    (*outputs).push((name, path));
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::path::{is_store_path_like, parse_store_path_components, StorePathName};
 
     #[test]
     fn auto_works() {
@@ -108,10 +313,22 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn invalid_uri_error_site() {
+        let res = Store::open("invalid://uri");
+        let err = match res {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e,
+        };
+        assert!(err.to_string().starts_with("in nix_store_open (store.rs:"));
+        let nix_err = err.downcast_ref::<NixError>().unwrap();
+        assert_eq!(nix_err.site().call, "nix_store_open");
+    }
+
     #[test]
     fn get_uri() {
         let store = Store::open("auto").unwrap();
-        let uri = store.get_uri().unwrap();
+        let uri = store.uri().unwrap();
         assert!(!uri.is_empty());
         // must be ascii
         assert!(uri.is_ascii());
@@ -119,11 +336,223 @@ mod tests {
         println!("uri: {}", uri);
     }
 
+    #[test]
+    fn clone_shares_store() {
+        let store = Store::open("auto").unwrap();
+        let clone = store.clone();
+        assert_eq!(clone.raw_ptr(), store.raw_ptr());
+        drop(store);
+        assert!(!clone.uri().unwrap().is_empty());
+    }
+
+    #[test]
+    fn open_with_params_root() {
+        let root = std::env::temp_dir().join(format!("nix-store-test-root-{}", std::process::id()));
+        let root = root.to_str().unwrap();
+        let store = Store::open_with_params("local", &[("root", root)]).unwrap();
+        let uri = store.uri().unwrap();
+        assert!(uri.starts_with("local"), "{}", uri);
+        assert!(std::path::Path::new(root).join("nix/store").is_dir());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn version() {
+        let store = Store::open("auto").unwrap();
+        let version = store.version().unwrap();
+        assert!(!version.is_empty());
+    }
+
+    #[test]
+    fn clone_drop_order() {
+        let store = Store::open("auto").unwrap();
+        let clone = store.clone();
+        drop(store);
+        let path = clone.parse_store_path(FAKE_PATH).unwrap();
+        assert!(!clone.is_valid_path(&path).unwrap());
+        let clone2 = clone.clone();
+        drop(clone);
+        drop(path);
+        assert!(!clone2.uri().unwrap().is_empty());
+    }
+
+    #[test]
+    fn send_to_thread() {
+        let store = Store::open("auto").unwrap();
+        let clone = store.clone();
+        let uri = std::thread::spawn(move || clone.uri().unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(uri, store.uri().unwrap());
+    }
+
+    #[test]
+    fn ping() {
+        let store = Store::open("auto").unwrap();
+        store.ping().unwrap();
+    }
+
+    const FAKE_PATH: &str = "/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixops4-test-fake";
+
+    #[test]
+    fn parse_store_path() {
+        let store = Store::open("auto").unwrap();
+        let p = store.parse_store_path(FAKE_PATH).unwrap();
+        assert_eq!(p.as_str(), FAKE_PATH);
+        assert_eq!(p.name(), "nixops4-test-fake");
+        assert_eq!(p.hash_part(), "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        assert_eq!(p.to_string(), FAKE_PATH);
+        assert!(!store.is_valid_path(&p).unwrap());
+    }
+
+    #[test]
+    fn parse_store_path_canonical() {
+        let store = Store::open("auto").unwrap();
+        let p = store
+            .parse_store_path("/nix//store/./aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixops4-test-fake/")
+            .unwrap();
+        assert_eq!(p.as_str(), FAKE_PATH);
+        assert_eq!(p.name(), "nixops4-test-fake");
+        assert_eq!(p.hash_part(), "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+        assert_eq!(p, store.parse_store_path(FAKE_PATH).unwrap());
+    }
+
+    #[test]
+    fn parse_store_path_wrong_prefix() {
+        let store = Store::open("auto").unwrap();
+        let e = store
+            .parse_store_path("/tmp/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-nixops4-test-fake")
+            .unwrap_err();
+        assert!(e.to_string().contains("is not in the Nix store"), "{}", e);
+    }
+
+    #[test]
+    fn parse_store_path_invalid() {
+        let store = Store::open("auto").unwrap();
+        // Malformed hash
+        assert!(store.parse_store_path("/nix/store/tooshort-x").is_err());
+        // Invalid character in the name
+        let e = store
+            .parse_store_path("/nix/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-not*allowed")
+            .unwrap_err();
+        assert!(e.to_string().contains("not*allowed"), "{}", e);
+    }
+
+    #[test]
+    fn parse_store_path_components_agrees() {
+        let store = Store::open("auto").unwrap();
+        let hash = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+        let long_name = "x".repeat(211);
+        let too_long_name = "x".repeat(212);
+        let paths = [
+            FAKE_PATH.to_string(),
+            format!("/nix/store/{}-hello-2.12.drv", hash),
+            format!("/nix/store/{}-a+b=c?d_e.f", hash),
+            format!("/nix/store/{}-{}", hash, long_name),
+            format!("/nix/store/{}-{}", hash, too_long_name),
+            format!("/nix//store/./{}-x/", hash),
+            format!("/nix/store/../store/{}-x", hash),
+            format!("/nix/store/{}-", hash),
+            format!("/nix/store/{}", hash),
+            format!("/nix/store/{}-.", hash),
+            format!("/nix/store/{}-..", hash),
+            format!("/nix/store/{}-.-x", hash),
+            format!("/nix/store/{}-..-x", hash),
+            format!("/nix/store/{}-.x", hash),
+            format!("/nix/store/{}-not*allowed", hash),
+            format!("/nix/store/{}-sp ace", hash),
+            format!("/nix/store/{}-ünicode", hash),
+            format!("/nix/store/{}-x", hash.replace('a', "e")),
+            format!("/nix/store/{}-x", hash.replace('a', "A")),
+            format!("/nix/store/{}-x/bin", hash),
+            "/nix/store/tooshort-x".to_string(),
+            format!("/tmp/{}-x", hash),
+            format!("nix/store/{}-x", hash),
+            "/nix/store".to_string(),
+            "/".to_string(),
+        ];
+        for path in &paths {
+            let ours = parse_store_path_components("/nix/store", path);
+            let nix = store.parse_store_path(path);
+            assert_eq!(
+                ours.is_ok(),
+                nix.is_ok(),
+                "{}: {:?} vs {:?}",
+                path,
+                ours,
+                nix.err()
+            );
+            if let (Ok((hash_part, name)), Ok(p)) = (ours, store.parse_store_path(path)) {
+                // Agrees for non-canonical spellings too, because the StorePath is canonical
+                assert_eq!(p.as_str(), format!("/nix/store/{}-{}", hash_part, name));
+                assert_eq!(hash_part, p.hash_part());
+                assert_eq!(name.as_str(), p.name());
+            }
+        }
+    }
+
+    #[test]
+    fn store_path_name_and_like() {
+        StorePathName::validate("hello-2.12").unwrap();
+        let e = StorePathName::validate("not*allowed").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "store path name \"not*allowed\" contains illegal character '*'"
+        );
+        assert!(StorePathName::validate("").is_err());
+        assert!(is_store_path_like(FAKE_PATH));
+        assert!(is_store_path_like(
+            "/custom/store/aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-x"
+        ));
+        assert!(!is_store_path_like("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa-x"));
+        assert!(!is_store_path_like("/nix/store/hello"));
+    }
+
+    #[test]
+    fn realise_missing_derivation() {
+        let store = Store::open("auto").unwrap();
+        let p = store
+            .parse_store_path(&format!("{}.drv", FAKE_PATH))
+            .unwrap();
+        let e = store.realise(&p).unwrap_err();
+        assert!(e.to_string().contains("nixops4-test-fake.drv"), "{}", e);
+    }
+
+    #[test]
+    fn build_missing_derivation() {
+        let store = Store::open("auto").unwrap();
+        let p = store
+            .parse_store_path(&format!("{}.drv", FAKE_PATH))
+            .unwrap();
+        let r = store.build(&p).unwrap();
+        assert_eq!(r.status, BuildStatus::Failed);
+        assert!(r.outputs.is_empty());
+        let error = r.error.unwrap();
+        assert!(error.contains("nixops4-test-fake.drv"), "{}", error);
+    }
+
+    #[test]
+    fn build_many_missing_derivations() {
+        let store = Store::open("auto").unwrap();
+        let paths = [
+            store
+                .parse_store_path(&format!("{}.drv", FAKE_PATH))
+                .unwrap(),
+            store
+                .parse_store_path(&format!("{}-2.drv", FAKE_PATH))
+                .unwrap(),
+        ];
+        let rs = store.build_many(&paths).unwrap();
+        assert_eq!(rs.len(), 2);
+        assert!(rs[1].error.as_ref().unwrap().contains("fake-2.drv"));
+        assert!(rs.iter().all(|r| r.status == BuildStatus::Failed));
+    }
+
     #[test]
     #[ignore] // Needs network access
     fn get_uri_nixos_cache() {
         let store = Store::open("https://cache.nixos.org/").unwrap();
-        let uri = store.get_uri().unwrap();
+        let uri = store.uri().unwrap();
         assert_eq!(uri, "https://cache.nixos.org");
     }
 }
@@ -1,5 +1,7 @@
-use anyhow::{bail, Result};
+use crate::error::{parse_forbidden_path, parse_rendered_trace, ErrorSite, NixError};
+use crate::string_return::callback_get_vec_u8;
 use nix_c_raw as raw;
+use std::ffi::c_void;
 use std::ptr::null_mut;
 use std::ptr::NonNull;
 
@@ -21,16 +23,91 @@ impl Context {
     pub fn ptr(&self) -> *mut raw::nix_c_context {
         self.inner.as_ptr()
     }
-    pub fn check_err(&self) -> Result<()> {
+    /// Turn the error state of the context into a [`NixError`], if any.
+    ///
+    /// `site` identifies the call that was made with this context, see [`error_site!`](crate::error_site).
+    // Errors are the exceptional path, and an unboxed NixError can be matched on directly
+    #[allow(clippy::result_large_err)]
+    pub fn check_err(&self, site: ErrorSite) -> Result<(), NixError> {
         let err = unsafe { raw::nix_err_code(self.inner.as_ptr()) };
-        if err != raw::NIX_OK.try_into().unwrap() {
-            // msgp is a borrowed pointer, so we don't need to free it
-            let msgp = unsafe { raw::nix_err_msg(null_mut(), self.inner.as_ptr(), null_mut()) };
-            // Turn the i8 pointer into a Rust string by copying
-            let msg: &str = unsafe { core::ffi::CStr::from_ptr(msgp).to_str()? };
-            bail!("{}", msg);
+        if err == raw::NIX_OK as raw::nix_err {
+            return Ok(());
         }
-        Ok(())
+        // msgp is a borrowed pointer, so we don't need to free it
+        let msgp = unsafe { raw::nix_err_msg(null_mut(), self.inner.as_ptr(), null_mut()) };
+        // Turn the i8 pointer into a Rust string by copying
+        let message = if msgp.is_null() {
+            String::new()
+        } else {
+            unsafe { core::ffi::CStr::from_ptr(msgp) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        Err(match err {
+            raw::NIX_ERR_NIX_ERROR => {
+                let (positions, traces) = parse_rendered_trace(&message);
+                let info_msg = self.err_info_msg();
+                let forbidden_path = parse_forbidden_path(info_msg.as_deref().unwrap_or(&message));
+                NixError::Exception {
+                    site,
+                    message,
+                    name: self.err_name(),
+                    info_msg,
+                    positions,
+                    traces,
+                    forbidden_path,
+                }
+            }
+            raw::NIX_ERR_KEY => NixError::Key { site, message },
+            raw::NIX_ERR_OVERFLOW => NixError::Overflow { site, message },
+            code => NixError::Unknown {
+                site,
+                message,
+                code,
+            },
+        })
+    }
+    /// The class name of the Nix exception in the error state, e.g. `nix::ThrownError`.
+    ///
+    /// Only errors with code `NIX_ERR_NIX_ERROR` have one; otherwise this returns `None`.
+    pub fn err_name(&self) -> Option<String> {
+        self.read_err_string(raw::nix_err_name)
+    }
+    /// The message of the Nix exception in the error state, without the trace or position.
+    ///
+    /// It may contain ANSI escape codes for highlighting. Only errors with code `NIX_ERR_NIX_ERROR` have one; otherwise this returns `None`.
+    pub fn err_info_msg(&self) -> Option<String> {
+        self.read_err_string(raw::nix_err_info_msg)
+    }
+    fn read_err_string(
+        &self,
+        f: unsafe extern "C" fn(
+            *mut raw::nix_c_context,
+            *const raw::nix_c_context,
+            *mut c_void,
+            *mut c_void,
+        ) -> raw::nix_err,
+    ) -> Option<String> {
+        let err = unsafe { raw::nix_err_code(self.inner.as_ptr()) };
+        if err != raw::NIX_ERR_NIX_ERROR {
+            return None;
+        }
+        // Errors about reading the error go into a separate context, so that ours is preserved
+        let read_ctx = Context::new();
+        let mut raw_buffer: Vec<u8> = Vec::new();
+        unsafe {
+            f(
+                read_ctx.ptr(),
+                self.inner.as_ptr(),
+                callback_get_vec_u8 as *mut c_void,
+                &mut raw_buffer as *mut Vec<u8> as *mut c_void,
+            );
+        }
+        let read_err = unsafe { raw::nix_err_code(read_ctx.ptr()) };
+        if read_err != raw::NIX_OK.try_into().unwrap() {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&raw_buffer).into_owned())
     }
 }
 
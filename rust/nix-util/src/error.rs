@@ -0,0 +1,408 @@
+use crate::version::Capability;
+use nix_c_raw as raw;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// The FFI call that an error was reported by, and where the wrapper made that call.
+///
+/// Construct it with [`error_site!`](crate::error_site), which fills in the file and line.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ErrorSite {
+    pub call: &'static str,
+    pub file: &'static str,
+    pub line: u32,
+}
+
+impl fmt::Display for ErrorSite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // file!() is relative to the workspace; the file name is enough to find the wrapper
+        let file = Path::new(self.file)
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or(self.file);
+        write!(f, "in {} ({}:{})", self.call, file, self.line)
+    }
+}
+
+/// Label a call to the Nix C API, for use with [`Context::check_err`](crate::context::Context::check_err).
+///
+/// ```ignore
+/// raw::nix_value_force(context.ptr(), state, value);
+/// context.check_err(error_site!("nix_value_force"))?;
+/// ```
+#[macro_export]
+macro_rules! error_site {
+    ($call:expr) => {
+        $crate::error::ErrorSite {
+            call: $call,
+            file: file!(),
+            line: line!(),
+        }
+    };
+}
+
+/// An error reported by Nix through a [`Context`](crate::context::Context), by error code.
+///
+/// Returned by [`Context::check_err`](crate::context::Context::check_err).
+/// Inside an `anyhow::Error` it can be recovered with `downcast_ref`.
+#[derive(Clone, Debug)]
+pub enum NixError {
+    /// `NIX_ERR_NIX_ERROR`: a Nix exception, e.g. an evaluation error.
+    Exception {
+        site: ErrorSite,
+        message: String,
+        /// The class of the exception, e.g. `nix::ThrownError`.
+        name: Option<String>,
+        /// The message of the exception without trace or position.
+        info_msg: Option<String>,
+        /// The positions in the trace, from the outermost to the innermost frame, as Nix prints them.
+        positions: Vec<ErrPos>,
+        /// The trace frames, e.g. `while evaluating the attribute 'foo'`, from the outermost to the innermost.
+        traces: Vec<String>,
+        /// The file that evaluation was not allowed to access, under `restrict-eval` or `pure-eval`.
+        forbidden_path: Option<PathBuf>,
+    },
+    /// `NIX_ERR_KEY`: a key that was looked up, e.g. an attribute name, does not exist.
+    Key { site: ErrorSite, message: String },
+    /// `NIX_ERR_OVERFLOW`: a value did not fit, e.g. in a buffer.
+    Overflow { site: ErrorSite, message: String },
+    /// The Nix libraries are too old for the call, see [`version::require`](crate::version::require). Not reported by Nix, so the call was not made.
+    Unsupported {
+        site: ErrorSite,
+        message: String,
+        needs: Capability,
+    },
+    /// `NIX_ERR_UNKNOWN`, or an error code that these bindings do not know.
+    Unknown {
+        site: ErrorSite,
+        message: String,
+        code: raw::nix_err,
+    },
+}
+
+impl NixError {
+    pub fn site(&self) -> &ErrorSite {
+        match self {
+            NixError::Exception { site, .. }
+            | NixError::Key { site, .. }
+            | NixError::Overflow { site, .. }
+            | NixError::Unsupported { site, .. }
+            | NixError::Unknown { site, .. } => site,
+        }
+    }
+    /// The full message, as Nix would print it.
+    pub fn message(&self) -> &str {
+        match self {
+            NixError::Exception { message, .. }
+            | NixError::Key { message, .. }
+            | NixError::Overflow { message, .. }
+            | NixError::Unsupported { message, .. }
+            | NixError::Unknown { message, .. } => message,
+        }
+    }
+    /// The class of the Nix exception, e.g. `nix::ThrownError`, if the error was one.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            NixError::Exception { name, .. } => name.as_deref(),
+            _ => None,
+        }
+    }
+    /// The message of the Nix exception without trace or position, if the error was one.
+    pub fn info_msg(&self) -> Option<&str> {
+        match self {
+            NixError::Exception { info_msg, .. } => info_msg.as_deref(),
+            _ => None,
+        }
+    }
+    /// The source positions in the trace of a Nix exception, from the outermost to the innermost.
+    pub fn positions(&self) -> &[ErrPos] {
+        match self {
+            NixError::Exception { positions, .. } => positions,
+            _ => &[],
+        }
+    }
+    /// The trace frames of a Nix exception, from the outermost to the innermost.
+    pub fn traces(&self) -> &[String] {
+        match self {
+            NixError::Exception { traces, .. } => traces,
+            _ => &[],
+        }
+    }
+    /// The file that evaluation was not allowed to access, if that was the error.
+    pub fn forbidden_path(&self) -> Option<&Path> {
+        match self {
+            NixError::Exception { forbidden_path, .. } => forbidden_path.as_deref(),
+            _ => None,
+        }
+    }
+    /// The error code, e.g. `NIX_ERR_KEY`.
+    pub fn code(&self) -> raw::nix_err {
+        match self {
+            NixError::Exception { .. } => raw::NIX_ERR_NIX_ERROR,
+            NixError::Key { .. } => raw::NIX_ERR_KEY,
+            NixError::Overflow { .. } => raw::NIX_ERR_OVERFLOW,
+            NixError::Unsupported { .. } => raw::NIX_ERR_UNKNOWN,
+            NixError::Unknown { code, .. } => *code,
+        }
+    }
+}
+
+impl fmt::Display for NixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.site(), self.message())
+    }
+}
+
+impl std::error::Error for NixError {}
+
+/// A position in Nix source code, as reported in an error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ErrPos {
+    /// The file, or e.g. `«string»` for an expression that was not read from a file.
+    pub file: String,
+    pub line: u32,
+    pub column: u32,
+}
+
+impl fmt::Display for ErrPos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+/// Extract the positions and trace frames from an error message rendered by Nix.
+///
+/// The C API only provides the rendered message, in which a frame is a line starting with `…`, and a position is a line of the form `at <file>:<line>:<column>:`.
+pub(crate) fn parse_rendered_trace(message: &str) -> (Vec<ErrPos>, Vec<String>) {
+    let message = strip_ansi_escapes(message);
+    let mut positions = Vec::new();
+    let mut traces = Vec::new();
+    for line in message.lines() {
+        let line = line.trim();
+        if let Some(frame) = line.strip_prefix('…') {
+            traces.push(frame.trim().to_string());
+        } else if let Some(pos) = line.strip_prefix("at ").and_then(parse_pos) {
+            positions.push(pos);
+        }
+    }
+    (positions, traces)
+}
+
+fn parse_pos(s: &str) -> Option<ErrPos> {
+    let s = s.strip_suffix(':')?;
+    // The file name may contain colons, so take the numbers from the end
+    let mut parts = s.rsplitn(3, ':');
+    let column = parts.next()?.parse().ok()?;
+    let line = parts.next()?.parse().ok()?;
+    let file = parts.next()?;
+    if file.is_empty() {
+        return None;
+    }
+    Some(ErrPos {
+        file: file.to_string(),
+        line,
+        column,
+    })
+}
+
+/// Extract the path from an error message rendered by Nix for a denied file access, e.g.
+/// `access to absolute path '/etc/shadow' is forbidden in restricted mode`.
+pub(crate) fn parse_forbidden_path(message: &str) -> Option<PathBuf> {
+    let message = strip_ansi_escapes(message);
+    // The innermost error is printed last
+    message.lines().rev().find_map(|line| {
+        let (_, rest) = line.split_once("access to absolute path '")?;
+        // The path may contain quotes, so look for the end from the right
+        let end = rest.rfind("' is forbidden")?;
+        Some(PathBuf::from(&rest[..end]))
+    })
+}
+
+/// Remove the highlighting that Nix puts in error messages.
+pub fn strip_ansi_escapes(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        // CSI sequences, the only kind Nix emits, end with a byte in @..~
+        if chars.next() == Some('[') {
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_site_display() {
+        let site = ErrorSite {
+            call: "nix_value_force",
+            file: "nix-expr/src/eval_state.rs",
+            line: 87,
+        };
+        assert_eq!(site.to_string(), "in nix_value_force (eval_state.rs:87)");
+    }
+
+    #[test]
+    fn error_site_macro() {
+        let site = crate::error_site!("nix_foo");
+        assert_eq!(site.call, "nix_foo");
+        assert!(site.file.ends_with("error.rs"));
+        assert_eq!(site.line, line!() - 3);
+    }
+
+    #[test]
+    fn strip_ansi() {
+        assert_eq!(strip_ansi_escapes("\x1b[35;1mnope\x1b[0m"), "nope");
+        assert_eq!(strip_ansi_escapes("plain"), "plain");
+    }
+
+    #[test]
+    fn parse_rendered_eval_error() {
+        let message = "error:
+       \x1b[35;1m…\x1b[0m while evaluating the attribute 'y'
+         at \x1b[35;1m«string»:3:3\x1b[0m:
+            2|   x = 1;
+            3|   y = throw \"boom\";
+             |   ^
+            4| }
+
+       … while calling the 'throw' builtin
+         at «string»:3:7:
+            2|   x = 1;
+            3|   y = throw \"boom\";
+             |       ^
+            4| }
+
+       error: boom";
+        let (positions, traces) = parse_rendered_trace(message);
+        assert_eq!(
+            positions,
+            vec![
+                ErrPos {
+                    file: "«string»".to_string(),
+                    line: 3,
+                    column: 3
+                },
+                ErrPos {
+                    file: "«string»".to_string(),
+                    line: 3,
+                    column: 7
+                },
+            ]
+        );
+        assert_eq!(
+            traces,
+            vec![
+                "while evaluating the attribute 'y'",
+                "while calling the 'throw' builtin"
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rendered_parse_error() {
+        let message = "error: syntax error, unexpected end of file, expecting ';'
+       at /home/user/my:project/default.nix:1:8:
+            1| { a = 1
+             |        ^";
+        let (positions, traces) = parse_rendered_trace(message);
+        assert_eq!(
+            positions,
+            vec![ErrPos {
+                file: "/home/user/my:project/default.nix".to_string(),
+                line: 1,
+                column: 8
+            }]
+        );
+        assert_eq!(
+            positions[0].to_string(),
+            "/home/user/my:project/default.nix:1:8"
+        );
+        assert!(traces.is_empty());
+    }
+
+    #[test]
+    fn parse_rendered_no_positions() {
+        let (positions, traces) = parse_rendered_trace("error: at least one thing: went wrong:");
+        assert!(positions.is_empty());
+        assert!(traces.is_empty());
+    }
+
+    #[test]
+    fn parse_forbidden_paths() {
+        assert_eq!(
+            parse_forbidden_path(
+                "error: access to absolute path '\x1b[35;1m/etc/shadow\x1b[0m' is forbidden in restricted mode"
+            ),
+            Some(PathBuf::from("/etc/shadow"))
+        );
+        assert_eq!(
+            parse_forbidden_path(
+                "error:
+       … while calling the 'readFile' builtin
+         at «string»:1:1:
+            1| builtins.readFile /home/it's/x
+             | ^
+
+       error: access to absolute path '/home/it's/x' is forbidden in pure evaluation mode (use '--impure' to override)"
+            ),
+            Some(PathBuf::from("/home/it's/x"))
+        );
+        assert_eq!(parse_forbidden_path("error: boom"), None);
+    }
+
+    const SITE: ErrorSite = ErrorSite {
+        call: "nix_store_open",
+        file: "nix-store/src/store.rs",
+        line: 12,
+    };
+
+    #[test]
+    fn nix_error_display() {
+        let e = NixError::Unknown {
+            site: SITE,
+            message: "error: oops".to_string(),
+            code: raw::NIX_ERR_UNKNOWN,
+        };
+        assert_eq!(
+            e.to_string(),
+            "in nix_store_open (store.rs:12): error: oops"
+        );
+    }
+
+    #[test]
+    fn nix_error_accessors() {
+        let e = NixError::Exception {
+            site: SITE,
+            message: "error: boom".to_string(),
+            name: Some("nix::ThrownError".to_string()),
+            info_msg: Some("boom".to_string()),
+            positions: vec![],
+            traces: vec![],
+            forbidden_path: None,
+        };
+        assert_eq!(e.code(), raw::NIX_ERR_NIX_ERROR);
+        assert_eq!(e.forbidden_path(), None);
+        assert_eq!(e.name(), Some("nix::ThrownError"));
+        assert_eq!(e.info_msg(), Some("boom"));
+        assert_eq!(e.site().call, "nix_store_open");
+        let e = NixError::Key {
+            site: SITE,
+            message: "missing".to_string(),
+        };
+        assert_eq!(e.code(), raw::NIX_ERR_KEY);
+        assert_eq!(e.name(), None);
+        assert_eq!(e.message(), "missing");
+    }
+}
@@ -0,0 +1,295 @@
+//! Hashes in the formats that Nix uses: base16, Nix's own base32, base64 and SRI.
+//!
+//! The C API has no hashing functions, so the encodings are implemented here. To compute a hash, see `EvalState::hash_string` and `EvalState::hash_file` in nix-expr.
+
+use anyhow::{bail, Result};
+use std::fmt;
+
+/// A hash algorithm that Nix supports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Algo {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+impl Algo {
+    /// The name of the algorithm, as in `sha256:...`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algo::Md5 => "md5",
+            Algo::Sha1 => "sha1",
+            Algo::Sha256 => "sha256",
+            Algo::Sha512 => "sha512",
+        }
+    }
+    /// The size of a hash in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Algo::Md5 => 16,
+            Algo::Sha1 => 20,
+            Algo::Sha256 => 32,
+            Algo::Sha512 => 64,
+        }
+    }
+    pub fn parse(s: &str) -> Result<Algo> {
+        match s {
+            "md5" => Ok(Algo::Md5),
+            "sha1" => Ok(Algo::Sha1),
+            "sha256" => Ok(Algo::Sha256),
+            "sha512" => Ok(Algo::Sha512),
+            _ => bail!("unknown hash algorithm {:?}", s),
+        }
+    }
+}
+impl fmt::Display for Algo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A hash and its algorithm.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Hash {
+    algo: Algo,
+    bytes: Vec<u8>,
+}
+impl Hash {
+    pub fn new(algo: Algo, bytes: Vec<u8>) -> Result<Hash> {
+        if bytes.len() != algo.size() {
+            bail!(
+                "{} hashes have {} bytes, not {}",
+                algo,
+                algo.size(),
+                bytes.len()
+            );
+        }
+        Ok(Hash { algo, bytes })
+    }
+    pub fn algo(&self) -> Algo {
+        self.algo
+    }
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Parse an SRI hash, `sha256-<base64>`, or a hash with a prefix, `sha256:<digest>`, where the digest is in base16, base32 or base64.
+    pub fn parse(s: &str) -> Result<Hash> {
+        if let Some((algo, digest)) = s.split_once(':') {
+            return Self::parse_digest(Algo::parse(algo)?, digest);
+        }
+        if let Some((algo, digest)) = s.split_once('-') {
+            let algo = Algo::parse(algo)?;
+            return match decode_base64(digest) {
+                Some(bytes) if bytes.len() == algo.size() => Ok(Hash { algo, bytes }),
+                _ => bail!("invalid SRI hash {:?}", s),
+            };
+        }
+        bail!(
+            "hash {:?} has neither an algorithm prefix, such as `sha256:`, nor is it an SRI hash",
+            s
+        )
+    }
+    /// Parse a digest without an algorithm, in base16, base32 or base64, which Nix tells apart by their length.
+    pub fn parse_digest(algo: Algo, digest: &str) -> Result<Hash> {
+        let size = algo.size();
+        let bytes = if digest.len() == base16_len(size) {
+            decode_base16(digest)
+        } else if digest.len() == base32_len(size) {
+            decode_base32(digest, size)
+        } else if digest.len() == base64_len(size) {
+            decode_base64(digest).filter(|b| b.len() == size)
+        } else {
+            bail!("hash {:?} has the wrong length for a {} hash", digest, algo);
+        };
+        match bytes {
+            Some(bytes) => Ok(Hash { algo, bytes }),
+            None => bail!("invalid {} hash {:?}", algo, digest),
+        }
+    }
+
+    pub fn to_base16(&self) -> String {
+        self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+    /// Nix's base32, as in store paths, which differs from RFC 4648 in its alphabet and byte order.
+    pub fn to_base32(&self) -> String {
+        let size = self.bytes.len();
+        (0..base32_len(size))
+            .rev()
+            .map(|n| {
+                let b = n * 5;
+                let i = b / 8;
+                let j = b % 8;
+                let low = self.bytes[i] as u16 >> j;
+                let high = match self.bytes.get(i + 1) {
+                    Some(&next) => (next as u16) << (8 - j),
+                    None => 0,
+                };
+                BASE32_CHARS[((low | high) & 0x1f) as usize] as char
+            })
+            .collect()
+    }
+    pub fn to_base64(&self) -> String {
+        encode_base64(&self.bytes)
+    }
+    /// The SRI form, `sha256-<base64>`, which is also the [`Display`](fmt::Display) form.
+    pub fn to_sri(&self) -> String {
+        format!("{}-{}", self.algo, self.to_base64())
+    }
+}
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.to_sri())
+    }
+}
+
+const BASE32_CHARS: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+const BASE64_CHARS: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base16_len(size: usize) -> usize {
+    size * 2
+}
+fn base32_len(size: usize) -> usize {
+    (size * 8 - 1) / 5 + 1
+}
+fn base64_len(size: usize) -> usize {
+    (4 * size / 3 + 3) & !3
+}
+
+fn decode_base16(s: &str) -> Option<Vec<u8>> {
+    if !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    // An odd length leaves a single digit at the end, for which get returns None
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_base32(s: &str, size: usize) -> Option<Vec<u8>> {
+    let mut bytes = vec![0u8; size];
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = BASE32_CHARS.iter().position(|&x| x == c)? as u16;
+        let b = n * 5;
+        let i = b / 8;
+        let j = b % 8;
+        bytes[i] |= (digit << j) as u8;
+        let carry = (digit << j) >> 8;
+        match bytes.get_mut(i + 1) {
+            Some(next) => *next |= carry as u8,
+            None if carry != 0 => return None,
+            None => {}
+        }
+    }
+    Some(bytes)
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(base64_len(bytes.len()));
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | ((b as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(BASE64_CHARS[((n >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(s.len() / 4 * 3);
+    let chunks = s.as_bytes().chunks(4).collect::<Vec<_>>();
+    for (k, chunk) in chunks.iter().enumerate() {
+        if chunk.len() != 4 {
+            return None;
+        }
+        let padding = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && k + 1 != chunks.len()) {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, &c) in chunk[..4 - padding].iter().enumerate() {
+            let digit = BASE64_CHARS.iter().position(|&x| x == c)? as u32;
+            n |= digit << (18 - 6 * i);
+        }
+        for i in 0..3 - padding {
+            bytes.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // sha256 of "hello"
+    const HELLO_BASE16: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+    const HELLO_BASE32: &str = "094qif9n4cq4fdg459qzbhg1c6wywawwaaivx0k0x8xhbyx4vwic";
+    const HELLO_SRI: &str = "sha256-LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=";
+
+    #[test]
+    fn hash_encodings() {
+        let h = Hash::parse(&format!("sha256:{}", HELLO_BASE16)).unwrap();
+        assert_eq!(h.algo(), Algo::Sha256);
+        assert_eq!(h.to_base16(), HELLO_BASE16);
+        assert_eq!(h.to_base32(), HELLO_BASE32);
+        assert_eq!(h.to_sri(), HELLO_SRI);
+        assert_eq!(h.to_string(), HELLO_SRI);
+    }
+
+    #[test]
+    fn hash_parse_round_trip() {
+        let h = Hash::parse(HELLO_SRI).unwrap();
+        for s in [
+            format!("sha256:{}", HELLO_BASE16),
+            format!("sha256:{}", HELLO_BASE32),
+            format!("sha256:{}", h.to_base64()),
+            HELLO_SRI.to_string(),
+        ] {
+            assert_eq!(Hash::parse(&s).unwrap(), h, "{}", s);
+        }
+        assert_eq!(Hash::parse_digest(Algo::Sha256, HELLO_BASE32).unwrap(), h);
+    }
+
+    #[test]
+    fn hash_other_algos() {
+        // md5, sha1 and sha512 of "hello"
+        let h = Hash::parse("md5:5d41402abc4b2a76b9719d911017c592").unwrap();
+        assert_eq!(h.to_base32(), "4jqlbi14cxf6wpcajbphm40hax");
+        assert_eq!(h.to_sri(), "md5-XUFAKrxLKna5cZ2REBfFkg==");
+        let h = Hash::parse("sha1:9m1skbnr5i43n3yypvda5s65vhfwdx5a").unwrap();
+        assert_eq!(h.to_base16(), "aaf4c61ddcc5e8a2dabede0f3b482cd9aea9434d");
+        assert_eq!(h.to_sri(), "sha1-qvTGHdzF6KLavt4PO0gs2a6pQ00=");
+        let h = Hash::parse("sha512-m3HSJL1i83hdltRq0+o9czGb+8KJDKra4t/3JRlnPKcjI8PZm6XBHXx6zG4UuMXaDEZjR1wuXDre9G9zvN7AQw==").unwrap();
+        assert_eq!(
+            Hash::parse(&format!("sha512:{}", h.to_base32())).unwrap(),
+            h
+        );
+    }
+
+    #[test]
+    fn hash_parse_errors() {
+        let e = Hash::parse("sha3:abcd").unwrap_err();
+        assert_eq!(e.to_string(), "unknown hash algorithm \"sha3\"");
+        let e = Hash::parse("sha256:abcd").unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "hash \"abcd\" has the wrong length for a sha256 hash"
+        );
+        // An `e` is not in Nix's base32 alphabet
+        let e = Hash::parse(&format!("sha256:{}", HELLO_BASE32.replace('0', "e"))).unwrap_err();
+        assert!(e.to_string().starts_with("invalid sha256 hash"), "{}", e);
+        assert!(Hash::parse("sha256-LPJNul+wow4m").is_err());
+        assert!(Hash::parse(HELLO_BASE16).is_err());
+        let e = Hash::new(Algo::Md5, vec![0; 3]).unwrap_err();
+        assert_eq!(e.to_string(), "md5 hashes have 16 bytes, not 3");
+    }
+}
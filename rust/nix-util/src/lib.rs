@@ -1,2 +1,6 @@
 pub mod context;
+pub mod error;
+pub mod hash;
+pub mod settings;
 pub mod string_return;
+pub mod version;
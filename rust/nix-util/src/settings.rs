@@ -0,0 +1,92 @@
+//! Nix settings, as in `nix.conf` or `--option`.
+//!
+//! Settings are global to the process. Some are only read when a component is initialized or created,
+//! e.g. `store` is read when a store is opened and `pure-eval` when an `EvalState` is created,
+//! so set those before that happens.
+
+use crate::context::Context;
+use crate::error_site;
+use crate::string_return::callback_get_vec_u8;
+use anyhow::{Context as _, Result};
+use lazy_static::lazy_static;
+use nix_c_raw as raw;
+use std::ffi::{c_void, CString};
+
+lazy_static! {
+    static ref INIT: Result<()> = {
+        let context: Context = Context::new();
+        unsafe {
+            raw::nix_libutil_init(context.ptr());
+        }
+        context.check_err(error_site!("nix_libutil_init"))?;
+        Ok(())
+    };
+}
+fn init() -> Result<()> {
+    match INIT.as_ref() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(anyhow::format_err!("nix_libutil_init error: {}", e)),
+    }
+}
+
+/// Set a setting, e.g. `set("experimental-features", "flakes nix-command")`.
+///
+/// Unknown settings are an error.
+pub fn set(key: &str, value: &str) -> Result<()> {
+    init()?;
+    let context = Context::new();
+    let key_ptr = CString::new(key)
+        .with_context(|| format!("settings::set: key contains null byte: {:?}", key))?;
+    let value_ptr = CString::new(value)
+        .with_context(|| format!("settings::set: value contains null byte: {:?}", value))?;
+    unsafe {
+        raw::nix_setting_set(context.ptr(), key_ptr.as_ptr(), value_ptr.as_ptr());
+    }
+    context
+        .check_err(error_site!("nix_setting_set"))
+        .with_context(|| format!("setting {}", key))?;
+    Ok(())
+}
+
+/// Get the value of a setting, rendered as in `nix.conf`.
+pub fn get(key: &str) -> Result<String> {
+    init()?;
+    let context = Context::new();
+    let key_ptr = CString::new(key)
+        .with_context(|| format!("settings::get: key contains null byte: {:?}", key))?;
+    let mut raw_buffer: Vec<u8> = Vec::new();
+    unsafe {
+        raw::nix_setting_get(
+            context.ptr(),
+            key_ptr.as_ptr(),
+            callback_get_vec_u8 as *mut c_void,
+            &mut raw_buffer as *mut Vec<u8> as *mut c_void,
+        );
+    }
+    context
+        .check_err(error_site!("nix_setting_get"))
+        .with_context(|| format!("getting setting {}", key))?;
+    String::from_utf8(raw_buffer).map_err(|e| e.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_get_experimental_features() {
+        set("experimental-features", "flakes nix-command").unwrap();
+        let v = get("experimental-features").unwrap();
+        let mut features: Vec<&str> = v.split_whitespace().collect();
+        features.sort();
+        assert_eq!(features, vec!["flakes", "nix-command"]);
+    }
+
+    #[test]
+    fn unknown_setting() {
+        let e = set("nixops4-no-such-setting", "1").unwrap_err();
+        assert!(e.to_string().contains("nixops4-no-such-setting"));
+        assert!(e.downcast_ref::<crate::error::NixError>().is_some());
+        assert!(get("nixops4-no-such-setting").is_err());
+    }
+}
@@ -0,0 +1,183 @@
+//! The version of the Nix libraries, and which parts of the C API it has.
+//!
+//! These bindings are generated from the headers of one Nix, but may be linked against the libraries of another.
+//! Functions that need parts of the C API that an older Nix lacks check for them with [`require`], and fail with [`NixError::Unsupported`] instead of calling into the library.
+
+use crate::error::{ErrorSite, NixError};
+use anyhow::{bail, Result};
+use lazy_static::lazy_static;
+use nix_c_raw as raw;
+use std::ffi::CStr;
+use std::fmt;
+
+/// A Nix version, e.g. `2.21.0` or `2.21.0pre20240310_0123abc`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+    /// What follows the numbers, e.g. `pre20240310_0123abc` for a build from the development branch.
+    pub suffix: String,
+}
+impl Version {
+    pub fn parse(s: &str) -> Result<Version> {
+        let end = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let numbers = s[..end]
+            .split('.')
+            .map(|n| n.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>();
+        match numbers.as_deref() {
+            Some(&[major, minor]) => Ok(Version {
+                major,
+                minor,
+                patch: 0,
+                suffix: s[end..].to_string(),
+            }),
+            Some(&[major, minor, patch]) => Ok(Version {
+                major,
+                minor,
+                patch,
+                suffix: s[end..].to_string(),
+            }),
+            _ => bail!("invalid Nix version {:?}", s),
+        }
+    }
+    /// Whether this version is `major.minor` or newer.
+    ///
+    /// Development builds count as the release they precede, e.g. `2.21.0pre...` as `2.21`.
+    pub fn at_least(&self, major: u32, minor: u32) -> bool {
+        (self.major, self.minor) >= (major, minor)
+    }
+}
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}{}",
+            self.major, self.minor, self.patch, self.suffix
+        )
+    }
+}
+
+/// A part of the C API that not every Nix version has.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum Capability {
+    /// `nix_string_realise`, for `EvalState::realise_string` in nix-expr.
+    RealiseString,
+    /// `nix_create_external_value` and related functions, for external values.
+    ExternalValues,
+    /// The `nix_flake_*` functions, which these bindings don't use yet; flakes are evaluated with `builtins.getFlake`.
+    FlakeCApi,
+}
+impl Capability {
+    /// The first Nix release that has it, as `(major, minor)`.
+    pub fn since(&self) -> (u32, u32) {
+        match self {
+            Capability::RealiseString => (2, 21),
+            Capability::ExternalValues => (2, 21),
+            Capability::FlakeCApi => (2, 26),
+        }
+    }
+    pub fn supported_by(&self, version: &Version) -> bool {
+        let (major, minor) = self.since();
+        version.at_least(major, minor)
+    }
+}
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::RealiseString => "realising strings",
+            Capability::ExternalValues => "external values",
+            Capability::FlakeCApi => "the flake C API",
+        })
+    }
+}
+
+lazy_static! {
+    static ref LIBNIX_VERSION: Result<Version, String> = {
+        let version = unsafe { CStr::from_ptr(raw::nix_version_get()) };
+        Version::parse(&version.to_string_lossy()).map_err(|e| e.to_string())
+    };
+}
+
+/// The version of the Nix libraries that this process uses, from `nix_version_get`.
+pub fn libnix_version() -> Result<Version> {
+    match LIBNIX_VERSION.as_ref() {
+        Ok(v) => Ok(v.clone()),
+        Err(e) => bail!("{}", e),
+    }
+}
+
+/// Whether the Nix libraries have a capability.
+pub fn has_capability(capability: Capability) -> bool {
+    libnix_version()
+        .map(|v| capability.supported_by(&v))
+        .unwrap_or(false)
+}
+
+/// Fail with [`NixError::Unsupported`] if the Nix libraries lack a capability that the call at `site` needs.
+pub fn require(capability: Capability, site: ErrorSite) -> Result<()> {
+    require_in(&libnix_version()?, capability, site)
+}
+
+fn require_in(version: &Version, capability: Capability, site: ErrorSite) -> Result<()> {
+    if capability.supported_by(version) {
+        return Ok(());
+    }
+    let (major, minor) = capability.since();
+    Err(NixError::Unsupported {
+        site,
+        message: format!(
+            "{} requires Nix {}.{} or newer, but the Nix libraries are version {}",
+            capability, major, minor, version
+        ),
+        needs: capability,
+    }
+    .into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_parse() {
+        let v = Version::parse("2.21.0pre20240310_0123abc").unwrap();
+        assert_eq!((v.major, v.minor, v.patch), (2, 21, 0));
+        assert_eq!(v.suffix, "pre20240310_0123abc");
+        assert_eq!(v.to_string(), "2.21.0pre20240310_0123abc");
+        assert!(v.at_least(2, 21) && v.at_least(1, 30) && !v.at_least(2, 22));
+        let v = Version::parse("2.18").unwrap();
+        assert_eq!(v.to_string(), "2.18.0");
+        assert!(Version::parse("nix").is_err());
+        assert!(Version::parse("2").is_err());
+        assert!(Version::parse("2.x").is_err());
+    }
+
+    #[test]
+    fn libnix_version_has_c_api() {
+        let v = libnix_version().unwrap();
+        assert!(v.at_least(2, 21), "{}", v);
+        assert!(has_capability(Capability::RealiseString));
+    }
+
+    #[test]
+    fn require_unsupported() {
+        let site = crate::error_site!("nix_string_realise");
+        let old = Version::parse("2.18.1").unwrap();
+        let e = require_in(&old, Capability::RealiseString, site).unwrap_err();
+        match e.downcast_ref::<NixError>() {
+            Some(NixError::Unsupported { needs, .. }) => {
+                assert_eq!(*needs, Capability::RealiseString)
+            }
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert!(e.to_string().ends_with(
+            "realising strings requires Nix 2.21 or newer, but the Nix libraries are version 2.18.1"
+        ));
+        let new = Version::parse("2.24.0").unwrap();
+        require_in(&new, Capability::RealiseString, site).unwrap();
+    }
+}
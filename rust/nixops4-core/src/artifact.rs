@@ -0,0 +1,475 @@
+//! Deterministic archives of store paths, for providers that upload artifacts.
+//!
+//! Archiving the same tree always produces the same bytes: entries are sorted by path,
+//! timestamps are fixed, ownership is dropped and permissions are normalized to `0755` or `0644`,
+//! depending on whether the owner may execute the file.
+
+use anyhow::{bail, Context as _, Result};
+use sha2::{Digest as _, Sha256};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// The modification time of every tar entry, matching the timestamps of store paths.
+const TAR_MTIME: u64 = 1;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    TarGz,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub fn parse(s: &str) -> Result<ArchiveFormat> {
+        match s {
+            "zip" => Ok(ArchiveFormat::Zip),
+            "tar.gz" => Ok(ArchiveFormat::TarGz),
+            "tar.zst" => Ok(ArchiveFormat::TarZst),
+            _ => bail!(
+                "unknown archive format {:?}; expected one of zip, tar.gz, tar.zst",
+                s
+            ),
+        }
+    }
+    /// The file name extension, without the leading dot.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::TarZst => "tar.zst",
+        }
+    }
+}
+
+enum EntryKind {
+    Directory,
+    File { executable: bool },
+    Symlink { target: PathBuf },
+}
+
+struct Entry {
+    /// Relative to the archive root, with `/` separators.
+    name: String,
+    source: PathBuf,
+    kind: EntryKind,
+}
+
+/// An archive written by [`pack`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PackedArchive {
+    pub path: PathBuf,
+    /// The SHA-256 of the archive, in lowercase hexadecimal.
+    pub sha256: String,
+}
+
+/// Write an archive of `src` to `dest`.
+///
+/// If `src` is a directory, its contents are the root of the archive; otherwise the archive contains the single file or symlink, under its file name.
+/// The archive is written to a temporary file next to `dest`, and renamed to `dest` when it is complete, so `dest` is never left partially written.
+pub fn pack(src: &Path, format: ArchiveFormat, dest: &Path) -> Result<PackedArchive> {
+    let entries = collect_entries(src)?;
+    let tmp = match dest.file_name().and_then(|n| n.to_str()) {
+        Some(name) => dest.with_file_name(format!(".{}.tmp-{}", name, std::process::id())),
+        None => bail!("archive path {} has no file name", dest.display()),
+    };
+    let r = write_archive(&entries, format, &tmp)
+        .and_then(|()| sha256_file(&tmp))
+        .with_context(|| format!("writing archive {}", dest.display()))
+        .and_then(|sha256| {
+            std::fs::rename(&tmp, dest)
+                .with_context(|| format!("renaming {} to {}", tmp.display(), dest.display()))?;
+            Ok(sha256)
+        });
+    match r {
+        Ok(sha256) => Ok(PackedArchive {
+            path: dest.to_path_buf(),
+            sha256,
+        }),
+        Err(e) => {
+            let _ = std::fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+fn write_archive(entries: &[Entry], format: ArchiveFormat, dest: &Path) -> Result<()> {
+    let file =
+        File::create(dest).with_context(|| format!("creating archive {}", dest.display()))?;
+    let out = BufWriter::new(file);
+    match format {
+        ArchiveFormat::Zip => write_zip(entries, out),
+        ArchiveFormat::TarGz => {
+            // No file name in the header; its mtime defaults to 0
+            let gz = flate2::GzBuilder::new().write(out, flate2::Compression::default());
+            write_tar(entries, gz)
+                .and_then(|gz| gz.finish().map_err(Into::into))
+                .and_then(finish)
+        }
+        ArchiveFormat::TarZst => {
+            let zst = zstd::Encoder::new(out, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+            write_tar(entries, zst)
+                .and_then(|zst| zst.finish().map_err(Into::into))
+                .and_then(finish)
+        }
+    }
+}
+
+fn finish(mut out: BufWriter<File>) -> Result<()> {
+    out.flush()?;
+    Ok(())
+}
+
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("reading {}", path.display()))?;
+    let mut hash = Sha256::new();
+    std::io::copy(&mut file, &mut hash).with_context(|| format!("reading {}", path.display()))?;
+    Ok(format!("{:x}", hash.finalize()))
+}
+
+fn collect_entries(src: &Path) -> Result<Vec<Entry>> {
+    let meta =
+        std::fs::symlink_metadata(src).with_context(|| format!("reading {}", src.display()))?;
+    let mut entries = Vec::new();
+    if meta.is_dir() {
+        collect_dir(src, "", &mut entries)?;
+    } else {
+        let name = match src.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => bail!("cannot archive {}: no UTF-8 file name", src.display()),
+        };
+        entries.push(entry(src.to_path_buf(), name, &meta)?);
+    }
+    Ok(entries)
+}
+
+fn collect_dir(dir: &Path, prefix: &str, entries: &mut Vec<Entry>) -> Result<()> {
+    let mut children = Vec::new();
+    for child in std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let child = child.with_context(|| format!("reading {}", dir.display()))?;
+        let name = match child.file_name().into_string() {
+            Ok(name) => name,
+            Err(name) => bail!(
+                "cannot archive {}: file name is not valid UTF-8: {:?}",
+                dir.display(),
+                name
+            ),
+        };
+        children.push((name, child.path()));
+    }
+    children.sort();
+    for (name, path) in children {
+        let meta = std::fs::symlink_metadata(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        let name = format!("{}{}", prefix, name);
+        let is_dir = meta.is_dir();
+        entries.push(entry(path.clone(), name.clone(), &meta)?);
+        if is_dir {
+            collect_dir(&path, &format!("{}/", name), entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn entry(source: PathBuf, name: String, meta: &std::fs::Metadata) -> Result<Entry> {
+    let file_type = meta.file_type();
+    let kind = if file_type.is_dir() {
+        EntryKind::Directory
+    } else if file_type.is_symlink() {
+        let target = std::fs::read_link(&source)
+            .with_context(|| format!("reading symlink {}", source.display()))?;
+        EntryKind::Symlink { target }
+    } else if file_type.is_file() {
+        EntryKind::File {
+            executable: meta.permissions().mode() & 0o100 != 0,
+        }
+    } else {
+        bail!(
+            "cannot archive {}: not a file, directory or symlink",
+            source.display()
+        );
+    };
+    Ok(Entry { name, source, kind })
+}
+
+fn mode(kind: &EntryKind) -> u32 {
+    match kind {
+        EntryKind::Directory | EntryKind::File { executable: true } => 0o755,
+        EntryKind::File { executable: false } => 0o644,
+        EntryKind::Symlink { .. } => 0o777,
+    }
+}
+
+fn write_tar<W: Write>(entries: &[Entry], out: W) -> Result<W> {
+    let mut builder = tar::Builder::new(out);
+    builder.mode(tar::HeaderMode::Deterministic);
+    for e in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(TAR_MTIME);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mode(mode(&e.kind));
+        match &e.kind {
+            EntryKind::Directory => {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, format!("{}/", e.name), std::io::empty())?;
+            }
+            EntryKind::File { .. } => {
+                let file = File::open(&e.source)
+                    .with_context(|| format!("reading {}", e.source.display()))?;
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(file.metadata()?.len());
+                builder.append_data(&mut header, &e.name, file)?;
+            }
+            EntryKind::Symlink { target } => {
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, &e.name, target)?;
+            }
+        }
+    }
+    Ok(builder.into_inner()?)
+}
+
+fn write_zip(entries: &[Entry], out: BufWriter<File>) -> Result<()> {
+    let mut zip = zip::ZipWriter::new(out);
+    for e in entries {
+        // The default timestamp is the earliest one zip can represent, 1980-01-01
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(zip::DateTime::default())
+            .unix_permissions(mode(&e.kind));
+        match &e.kind {
+            EntryKind::Directory => zip.add_directory(e.name.as_str(), options)?,
+            EntryKind::File { .. } => {
+                zip.start_file(e.name.as_str(), options)?;
+                let mut file = File::open(&e.source)
+                    .with_context(|| format!("reading {}", e.source.display()))?;
+                std::io::copy(&mut file, &mut zip)?;
+            }
+            EntryKind::Symlink { target } => {
+                let target = match target.to_str() {
+                    Some(target) => target,
+                    None => bail!(
+                        "cannot archive {}: symlink target is not valid UTF-8",
+                        e.source.display()
+                    ),
+                };
+                zip.add_symlink(e.name.as_str(), target, options)?
+            }
+        }
+    }
+    finish(zip.finish()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "nixops4-core-test-artifact-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A small tree, created in an order that differs from the sorted order.
+    fn make_tree(root: &Path) {
+        std::fs::create_dir_all(root.join("lib")).unwrap();
+        std::fs::write(root.join("lib/z.txt"), "z").unwrap();
+        std::fs::write(root.join("lib/a.txt"), "a").unwrap();
+        std::fs::create_dir_all(root.join("bin")).unwrap();
+        std::fs::write(root.join("bin/run"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(root.join("bin/run"), std::fs::Permissions::from_mode(0o700))
+            .unwrap();
+        std::os::unix::fs::symlink("lib/a.txt", root.join("link")).unwrap();
+    }
+
+    const EXPECTED_NAMES: [&str; 6] = ["bin/", "bin/run", "lib/", "lib/a.txt", "lib/z.txt", "link"];
+
+    #[test]
+    fn parse_format() {
+        for f in [
+            ArchiveFormat::Zip,
+            ArchiveFormat::TarGz,
+            ArchiveFormat::TarZst,
+        ] {
+            assert_eq!(ArchiveFormat::parse(f.extension()).unwrap(), f);
+        }
+        assert!(ArchiveFormat::parse("rar").is_err());
+    }
+
+    #[test]
+    fn identical_bytes() {
+        let dir = test_dir("identical");
+        let src = dir.join("src");
+        make_tree(&src);
+        for format in [
+            ArchiveFormat::Zip,
+            ArchiveFormat::TarGz,
+            ArchiveFormat::TarZst,
+        ] {
+            let a = dir.join(format!("a.{}", format.extension()));
+            let b = dir.join(format!("b.{}", format.extension()));
+            let packed_a = pack(&src, format, &a).unwrap();
+            assert_eq!(packed_a.path, a);
+            assert_eq!(packed_a.sha256.len(), 64);
+            // Change timestamps, which must not matter
+            std::fs::write(src.join("lib/z.txt"), "z").unwrap();
+            let packed_b = pack(&src, format, &b).unwrap();
+            assert_eq!(packed_a.sha256, packed_b.sha256, "{:?}", format);
+            assert_eq!(
+                std::fs::read(&a).unwrap(),
+                std::fs::read(&b).unwrap(),
+                "{:?}",
+                format
+            );
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn check_tar<R: Read>(r: R) {
+        let mut archive = tar::Archive::new(r);
+        let mut names = Vec::new();
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_str().unwrap().to_string();
+            let header = entry.header();
+            assert_eq!(header.mtime().unwrap(), TAR_MTIME, "{}", name);
+            assert_eq!(header.uid().unwrap(), 0);
+            match name.as_str() {
+                "bin/run" => assert_eq!(header.mode().unwrap(), 0o755),
+                "lib/a.txt" => {
+                    assert_eq!(header.mode().unwrap(), 0o644);
+                    let mut s = String::new();
+                    entry.read_to_string(&mut s).unwrap();
+                    assert_eq!(s, "a");
+                }
+                "link" => assert_eq!(
+                    entry.link_name().unwrap().unwrap().to_str().unwrap(),
+                    "lib/a.txt"
+                ),
+                _ => {}
+            }
+            names.push(name);
+        }
+        assert_eq!(names, EXPECTED_NAMES);
+    }
+
+    #[test]
+    fn tar_gz_entries() {
+        let dir = test_dir("tar-gz");
+        let src = dir.join("src");
+        make_tree(&src);
+        let dest = dir.join("out.tar.gz");
+        pack(&src, ArchiveFormat::TarGz, &dest).unwrap();
+        check_tar(flate2::read::GzDecoder::new(File::open(&dest).unwrap()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tar_zst_entries() {
+        let dir = test_dir("tar-zst");
+        let src = dir.join("src");
+        make_tree(&src);
+        let dest = dir.join("out.tar.zst");
+        pack(&src, ArchiveFormat::TarZst, &dest).unwrap();
+        check_tar(zstd::Decoder::new(File::open(&dest).unwrap()).unwrap());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn zip_entries() {
+        let dir = test_dir("zip");
+        let src = dir.join("src");
+        make_tree(&src);
+        let dest = dir.join("out.zip");
+        pack(&src, ArchiveFormat::Zip, &dest).unwrap();
+        let mut archive = zip::ZipArchive::new(File::open(&dest).unwrap()).unwrap();
+        let mut names = Vec::new();
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).unwrap();
+            let name = file.name().to_string();
+            assert_eq!(
+                file.last_modified(),
+                Some(zip::DateTime::default()),
+                "{}",
+                name
+            );
+            match name.as_str() {
+                "bin/run" => assert_eq!(file.unix_mode().unwrap() & 0o777, 0o755),
+                "lib/a.txt" => {
+                    assert_eq!(file.unix_mode().unwrap() & 0o777, 0o644);
+                    let mut s = String::new();
+                    file.read_to_string(&mut s).unwrap();
+                    assert_eq!(s, "a");
+                }
+                _ => {}
+            }
+            names.push(name);
+        }
+        assert_eq!(names, EXPECTED_NAMES);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn single_file() {
+        let dir = test_dir("single");
+        std::fs::write(dir.join("hello.txt"), "hello").unwrap();
+        let dest = dir.join("out.tar.gz");
+        pack(&dir.join("hello.txt"), ArchiveFormat::TarGz, &dest).unwrap();
+        let mut archive =
+            tar::Archive::new(flate2::read::GzDecoder::new(File::open(&dest).unwrap()));
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["hello.txt"]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sha256_of_archive() {
+        let dir = test_dir("sha256");
+        std::fs::write(dir.join("hello.txt"), "hello").unwrap();
+        let dest = dir.join("out.tar.gz");
+        let packed = pack(&dir.join("hello.txt"), ArchiveFormat::TarGz, &dest).unwrap();
+        // `sha256sum out.tar.gz`
+        assert_eq!(
+            packed.sha256,
+            "0e30a284eb109d4cf70da8cc508ca22015f45647fcd28438ca1c3713cd83a3c6"
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn failed_pack_leaves_no_file() {
+        let dir = test_dir("failed");
+        let src = dir.join("src");
+        make_tree(&src);
+        // Zip can't store this symlink, which is only noticed while writing
+        std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(b"\xff"), src.join("zz")).unwrap();
+        let dest = dir.join("out.zip");
+        assert!(pack(&src, ArchiveFormat::Zip, &dest).is_err());
+        assert!(!dest.exists());
+        // An existing archive is kept
+        std::fs::write(&dest, "old").unwrap();
+        assert!(pack(&src, ArchiveFormat::Zip, &dest).is_err());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "old");
+        let names = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<std::collections::BTreeSet<_>>();
+        assert_eq!(names, ["out.zip", "src"].map(String::from).into());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
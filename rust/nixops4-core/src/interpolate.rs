@@ -0,0 +1,193 @@
+//! String templates with `${a.b.c}` references into a JSON document.
+//!
+//! This is explicitly not Nix: there are no expressions, only dotted attribute paths.
+//! It exists for the places where starting an evaluator is not warranted, such as
+//! building a connection string from the recorded outputs of a resource.
+
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// Replace each `${path}` in `template` by the value at `path` in `ctx`.
+///
+/// `path` is a `.`-separated list of object keys, e.g. `resources.db.host`.
+/// Write `$${` to produce a literal `${`. A `$` that is not followed by `{` is copied as is.
+///
+/// Strings are inserted verbatim; numbers and booleans are rendered as in JSON.
+/// Referencing anything else, or a key that does not exist, is an error.
+pub fn interpolate(template: &str, ctx: &Value) -> Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        let tail = &rest[i..];
+        if let Some(after) = tail.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+        } else if let Some(after) = tail.strip_prefix("${") {
+            let end = match after.find('}') {
+                Some(end) => end,
+                None => bail!("unterminated reference in template: {}", tail),
+            };
+            let path = &after[..end];
+            render(lookup(ctx, path)?, path, &mut out)?;
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &tail[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn lookup<'a>(ctx: &'a Value, path: &str) -> Result<&'a Value> {
+    if path.is_empty() {
+        bail!("empty reference ${{}} in template");
+    }
+    let mut current = ctx;
+    let mut parent = String::new();
+    for key in path.split('.') {
+        let map = match current {
+            Value::Object(map) => map,
+            _ => bail!(
+                "unknown reference ${{{}}}: {} is not an attribute set",
+                path,
+                describe_level(&parent)
+            ),
+        };
+        current = match map.get(key) {
+            Some(v) => v,
+            None => {
+                let available: Vec<&str> = map.keys().map(|k| k.as_str()).collect();
+                bail!(
+                    "unknown reference ${{{}}}: no attribute `{}` at {}; available: {}",
+                    path,
+                    key,
+                    describe_level(&parent),
+                    if available.is_empty() {
+                        "none".to_string()
+                    } else {
+                        available.join(", ")
+                    }
+                )
+            }
+        };
+        if !parent.is_empty() {
+            parent.push('.');
+        }
+        parent.push_str(key);
+    }
+    Ok(current)
+}
+
+fn describe_level(parent: &str) -> String {
+    if parent.is_empty() {
+        "the top level".to_string()
+    } else {
+        format!("`{}`", parent)
+    }
+}
+
+fn render(value: &Value, path: &str, out: &mut String) -> Result<()> {
+    match value {
+        Value::String(s) => out.push_str(s),
+        Value::Number(n) => out.push_str(&n.to_string()),
+        Value::Bool(b) => out.push_str(&b.to_string()),
+        Value::Null => bail!("reference ${{{}}} is null", path),
+        Value::Array(_) => bail!(
+            "reference ${{{}}} is a list, which can not be interpolated",
+            path
+        ),
+        Value::Object(_) => bail!(
+            "reference ${{{}}} is an attribute set, which can not be interpolated",
+            path
+        ),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn ctx() -> Value {
+        json!({
+            "resources": {
+                "db": { "host": "db.example.com", "port": 5432, "tls": true },
+                "web": { "host": "web.example.com" }
+            },
+            "name": "prod"
+        })
+    }
+
+    #[test]
+    fn no_references() {
+        assert_eq!(interpolate("plain text", &ctx()).unwrap(), "plain text");
+        assert_eq!(interpolate("", &ctx()).unwrap(), "");
+    }
+
+    #[test]
+    fn nested_references() {
+        let r = interpolate(
+            "postgres://${resources.db.host}:${resources.db.port}/${name}?tls=${resources.db.tls}",
+            &ctx(),
+        )
+        .unwrap();
+        assert_eq!(r, "postgres://db.example.com:5432/prod?tls=true");
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(
+            interpolate("$${resources.db.host} is ${resources.db.host}", &ctx()).unwrap(),
+            "${resources.db.host} is db.example.com"
+        );
+        assert_eq!(interpolate("costs $5, $", &ctx()).unwrap(), "costs $5, $");
+    }
+
+    #[test]
+    fn missing_key_lists_available() {
+        let r = interpolate("${resources.dbx.host}", &ctx());
+        assert_eq!(
+            r.unwrap_err().to_string(),
+            "unknown reference ${resources.dbx.host}: no attribute `dbx` at `resources`; available: db, web"
+        );
+    }
+
+    #[test]
+    fn missing_key_top_level() {
+        let r = interpolate("${nme}", &ctx());
+        assert_eq!(
+            r.unwrap_err().to_string(),
+            "unknown reference ${nme}: no attribute `nme` at the top level; available: name, resources"
+        );
+    }
+
+    #[test]
+    fn descend_into_scalar() {
+        let r = interpolate("${name.first}", &ctx());
+        assert_eq!(
+            r.unwrap_err().to_string(),
+            "unknown reference ${name.first}: `name` is not an attribute set"
+        );
+    }
+
+    #[test]
+    fn non_scalar_value() {
+        let r = interpolate("${resources.db}", &ctx());
+        assert_eq!(
+            r.unwrap_err().to_string(),
+            "reference ${resources.db} is an attribute set, which can not be interpolated"
+        );
+    }
+
+    #[test]
+    fn unterminated() {
+        let r = interpolate("host=${resources.db.host", &ctx());
+        assert!(r
+            .unwrap_err()
+            .to_string()
+            .contains("unterminated reference"));
+    }
+}
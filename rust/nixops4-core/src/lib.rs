@@ -1,3 +1,7 @@
+pub mod artifact;
+pub mod interpolate;
+pub mod workdir;
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -0,0 +1,317 @@
+//! Per-run scratch directories for temporary artifacts.
+//!
+//! Each run of nixops4 gets one directory, `run-<pid>-<millis>`, under a base directory; a clash within the process moves on to the next millisecond.
+//! Within it, artifacts for a resource operation go in `resources/<resource>/<operation>`.
+//! The run directory is removed when the [`Workdir`] is dropped, including on panic, unless it is kept for debugging.
+//! Directories left behind by a crashed run are removed by [`reap_stale`].
+
+use anyhow::{bail, Context as _, Result};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The default base directory, `$XDG_CACHE_HOME/nixops4/workdir`, or `~/.cache/nixops4/workdir`.
+pub fn default_base() -> Result<PathBuf> {
+    let cache = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match std::env::var_os("HOME") {
+            Some(home) if !home.is_empty() => PathBuf::from(home).join(".cache"),
+            _ => bail!(
+                "cannot determine the cache directory: neither XDG_CACHE_HOME nor HOME is set"
+            ),
+        },
+    };
+    Ok(cache.join("nixops4").join("workdir"))
+}
+
+/// The scratch directory of one run.
+pub struct Workdir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Workdir {
+    /// Create a new run directory under `base`, creating `base` if needed.
+    ///
+    /// If `keep` is set, the directory is not removed on drop.
+    pub fn create(base: &Path, keep: bool) -> Result<Workdir> {
+        std::fs::create_dir_all(base)
+            .with_context(|| format!("creating workdir base {}", base.display()))?;
+        let mut millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        loop {
+            let path = base.join(format!("run-{}-{}", std::process::id(), millis));
+            match std::fs::create_dir(&path) {
+                Ok(()) => return Ok(Workdir { path, keep }),
+                // Another Workdir of this process, created in the same millisecond
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => millis += 1,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("creating workdir {}", path.display()))
+                }
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the directory will be kept after the run.
+    pub fn keep(&self) -> bool {
+        self.keep
+    }
+
+    /// The directory for one operation on one resource, e.g. `resources/web/create`, created if needed.
+    ///
+    /// The same arguments always yield the same directory within a run.
+    pub fn operation_dir(&self, resource: &str, operation: &str) -> Result<PathBuf> {
+        check_component("resource name", resource)?;
+        check_component("operation name", operation)?;
+        let dir = self.path.join("resources").join(resource).join(operation);
+        std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        Ok(dir)
+    }
+}
+
+impl Drop for Workdir {
+    fn drop(&mut self) {
+        if !self.keep {
+            // Nothing sensible to do about a failure here; whatever is left is reaped by a later run
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+fn check_component(what: &str, s: &str) -> Result<()> {
+    if s.is_empty() || s == "." || s == ".." || s.contains('/') || s.contains('\0') {
+        bail!("{} can not be used as a directory name: {:?}", what, s);
+    }
+    Ok(())
+}
+
+/// The outcome of [`reap_stale`].
+#[derive(Debug, Default)]
+pub struct Reaped {
+    /// The directories that were removed.
+    pub removed: Vec<PathBuf>,
+    /// Why other stale directories could not be checked or removed.
+    pub errors: Vec<anyhow::Error>,
+}
+
+/// Remove run directories under `base` that were left behind by a run that is no longer alive.
+///
+/// A directory is removed when its owning process does not exist anymore and it was last modified more than `older_than` ago.
+/// A directory that can not be removed does not stop the others from being removed; its error is collected in [`Reaped::errors`].
+/// Only failing to read `base` itself is an error of the whole call.
+pub fn reap_stale(base: &Path, older_than: Duration) -> Result<Reaped> {
+    let entries = match std::fs::read_dir(base) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Reaped::default()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", base.display())),
+    };
+    let now = SystemTime::now();
+    let mut reaped = Reaped::default();
+    for entry in entries {
+        match reap_entry(entry, base, now, older_than) {
+            Ok(Some(path)) => reaped.removed.push(path),
+            Ok(None) => {}
+            Err(e) => reaped.errors.push(e),
+        }
+    }
+    Ok(reaped)
+}
+
+/// Remove one entry of the base directory if it is a stale run directory, and return its path if so.
+fn reap_entry(
+    entry: std::io::Result<std::fs::DirEntry>,
+    base: &Path,
+    now: SystemTime,
+    older_than: Duration,
+) -> Result<Option<PathBuf>> {
+    let entry = entry.with_context(|| format!("reading {}", base.display()))?;
+    let pid = match entry.file_name().to_str().and_then(parse_run_dir_pid) {
+        Some(pid) => pid,
+        None => return Ok(None),
+    };
+    if pid == std::process::id() || process_alive(pid) {
+        return Ok(None);
+    }
+    let path = entry.path();
+    let modified = entry
+        .metadata()
+        .and_then(|m| m.modified())
+        .with_context(|| format!("reading metadata of {}", path.display()))?;
+    let age = now.duration_since(modified).unwrap_or_default();
+    if age < older_than {
+        return Ok(None);
+    }
+    std::fs::remove_dir_all(&path)
+        .with_context(|| format!("removing stale workdir {}", path.display()))?;
+    Ok(Some(path))
+}
+
+fn parse_run_dir_pid(name: &str) -> Option<u32> {
+    let rest = name.strip_prefix("run-")?;
+    let (pid, millis) = rest.split_once('-')?;
+    millis.parse::<u128>().ok()?;
+    pid.parse().ok()
+}
+
+fn process_alive(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        // Not a pid that a run directory could have been created by
+        _ => return false,
+    };
+    // Signal 0 only checks whether the process exists and we may signal it
+    let r = unsafe { libc::kill(pid, 0) };
+    r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_base(name: &str) -> PathBuf {
+        let base = std::env::temp_dir().join(format!(
+            "nixops4-core-test-workdir-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&base);
+        base
+    }
+
+    #[test]
+    fn cleanup_on_drop() {
+        let base = test_base("cleanup");
+        let wd = Workdir::create(&base, false).unwrap();
+        let path = wd.path().to_path_buf();
+        let dir = wd.operation_dir("web", "create").unwrap();
+        std::fs::write(dir.join("input.json"), "{}").unwrap();
+        assert!(path.is_dir());
+        drop(wd);
+        assert!(!path.exists());
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn keep_retains() {
+        let base = test_base("keep");
+        let wd = Workdir::create(&base, true).unwrap();
+        let path = wd.path().to_path_buf();
+        drop(wd);
+        assert!(path.is_dir());
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn operation_dir_deterministic() {
+        let base = test_base("operation-dir");
+        let wd = Workdir::create(&base, false).unwrap();
+        let a = wd.operation_dir("db", "update").unwrap();
+        let b = wd.operation_dir("db", "update").unwrap();
+        assert_eq!(a, b);
+        assert_eq!(a, wd.path().join("resources/db/update"));
+        assert!(a.is_dir());
+        drop(wd);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn operation_dir_rejects_bad_names() {
+        let base = test_base("bad-names");
+        let wd = Workdir::create(&base, false).unwrap();
+        for bad in ["", ".", "..", "a/b"] {
+            assert!(wd.operation_dir(bad, "create").is_err(), "{:?}", bad);
+            assert!(wd.operation_dir("web", bad).is_err(), "{:?}", bad);
+        }
+        drop(wd);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    fn set_mtime(path: &Path, mtime: SystemTime) {
+        std::fs::File::open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    #[test]
+    fn create_twice_in_the_same_millisecond() {
+        let base = test_base("create-twice");
+        let wds = (0..20)
+            .map(|_| Workdir::create(&base, false).unwrap())
+            .collect::<Vec<_>>();
+        let mut paths = wds.iter().map(|wd| wd.path()).collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        assert_eq!(paths.len(), wds.len());
+        for wd in &wds {
+            assert!(parse_run_dir_pid(wd.path().file_name().unwrap().to_str().unwrap()).is_some());
+        }
+        drop(wds);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn reap_stale_dirs() {
+        let base = test_base("reap");
+        std::fs::create_dir_all(&base).unwrap();
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 3600);
+        // Larger than any pid_max, so never alive
+        let old = ["run-999999999-1000", "run-999999998-1000"].map(|name| base.join(name));
+        for dir in &old {
+            std::fs::create_dir(dir).unwrap();
+            std::fs::write(dir.join("leftover"), "x").unwrap();
+            set_mtime(dir, two_hours_ago);
+        }
+        // Not a directory, so it can not be removed as one
+        let bad = base.join("run-999999996-1000");
+        std::fs::write(&bad, "x").unwrap();
+        set_mtime(&bad, two_hours_ago);
+        let fresh = base.join("run-999999997-1000");
+        std::fs::create_dir(&fresh).unwrap();
+        let unrelated = base.join("not-a-run");
+        std::fs::create_dir(&unrelated).unwrap();
+        set_mtime(&unrelated, two_hours_ago);
+        let live = Workdir::create(&base, false).unwrap();
+        set_mtime(live.path(), two_hours_ago);
+
+        let reaped = reap_stale(&base, Duration::from_secs(3600)).unwrap();
+        assert_eq!(reaped.errors.len(), 1, "{:?}", reaped.errors);
+        let error = format!("{:#}", reaped.errors[0]);
+        assert!(error.contains("run-999999996-1000"), "{}", error);
+        let mut removed = reaped.removed;
+        removed.sort();
+        let mut expected = old.to_vec();
+        expected.sort();
+        assert_eq!(removed, expected);
+        for dir in &old {
+            assert!(!dir.exists());
+        }
+        assert!(fresh.exists());
+        assert!(unrelated.exists());
+        assert!(live.path().exists());
+
+        drop(live);
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn reap_stale_missing_base() {
+        let base = test_base("reap-missing");
+        let reaped = reap_stale(&base, Duration::ZERO).unwrap();
+        assert!(reaped.removed.is_empty());
+        assert!(reaped.errors.is_empty());
+    }
+
+    #[test]
+    fn parse_pid() {
+        assert_eq!(parse_run_dir_pid("run-123-456"), Some(123));
+        assert_eq!(parse_run_dir_pid("run-123"), None);
+        assert_eq!(parse_run_dir_pid("run-x-456"), None);
+        assert_eq!(parse_run_dir_pid("other-123-456"), None);
+    }
+}